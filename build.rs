@@ -29,7 +29,9 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
-    println!("cargo:rustc-link-arg-bins=--nmagic");
-    println!("cargo:rustc-link-arg-bins=-Tlink.x");
-    println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+    // Only the firmware binary uses the embedded linker scripts; the host
+    // simulator links like any other desktop program.
+    println!("cargo:rustc-link-arg-bin=pico2-synth=--nmagic");
+    println!("cargo:rustc-link-arg-bin=pico2-synth=-Tlink.x");
+    println!("cargo:rustc-link-arg-bin=pico2-synth=-Tdefmt.x");
 }
@@ -0,0 +1,97 @@
+//! Analog control inputs read through the RP2350 ADC.
+//!
+//! A spring-return pitch-bend pot (or one axis of a joystick) is wired as a
+//! voltage divider between 3V3 and GND with the wiper on an ADC-capable pin.
+//! An optional second pot drives a modulation target (the resonator frequency,
+//! the same target the ToF sensor controls).
+//!
+//! ADC-capable pins on the RP2350A are GPIO 26-29. GPIO 26/27 are used by the
+//! I2C bus for the VL53L0X and GPIO 29 is wired to the VSYS divider on the
+//! Pico 2 board, so by default only the bend input on GPIO 28 is enabled.
+
+use embassy_rp::adc::{Adc, Async, Channel};
+use embassy_time::{Duration, Ticker};
+use fundsp::shared::Shared;
+
+/// Full-scale reading of the 12-bit ADC
+const ADC_MAX: f32 = 4095.0;
+
+/// Assignment and scaling of the analog control inputs.
+#[derive(Clone, Copy)]
+pub struct AnalogConfig {
+    /// Pitch bend in semitones at full deflection (either direction)
+    pub bend_range: f32,
+    /// Dead zone around the pot center, as a fraction of half travel (0.0-1.0).
+    /// Readings inside it snap to exactly zero bend (ratio 1.0).
+    pub center_deadzone: f32,
+    /// Mod target value at the bottom of the mod pot travel
+    pub mod_min: f32,
+    /// Mod target value at the top of the mod pot travel
+    pub mod_max: f32,
+    /// Time between ADC polls
+    pub poll_interval: Duration,
+}
+
+impl Default for AnalogConfig {
+    fn default() -> Self {
+        Self {
+            bend_range: 2.0,
+            center_deadzone: 0.06,
+            mod_min: 0.0,
+            mod_max: 1480.0,
+            poll_interval: Duration::from_millis(5),
+        }
+    }
+}
+
+impl AnalogConfig {
+    /// Convert a raw ADC reading into pitch bend semitones.
+    /// The dead zone is removed from the travel so the output still reaches
+    /// the full bend range at the end stops without a jump at the edge.
+    pub fn bend_from_raw(&self, raw: u16) -> f32 {
+        // -1.0 .. 1.0 around the electrical center
+        let pos = (raw as f32 / ADC_MAX) * 2.0 - 1.0;
+        let magnitude = libm::fabsf(pos);
+        if magnitude <= self.center_deadzone {
+            return 0.0;
+        }
+        let scaled = ((magnitude - self.center_deadzone) / (1.0 - self.center_deadzone)).min(1.0);
+        libm::copysignf(scaled * self.bend_range, pos)
+    }
+
+    /// Convert a raw ADC reading into a mod target value.
+    pub fn mod_from_raw(&self, raw: u16) -> f32 {
+        self.mod_min + (raw as f32 / ADC_MAX) * (self.mod_max - self.mod_min)
+    }
+}
+
+// Task to poll the analog bend (and optional mod) pots.
+// Bend is published in semitones to `bend`, which the audio loop applies via
+// `set_pitch_bend`; the mod pot writes directly into its target Shared.
+#[embassy_executor::task]
+pub async fn analog_task(
+    mut adc: Adc<'static, Async>,
+    mut bend_channel: Channel<'static>,
+    mut mod_channel: Option<Channel<'static>>,
+    config: AnalogConfig,
+    bend: Shared,
+    mod_target: Shared,
+) {
+    let mut ticker = Ticker::every(config.poll_interval);
+
+    loop {
+        match adc.read(&mut bend_channel).await {
+            Ok(raw) => bend.set_value(config.bend_from_raw(raw)),
+            Err(_) => defmt::warn!("ADC bend read failed"),
+        }
+
+        if let Some(channel) = mod_channel.as_mut() {
+            match adc.read(channel).await {
+                Ok(raw) => mod_target.set_value(config.mod_from_raw(raw)),
+                Err(_) => defmt::warn!("ADC mod read failed"),
+            }
+        }
+
+        ticker.next().await;
+    }
+}
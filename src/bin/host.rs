@@ -0,0 +1,216 @@
+//! Host simulator: runs `KeyboardSynth` on the desktop and renders a scripted
+//! performance to a 16-bit stereo WAV file (or raw samples on stdout), so the
+//! DSP and voice logic can be checked without flashing hardware.
+//!
+//! Run from the crate root (the default target is the Pico, so the host
+//! target has to be given explicitly):
+//!
+//! ```text
+//! cargo run --release --no-default-features --features simulator \
+//!     --bin host --target x86_64-unknown-linux-gnu -- synth.wav
+//! ```
+//!
+//! Pass `-` instead of a file name to write raw interleaved little-endian i16
+//! samples to stdout, e.g. to pipe into `aplay -f S16_LE -c 2 -r 44100`.
+//!
+//! Pass `--aliasing` to print how much of a top-octave saw's energy is
+//! aliasing (energy off the harmonic series) with 1x and 2x oversampling.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use pico2_synth::keyboard::KeyboardSynth;
+
+const SAMPLE_RATE: u32 = 44_100;
+/// Same block size as the firmware's DMA buffers
+const BUFFER_SIZE: usize = 640;
+/// Length of the rendered performance in seconds
+const DURATION: f32 = 8.0;
+const CHANNELS: u16 = 2;
+
+/// A key event at `time` seconds: (time, key, octave, pressed)
+type Event = (f32, usize, u8, bool);
+
+/// C major chord, a melody on top that forces voice stealing, then release
+const SCRIPT: &[Event] = &[
+    // C4 E4 G4 chord
+    (0.1, 0, 1, true),
+    (0.1, 4, 1, true),
+    (0.1, 7, 1, true),
+    // Melody C5 D5 E5 F5 G5 (8 notes held > 7 voices -> steals)
+    (1.0, 0, 2, true),
+    (1.5, 2, 2, true),
+    (2.0, 4, 2, true),
+    (2.5, 5, 2, true),
+    (3.0, 7, 2, true),
+    // Release everything
+    (5.0, 0, 1, false),
+    (5.0, 4, 1, false),
+    (5.0, 7, 1, false),
+    (5.0, 0, 2, false),
+    (5.0, 2, 2, false),
+    (5.0, 4, 2, false),
+    (5.0, 5, 2, false),
+    (5.0, 7, 2, false),
+];
+
+fn main() -> io::Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "synth.wav".into());
+    if path == "--aliasing" {
+        aliasing_report();
+        return Ok(());
+    }
+    let samples = render();
+
+    if path == "-" {
+        let mut out = BufWriter::new(io::stdout().lock());
+        write_samples(&mut out, &samples)?;
+        out.flush()
+    } else {
+        let mut out = BufWriter::new(File::create(&path)?);
+        write_wav(&mut out, &samples)?;
+        out.flush()?;
+        eprintln!("Wrote {} samples to {}", samples.len(), path);
+        Ok(())
+    }
+}
+
+/// Play the script through the synth one buffer at a time, like the firmware.
+/// Returns interleaved left/right samples.
+fn render() -> Vec<i16> {
+    let mut synth = KeyboardSynth::new();
+    let total = (DURATION * SAMPLE_RATE as f32) as usize * CHANNELS as usize;
+    let mut samples = Vec::with_capacity(total);
+    let mut left = [0.0f32; BUFFER_SIZE];
+    let mut right = [0.0f32; BUFFER_SIZE];
+    let mut next_event = 0;
+
+    while samples.len() < total {
+        let now = (samples.len() / CHANNELS as usize) as f32 / SAMPLE_RATE as f32;
+        while let Some(&(time, key, octave, pressed)) = SCRIPT.get(next_event) {
+            if time > now {
+                break;
+            }
+            synth.update_key(key, octave, pressed);
+            next_event += 1;
+        }
+
+        synth.process_block_stereo(&mut left, &mut right);
+        for (l, r) in left.iter().zip(&right) {
+            samples.push((l * 32767.0) as i16);
+            samples.push((r * 32767.0) as i16);
+        }
+    }
+    samples.truncate(total);
+    samples
+}
+
+/// Samples analysed per aliasing measurement (~0.37 s, 2.7 Hz bins)
+const ALIAS_FFT_SIZE: usize = 16_384;
+/// Bins either side of a harmonic counted as harmonic energy (window main lobe)
+const ALIAS_HARMONIC_WIDTH: f32 = 5.0;
+
+/// Play B6, the top key, unbent and bent up 2 semitones at each
+/// oversampling factor and print the aliasing energy relative to the total.
+fn aliasing_report() {
+    println!("note        1x        2x");
+    for bend in [0.0, 2.0] {
+        let ratios = [1, 2].map(|factor| {
+            let mut synth = KeyboardSynth::new();
+            synth.set_oversampling(factor);
+            synth.set_envelope(0.001, 0.001, 1.0, 0.5);
+            synth.filter_cutoff_control().set(20_000.0);
+            synth.set_pitch_bend(bend);
+            synth.update_key(11, 3, true);
+            let mut block = vec![0.0f32; ALIAS_FFT_SIZE];
+            // Let the envelope settle, then measure
+            synth.process_block(&mut block, ALIAS_FFT_SIZE);
+            synth.process_block(&mut block, ALIAS_FFT_SIZE);
+            alias_ratio(&block)
+        });
+        println!(
+            "B6{:+.0}   {:6.1} dB {:6.1} dB",
+            bend,
+            10.0 * ratios[0].log10(),
+            10.0 * ratios[1].log10()
+        );
+    }
+}
+
+/// Fraction of the energy of a periodic signal that lies off its harmonic
+/// series, from a Blackman-Harris windowed DFT. The fundamental is taken
+/// from the strongest peak.
+fn alias_ratio(samples: &[f32]) -> f32 {
+    use std::f32::consts::TAU;
+    let n = samples.len();
+    let windowed: Vec<f32> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let x = TAU * i as f32 / n as f32;
+            s * (0.35875 - 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos()
+                - 0.01168 * (3.0 * x).cos())
+        })
+        .collect();
+    let twiddles: Vec<(f32, f32)> = (0..n)
+        .map(|i| (TAU * i as f32 / n as f32).sin_cos())
+        .collect();
+    let power: Vec<f32> = (0..n / 2)
+        .map(|bin| {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, s) in windowed.iter().enumerate() {
+                let (sin, cos) = twiddles[bin * i % n];
+                re += s * cos;
+                im -= s * sin;
+            }
+            re * re + im * im
+        })
+        .collect();
+
+    let peak = (1..power.len() - 1)
+        .max_by(|a, b| power[*a].total_cmp(&power[*b]))
+        .unwrap();
+    // Parabolic interpolation on the log power for a sub-bin fundamental,
+    // so the upper harmonics stay inside their windows
+    let (l, c, r) = (power[peak - 1].ln(), power[peak].ln(), power[peak + 1].ln());
+    let fundamental = peak as f32 + 0.5 * (l - r) / (l - 2.0 * c + r);
+    let total: f32 = power.iter().skip(1).sum();
+    let aliased: f32 = power
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(bin, _)| {
+            let harmonic = (*bin as f32 / fundamental).round() * fundamental;
+            (*bin as f32 - harmonic).abs() > ALIAS_HARMONIC_WIDTH
+        })
+        .map(|(_, p)| p)
+        .sum();
+    aliased / total
+}
+
+fn write_samples(out: &mut impl Write, samples: &[i16]) -> io::Result<()> {
+    for sample in samples {
+        out.write_all(&sample.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Write a canonical 44-byte-header PCM WAV file.
+fn write_wav(out: &mut impl Write, samples: &[i16]) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?; // fmt chunk size
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&(SAMPLE_RATE * CHANNELS as u32 * 2).to_le_bytes())?; // byte rate
+    out.write_all(&(CHANNELS * 2).to_le_bytes())?; // block align
+    out.write_all(&16u16.to_le_bytes())?; // bits per sample
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    write_samples(out, samples)
+}
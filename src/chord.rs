@@ -0,0 +1,179 @@
+//! Chord recognition over the held notes, for showing the played chord on a
+//! display.
+//!
+//! The held notes are reduced to pitch classes and matched against a table
+//! of chord shapes with each held pitch class tried as the root, the bass
+//! note first. So the bass decides between chords with the same notes
+//! (C-E-G-A is C6 over C but Am7/C over A, shown as the root position
+//! name with the bass after a slash), and any inversion or doubling of a
+//! known shape is recognized. Seventh chords without their fifth and bare
+//! fifths are in the table; anything else (single notes, clusters) gives
+//! no name.
+
+use core::fmt;
+
+/// Note names by pitch class, C = 0
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Chord type, named from its root.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Sus2,
+    Sus4,
+    /// Root and fifth only
+    Power,
+    Major6,
+    Minor6,
+    Dominant7,
+    Major7,
+    Minor7,
+    MinorMajor7,
+    HalfDiminished7,
+    Diminished7,
+    Add9,
+}
+
+impl ChordQuality {
+    /// Suffix after the root name, e.g. "m7".
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Self::Major => "",
+            Self::Minor => "m",
+            Self::Diminished => "dim",
+            Self::Augmented => "aug",
+            Self::Sus2 => "sus2",
+            Self::Sus4 => "sus4",
+            Self::Power => "5",
+            Self::Major6 => "6",
+            Self::Minor6 => "m6",
+            Self::Dominant7 => "7",
+            Self::Major7 => "maj7",
+            Self::Minor7 => "m7",
+            Self::MinorMajor7 => "mMaj7",
+            Self::HalfDiminished7 => "m7b5",
+            Self::Diminished7 => "dim7",
+            Self::Add9 => "add9",
+        }
+    }
+}
+
+/// Pitch-class set from intervals above the root.
+const fn shape(intervals: &[u8]) -> u16 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < intervals.len() {
+        mask |= 1 << intervals[i];
+        i += 1;
+    }
+    mask
+}
+
+/// Chord shapes as pitch-class sets above the root. Where two shapes are
+/// the same set from different roots (C6 and Am7), the bass picks.
+const CHORD_SHAPES: [(u16, ChordQuality); 19] = [
+    (shape(&[0, 4, 7]), ChordQuality::Major),
+    (shape(&[0, 3, 7]), ChordQuality::Minor),
+    (shape(&[0, 3, 6]), ChordQuality::Diminished),
+    (shape(&[0, 4, 8]), ChordQuality::Augmented),
+    (shape(&[0, 2, 7]), ChordQuality::Sus2),
+    (shape(&[0, 5, 7]), ChordQuality::Sus4),
+    (shape(&[0, 7]), ChordQuality::Power),
+    (shape(&[0, 4, 7, 9]), ChordQuality::Major6),
+    (shape(&[0, 3, 7, 9]), ChordQuality::Minor6),
+    (shape(&[0, 4, 7, 10]), ChordQuality::Dominant7),
+    (shape(&[0, 4, 7, 11]), ChordQuality::Major7),
+    (shape(&[0, 3, 7, 10]), ChordQuality::Minor7),
+    (shape(&[0, 3, 7, 11]), ChordQuality::MinorMajor7),
+    (shape(&[0, 3, 6, 10]), ChordQuality::HalfDiminished7),
+    (shape(&[0, 3, 6, 9]), ChordQuality::Diminished7),
+    (shape(&[0, 2, 4, 7]), ChordQuality::Add9),
+    // Sevenths with the fifth left out, as often voiced
+    (shape(&[0, 4, 10]), ChordQuality::Dominant7),
+    (shape(&[0, 4, 11]), ChordQuality::Major7),
+    (shape(&[0, 3, 10]), ChordQuality::Minor7),
+];
+
+/// A recognized chord: root, type and the note in the bass.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct ChordName {
+    /// Pitch class of the root, C = 0
+    pub root: u8,
+    pub quality: ChordQuality,
+    /// Pitch class of the lowest held note, the root unless inverted
+    pub bass: u8,
+}
+
+impl fmt::Display for ChordName {
+    /// Formats as e.g. "Cmaj7", "Dm" or "C/E" for an inversion.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            NOTE_NAMES[self.root as usize],
+            self.quality.suffix()
+        )?;
+        if self.bass != self.root {
+            write!(f, "/{}", NOTE_NAMES[self.bass as usize])?;
+        }
+        Ok(())
+    }
+}
+
+/// Name the chord formed by a set of pitch classes (bit n = pitch class n)
+/// with `bass` the lowest one, or `None` if it isn't a known shape.
+pub fn recognize(pitch_classes: u16, bass: u8) -> Option<ChordName> {
+    let pitch_classes = pitch_classes & 0xFFF;
+    let roots = core::iter::once(bass).chain((0..12).filter(move |&root| root != bass));
+    for root in roots.filter(|&root| pitch_classes & (1 << root) != 0) {
+        // Rotate so the root is bit 0
+        let relative = ((pitch_classes >> root) | (pitch_classes << (12 - root))) & 0xFFF;
+        if let Some(&(_, quality)) = CHORD_SHAPES.iter().find(|(shape, _)| *shape == relative) {
+            return Some(ChordName {
+                root,
+                quality,
+                bass,
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    /// Name for notes given as semitones above C (any octave), lowest first
+    fn name(notes: &[u8]) -> Option<alloc::string::String> {
+        let mask = notes.iter().fold(0, |mask, note| mask | 1 << (note % 12));
+        recognize(mask, notes[0] % 12).map(|chord| chord.to_string())
+    }
+
+    #[test]
+    fn known_chords_inversions_and_partials() {
+        assert_eq!(name(&[0, 4, 7]).as_deref(), Some("C"));
+        assert_eq!(name(&[2, 5, 9]).as_deref(), Some("Dm"));
+        assert_eq!(name(&[0, 4, 7, 11]).as_deref(), Some("Cmaj7"));
+        assert_eq!(name(&[7, 11, 14, 17]).as_deref(), Some("G7"));
+        assert_eq!(name(&[11, 14, 17, 21]).as_deref(), Some("Bm7b5"));
+        assert_eq!(name(&[6, 10, 13]).as_deref(), Some("F#"));
+        // Inversions and doublings keep the root, with the bass shown
+        assert_eq!(name(&[4, 7, 12]).as_deref(), Some("C/E"));
+        assert_eq!(name(&[7, 12, 16, 19, 24]).as_deref(), Some("C/G"));
+        // The bass picks between same-note chords
+        assert_eq!(name(&[0, 4, 7, 9]).as_deref(), Some("C6"));
+        assert_eq!(name(&[9, 12, 16, 19]).as_deref(), Some("Am7"));
+        // Partial chords: no fifth, bare fifth; too little or a cluster
+        assert_eq!(name(&[0, 4, 10]).as_deref(), Some("C7"));
+        assert_eq!(name(&[9, 16]).as_deref(), Some("A5"));
+        assert_eq!(name(&[0]), None);
+        assert_eq!(name(&[0, 4]), None);
+        assert_eq!(name(&[0, 1, 2]), None);
+    }
+}
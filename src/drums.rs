@@ -0,0 +1,236 @@
+//! Step-sequenced drum machine: kick, snare and hi-hat tracks of
+//! `DRUM_STEPS` 16th-note steps, looping at the synth's tempo.
+//!
+//! The drums are synthesized per frame and mixed into the output, so they
+//! don't take any of the melodic voices: the kick is a sine swept down from
+//! 150 Hz, the snare a tone plus noise and the hat differentiated noise,
+//! each with an exponential decay. A step retriggers its sound from the
+//! start. Idle sounds are skipped, so a silent machine costs a few compares
+//! per frame.
+
+use fundsp::DEFAULT_SR;
+
+/// Steps in a pattern, 16ths over one bar
+pub const DRUM_STEPS: usize = 16;
+/// Drum tracks
+pub const DRUM_TRACKS: usize = 3;
+/// Default drum mix level
+pub const DRUM_LEVEL: f32 = 0.5;
+/// Level below which a sound counts as finished
+const DRUM_SILENCE: f32 = 1e-4;
+
+/// Kick pitch: `KICK_FREQ` plus `KICK_SWEEP` decaying over `KICK_SWEEP_TIME`
+const KICK_FREQ: f32 = 50.0;
+const KICK_SWEEP: f32 = 100.0;
+const KICK_SWEEP_TIME: f32 = 0.03;
+const KICK_DECAY: f32 = 0.25;
+/// Snare body tone and the share of noise in the mix
+const SNARE_FREQ: f32 = 185.0;
+const SNARE_NOISE: f32 = 0.6;
+const SNARE_DECAY: f32 = 0.12;
+const HAT_DECAY: f32 = 0.04;
+
+/// One of the drum machine's tracks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum DrumTrack {
+    Kick = 0,
+    Snare = 1,
+    Hat = 2,
+}
+
+/// Per-frame decay multiplier for an exponential fall to 1/e in `seconds`.
+fn decay(seconds: f32) -> f32 {
+    libm::expf(-1.0 / (seconds * DEFAULT_SR as f32))
+}
+
+/// State of one drum sound.
+#[derive(Clone, Copy, Default)]
+struct DrumSound {
+    amp: f32,
+    /// Level of the kick's pitch sweep
+    sweep: f32,
+    /// Kick or snare tone phase, 0.0-1.0
+    phase: f32,
+    /// Previous noise sample, for the hat's highpass
+    last_noise: f32,
+}
+
+/// Drum patterns, step clock and sound generators.
+pub struct DrumMachine {
+    /// One bit per step for each track
+    patterns: [u16; DRUM_TRACKS],
+    playing: bool,
+    /// Step about to play
+    step: usize,
+    /// Frames left until `step` plays
+    until_step: f32,
+    /// Frames per 16th-note step
+    step_frames: f32,
+    level: f32,
+    sounds: [DrumSound; DRUM_TRACKS],
+    /// Per-frame decays: kick sweep, kick, snare, hat
+    decays: [f32; 4],
+    /// xorshift32 state
+    rng: u32,
+}
+
+impl Default for DrumMachine {
+    fn default() -> Self {
+        let mut drums = Self {
+            patterns: [0; DRUM_TRACKS],
+            playing: false,
+            step: 0,
+            until_step: 0.0,
+            step_frames: 0.0,
+            level: DRUM_LEVEL,
+            sounds: [DrumSound::default(); DRUM_TRACKS],
+            decays: [
+                decay(KICK_SWEEP_TIME),
+                decay(KICK_DECAY),
+                decay(SNARE_DECAY),
+                decay(HAT_DECAY),
+            ],
+            rng: 0xA54F_F53A,
+        };
+        drums.set_tempo(crate::keyboard::TEMPO);
+        drums
+    }
+}
+
+impl DrumMachine {
+    /// Switch a step (0 to `DRUM_STEPS - 1`) of a track on or off.
+    pub fn set_step(&mut self, track: DrumTrack, step: usize, on: bool) {
+        if step >= DRUM_STEPS {
+            return;
+        }
+        let pattern = &mut self.patterns[track as usize];
+        if on {
+            *pattern |= 1 << step;
+        } else {
+            *pattern &= !(1 << step);
+        }
+    }
+
+    pub fn step(&self, track: DrumTrack, step: usize) -> bool {
+        step < DRUM_STEPS && self.patterns[track as usize] & (1 << step) != 0
+    }
+
+    /// Whether every track's steps are off.
+    pub fn is_empty(&self) -> bool {
+        self.patterns.iter().all(|&pattern| pattern == 0)
+    }
+
+    /// Clear every track's steps.
+    pub fn clear(&mut self) {
+        self.patterns = [0; DRUM_TRACKS];
+    }
+
+    /// Kick on every beat, snare on 2 and 4, hat on the off-beat 8ths.
+    pub fn four_on_the_floor(&mut self) {
+        self.clear();
+        for beat in 0..4 {
+            self.set_step(DrumTrack::Kick, beat * 4, true);
+            self.set_step(DrumTrack::Hat, beat * 4 + 2, true);
+        }
+        self.set_step(DrumTrack::Snare, 4, true);
+        self.set_step(DrumTrack::Snare, 12, true);
+    }
+
+    /// Step length from the tempo in BPM, 4 steps to the beat.
+    pub(crate) fn set_tempo(&mut self, bpm: f32) {
+        self.step_frames = DEFAULT_SR as f32 * 60.0 / (bpm * 4.0);
+    }
+
+    /// Start the pattern from step 0 in `delay` frames, or stop it (the
+    /// sounding hits ring out).
+    pub(crate) fn set_playing(&mut self, on: bool, delay: usize) {
+        if on && !self.playing {
+            self.step = 0;
+            self.until_step = delay as f32;
+        }
+        self.playing = on;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Frames until the next step plays.
+    pub(crate) fn frames_to_step(&self) -> u64 {
+        libm::ceilf(self.until_step.max(0.0)) as u64
+    }
+
+    /// Mix level of the drums (0.0-1.0).
+    pub fn set_level(&mut self, level: f32) {
+        self.level = level.clamp(0.0, 1.0);
+    }
+
+    /// Play a track's sound now, as a step would.
+    pub fn trigger(&mut self, track: DrumTrack) {
+        let sound = &mut self.sounds[track as usize];
+        sound.amp = 1.0;
+        sound.sweep = 1.0;
+        sound.phase = 0.0;
+    }
+
+    /// Random value in -1.0..1.0
+    fn noise(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+
+    /// Advance the step clock one frame and return the drum mix.
+    #[inline]
+    pub fn process(&mut self) -> f32 {
+        if self.playing {
+            if self.until_step <= 0.0 {
+                for track in [DrumTrack::Kick, DrumTrack::Snare, DrumTrack::Hat] {
+                    if self.step(track, self.step) {
+                        self.trigger(track);
+                    }
+                }
+                self.step = (self.step + 1) % DRUM_STEPS;
+                self.until_step += self.step_frames;
+            }
+            self.until_step -= 1.0;
+        }
+        if self.sounds.iter().all(|sound| sound.amp < DRUM_SILENCE) {
+            return 0.0;
+        }
+
+        let sample_duration = 1.0 / DEFAULT_SR as f32;
+        let [sweep_decay, kick_decay, snare_decay, hat_decay] = self.decays;
+        let mut mix = 0.0;
+
+        let kick = &mut self.sounds[DrumTrack::Kick as usize];
+        if kick.amp >= DRUM_SILENCE {
+            let freq = KICK_FREQ + KICK_SWEEP * kick.sweep;
+            kick.phase = libm::fmodf(kick.phase + freq * sample_duration, 1.0);
+            mix += libm::sinf(core::f32::consts::TAU * kick.phase) * kick.amp;
+            kick.sweep *= sweep_decay;
+            kick.amp *= kick_decay;
+        }
+
+        if self.sounds[DrumTrack::Snare as usize].amp >= DRUM_SILENCE {
+            let noise = self.noise();
+            let snare = &mut self.sounds[DrumTrack::Snare as usize];
+            snare.phase = libm::fmodf(snare.phase + SNARE_FREQ * sample_duration, 1.0);
+            let tone = libm::sinf(core::f32::consts::TAU * snare.phase);
+            mix += (noise * SNARE_NOISE + tone * (1.0 - SNARE_NOISE)) * snare.amp;
+            snare.amp *= snare_decay;
+        }
+
+        if self.sounds[DrumTrack::Hat as usize].amp >= DRUM_SILENCE {
+            let noise = self.noise();
+            let hat = &mut self.sounds[DrumTrack::Hat as usize];
+            // First difference: a crude highpass leaving the sizzle
+            mix += (noise - hat.last_noise) * 0.5 * hat.amp;
+            hat.last_noise = noise;
+            hat.amp *= hat_decay;
+        }
+
+        mix * self.level
+    }
+}
@@ -0,0 +1,38 @@
+//! Stream generated audio over defmt/RTT for host-side analysis.
+//!
+//! Enabled with the `dump_audio` feature. Each filled buffer is decimated by
+//! `DUMP_DECIMATION` (no anti-alias filter, so only use it for waveform and
+//! level checks below the reduced Nyquist, or set it to 1 for full rate) and
+//! sent as one `audio` frame of little-endian i16 samples. Only the left
+//! channel is sent.
+//!
+//! defmt-rtt drops data when the host can't keep up instead of blocking, so
+//! the dump never stalls the audio loop. At the default decimation it costs
+//! ~22 kB/s of RTT bandwidth. Without the feature `dump_block` is a no-op.
+
+/// Keep every Nth sample of each buffer
+#[cfg(feature = "dump_audio")]
+pub const DUMP_DECIMATION: usize = 4;
+
+/// Largest audio buffer the dump has to handle
+#[cfg(feature = "dump_audio")]
+const DUMP_MAX_SAMPLES: usize = 1024;
+
+#[cfg(feature = "dump_audio")]
+pub fn dump_block(block: &[f32]) {
+    let mut bytes = [0u8; DUMP_MAX_SAMPLES / DUMP_DECIMATION * 2];
+    let mut len = 0;
+    for sample in block.iter().step_by(DUMP_DECIMATION) {
+        if len + 2 > bytes.len() {
+            break;
+        }
+        let value = (sample * 32767.0) as i16;
+        bytes[len..len + 2].copy_from_slice(&value.to_le_bytes());
+        len += 2;
+    }
+    defmt::info!("audio {=[u8]}", &bytes[..len]);
+}
+
+#[cfg(not(feature = "dump_audio"))]
+#[inline(always)]
+pub fn dump_block(_block: &[f32]) {}
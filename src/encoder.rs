@@ -0,0 +1,140 @@
+//! Quadrature rotary encoder decoding, for picking the octave with a knob
+//! instead of strobing the key matrix.
+//!
+//! The encoder's A and B contacts are read as two levels; each change moves
+//! a Gray-code state machine one transition clockwise or counter-clockwise,
+//! and `DETENT_TRANSITIONS` of them make one click of the knob. Contact
+//! bounce shows up as a transition and its reverse, which cancel, or as an
+//! impossible jump of both contacts, which is ignored. The count restarts
+//! whenever the knob rests in a detent (both contacts open, high with the
+//! pull-ups), so a missed transition never carries over into the next
+//! click.
+
+use crate::keyboard::OCTAVE_COUNT;
+
+/// Contact transitions per detent: one full quadrature cycle, as on the
+/// common EC11-style encoders
+pub const DETENT_TRANSITIONS: i8 = 4;
+/// A and B levels at rest in a detent
+const REST: u8 = 0b11;
+
+/// Direction of each transition, indexed by the previous and the current
+/// AB state: +1 clockwise, -1 counter-clockwise, 0 for none or a bounce
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// Turns A/B contact levels into detent steps.
+pub struct QuadratureDecoder {
+    state: u8,
+    count: i8,
+}
+
+impl Default for QuadratureDecoder {
+    fn default() -> Self {
+        Self {
+            state: REST,
+            count: 0,
+        }
+    }
+}
+
+impl QuadratureDecoder {
+    /// Take the current contact levels (true = high) and return the
+    /// detents turned since the last call: 1 clockwise, -1 counter-clockwise
+    /// or 0. Poll often enough to see every transition, ~1 ms for a knob
+    /// turned by hand.
+    pub fn update(&mut self, a: bool, b: bool) -> i8 {
+        let state = (a as u8) << 1 | b as u8;
+        self.count += TRANSITIONS[(self.state << 2 | state) as usize];
+        self.state = state;
+        if self.count.abs() >= DETENT_TRANSITIONS {
+            let step = self.count.signum();
+            self.count = 0;
+            return step;
+        }
+        if state == REST {
+            self.count = 0;
+        }
+        0
+    }
+}
+
+/// Octave selected by a rotary encoder, one octave per detent, stopping at
+/// the lowest and highest octave.
+#[derive(Default)]
+pub struct EncoderOctave {
+    decoder: QuadratureDecoder,
+    octave: u8,
+}
+
+impl EncoderOctave {
+    /// Start at `octave` (clamped to the keyboard).
+    pub fn new(octave: u8) -> Self {
+        Self {
+            decoder: QuadratureDecoder::default(),
+            octave: octave.min(OCTAVE_COUNT as u8 - 1),
+        }
+    }
+
+    pub fn octave(&self) -> u8 {
+        self.octave
+    }
+
+    /// Take the contact levels; returns the new octave when a detent moved
+    /// it.
+    pub fn update(&mut self, a: bool, b: bool) -> Option<u8> {
+        let octave = match self.decoder.update(a, b) {
+            1 if self.octave < OCTAVE_COUNT as u8 - 1 => self.octave + 1,
+            -1 if self.octave > 0 => self.octave - 1,
+            _ => return None,
+        };
+        self.octave = octave;
+        Some(octave)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clockwise AB sequence for one detent, from rest back to rest
+    const CLOCKWISE: [(bool, bool); 4] =
+        [(false, true), (false, false), (true, false), (true, true)];
+
+    fn turn(encoder: &mut EncoderOctave, clockwise: bool) -> Option<u8> {
+        let steps = if clockwise {
+            CLOCKWISE
+        } else {
+            // The same states the other way round
+            [(true, false), (false, false), (false, true), (true, true)]
+        };
+        let mut moved = None;
+        for (a, b) in steps {
+            moved = moved.or(encoder.update(a, b));
+        }
+        moved
+    }
+
+    #[test]
+    fn detents_step_the_octave_within_the_keyboard() {
+        let mut encoder = EncoderOctave::new(1);
+        assert_eq!(turn(&mut encoder, true), Some(2));
+        assert_eq!(turn(&mut encoder, true), Some(3));
+        // Stops at the top
+        assert_eq!(turn(&mut encoder, true), None);
+        assert_eq!(encoder.octave(), 3);
+        for octave in [2, 1, 0] {
+            assert_eq!(turn(&mut encoder, false), Some(octave));
+        }
+        assert_eq!(turn(&mut encoder, false), None);
+
+        // Bounce on A back and forth, then a half turn back into the same
+        // detent: no step
+        for (a, b) in [(false, true), (true, true), (false, true), (true, true)] {
+            assert_eq!(encoder.update(a, b), None);
+        }
+        for (a, b) in [(false, true), (false, false), (false, true), (true, true)] {
+            assert_eq!(encoder.update(a, b), None);
+        }
+        assert_eq!(turn(&mut encoder, true), Some(1));
+    }
+}
@@ -0,0 +1,234 @@
+//! Amplitude envelope with live parameters and selectable curve shape.
+
+use fundsp::prelude::*;
+
+/// Shortest attack and release with click suppression on, in seconds: a
+/// tight pluck, but no step in the level
+pub const CLICK_RAMP: f32 = 0.003;
+
+/// Shape of the envelope segments.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum EnvCurve {
+    /// Straight-line segments (fundsp `adsr_live` shape)
+    Linear = 0,
+    /// Linear attack, decay and release fall quadratically for a natural,
+    /// exponential-sounding tail
+    Exponential = 1,
+    /// Fast-rising (logarithmic) attack with exponential decay and release
+    Logarithmic = 2,
+}
+
+impl EnvCurve {
+    /// Decode a curve stored in a Shared.
+    pub(crate) fn from_code(code: f32) -> Self {
+        match code as u8 {
+            1 => Self::Exponential,
+            2 => Self::Logarithmic,
+            _ => Self::Linear,
+        }
+    }
+
+    /// Level during the attack for a fraction `x` (0.0-1.0) of attack time.
+    #[inline]
+    fn rise(self, x: f32) -> f32 {
+        match self {
+            Self::Logarithmic => 1.0 - (1.0 - x) * (1.0 - x),
+            _ => x,
+        }
+    }
+
+    /// Remaining fraction of a falling segment after fraction `x` of its time.
+    #[inline]
+    fn fall(self, x: f32) -> f32 {
+        let rest = 1.0 - x;
+        match self {
+            Self::Linear => rest,
+            _ => rest * rest,
+        }
+    }
+
+    /// Fraction of a falling segment's time after which less than `level`
+    /// (0.0-1.0) of its start remains: the inverse of `fall`.
+    pub(crate) fn fall_time(self, level: f32) -> f32 {
+        match self {
+            Self::Linear => 1.0 - level,
+            _ => 1.0 - libm::sqrtf(level),
+        }
+    }
+}
+
+/// Envelope parameters shared between the synth and the audio graph.
+#[derive(Clone)]
+pub(crate) struct EnvControls {
+    /// Attack, decay and release times in seconds
+    pub attack: Shared,
+    pub decay: Shared,
+    /// Sustain level (0.0-1.0)
+    pub sustain: Shared,
+    pub release: Shared,
+    /// `EnvCurve` as f32
+    pub curve: Shared,
+    /// Freeze: > 0.0 holds every sounding envelope at its current level
+    pub freeze: Shared,
+    /// Shortest attack and release in seconds, 0.0 without click suppression
+    pub min_ramp: Shared,
+}
+
+impl EnvControls {
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+        Self {
+            attack: Shared::new(attack),
+            decay: Shared::new(decay),
+            sustain: Shared::new(sustain),
+            release: Shared::new(release),
+            curve: Shared::new(EnvCurve::Linear as u8 as f32),
+            freeze: Shared::new(0.0),
+            min_ramp: Shared::new(CLICK_RAMP),
+        }
+    }
+
+    pub fn set_curve(&self, curve: EnvCurve) {
+        self.curve.set_value(curve as u8 as f32);
+    }
+}
+
+/// Envelope level `elapsed` seconds after the attack started. `released_for`
+/// is the time since the gate went low, or None while it is held.
+/// The release fades the attack/decay curve, like fundsp's `adsr_live`.
+pub(crate) fn env_level(
+    curve: EnvCurve,
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+    elapsed: f32,
+    released_for: Option<f32>,
+) -> f32 {
+    let ads = if elapsed < attack {
+        curve.rise(elapsed / attack)
+    } else if elapsed - attack < decay {
+        sustain + (1.0 - sustain) * curve.fall((elapsed - attack) / decay)
+    } else {
+        sustain
+    };
+    apply_release(curve, ads, release, released_for)
+}
+
+/// Fade `level` by the release segment, if the gate has been released.
+#[inline]
+fn apply_release(curve: EnvCurve, level: f32, release: f32, released_for: Option<f32>) -> f32 {
+    match released_for {
+        None => level,
+        Some(_) if release <= 0.0 => 0.0,
+        Some(time) => level * curve.fall((time / release).clamp(0.0, 1.0)),
+    }
+}
+
+/// Amplitude envelope driven by a gate input. The parameters are read on
+/// every envelope segment (~2 ms), so they can be changed while playing.
+///
+/// While frozen, a sounding envelope holds its current level and ignores the
+/// gate. After the freeze it stays at that level until the gate is (or
+/// already was) released, then releases from it normally. A note attacked
+/// during the freeze plays through it unheld.
+///
+/// `attack_scale` and `release_scale` multiply the attack and release
+/// times for this envelope only, e.g. from the note-on and note-off
+/// velocities. Either time is at least `min_ramp`.
+pub(crate) fn amp_env(
+    controls: &EnvControls,
+    attack_scale: &Shared,
+    release_scale: &Shared,
+) -> An<impl AudioNode<Inputs = U1, Outputs = U1> + use<>> {
+    let controls = controls.clone();
+    let attack_scale = attack_scale.clone();
+    let release_scale = release_scale.clone();
+    let mut attacked = false;
+    let mut attack_start = 0.0;
+    // Time the gate went low, negative while the gate is held
+    let mut release_start: f32 = 0.0;
+    let mut last = 0.0;
+    // Level held while frozen
+    let mut frozen: Option<f32> = None;
+    // Level the note sits at after a freeze, until its next attack
+    let mut hold_level: Option<f32> = None;
+    // Whether the note was attacked during the current freeze
+    let mut attacked_frozen = false;
+    envelope2(move |t: f32, gate: f32| {
+        if controls.freeze.value() > 0.0 {
+            let attacking = release_start >= 0.0 && gate > 0.0;
+            if frozen.is_none() && attacked && last > 0.0 && !attacking && !attacked_frozen {
+                frozen = Some(last);
+            }
+            if let Some(level) = frozen {
+                return level;
+            }
+        } else {
+            attacked_frozen = false;
+            if let Some(level) = frozen.take() {
+                hold_level = Some(level);
+                if gate <= 0.0 {
+                    release_start = t;
+                }
+            }
+        }
+
+        if release_start >= 0.0 && gate > 0.0 {
+            attacked = true;
+            attacked_frozen = controls.freeze.value() > 0.0;
+            attack_start = t;
+            release_start = -1.0;
+            hold_level = None;
+        } else if release_start < 0.0 && gate <= 0.0 {
+            release_start = t;
+        }
+        if !attacked {
+            return 0.0;
+        }
+        let curve = EnvCurve::from_code(controls.curve.value());
+        let released_for = (release_start >= 0.0).then_some(t - release_start);
+        let min_ramp = controls.min_ramp.value();
+        let release = (controls.release.value() * release_scale.value()).max(min_ramp);
+        last = match hold_level {
+            Some(level) => apply_release(curve, level, release, released_for),
+            None => env_level(
+                curve,
+                (controls.attack.value() * attack_scale.value()).max(min_ramp),
+                controls.decay.value(),
+                controls.sustain.value(),
+                release,
+                t - attack_start,
+                released_for,
+            ),
+        };
+        last
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_release_falls_faster_than_linear() {
+        let level = |curve, time| env_level(curve, 0.0, 0.0, 1.0, 1.0, 10.0, Some(time));
+        // Same 1 s release: at 80% the exponential tail is already -28 dB
+        assert!((level(EnvCurve::Linear, 0.8) - 0.2).abs() < 1e-6);
+        assert!(level(EnvCurve::Exponential, 0.8) < 0.05);
+        for step in 1..10 {
+            let time = step as f32 / 10.0;
+            assert!(level(EnvCurve::Exponential, time) < level(EnvCurve::Linear, time));
+        }
+    }
+
+    #[test]
+    fn linear_curve_matches_adsr_live_shape() {
+        let level =
+            |elapsed, released| env_level(EnvCurve::Linear, 0.5, 0.5, 0.5, 0.5, elapsed, released);
+        assert!((level(0.25, None) - 0.5).abs() < 1e-6);
+        assert!((level(0.75, None) - 0.75).abs() < 1e-6);
+        assert!((level(2.0, None) - 0.5).abs() < 1e-6);
+        assert!((level(2.0, Some(0.25)) - 0.25).abs() < 1e-6);
+    }
+}
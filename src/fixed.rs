@@ -0,0 +1,239 @@
+//! Fixed-point rendering backend.
+//!
+//! A lean integer alternative to the fundsp graph for the hot loop: phase
+//! accumulator oscillators, linear ADSR envelopes, a one-pole lowpass and
+//! a Chamberlin state-variable filter standing in for the resonator peak,
+//! all in Q15/Q31 arithmetic. It reads the same control values as the
+//! float graph (frequencies, gates, levels, envelope times, filter and
+//! resonator frequency), converting them once per 64-sample chunk, so
+//! voice allocation works unchanged.
+//!
+//! Accuracy vs the float path:
+//! - Oscillators are naive (no polyBLEP), so saw, square and pulse alias
+//!   audibly in the top octave. Sine is a parabolic approximation (~0.1%
+//!   THD).
+//! - The filter is always a 6 dB/oct one-pole without resonance or key
+//!   tracking, whatever slope is selected, and the effects order is
+//!   fixed. The chorus, formant filter, overdrive, voice highpass, pitch
+//!   envelope, glide, cross-mod, the LFO's filter and amplitude routes and
+//!   the sensor sample-and-hold filter target are skipped, and the output
+//!   is mono.
+//! - Envelopes are always linear and ignore freeze and the velocity to
+//!   attack and release times.
+//!
+//! Levels and the envelope shape otherwise track the float path within a
+//! few percent. In exchange the per-sample cost is a handful of integer
+//! multiply-adds per voice; compare both backends with the `busy_pin`
+//! timing on a scope to see the difference on hardware.
+
+use crate::keyboard::{VOICE_COUNT, VOICE_GAIN, Waveform};
+use fundsp::DEFAULT_SR;
+
+/// Sample format conversion scale for Q15
+const Q15_ONE: i32 = 1 << 15;
+/// Full scale of the Q31 envelope level
+const ENV_MAX: i32 = i32::MAX;
+
+/// Selects how `KeyboardSynth` renders audio.
+#[derive(Clone, Copy, PartialEq, Eq, Default, defmt::Format)]
+pub enum RenderBackend {
+    /// fundsp graph with all features (default)
+    #[default]
+    Float,
+    /// Integer oscillators, envelopes and one-pole filter (see module docs)
+    Fixed,
+}
+
+/// Control values sampled for one chunk, in float units.
+pub(crate) struct FixedParams<'a> {
+    pub waveforms: &'a [Waveform; VOICE_COUNT],
+    /// Layered second waveform and its blend (0.0-1.0), on the same phase
+    pub layer: Option<(Waveform, f32)>,
+    pub freqs: [f32; VOICE_COUNT],
+    pub gates: [bool; VOICE_COUNT],
+    pub levels: [f32; VOICE_COUNT],
+    /// Phase reset counters, a change restarts the oscillator at phase 0
+    pub phase_resets: [f32; VOICE_COUNT],
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub cutoff: f32,
+    pub resonator: f32,
+    /// Width of `Waveform::Pulse` with its LFO swing, 0.0-1.0
+    pub pulse_width: f32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Clone, Copy)]
+struct FixedVoice {
+    phase: u32,
+    /// Last seen phase reset counter
+    phase_reset: f32,
+    /// Envelope level in Q31
+    env: i32,
+    stage: Stage,
+    /// Per-sample release decrement, fixed when the release starts
+    release_step: i32,
+}
+
+/// Per-voice and filter state of the fixed-point renderer.
+pub(crate) struct FixedRenderer {
+    voices: [FixedVoice; VOICE_COUNT],
+    /// One-pole filter state in Q15
+    filter: i32,
+    /// Resonator SVF lowpass and bandpass state in Q15
+    svf_low: i32,
+    svf_band: i32,
+}
+
+/// Samples needed to cover `seconds`, at least 1.
+#[inline]
+fn samples(seconds: f32) -> i32 {
+    ((seconds * DEFAULT_SR as f32) as i32).max(1)
+}
+
+/// Q15 oscillator output for a 32-bit phase. `pulse_edge` is the signed
+/// phase where `Waveform::Pulse` goes high.
+#[inline]
+fn oscillator(waveform: Waveform, phase: u32, pulse_edge: i32) -> i32 {
+    // Signed phase: -1.0 .. 1.0 as Q15
+    let x = (phase >> 16) as i32 - Q15_ONE;
+    match waveform {
+        Waveform::Saw => x,
+        Waveform::Square => {
+            if x < 0 {
+                -(Q15_ONE - 1)
+            } else {
+                Q15_ONE - 1
+            }
+        }
+        Waveform::Pulse => {
+            if x < pulse_edge {
+                -(Q15_ONE - 1)
+            } else {
+                Q15_ONE - 1
+            }
+        }
+        Waveform::Triangle => Q15_ONE - 2 * x.abs(),
+        Waveform::Sine => {
+            // sin(pi x) ~ y = 4x(1 - |x|), refined with y + 0.225(y|y| - y)
+            let y = (x * (Q15_ONE - x.abs())) >> 13;
+            let y = y + (((y * y.abs()) >> 15) - y) * 225 / 1000;
+            // The phase starts at x = -1, half a cycle in
+            -y
+        }
+    }
+}
+
+impl FixedRenderer {
+    pub(crate) fn new() -> Self {
+        Self {
+            voices: [FixedVoice {
+                phase: 0,
+                phase_reset: 0.0,
+                env: 0,
+                stage: Stage::Idle,
+                release_step: 0,
+            }; VOICE_COUNT],
+            filter: 0,
+            svf_low: 0,
+            svf_band: 0,
+        }
+    }
+
+    /// Render one chunk of mono samples into `out`.
+    pub(crate) fn render(&mut self, params: &FixedParams, out: &mut [f32]) {
+        let attack_step = ENV_MAX / samples(params.attack);
+        let sustain = (params.sustain.clamp(0.0, 1.0) * ENV_MAX as f32) as i32;
+        let decay_step = ((ENV_MAX - sustain) / samples(params.decay)).max(1);
+        let release_samples = samples(params.release);
+        let voice_gain = (VOICE_GAIN * Q15_ONE as f32) as i32;
+        // One-pole coefficient 1 - e^(-2 pi fc / sr) in Q15
+        let coeff =
+            1.0 - libm::expf(-core::f32::consts::TAU * params.cutoff.max(0.0) / DEFAULT_SR as f32);
+        let coeff = (coeff * Q15_ONE as f32) as i64;
+        // Chamberlin frequency coefficient 2 sin(pi fc / sr) in Q15, kept well
+        // inside the range where the Q = 1 filter is stable
+        let resonator = params.resonator.clamp(20.0, 4000.0);
+        let svf_f = 2.0 * libm::sinf(core::f32::consts::PI * resonator / DEFAULT_SR as f32);
+        let svf_f = (svf_f * Q15_ONE as f32) as i64;
+        let layer = params
+            .layer
+            .map(|(waveform, blend)| (waveform, (blend.clamp(0.0, 1.0) * Q15_ONE as f32) as i32));
+        // High for the last `pulse_width` of each cycle
+        let pulse_edge = ((1.0 - 2.0 * params.pulse_width.clamp(0.0, 1.0)) * Q15_ONE as f32) as i32;
+
+        let mut incs = [0u32; VOICE_COUNT];
+        let mut gains = [0i32; VOICE_COUNT];
+        for (v, voice) in self.voices.iter_mut().enumerate() {
+            incs[v] =
+                (params.freqs[v].max(0.0) as f64 * (u32::MAX as f64 + 1.0) / DEFAULT_SR) as u32;
+            gains[v] = (params.levels[v].clamp(0.0, 1.0) * voice_gain as f32) as i32;
+            if params.phase_resets[v] != voice.phase_reset {
+                voice.phase_reset = params.phase_resets[v];
+                voice.phase = 0;
+            }
+            match (params.gates[v], voice.stage) {
+                (true, Stage::Idle | Stage::Release) => voice.stage = Stage::Attack,
+                (false, Stage::Attack | Stage::Decay | Stage::Sustain) => {
+                    voice.stage = Stage::Release;
+                    voice.release_step = (voice.env / release_samples).max(1);
+                }
+                _ => {}
+            }
+        }
+
+        for sample in out.iter_mut() {
+            let mut mix: i32 = 0;
+            for (v, voice) in self.voices.iter_mut().enumerate() {
+                match voice.stage {
+                    Stage::Idle => continue,
+                    Stage::Attack => {
+                        voice.env = voice.env.saturating_add(attack_step);
+                        if voice.env == ENV_MAX {
+                            voice.stage = Stage::Decay;
+                        }
+                    }
+                    Stage::Decay => {
+                        voice.env = voice.env.saturating_sub(decay_step).max(sustain);
+                        if voice.env == sustain {
+                            voice.stage = Stage::Sustain;
+                        }
+                    }
+                    Stage::Sustain => voice.env = sustain,
+                    Stage::Release => {
+                        voice.env = voice.env.saturating_sub(voice.release_step).max(0);
+                        if voice.env == 0 {
+                            voice.stage = Stage::Idle;
+                        }
+                    }
+                }
+                voice.phase = voice.phase.wrapping_add(incs[v]);
+                let mut osc = oscillator(params.waveforms[v], voice.phase, pulse_edge);
+                if let Some((waveform, blend)) = layer {
+                    osc += ((oscillator(waveform, voice.phase, pulse_edge) - osc) * blend) >> 15;
+                }
+                let env = voice.env >> 16;
+                mix += (((osc * env) >> 15) * gains[v]) >> 15;
+            }
+            // Average like the float graph's voice join
+            let mix = mix / VOICE_COUNT as i32;
+            self.filter += (((mix - self.filter) as i64 * coeff) >> 15) as i32;
+
+            // Peak output (low - high) of a Q = 1 state-variable filter
+            self.svf_low += ((svf_f * self.svf_band as i64) >> 15) as i32;
+            let high = self.filter - self.svf_low - self.svf_band;
+            self.svf_band += ((svf_f * high as i64) >> 15) as i32;
+            *sample = (self.svf_low - high) as f32 / Q15_ONE as f32;
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Envelope follower on the synth's output, for driving external gear (an
+//! analog filter, lights) from the music's dynamics.
+//!
+//! The follower rectifies each frame, taking the louder channel, and
+//! smooths it with separate attack and release times, like the detector of
+//! a compressor: a fast attack catches note onsets, a slower release rides
+//! over the waveform's cycles without rippling. `level` is 0.0 to 1.0 of
+//! full scale; `duty` scales it for a PWM output.
+
+use core::time::Duration;
+use fundsp::DEFAULT_SR;
+
+/// Default time to follow a rise and a fall by 63%
+pub const FOLLOWER_ATTACK: Duration = Duration::from_millis(5);
+pub const FOLLOWER_RELEASE: Duration = Duration::from_millis(150);
+
+/// Smoothed output amplitude, see the module docs.
+pub struct EnvelopeFollower {
+    level: f32,
+    /// Per-frame one-pole coefficients for a rising and a falling level
+    attack: f32,
+    release: f32,
+}
+
+impl Default for EnvelopeFollower {
+    fn default() -> Self {
+        Self::new(FOLLOWER_ATTACK, FOLLOWER_RELEASE)
+    }
+}
+
+/// One-pole coefficient reaching 63% of a step in `time`.
+fn coefficient(time: Duration) -> f32 {
+    let frames = time.as_secs_f32() * DEFAULT_SR as f32;
+    if frames < 1.0 {
+        1.0
+    } else {
+        1.0 - libm::expf(-1.0 / frames)
+    }
+}
+
+impl EnvelopeFollower {
+    pub fn new(attack: Duration, release: Duration) -> Self {
+        Self {
+            level: 0.0,
+            attack: coefficient(attack),
+            release: coefficient(release),
+        }
+    }
+
+    /// Change the smoothing; the level carries on from where it is.
+    pub fn set_times(&mut self, attack: Duration, release: Duration) {
+        self.attack = coefficient(attack);
+        self.release = coefficient(release);
+    }
+
+    /// Follow a block of stereo output.
+    pub fn process(&mut self, left: &[f32], right: &[f32]) {
+        let mut level = self.level;
+        for (&l, &r) in left.iter().zip(right) {
+            let input = l.abs().max(r.abs()).min(1.0);
+            let coefficient = if input > level {
+                self.attack
+            } else {
+                self.release
+            };
+            level += (input - level) * coefficient;
+        }
+        self.level = level;
+    }
+
+    /// Current level, 0.0 (silence) to 1.0 (full scale).
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    /// The level as a PWM compare value for a counter wrapping at `top`.
+    pub fn duty(&self, top: u16) -> u16 {
+        (self.level * (top as f32 + 1.0)) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::KeyboardSynth;
+
+    #[test]
+    fn follower_tracks_played_dynamics() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_startup_fade(Duration::ZERO);
+        synth.set_envelope(0.005, 0.0, 1.0, 0.05);
+        let mut follower = EnvelopeFollower::default();
+        let (mut left, mut right) = ([0.0f32; 441], [0.0f32; 441]);
+        // Duty after 0.2 s of rendering, as an LED on a 1000-step PWM
+        let mut play = |synth: &mut KeyboardSynth| {
+            for _ in 0..20 {
+                synth.process_block_stereo(&mut left, &mut right);
+                follower.process(&left, &right);
+            }
+            follower.duty(999)
+        };
+        assert_eq!(play(&mut synth), 0);
+        synth.note_on(9, 1, 30);
+        let soft = play(&mut synth);
+        synth.note_off(9, 1, 64);
+        synth.note_on(9, 1, 127);
+        let loud = play(&mut synth);
+        assert!(soft > 0 && loud > 2 * soft, "{soft} {loud}");
+        // Dims again once the note has died away
+        synth.note_off(9, 1, 64);
+        let released = play(&mut synth);
+        assert!(released < loud / 2, "{released}");
+    }
+}
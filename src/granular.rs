@@ -0,0 +1,224 @@
+//! Granular freeze: captures a short window of the output and keeps
+//! replaying tiny grains of it, for holding a chord as an evolving pad
+//! after the keys are released.
+//!
+//! Turning the freeze on allocates `FREEZE_BYTES` of heap and records the
+//! next `FREEZE_WINDOW_MS` of the output (so hold the chord until the
+//! texture starts). From then on grains of `grain_ms` play from random
+//! places in the window, each slightly detuned, some an octave up for
+//! shimmer, and panned at random. They overlap `GRAIN_OVERLAP` deep with
+//! at most `GRAINS_MAX` running, which bounds the cost to a few
+//! interpolated reads per frame (see `InterpQuality`). Turning it off
+//! fades the grains out and frees the buffer.
+
+use crate::interp::InterpQuality;
+use alloc::vec::Vec;
+use fundsp::DEFAULT_SR;
+
+/// Length of the captured window in ms
+pub const FREEZE_WINDOW_MS: usize = 600;
+/// Samples in the captured window, mono
+const FREEZE_SAMPLES: usize = FREEZE_WINDOW_MS * DEFAULT_SR as usize / 1000;
+/// Heap the capture buffer takes
+pub const FREEZE_BYTES: usize = FREEZE_SAMPLES * core::mem::size_of::<i16>();
+/// Grain length limits in ms; an octave-up grain reads twice its length,
+/// which must fit in the window
+pub const GRAIN_MS_MIN: f32 = 10.0;
+pub const GRAIN_MS_MAX: f32 = 150.0;
+/// Grains running at once at most
+const GRAINS_MAX: usize = 4;
+/// Grains started per grain length
+const GRAIN_OVERLAP: usize = 3;
+/// Output level: the parabolic windows of the overlapping grains sum to
+/// about 2
+const GRAIN_GAIN: f32 = 0.5;
+/// Random detune of each grain, either way
+const GRAIN_DETUNE_CENTS: f32 = 12.0;
+/// One grain in this many plays an octave up
+const GRAIN_OCTAVE_ODDS: u32 = 4;
+/// Level change per frame when fading out, ~50 ms from full
+const FADE_STEP: f32 = 1.0 / 2205.0;
+
+/// What the freeze is doing.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, defmt::Format)]
+pub enum FreezeState {
+    #[default]
+    Off,
+    /// Recording the window, no grains yet
+    Capturing,
+    /// Playing grains
+    Frozen,
+    /// Turned off, grains fading out
+    Fading,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Grain {
+    /// Read position in samples
+    position: f32,
+    /// Read step per frame (pitch ratio)
+    rate: f32,
+    age: usize,
+    /// Length in frames, 0 when the slot is free
+    len: usize,
+    left: f32,
+    right: f32,
+}
+
+/// Capture buffer and grain scheduler.
+pub struct GranularFreeze {
+    state: FreezeState,
+    buffer: Vec<i16>,
+    grains: [Grain; GRAINS_MAX],
+    grain_len: usize,
+    /// Frames until the next grain starts
+    until_next: usize,
+    level: f32,
+    interp: InterpQuality,
+    /// xorshift32 state
+    rng: u32,
+}
+
+impl Default for GranularFreeze {
+    fn default() -> Self {
+        Self {
+            state: FreezeState::Off,
+            buffer: Vec::new(),
+            grains: [Grain::default(); GRAINS_MAX],
+            grain_len: 0,
+            until_next: 0,
+            level: 0.0,
+            interp: InterpQuality::default(),
+            rng: 0x3C6E_F372,
+        }
+    }
+}
+
+impl GranularFreeze {
+    pub fn state(&self) -> FreezeState {
+        self.state
+    }
+
+    /// Start capturing a new window with grains of `grain_ms`, or just
+    /// change the grain length while frozen. Allocating the buffer can
+    /// fail: the needed bytes are returned and the freeze stays off.
+    pub fn start(&mut self, grain_ms: f32) -> Result<(), usize> {
+        let grain_ms = grain_ms.clamp(GRAIN_MS_MIN, GRAIN_MS_MAX);
+        self.grain_len = (grain_ms * DEFAULT_SR as f32 / 1000.0) as usize;
+        if matches!(self.state, FreezeState::Capturing | FreezeState::Frozen) {
+            return Ok(());
+        }
+        self.buffer.clear();
+        self.buffer
+            .try_reserve_exact(FREEZE_SAMPLES)
+            .map_err(|_| FREEZE_BYTES)?;
+        self.grains = [Grain::default(); GRAINS_MAX];
+        self.until_next = 0;
+        self.level = 1.0;
+        self.state = FreezeState::Capturing;
+        Ok(())
+    }
+
+    /// How grains read between the captured samples.
+    pub fn set_interp_quality(&mut self, quality: InterpQuality) {
+        self.interp = quality;
+    }
+
+    /// Fade the grains out; the buffer is freed once they are silent.
+    pub fn stop(&mut self) {
+        match self.state {
+            FreezeState::Capturing => self.free(),
+            FreezeState::Frozen => self.state = FreezeState::Fading,
+            FreezeState::Off | FreezeState::Fading => {}
+        }
+    }
+
+    fn free(&mut self) {
+        self.state = FreezeState::Off;
+        self.buffer = Vec::new();
+    }
+
+    fn random(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+
+    /// Random value in 0.0-1.0
+    fn random_unit(&mut self) -> f32 {
+        self.random() as f32 / u32::MAX as f32
+    }
+
+    fn start_grain(&mut self) {
+        let Some(slot) = self.grains.iter().position(|grain| grain.age >= grain.len) else {
+            return;
+        };
+        let octave = self.random().is_multiple_of(GRAIN_OCTAVE_ODDS);
+        let cents = (self.random_unit() * 2.0 - 1.0) * GRAIN_DETUNE_CENTS;
+        let rate = libm::exp2f(cents / 1200.0) * if octave { 2.0 } else { 1.0 };
+        // Room for the whole grain plus the interpolation samples
+        let span = self.grain_len as f32 * rate + 2.0;
+        let room = (self.buffer.len() as f32 - span).max(0.0);
+        let position = self.random_unit() * room;
+        // Equal-power pan
+        let pan = self.random_unit();
+        self.grains[slot] = Grain {
+            position,
+            rate,
+            age: 0,
+            len: self.grain_len,
+            left: libm::sqrtf(1.0 - pan),
+            right: libm::sqrtf(pan),
+        };
+    }
+
+    /// Take one frame of the live output and return the grains' stereo
+    /// frame to mix with it.
+    #[inline]
+    pub fn process(&mut self, live: f32) -> (f32, f32) {
+        match self.state {
+            FreezeState::Off => return (0.0, 0.0),
+            FreezeState::Capturing => {
+                let level = live.clamp(-1.0, 1.0);
+                self.buffer.push((level * i16::MAX as f32) as i16);
+                if self.buffer.len() == FREEZE_SAMPLES {
+                    self.state = FreezeState::Frozen;
+                }
+                return (0.0, 0.0);
+            }
+            FreezeState::Fading => {
+                self.level -= FADE_STEP;
+                if self.level <= 0.0 {
+                    self.free();
+                    return (0.0, 0.0);
+                }
+            }
+            FreezeState::Frozen => {}
+        }
+
+        if self.until_next == 0 {
+            self.start_grain();
+            self.until_next = self.grain_len / GRAIN_OVERLAP;
+        }
+        self.until_next -= 1;
+
+        let (mut left, mut right) = (0.0, 0.0);
+        let last = self.buffer.len() - 1;
+        for grain in self.grains.iter_mut().filter(|grain| grain.age < grain.len) {
+            let index = grain.position as usize;
+            let t = grain.position - index as f32;
+            let s = core::array::from_fn(|k| {
+                self.buffer[(index + k).saturating_sub(1).min(last)] as f32
+            });
+            let x = grain.age as f32 / grain.len as f32;
+            let sample = self.interp.interpolate(s, t) * 4.0 * x * (1.0 - x);
+            left += sample * grain.left;
+            right += sample * grain.right;
+            grain.position += grain.rate;
+            grain.age += 1;
+        }
+        let gain = GRAIN_GAIN * self.level / i16::MAX as f32;
+        (left * gain, right * gain)
+    }
+}
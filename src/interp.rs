@@ -0,0 +1,88 @@
+//! Reads between the samples of a buffer, for playing recorded audio back
+//! at another pitch or rate: the granular freeze's detuned grains and the
+//! looper's half-rate buffer.
+//!
+//! Pitch-shifted reads land at fractional positions, and how the gap is
+//! filled decides how much of the buffer's spectrum gets mirrored into
+//! audible images. Nearest takes the closer sample (one read, a gritty
+//! lo-fi sound), linear draws a line between two and cubic fits a
+//! Catmull-Rom spline through four, which keeps sweeps far down clean for
+//! about twice linear's cost.
+
+/// Trade-off between cost and fidelity of fractional reads, see
+/// `KeyboardSynth::set_interp_quality`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, defmt::Format)]
+pub enum InterpQuality {
+    Nearest,
+    #[default]
+    Linear,
+    Cubic,
+}
+
+impl InterpQuality {
+    /// Value at fraction `t` (0.0-1.0) of the way from `s[1]` to `s[2]`,
+    /// with `s[0]` and `s[3]` the samples either side for the cubic.
+    #[inline]
+    pub fn interpolate(self, s: [f32; 4], t: f32) -> f32 {
+        match self {
+            Self::Nearest => {
+                if t < 0.5 {
+                    s[1]
+                } else {
+                    s[2]
+                }
+            }
+            Self::Linear => s[1] + (s[2] - s[1]) * t,
+            Self::Cubic => {
+                let c1 = 0.5 * (s[2] - s[0]);
+                let c2 = s[0] - 2.5 * s[1] + 2.0 * s[2] - 0.5 * s[3];
+                let c3 = 0.5 * (s[3] - s[0]) + 1.5 * (s[1] - s[2]);
+                ((c3 * t + c2) * t + c1) * t + s[1]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cubic_keeps_a_downward_wavetable_sweep_cleanest() {
+        // One sine cycle as a wavetable, swept from 4x down to 1/8x its
+        // stored rate; the error against the true sine is what aliases
+        const TABLE: usize = 32;
+        let table: [f32; TABLE] =
+            core::array::from_fn(|i| libm::sinf(core::f32::consts::TAU * i as f32 / TABLE as f32));
+        let error = |quality: InterpQuality| {
+            let (mut phase, mut error) = (0.0f32, 0.0f32);
+            for n in 0..4096 {
+                let rate = 4.0 * libm::exp2f(-5.0 * n as f32 / 4096.0);
+                let index = phase as usize;
+                let s = core::array::from_fn(|k| table[(index + TABLE + k - 1) % TABLE]);
+                let read = quality.interpolate(s, phase - index as f32);
+                let exact = libm::sinf(core::f32::consts::TAU * phase / TABLE as f32);
+                error += (read - exact) * (read - exact);
+                phase = libm::fmodf(phase + rate, TABLE as f32);
+            }
+            error
+        };
+        let (nearest, linear, cubic) = (
+            error(InterpQuality::Nearest),
+            error(InterpQuality::Linear),
+            error(InterpQuality::Cubic),
+        );
+        assert!(linear < nearest / 10.0, "{nearest} {linear}");
+        assert!(cubic < linear / 10.0, "{linear} {cubic}");
+
+        // Every quality hits the samples themselves
+        let s = [0.1, 0.4, -0.3, 0.2];
+        for quality in [
+            InterpQuality::Nearest,
+            InterpQuality::Linear,
+            InterpQuality::Cubic,
+        ] {
+            assert_eq!(quality.interpolate(s, 0.0), 0.4);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Key scanning, inline in the audio loop or in a task of its own.
+//!
+//! `KeyScanner` reads one scan's keys (see `octave_select.rs`), applies the
+//! bend pot and the sensor sample-and-hold, and now and then cross-checks
+//! the voices against the held keys. With `SCAN_TASK` off in main it runs
+//! at the top of the audio loop, at most once per scan interval, which in
+//! practice is once per buffer. With it on, `scan_task` runs it every scan
+//! interval while the audio loop waits for the DMA, so the scan no longer
+//! takes time from the fill and every octave is read within a millisecond.
+//!
+//! The task shares the synth with the audio loop through a `RefCell`. Both
+//! run on the one executor, which only switches tasks at an `.await`, and
+//! neither holds the borrow across one, so the borrows never overlap.
+//!
+//! Key-to-sound latency: a note is rendered by the first fill after its key
+//! is read and heard once the buffer playing meanwhile has finished (and
+//! any more queued, see `BUFFER_COUNT` in main). Inline, the read comes
+//! right before a fill, so the worst case is (scan gap + 1) buffers, the
+//! scan gap being 1 buffer with a full scan and up to 4 with one octave per
+//! scan. In the task, it is the scan gap in scan intervals
+//! plus 2 buffers. Worked out from those bounds (estimates: the task's
+//! latency has not been measured on a board yet) at 640 frames and 250 µs:
+//!
+//! | octaves per scan | inline  | task    |
+//! |------------------|---------|---------|
+//! | 4 (full)         | 29.0 ms | 29.3 ms |
+//! | 1                | 72.6 ms | 30.0 ms |
+//!
+//! so the task mainly pays off with partial scans, small buffers or heavy
+//! fills; a full inline scan already reads the keys at the last moment.
+//! Both bounds are logged per octave at boot. What is measured, with
+//! `Instant`, is the scan time and the read-to-DAC part of the latency,
+//! both logged with the scan report, along with the scan's cost in CPU
+//! cycles from the DWT cycle counter and the heap's high-water mark; the
+//! wait for the scan to reach a key isn't.
+
+use crate::octave_select::OctaveSelector;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::DWT;
+use embassy_rp::gpio::Input;
+use embassy_time::{Duration, Instant, Timer};
+use fundsp::shared::Shared;
+use pico2_synth::chord::ChordName;
+use pico2_synth::keyboard::{KEY_COUNT, KeyboardSynth};
+use pico2_synth::scan::ScanOrder;
+
+/// Shortest and longest scan interval `set_scan_interval` accepts
+const SCAN_INTERVAL_MIN: Duration = Duration::from_micros(50);
+const SCAN_INTERVAL_MAX: Duration = Duration::from_millis(100);
+static SCAN_INTERVAL_US: AtomicU32 = AtomicU32::new(250);
+/// Longest scan since the last report, in µs
+static SCAN_TIME_MAX_US: AtomicU32 = AtomicU32::new(0);
+/// Most CPU cycles a scan took since the last report, from the DWT cycle
+/// counter main starts at boot
+static SCAN_CYCLES_MAX: AtomicU32 = AtomicU32::new(0);
+/// Low 32 bits of the µs time the newest key press was read, 0 for none
+static PRESS_READ_US: AtomicU32 = AtomicU32::new(0);
+
+/// Set the minimum time between key scans, from the next scan on. Clamped
+/// to 50 µs..100 ms.
+pub fn set_scan_interval(interval: Duration) {
+    let clamped = interval.clamp(SCAN_INTERVAL_MIN, SCAN_INTERVAL_MAX);
+    if clamped != interval {
+        defmt::warn!(
+            "Scan interval {} us out of range, using {}",
+            interval.as_micros(),
+            clamped.as_micros()
+        );
+    }
+    SCAN_INTERVAL_US.store(clamped.as_micros() as u32, Ordering::Relaxed);
+}
+
+pub fn scan_interval() -> Duration {
+    Duration::from_micros(SCAN_INTERVAL_US.load(Ordering::Relaxed) as u64)
+}
+
+/// Time since the newest key press was read, if one was since the last
+/// call.
+pub fn take_press_read() -> Option<Duration> {
+    let read = PRESS_READ_US.swap(0, Ordering::Relaxed);
+    let now = Instant::now().as_micros() as u32;
+    (read != 0).then(|| Duration::from_micros(now.wrapping_sub(read) as u64))
+}
+
+/// Longest scan since the last call.
+pub fn take_scan_time_max() -> Duration {
+    Duration::from_micros(SCAN_TIME_MAX_US.swap(0, Ordering::Relaxed) as u64)
+}
+
+/// Most CPU cycles a scan took since the last call.
+pub fn take_scan_cycles_max() -> u32 {
+    SCAN_CYCLES_MAX.swap(0, Ordering::Relaxed)
+}
+
+/// Everything a scan reads, and what it remembers between scans.
+pub struct KeyScanner {
+    inputs: [Input<'static>; KEY_COUNT],
+    selector: OctaveSelector,
+    order: ScanOrder,
+    /// Bend pot position, written by the analog task
+    bend_input: Shared,
+    /// Sensor position for the sample-and-hold, written by the sensor task
+    sample_hold_input: Option<Shared>,
+    /// Time between voice cross-checks, zero for none
+    reconcile_interval: Duration,
+    last_bend: f32,
+    last_sample_hold: Option<f32>,
+    last_reconcile: Instant,
+    /// Chord name of the held keys, logged when it changes
+    last_chord: Option<ChordName>,
+}
+
+impl KeyScanner {
+    pub fn new(
+        inputs: [Input<'static>; KEY_COUNT],
+        selector: OctaveSelector,
+        order: ScanOrder,
+        bend_input: Shared,
+        sample_hold_input: Option<Shared>,
+        reconcile_interval: Duration,
+    ) -> Self {
+        Self {
+            inputs,
+            selector,
+            order,
+            bend_input,
+            sample_hold_input,
+            reconcile_interval,
+            last_bend: 0.0,
+            last_sample_hold: None,
+            last_reconcile: Instant::now(),
+            last_chord: None,
+        }
+    }
+
+    /// Read this scan's keys and controls into the synth.
+    pub fn scan(&mut self, synth: &mut KeyboardSynth) {
+        let start = Instant::now();
+        let start_cycles = DWT::cycle_count();
+
+        // Apply the analog bend pot (only when it moved)
+        let bend = self.bend_input.value();
+        if bend != self.last_bend {
+            self.last_bend = bend;
+            synth.set_pitch_bend(bend);
+        }
+        if let Some(input) = &self.sample_hold_input {
+            let position = input.value();
+            if self.last_sample_hold != Some(position) {
+                self.last_sample_hold = Some(position);
+                synth.set_sensor_position(position);
+            }
+        }
+
+        if self.selector.scan(&self.inputs, &mut self.order, synth) {
+            PRESS_READ_US.store(
+                (Instant::now().as_micros() as u32).max(1),
+                Ordering::Relaxed,
+            );
+        }
+
+        if self.reconcile_interval > Duration::from_ticks(0)
+            && self.last_reconcile.elapsed() >= self.reconcile_interval
+        {
+            self.last_reconcile = Instant::now();
+            let fixes = synth.reconcile_voices();
+            if fixes > 0 {
+                defmt::warn!("Reconciled {} voices with the held keys", fixes);
+            }
+        }
+
+        let chord = synth.current_chord();
+        if chord != self.last_chord {
+            if let Some(chord) = &chord {
+                defmt::debug!("Chord: {}", defmt::Display2Format(chord));
+            }
+            self.last_chord = chord;
+        }
+
+        SCAN_TIME_MAX_US.fetch_max(start.elapsed().as_micros() as u32, Ordering::Relaxed);
+        SCAN_CYCLES_MAX.fetch_max(
+            DWT::cycle_count().wrapping_sub(start_cycles),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+// Task to scan the keys every scan interval, independently of the audio
+// buffer fills.
+#[embassy_executor::task]
+pub async fn scan_task(mut scanner: KeyScanner, synth: &'static RefCell<KeyboardSynth>) {
+    loop {
+        scanner.scan(&mut synth.borrow_mut());
+        Timer::after(scan_interval()).await;
+    }
+}
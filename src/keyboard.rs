@@ -1,6 +1,21 @@
-extern crate alloc;
 use crate::arrayinit_nostd::arr;
+use crate::chord::{ChordName, recognize};
+use crate::drums::DrumMachine;
+use crate::envelope::{CLICK_RAMP, EnvControls, EnvCurve, amp_env, env_level};
+use crate::fixed::{FixedParams, FixedRenderer, RenderBackend};
+use crate::granular::{FreezeState, GranularFreeze};
+use crate::interp::InterpQuality;
+use crate::layout::KeyboardMode;
+use crate::looper::{Looper, LooperState};
+use crate::midi::{CcMap, ControlTarget};
+use crate::preset::Preset;
+use crate::state::{STATE_BYTES_MAX, SynthState};
+use crate::sysex::{PATCH_DUMP_BYTES_MAX, SysExMessage, patch_dump};
+use crate::trance_gate::TranceGate;
 use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::ops::Add;
+use core::time::Duration;
 use fundsp::buffer::BufferArray;
 use fundsp::prelude::*;
 
@@ -26,14 +41,64 @@ pub const CHORUS_SEED: u64 = 1234;
 pub const CHORUS_SEPARATION: f32 = 0.01;
 pub const CHORUS_VARIATION: f32 = 0.05;
 pub const CHORUS_MOD_FREQ: f32 = 0.7;
+/// Default delayed copies per channel, as in fundsp's `chorus`
+pub const CHORUS_VOICES: u8 = 4;
+pub const CHORUS_VOICES_MAX: u8 = 6;
+/// Parameter limits, they bound the chorus delay line length
+pub const CHORUS_SEPARATION_MAX: f32 = 0.02;
+pub const CHORUS_VARIATION_MAX: f32 = 0.05;
+/// Crossfade from the old to the new chorus when its settings change
+const CHORUS_FADE_TIME: f32 = 0.1;
 
 pub const DELAY_TIME: f64 = 0.1;
 pub const DELAY_FEEDBACK: f32 = 0.9;
+/// Highest delay feedback; the loop also saturates, so repeats always decay
+pub const DELAY_FEEDBACK_MAX: f32 = 0.95;
 pub const LP_CUTOFF: f32 = 1500.0;
 
+/// Default cutoff of the main low-pass filter
+pub const FILTER_CUTOFF: f32 = 1200.0;
+/// Q of the 12 dB filter at resonance 0.0 (Butterworth) and 1.0
+pub const FILTER_Q_MIN: f32 = 0.707;
+pub const FILTER_Q_MAX: f32 = 8.0;
+/// Note frequency (C4) whose cutoff key tracking leaves unchanged
+const FILTER_KEYTRACK_REFERENCE: f32 = 261.63;
+/// Highest key-tracked cutoff, below Nyquist for every filter slope
+const FILTER_KEYTRACK_CUTOFF_MAX: f32 = 18_000.0;
+
+/// Default per-voice detune spread in cents
+pub const VOICE_SPREAD_CENTS: f32 = 2.0;
+
+/// Fixed per-voice detune pattern (fraction of the spread), so the same voice
+/// always gets the same offset and unisons never phase-lock
+const VOICE_SPREAD_PATTERN: [f32; VOICE_COUNT] = [0.0, 1.0, -1.0, 0.5, -0.5, 0.75, -0.75];
+
 /// Voice unassigned marker
 const VOICE_UNASSIGNED: u8 = u8::MAX;
 
+/// Largest `set_cross_mod` amount: the destination's frequency swings
+/// between zero and double
+pub const CROSS_MOD_MAX: f32 = 1.0;
+
+/// Most extra voices `set_stack` adds to a note: the rest of the voices
+pub const STACK_MAX: usize = VOICE_COUNT - 1;
+/// Largest stack detune in cents either way
+pub const STACK_DETUNE_MAX_CENTS: f32 = 50.0;
+
+/// Maximum number of simultaneously held keys tracked for mono mode
+const HELD_NOTE_MAX: usize = KEY_COUNT * OCTAVE_COUNT;
+/// Default arpeggiator rate in steps per second (16ths at 120 BPM)
+pub const ARP_RATE: f32 = 8.0;
+/// Default fraction of each arp step the note sounds
+pub const ARP_GATE: f32 = 0.5;
+/// Shortest arp gate fraction, so every step is audible
+const ARP_GATE_MIN: f32 = 0.05;
+/// Most octaves an arp pattern can span
+pub const ARP_OCTAVES_MAX: u8 = 4;
+/// Shortest gap between note-repeat hits, so the envelope (updated every
+/// ~2 ms) sees the gate close and attacks again (~2.9 ms)
+const NOTE_REPEAT_GAP: u64 = 128;
+
 /// Precomputed frequencies for all 12 semitones in octave 0 (C3-B3)
 const SEMITONE_FREQS: [f32; KEY_COUNT] = [
     130.81, // C  (C3)
@@ -50,6 +115,96 @@ const SEMITONE_FREQS: [f32; KEY_COUNT] = [
     246.94, // B  (B3)
 ];
 
+// ============================================================================
+// VELOCITY
+// ============================================================================
+
+/// Highest note velocity (MIDI range)
+pub const VELOCITY_MAX: u8 = 127;
+/// Default lowest velocity `note_on` plays at, see
+/// `KeyboardSynth::set_velocity_floor`: -18 dB on the linear curve
+pub const VELOCITY_FLOOR: u8 = 16;
+/// Per-voice filter darkening in octaves for the softest note at amount 1.0
+const VELOCITY_CUTOFF_OCTAVES: f32 = 3.0;
+/// Attack time change in octaves for a full-velocity note at amount 1.0:
+/// 16x faster
+const VELOCITY_ATTACK_OCTAVES: f32 = 4.0;
+/// Contact travel time in µs at or below which a dual-contact key plays at
+/// full velocity
+const CONTACT_TIME_FAST: u64 = 2_000;
+/// Contact travel time in µs at or above which it plays at velocity 1
+const CONTACT_TIME_SLOW: u64 = 60_000;
+/// Largest per-octave gain, +6 dB
+const OCTAVE_GAIN_MAX: f32 = 2.0;
+/// Steepest tilt in dB per octave, either way
+const TILT_MAX: f32 = 6.0;
+/// Release velocity that keeps the normal release time
+const RELEASE_VELOCITY_CENTER: u8 = 64;
+/// Release time change in octaves at the extreme release velocities:
+/// 127 releases 4x faster, 1 about 4x slower
+const RELEASE_VELOCITY_OCTAVES: f32 = 2.0;
+
+/// Time source for dual-contact key timing, in microseconds, see
+/// `KeyboardSynth::set_contact_clock`.
+pub type ContactClock = fn() -> u64;
+
+/// Note velocity for a dual-contact key's travel time between its
+/// contacts: full velocity up to `CONTACT_TIME_FAST`, falling
+/// logarithmically to 1 at `CONTACT_TIME_SLOW`, so each halving of the
+/// time adds the same step (about 26 of 127).
+fn contact_velocity(travel_us: u64) -> u8 {
+    let travel = travel_us.clamp(CONTACT_TIME_FAST, CONTACT_TIME_SLOW) as f32;
+    let range = libm::logf(CONTACT_TIME_SLOW as f32 / CONTACT_TIME_FAST as f32);
+    let position = libm::logf(CONTACT_TIME_SLOW as f32 / travel) / range;
+    1 + libm::roundf(position * (VELOCITY_MAX - 1) as f32) as u8
+}
+
+/// Release time multiplier for a note-off velocity.
+fn release_scale(velocity: u8) -> f32 {
+    let offset = RELEASE_VELOCITY_CENTER as f32 - velocity.clamp(1, VELOCITY_MAX) as f32;
+    libm::exp2f(RELEASE_VELOCITY_OCTAVES * offset / (VELOCITY_MAX - RELEASE_VELOCITY_CENTER) as f32)
+}
+
+/// Which voice a new note takes when every voice in its pool is busy.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, defmt::Format)]
+pub enum StealMode {
+    /// Each voice in turn
+    #[default]
+    RoundRobin,
+    /// The one sounding quietest: lowest velocity times its estimated
+    /// envelope level, so soft and fading notes go before prominent ones
+    Quietest,
+    /// The one released longest ago, its tail the furthest faded; each
+    /// voice in turn while every note is still held
+    OldestReleased,
+}
+
+/// Response of `note_on` velocities, mapping 1-127 to a 0.0-1.0 gain.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum VelocityCurve {
+    /// Gain proportional to velocity
+    Linear,
+    /// Square root: light playing comes out louder, for stiff controllers
+    Soft,
+    /// Square: needs a firm touch to get loud, for light controllers
+    Hard,
+    /// Ignore the played velocity and use this one (organ-style)
+    Fixed(u8),
+}
+
+impl VelocityCurve {
+    /// Voice gain for an incoming velocity.
+    pub fn gain(self, velocity: u8) -> f32 {
+        let linear = |velocity: u8| velocity.clamp(1, VELOCITY_MAX) as f32 / VELOCITY_MAX as f32;
+        match self {
+            VelocityCurve::Linear => linear(velocity),
+            VelocityCurve::Soft => libm::sqrtf(linear(velocity)),
+            VelocityCurve::Hard => linear(velocity) * linear(velocity),
+            VelocityCurve::Fixed(fixed) => linear(fixed),
+        }
+    }
+}
+
 // ============================================================================
 // VOICE NOTE ENCODING
 // ============================================================================
@@ -61,222 +216,7944 @@ const fn encode_note(key: u8, octave: u8) -> u8 {
     (octave << 4) | (key & 0x0F)
 }
 
+/// Decode a note produced by `encode_note` back into (key, octave).
+#[inline(always)]
+const fn decode_note(note: u8) -> (usize, u8) {
+    ((note & 0x0F) as usize, note >> 4)
+}
+
 // ============================================================================
-// SYNTHESIZER
+// PITCH BEND
 // ============================================================================
 
-/// Polyphonic synthesizer with multiplexed 48-key matrix (12 keys × 4 octaves).
-///
-/// Hardware setup:
-/// - 12 physical key inputs (buttons)
-/// - 4 octave select outputs (only one LOW at a time to enable that octave)
-/// - Scanning through octaves rapidly gives us 48 virtual keys
-///
-/// Features:
-/// - Full 4-octave range (C3-B3 up to C6-B6)
-/// - Octave multiplexing: same physical key can trigger different octaves
-/// - Round-robin voice stealing when all 7 voices are busy
-/// - Rapid octave scanning to catch all key presses
-///
-/// The synth scans through all 4 octaves on each poll, setting one output
-/// LOW at a time and reading the 12 keys for that octave.
-pub struct KeyboardSynth {
-    net: Box<dyn AudioUnit>,
-    freqs: [Shared; VOICE_COUNT],
-    gates: [Shared; VOICE_COUNT],
-    /// Maps voice index -> encoded note (key + octave), or VOICE_UNASSIGNED
-    voice_note: [u8; VOICE_COUNT],
-    /// Base frequencies for each voice (without pitch bend applied)
-    base_freqs: [f32; VOICE_COUNT],
-    /// Next voice to steal when all are busy (round-robin counter)
-    next_voice: usize,
-    /// Previous key states for edge detection (per octave)
-    key_states: [[bool; KEY_COUNT]; OCTAVE_COUNT],
-    pitch_bend: Shared,
-    resonator_freq: Shared,
+/// Default pitch wheel range in semitones
+pub const BEND_RANGE: f32 = 2.0;
+/// Widest pitch wheel range, the limit of `set_pitch_bend`
+pub const BEND_RANGE_MAX: f32 = 12.0;
+
+/// Pitch bend ratio per semitone: ln(2)/12 ≈ 0.05776, gives ~0.16% max
+/// error for ±1 semitone
+const BEND_FACTOR: f32 = 0.057762265;
+
+/// Convert a bend in semitones (-12.0 to 12.0) to a frequency ratio.
+/// Uses cheap linear approximation: ratio ≈ 1 + bend * ln(2)/12
+#[inline]
+fn bend_ratio(bend: f32) -> f32 {
+    assert!((-12.0..=12.0).contains(&bend), "Pitch bend out of range");
+    1.0 + bend * BEND_FACTOR
 }
 
-impl KeyboardSynth {
-    /// Create a new synthesizer with default settings.
-    pub fn new() -> Self {
-        let freqs = arr![|_| Shared::new(0.0)];
-        let gates = arr![|_| Shared::new(0.0)];
-        let pitch_bend = Shared::new(1.0);
-        let resonator_freq = Shared::new(880.0);
-        let net = Box::new(
-            (var(&freqs[0])
-                >> (poly_saw::<f32>()
-                    * (var(&gates[0])
-                        >> adsr_live(ENV_ATTACK, ENV_DECAY, ENV_SUSTAIN, ENV_RELEASE))
-                    * VOICE_GAIN)
-                | var(&freqs[1])
-                    >> (poly_saw::<f32>()
-                        * (var(&gates[1])
-                            >> adsr_live(ENV_ATTACK, ENV_DECAY, ENV_SUSTAIN, ENV_RELEASE))
-                        * VOICE_GAIN)
-                | var(&freqs[2])
-                    >> (poly_saw::<f32>()
-                        * (var(&gates[2])
-                            >> adsr_live(ENV_ATTACK, ENV_DECAY, ENV_SUSTAIN, ENV_RELEASE))
-                        * VOICE_GAIN)
-                | var(&freqs[3])
-                    >> (poly_saw::<f32>()
-                        * (var(&gates[3])
-                            >> adsr_live(ENV_ATTACK, ENV_DECAY, ENV_SUSTAIN, ENV_RELEASE))
-                        * VOICE_GAIN)
-                | var(&freqs[4])
-                    >> (poly_saw::<f32>()
-                        * (var(&gates[4])
-                            >> adsr_live(ENV_ATTACK, ENV_DECAY, ENV_SUSTAIN, ENV_RELEASE))
-                        * VOICE_GAIN)
-                | var(&freqs[5])
-                    >> (poly_saw::<f32>()
-                        * (var(&gates[5])
-                            >> adsr_live(ENV_ATTACK, ENV_DECAY, ENV_SUSTAIN, ENV_RELEASE))
-                        * VOICE_GAIN)
-                | var(&freqs[6])
-                    >> (poly_saw::<f32>()
-                        * (var(&gates[6])
-                            >> adsr_live(ENV_ATTACK, ENV_DECAY, ENV_SUSTAIN, ENV_RELEASE))
-                        * VOICE_GAIN))
-                >> join::<U7>()
-                >> lowpole_hz(1200.0)
-                >> (pass() | var(&resonator_freq) | dc(1.0))
-                >> peak::<f32>(), // Efficient peaking filter (Q=2.0)
-        );
+/// Semitones of a `bend_ratio`, its exact inverse.
+fn bend_semitones(ratio: f32) -> f32 {
+    (ratio - 1.0) / BEND_FACTOR
+}
 
-        Self {
-            net,
-            freqs,
-            gates,
-            voice_note: [VOICE_UNASSIGNED; VOICE_COUNT],
-            base_freqs: [0.0; VOICE_COUNT],
-            next_voice: 0,
-            key_states: [[false; KEY_COUNT]; OCTAVE_COUNT],
-            pitch_bend,
-            resonator_freq,
+// ============================================================================
+// VOICE SPREAD
+// ============================================================================
+
+/// Detune ratio for each voice for a spread of `cents`.
+fn spread_ratios(cents: f32) -> [f32; VOICE_COUNT] {
+    arr![|voice| libm::exp2f(VOICE_SPREAD_PATTERN[voice] * cents / 1200.0)]
+}
+
+// ============================================================================
+// VOICE GAIN
+// ============================================================================
+
+/// Per-sample smoothing coefficient of the normalized output gain (~5 ms)
+const GAIN_SMOOTHING: f32 = 0.005;
+/// Per-sample master gain change of the mute ramp (full scale in ~10 ms)
+const MUTE_RAMP_STEP: f32 = 1.0 / 441.0;
+/// Mono-safe width control: narrow when the stereo correlation falls below
+/// `MONO_SAFE_MIN`, widen back while it is above `MONO_SAFE_RECOVER`
+const MONO_SAFE_MIN: f32 = 0.2;
+const MONO_SAFE_RECOVER: f32 = 0.5;
+/// Per-buffer width factor when narrowing and width step when widening
+/// (about 0.7 s back to full width at 640 frames)
+const MONO_SAFE_NARROW: f32 = 0.7;
+const MONO_SAFE_WIDEN: f32 = 0.02;
+/// Estimated level below which a stolen voice is taken without a fade
+const STEAL_FADE_LEVEL: f32 = 0.001;
+/// Default fade-in of the output from startup
+pub const STARTUP_FADE: Duration = Duration::from_millis(50);
+/// Default crossfade from the old audio graph to the new one when a
+/// topology change rebuilds it
+pub const TOPOLOGY_FADE: Duration = Duration::from_millis(5);
+
+/// Per-sample master gain step that ramps from silence to full in `fade`.
+fn fade_step(fade: Duration) -> f32 {
+    1.0 / (fade.as_secs_f32() * DEFAULT_SR as f32).max(1.0)
+}
+
+/// The graph a rebuild replaced, still playing while it fades out.
+struct NetFade {
+    net: Net,
+    /// Frames left of the fade, and its length
+    remaining: usize,
+    frames: usize,
+}
+
+/// Render `frames` of `net` (one or two outputs) into stereo `out`.
+fn process_net(net: &mut Net, frames: usize, out: &mut [(f32, f32); MAX_BUFFER_SIZE]) {
+    if net.outputs() == 1 {
+        // Custom mono net: same signal on both channels
+        let mut buffer = BufferArray::<U1>::new();
+        net.process(frames, &BufferRef::empty(), &mut buffer.buffer_mut());
+        for (i, frame) in out[..frames].iter_mut().enumerate() {
+            let sample = buffer.at_f32(0, i);
+            *frame = (sample, sample);
+        }
+    } else {
+        let mut buffer = BufferArray::<U2>::new();
+        net.process(frames, &BufferRef::empty(), &mut buffer.buffer_mut());
+        for (i, frame) in out[..frames].iter_mut().enumerate() {
+            *frame = (buffer.at_f32(0, i), buffer.at_f32(1, i));
         }
     }
+}
 
-    /// Scan all octaves and handle key detection.
-    /// Update key state and handle press/release events.
-    /// This should be called on every scan with the current key state.
-    /// It will detect edge changes and trigger note on/off accordingly.
-    #[inline]
-    pub fn update_key(&mut self, key: usize, octave: u8, pressed: bool) {
-        let octave_idx = octave as usize;
-        if pressed != self.key_states[octave_idx][key] {
-            self.key_states[octave_idx][key] = pressed;
-            self.handle_key_change(key, octave, pressed);
+/// Samples in `fade`.
+fn fade_frames(fade: Duration) -> usize {
+    (fade.as_secs_f32() * DEFAULT_SR as f32) as usize
+}
+
+/// How the output level reacts to the number of sounding voices.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum GainMode {
+    /// Constant gain: the voice mix is averaged over all `VOICE_COUNT`
+    /// voices, so it can't clip but a single note is quiet
+    Fixed,
+    /// Scale by sqrt(VOICE_COUNT / sounding), keeping perceived loudness
+    /// roughly constant as voices come and go (release tails included). Equal to `Fixed` with every
+    /// voice playing.
+    Normalized,
+}
+
+/// Output gain for `sounding` voices, held or in their release.
+#[inline]
+fn voice_gain(mode: GainMode, sounding: usize) -> f32 {
+    match mode {
+        GainMode::Fixed => 1.0,
+        GainMode::Normalized => {
+            libm::sqrtf(VOICE_COUNT as f32 / core::cmp::max(sounding, 1) as f32)
+        }
+    }
+}
+
+// ============================================================================
+// LFO
+// ============================================================================
+
+/// Default LFO rate in Hz
+pub const LFO_RATE: f32 = 5.0;
+
+/// Waveform of the global LFO, see `KeyboardSynth::set_lfo`.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Square,
+    /// A new random level every cycle (stepped random modulation)
+    SampleHold,
+    /// Rising sawtooth
+    Ramp,
+}
+
+/// Where the global LFO is routed, see `KeyboardSynth::set_lfo_depth`.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LfoDestination {
+    /// Vibrato, depth in semitones
+    Pitch,
+    /// Main filter cutoff, depth in octaves
+    Filter,
+    /// Tremolo, depth 0.0-1.0 (1.0 dips to silence)
+    Amplitude,
+    /// Voice pan, depth 0.0-1.0, moves the auto-pan positions
+    Pan,
+    /// Width of `Waveform::Pulse`, depth 0.0-0.45 either way
+    PulseWidth,
+}
+
+/// Largest LFO swing of the pulse width either way
+pub const LFO_PULSE_WIDTH_MAX: f32 = 0.45;
+
+/// Note length an LFO cycle is synced to, see `KeyboardSynth::set_lfo_sync`,
+/// or a trance gate step lasts, see `KeyboardSynth::set_trance_gate`.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum NoteDivision {
+    Quarter,
+    Eighth,
+    EighthTriplet,
+    Sixteenth,
+}
+
+impl NoteDivision {
+    /// LFO cycles (or trance gate steps) per quarter-note beat
+    pub(crate) fn per_beat(self) -> f32 {
+        match self {
+            Self::Quarter => 1.0,
+            Self::Eighth => 2.0,
+            Self::EighthTriplet => 3.0,
+            Self::Sixteenth => 4.0,
         }
     }
+}
 
-    /// Handle a key press or release event.
-    /// This is called internally when a state change is detected.
-    #[inline]
-    fn handle_key_change(&mut self, key: usize, octave: u8, pressed: bool) {
-        let note = encode_note(key as u8, octave);
-        let octave_mult = 1 << octave; // 2^octave
+/// Default tempo in BPM
+pub const TEMPO: f32 = 120.0;
+/// Keys (key, octave) that switch drum step editing on and off when
+/// pressed together, see `KeyboardSynth::set_drum_edit_combo`: the bottom
+/// left and top right of the grid
+pub const DRUM_EDIT_COMBO: [(usize, u8); 2] = [(0, 0), (KEY_COUNT - 1, OCTAVE_COUNT as u8 - 1)];
+/// MIDI clock ticks per quarter note
+const MIDI_CLOCK_PPQ: u32 = 24;
 
-        if pressed {
-            // Check if this exact note (key + octave) already has a voice
-            for voice in 0..VOICE_COUNT {
-                if self.voice_note[voice] == note {
-                    self.gates[voice].set_value(1.0);
-                    return;
-                }
-            }
+/// Frames between LFO updates (~0.7 ms)
+const LFO_INTERVAL: usize = 32;
+/// Smoothing time of the LFO's filter, amplitude and pulse width
+/// modulation in seconds, so the update steps and square edges don't click
+const LFO_SMOOTHING: f32 = 0.002;
 
-            // Find first free voice
-            for voice in 0..VOICE_COUNT {
-                if self.voice_note[voice] == VOICE_UNASSIGNED {
-                    self.allocate_voice(voice, note, key, octave_mult);
-                    return;
+/// Global LFO state, advanced at control rate by `render`.
+struct Lfo {
+    shape: LfoShape,
+    rate: f32,
+    /// Cycle position, 0.0 to 1.0
+    phase: f32,
+    /// Sample-and-hold level, -1.0 to 1.0
+    held: f32,
+    /// xorshift32 state for sample-and-hold
+    rng: u32,
+}
+
+impl Lfo {
+    fn new() -> Self {
+        Self {
+            shape: LfoShape::Sine,
+            rate: LFO_RATE,
+            phase: 0.0,
+            held: 0.0,
+            rng: 0x2545_F491,
+        }
+    }
+
+    /// Current output, -1.0 to 1.0. Every shape starts its cycle rising
+    /// from 0 (square and sample-and-hold at their high and held level).
+    fn value(&self) -> f32 {
+        let p = self.phase;
+        match self.shape {
+            LfoShape::Sine => libm::sinf(core::f32::consts::TAU * p),
+            LfoShape::Triangle => {
+                if p < 0.25 {
+                    4.0 * p
+                } else if p < 0.75 {
+                    2.0 - 4.0 * p
+                } else {
+                    4.0 * p - 4.0
                 }
             }
-
-            // All voices busy - steal using round-robin
-            let voice = self.next_voice;
-            self.next_voice = (self.next_voice + 1) % VOICE_COUNT;
-            self.allocate_voice(voice, note, key, octave_mult);
-        } else {
-            // Key released - find the voice with this exact note
-            for voice in 0..VOICE_COUNT {
-                if self.voice_note[voice] == note {
-                    self.gates[voice].set_value(0.0);
-                    break;
+            LfoShape::Square => {
+                if p < 0.5 {
+                    1.0
+                } else {
+                    -1.0
                 }
             }
+            LfoShape::SampleHold => self.held,
+            LfoShape::Ramp => 2.0 * p - 1.0,
         }
     }
-    /// Allocate a voice to a note and trigger the envelope.
-    #[inline(always)]
-    fn allocate_voice(&mut self, voice: usize, note: u8, key: usize, octave_mult: u8) {
-        self.voice_note[voice] = note;
-        let base_freq = SEMITONE_FREQS[key] * octave_mult as f32;
-        self.base_freqs[voice] = base_freq;
-        // Apply current pitch bend
-        let bent_freq = base_freq * self.pitch_bend.value();
-        self.freqs[voice].set_value(bent_freq);
-        self.gates[voice].set_value(1.0);
+
+    fn advance(&mut self, frames: usize) {
+        self.phase += self.rate * frames as f32 / DEFAULT_SR as f32;
+        if self.phase >= 1.0 {
+            self.phase = libm::fmodf(self.phase, 1.0);
+            self.sample();
+        }
     }
 
-    /// Generate next audio sample (for single-sample processing).
-    #[inline(always)]
-    pub fn get_sample(&mut self) -> f32 {
-        self.net.get_mono()
+    /// Restart the cycle, with a fresh sample-and-hold level.
+    fn restart(&mut self) {
+        self.phase = 0.0;
+        self.sample();
     }
 
-    /// Process a block of audio samples efficiently.
-    /// Uses SIMD acceleration when available.
-    #[inline]
-    pub fn process_block(&mut self, output: &mut [f32], buffer_size: usize) {
-        // Create a mono buffer for fundsp block processing
-        let mut buffer = BufferArray::<U1>::new();
+    fn sample(&mut self) {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.held = self.rng as f32 / u32::MAX as f32 * 2.0 - 1.0;
+    }
+}
 
-        // Process in chunks of MAX_BUFFER_SIZE (64 samples) for optimal SIMD usage
-        let mut processed = 0;
-        while processed < buffer_size {
-            let chunk_size = core::cmp::min(buffer_size - processed, 64);
+// ============================================================================
+// SENSOR SAMPLE-AND-HOLD
+// ============================================================================
 
-            // Process chunk
-            self.net
-                .process(chunk_size, &BufferRef::empty(), &mut buffer.buffer_mut());
+/// Most zones the sensor range can be split into
+pub const SENSOR_SH_STEPS_MAX: u8 = 16;
+/// How far past a zone edge, in zones, the position has to move before the
+/// zone changes, so a hand resting on an edge doesn't flicker
+const SENSOR_SH_HYSTERESIS: f32 = 0.25;
+/// Range of the random filter levels in octaves, centered on the cutoff
+const SENSOR_SH_FILTER_OCTAVES: f32 = 4.0;
 
-            // Copy to output buffer
-            for i in 0..chunk_size {
-                output[processed + i] = buffer.at_f32(0, i);
-            }
+/// What the sensor's sample-and-hold steps, see
+/// `KeyboardSynth::set_sensor_sh`.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SensorShTarget {
+    /// A random semitone, 0 to 12 above the played notes
+    Pitch,
+    /// A random main filter cutoff, up to 2 octaves either way
+    Filter,
+}
 
-            processed += chunk_size;
+/// Sensor position quantized into zones, with a random level per zone
+/// change.
+struct SensorSh {
+    enabled: bool,
+    steps: u8,
+    target: SensorShTarget,
+    /// Current zone, None until the first reading
+    zone: Option<u8>,
+    /// Held random level, 0.0 to 1.0
+    held: f32,
+    /// xorshift32 state
+    rng: u32,
+}
+
+impl SensorSh {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            steps: 8,
+            target: SensorShTarget::Pitch,
+            zone: None,
+            held: 0.0,
+            rng: 0x6C07_8965,
         }
     }
 
-    /// Set pitch bend. Input range: -12.0 to 12.0 (semitones).
-    /// Uses cheap linear approximation: ratio ≈ 1 + bend * ln(2)/12
-    #[inline]
-    pub fn set_pitch_bend(&mut self, bend: f32) {
-        assert!(bend >= -12.0 && bend <= 12.0, "Pitch bend out of range");
-        // ln(2)/12 ≈ 0.05776, gives ~0.16% max error for ±1 semitone
-        const BEND_FACTOR: f32 = 0.057762265;
-        let ratio = 1.0 + bend * BEND_FACTOR;
-        self.pitch_bend.set_value(ratio);
-        // Update all active voices with new pitch bend
-        for voice in 0..VOICE_COUNT {
-            if self.voice_note[voice] != VOICE_UNASSIGNED {
-                let bent_freq = self.base_freqs[voice] * ratio;
-                self.freqs[voice].set_value(bent_freq);
-            }
+    /// Take a position (0.0-1.0); true when it entered a new zone and a new
+    /// level was drawn.
+    fn update(&mut self, position: f32) -> bool {
+        let x = position.clamp(0.0, 1.0) * self.steps as f32;
+        if let Some(zone) = self.zone
+            && x >= zone as f32 - SENSOR_SH_HYSTERESIS
+            && x <= (zone + 1) as f32 + SENSOR_SH_HYSTERESIS
+        {
+            return false;
+        }
+        let zone = core::cmp::min(x as u8, self.steps - 1);
+        if self.zone == Some(zone) {
+            return false;
         }
+        self.zone = Some(zone);
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.held = self.rng as f32 / u32::MAX as f32;
+        true
     }
+}
 
-    /// Get a clone of the pitch bend Shared for external control
-    #[inline]
-    pub fn pitch_bend_control(&self) -> Shared {
-        self.pitch_bend.clone()
+// ============================================================================
+// ANALOG DRIFT
+// ============================================================================
+
+/// Largest pitch drift in cents at amount 1.0
+const DRIFT_MAX_CENTS: f32 = 3.0;
+/// Samples between drift steps (~4 per second)
+const DRIFT_INTERVAL: u64 = 11_025;
+/// Largest random walk step, as a fraction of the full drift range
+const DRIFT_STEP: f32 = 0.1;
+
+/// Deepest unison drift in cents either way
+pub const UNISON_DRIFT_MAX_CENTS: f32 = 25.0;
+/// Fastest unison drift in Hz
+const UNISON_DRIFT_RATE_MAX: f32 = 2.0;
+/// Rate of each voice's unison drift LFO as a multiple of the set rate,
+/// uneven so the voices never fall back into step
+const UNISON_DRIFT_RATES: [f32; VOICE_COUNT] = [1.0, 1.27, 0.79, 1.41, 0.67, 1.13, 0.89];
+
+/// Deterministic per-voice random walk for pitch drift.
+#[derive(Clone, Copy)]
+struct Drift {
+    /// xorshift32 state, seeded from the voice index
+    rng: u32,
+    /// Walk position, -1.0 to 1.0
+    position: f32,
+}
+
+impl Drift {
+    fn new(voice: usize) -> Self {
+        Self {
+            rng: 0x9E37_79B9 ^ (voice as u32 + 1).wrapping_mul(0x85EB_CA6B),
+            position: 0.0,
+        }
     }
-    #[inline]
-    pub fn resonator_freq_control(&self) -> Shared {
-        self.resonator_freq.clone()
+
+    /// Take one bounded random step.
+    fn step(&mut self) {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        let uniform = self.rng as f32 / u32::MAX as f32 * 2.0 - 1.0;
+        self.position = (self.position + uniform * DRIFT_STEP).clamp(-1.0, 1.0);
+    }
+}
+
+// ============================================================================
+// AUTO-PAN
+// ============================================================================
+
+/// Pan position of each voice in `AutoPanMode::ByVoice`, alternating sides
+/// so consecutive allocations land apart
+const VOICE_PAN_PATTERN: [f32; VOICE_COUNT] = [0.0, -0.5, 0.5, -0.8, 0.8, -0.25, 0.25];
+
+/// How voices are spread across the stereo field.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AutoPanMode {
+    /// All voices centered
+    Off,
+    /// Fixed position per voice index
+    ByVoice,
+    /// By note: the lowest key sits in the center, higher notes spread
+    /// further out, neighbouring semitones on opposite sides
+    ByPitch,
+}
+
+/// Pan position for a note in `mode`.
+fn auto_pan(mode: AutoPanMode, voice: usize, note: u8) -> f32 {
+    match mode {
+        AutoPanMode::Off => 0.0,
+        AutoPanMode::ByVoice => VOICE_PAN_PATTERN[voice],
+        AutoPanMode::ByPitch => {
+            let (key, octave) = decode_note(note);
+            let index = octave as usize * KEY_COUNT + key;
+            let spread = index as f32 / (OCTAVE_COUNT * KEY_COUNT - 1) as f32;
+            if key % 2 == 0 { spread } else { -spread }
+        }
+    }
+}
+
+// ============================================================================
+// TEST TONE
+// ============================================================================
+
+/// Default tuning reference frequency (A4)
+pub const TEST_TONE_FREQ: f32 = 440.0;
+/// Test tone peak level (-6 dBFS). On its own it can't clip, and it leaves
+/// headroom for notes played alongside.
+const TEST_TONE_LEVEL: f32 = 0.5;
+
+// ============================================================================
+// MONO MODE
+// ============================================================================
+
+/// Which held note sounds in mono mode when several keys are down.
+/// Encoded notes sort by pitch, so Low/High compare them directly.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum NotePriority {
+    /// Most recently pressed key
+    Last,
+    /// Lowest held key
+    Low,
+    /// Highest held key
+    High,
+}
+
+/// Order in which the arpeggiator steps through the held notes.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ArpMode {
+    /// Lowest to highest
+    Up,
+    /// Highest to lowest
+    Down,
+    /// Up then down, without repeating the top and bottom notes
+    UpDown,
+    /// In the order the keys were pressed
+    Played,
+}
+
+// ============================================================================
+// ZONES
+// ============================================================================
+
+/// Oscillator waveform of a voice.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Waveform {
+    Saw,
+    Square,
+    Triangle,
+    Sine,
+    /// Variable-width pulse (`KeyboardSynth::set_pulse_width`), a square at
+    /// 50%
+    Pulse,
+}
+
+/// Default pulse width of `Waveform::Pulse`, a square
+pub const PULSE_WIDTH: f32 = 0.5;
+/// Narrowest and widest pulse: beyond these the pulse thins to a click
+/// train and its polyBLEP edges start to overlap in the top octave
+pub const PULSE_WIDTH_MIN: f32 = 0.05;
+pub const PULSE_WIDTH_MAX: f32 = 0.95;
+
+/// Sound settings for one keyboard zone.
+#[derive(Clone, Copy)]
+pub struct ZoneConfig {
+    pub waveform: Waveform,
+    /// Octave shift applied to every note in the zone
+    pub transpose: i8,
+    /// Linear volume multiplier (1.0 = unchanged)
+    pub volume: f32,
+    /// Number of voices reserved for this zone when the keyboard is split
+    pub voices: usize,
+}
+
+impl Default for ZoneConfig {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Saw,
+            transpose: 0,
+            volume: 1.0,
+            voices: VOICE_COUNT,
+        }
+    }
+}
+
+/// A new note waiting for the voice it stole to fade out.
+#[derive(Clone, Copy)]
+struct StealFade {
+    /// `clock` at which the fade is over
+    due: u64,
+    freq: f32,
+    /// Note velocity (gain) at the steal
+    velocity: f32,
+}
+
+/// A zone together with its voice pool `lo..hi` and round-robin state.
+#[derive(Clone, Copy)]
+struct Zone {
+    config: ZoneConfig,
+    lo: usize,
+    hi: usize,
+    /// Next voice to steal when all are busy (round-robin counter)
+    next_voice: usize,
+}
+
+impl Zone {
+    const fn new(config: ZoneConfig, lo: usize, hi: usize) -> Self {
+        Self {
+            config,
+            lo,
+            hi,
+            next_voice: lo,
+        }
+    }
+}
+
+/// Pitch slide envelope: a frequency ratio that starts `offset` semitones
+/// away on each gate rise and glides linearly in pitch back to 1.0 over `time`
+/// seconds. Both are read live, so changes apply from the next note on.
+fn pitch_env(
+    offset: &Shared,
+    time: &Shared,
+) -> An<impl AudioNode<Inputs = U1, Outputs = U1> + use<>> {
+    let offset = offset.clone();
+    let time = time.clone();
+    let mut start = f32::NEG_INFINITY;
+    let mut gate_was_on = false;
+    envelope2(move |t: f32, gate: f32| {
+        let gate_on = gate > 0.0;
+        if gate_on && !gate_was_on {
+            start = t;
+        }
+        gate_was_on = gate_on;
+        let slide = time.value();
+        let elapsed = t - start;
+        if slide <= 0.0 || elapsed >= slide {
+            1.0
+        } else {
+            libm::exp2f(offset.value() * (1.0 - elapsed / slide) / 12.0)
+        }
+    })
+}
+
+/// Portamento shape between mono legato notes.
+#[derive(Clone, Copy, PartialEq, Eq, Default, defmt::Format)]
+#[repr(u8)]
+pub enum GlideCurve {
+    /// Straight line in Hz: rushes through the low end of the interval
+    Linear = 0,
+    /// Straight line in pitch (constant cents per second), so an octave
+    /// glide sounds the same in any register
+    #[default]
+    Exponential = 1,
+}
+
+impl GlideCurve {
+    /// Decode a curve stored in a Shared.
+    fn from_code(code: f32) -> Self {
+        match code as u8 {
+            0 => Self::Linear,
+            _ => Self::Exponential,
+        }
+    }
+}
+
+/// Frequency ratio to the target note after fraction `x` (0.0-1.0) of a
+/// glide that started at `start_ratio` times the target.
+fn glide_ratio(curve: GlideCurve, start_ratio: f32, x: f32) -> f32 {
+    match curve {
+        GlideCurve::Linear => start_ratio + (1.0 - start_ratio) * x,
+        GlideCurve::Exponential => libm::powf(start_ratio, 1.0 - x),
+    }
+}
+
+/// Glide envelope: a frequency ratio that restarts at `start_ratio` whenever
+/// `trigger` changes and reaches 1.0 after `time` seconds along `curve`.
+fn glide_env(
+    start_ratio: &Shared,
+    trigger: &Shared,
+    time: &Shared,
+    curve: &Shared,
+) -> An<impl AudioNode<Inputs = U0, Outputs = U1> + use<>> {
+    let start_ratio = start_ratio.clone();
+    let trigger = trigger.clone();
+    let time = time.clone();
+    let curve = curve.clone();
+    let mut start = f32::NEG_INFINITY;
+    let mut last_trigger = trigger.value();
+    lfo(move |t: f32| {
+        if trigger.value() != last_trigger {
+            last_trigger = trigger.value();
+            start = t;
+        }
+        let glide = time.value();
+        let elapsed = t - start;
+        if glide <= 0.0 || elapsed >= glide {
+            1.0
+        } else {
+            let curve = GlideCurve::from_code(curve.value());
+            glide_ratio(curve, start_ratio.value(), elapsed / glide)
+        }
+    })
+}
+
+/// Frames an idle voice keeps running past the end of its release, for the
+/// envelope's ~2 ms update steps and the voice filters' tails (~20 ms)
+const IDLE_VOICE_MARGIN: u64 = 882;
+
+/// Frames the amp envelope may trail its gate: it reads the gate once per
+/// segment of up to 2.5 ms, starts the release at the end of that segment
+/// and reaches silence only at the segment point after the release ends
+const ENVELOPE_LAG: f32 = 331.0;
+
+/// A voice graph that only runs while `active` is non-zero and outputs
+/// silence otherwise, so idle voices cost next to nothing per block.
+#[derive(Clone)]
+struct IdleSkip<O: Size<f32>> {
+    voice: Net,
+    active: Shared,
+    outputs: PhantomData<O>,
+}
+
+impl<O: Size<f32>> AudioNode for IdleSkip<O> {
+    const ID: u64 = 0x1D1E_5C1B;
+    type Inputs = U0;
+    type Outputs = O;
+
+    fn reset(&mut self) {
+        self.voice.reset();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.voice.set_sample_rate(sample_rate);
+    }
+
+    fn tick(&mut self, _input: &Frame<f32, U0>) -> Frame<f32, O> {
+        let mut output = Frame::default();
+        if self.active.value() > 0.0 {
+            self.voice.tick(&[], &mut output);
+        }
+        output
+    }
+
+    fn process(&mut self, size: usize, input: &BufferRef, output: &mut BufferMut) {
+        if self.active.value() > 0.0 {
+            self.voice.process(size, input, output);
+        } else {
+            for channel in 0..output.channels() {
+                output.channel_mut(channel)[..size.div_ceil(SIMD_LEN)].fill(F32x::ZERO);
+            }
+        }
+    }
+
+    fn allocate(&mut self) {
+        self.voice.allocate();
+    }
+}
+
+/// Wrap a voice graph with `outputs` outputs in an `IdleSkip`.
+fn idle_skip(voice: Net, active: &Shared, outputs: usize) -> Net {
+    let active = active.clone();
+    if outputs == 2 {
+        Net::wrap(Box::new(An(IdleSkip::<U2> {
+            voice,
+            active,
+            outputs: PhantomData,
+        })))
+    } else {
+        Net::wrap(Box::new(An(IdleSkip::<U1> {
+            voice,
+            active,
+            outputs: PhantomData,
+        })))
+    }
+}
+
+/// An oscillator that restarts at its initial phase whenever `trigger`
+/// changes, checked at the start of each block.
+#[derive(Clone)]
+struct PhaseReset<X: AudioNode<Inputs = U1, Outputs = U1>> {
+    osc: X,
+    trigger: Shared,
+    last_trigger: f32,
+}
+
+impl<X: AudioNode<Inputs = U1, Outputs = U1>> PhaseReset<X> {
+    #[inline]
+    fn check_trigger(&mut self) {
+        let trigger = self.trigger.value();
+        if trigger != self.last_trigger {
+            self.last_trigger = trigger;
+            self.osc.reset();
+        }
+    }
+}
+
+impl<X: AudioNode<Inputs = U1, Outputs = U1>> AudioNode for PhaseReset<X> {
+    const ID: u64 = 0x9A5E_0E5E;
+    type Inputs = U1;
+    type Outputs = U1;
+
+    fn reset(&mut self) {
+        self.osc.reset();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.osc.set_sample_rate(sample_rate);
+    }
+
+    fn tick(&mut self, input: &Frame<f32, U1>) -> Frame<f32, U1> {
+        self.check_trigger();
+        self.osc.tick(input)
+    }
+
+    fn process(&mut self, size: usize, input: &BufferRef, output: &mut BufferMut) {
+        self.check_trigger();
+        self.osc.process(size, input, output);
+    }
+
+    fn allocate(&mut self) {
+        self.osc.allocate();
+    }
+}
+
+/// `freq` driving `osc`, which with a `reset` trigger starts at phase 0
+/// and restarts there whenever the trigger changes.
+fn oscillator<X: AudioNode<Inputs = U1, Outputs = U1> + 'static>(
+    freq: An<impl AudioNode<Outputs = U1> + 'static>,
+    mut osc: An<X>,
+    reset: Option<&Shared>,
+) -> Net {
+    match reset {
+        Some(trigger) => {
+            osc.0.set(Setting::phase(0.0));
+            osc.0.reset();
+            Net::wrap(Box::new(
+                freq >> An(PhaseReset {
+                    osc: osc.0,
+                    trigger: trigger.clone(),
+                    last_trigger: trigger.value(),
+                }),
+            ))
+        }
+        None => Net::wrap(Box::new(freq >> osc)),
+    }
+}
+
+/// `freq` driving an oscillator of a waveform, see `oscillator`.
+fn waveform_osc(
+    freq: An<impl AudioNode<Outputs = U1> + 'static>,
+    waveform: Waveform,
+    controls: &Controls,
+    reset: Option<&Shared>,
+) -> Net {
+    match waveform {
+        Waveform::Saw => oscillator(freq, poly_saw::<f32>(), reset),
+        Waveform::Square => oscillator(freq, poly_square::<f32>(), reset),
+        Waveform::Triangle => oscillator(freq, triangle(), reset),
+        Waveform::Sine => oscillator(freq, sine::<f32>(), reset),
+        Waveform::Pulse => {
+            // The phase sits behind the width input, out of reach of the
+            // phase setting `oscillator` makes, so set it here
+            let mut pulse = poly_pulse::<f32>();
+            if reset.is_some() {
+                pulse.set(Setting::phase(0.0));
+            }
+            oscillator(freq, (pass() | pulse_width(controls)) >> pulse, reset)
+        }
+    }
+}
+
+/// Pulse width of `Waveform::Pulse`: the set width plus the smoothed LFO
+/// swing, kept within `PULSE_WIDTH_MIN..=PULSE_WIDTH_MAX`.
+fn pulse_width(controls: &Controls) -> An<impl AudioNode<Inputs = U0, Outputs = U1> + use<>> {
+    (var(&controls.pulse_width) + (var(&controls.lfo_pulse_width) >> follow(LFO_SMOOTHING)))
+        >> map(|f: &Frame<f32, U1>| f[0].clamp(PULSE_WIDTH_MIN, PULSE_WIDTH_MAX))
+}
+
+/// Frequency of a voice: the set frequency under the pitch envelope and
+/// glide.
+fn voice_freq(
+    controls: &Controls,
+    voice: usize,
+) -> An<impl AudioNode<Inputs = U0, Outputs = U1> + use<>> {
+    var(&controls.freqs[voice])
+        * (var(&controls.gates[voice])
+            >> pitch_env(&controls.pitch_env_offset, &controls.pitch_env_time))
+        * glide_env(
+            &controls.glide_ratios[voice],
+            &controls.glide_triggers[voice],
+            &controls.glide_time,
+            &controls.glide_curve,
+        )
+}
+
+/// Build the audio graph for one voice with the given waveform, optionally
+/// with its own one-pole filter tracking the main cutoff. With 2x
+/// oversampling the oscillator runs at twice the sample rate and is
+/// decimated through fundsp's half-band filter before the envelope.
+fn voice_net(topology: &Topology, controls: &Controls, voice: usize) -> Net {
+    voice_net_at(topology, controls, voice, voice_freq(controls, voice))
+}
+
+/// Build the graph of a voice whose frequency another voice modulates (see
+/// `KeyboardSynth::set_cross_mod`); the modulator's output is its input.
+/// The input is normalized to a full-level note and soft-limited to ±1,
+/// so the deviation stays within the depth however loud the source plays,
+/// and the frequency can't go below zero.
+fn cross_mod_voice_net(topology: &Topology, controls: &Controls, voice: usize) -> Net {
+    let freq = (pass() | voice_freq(controls, voice) | var(&controls.cross_mod_depth))
+        >> map(|f: &Frame<f32, U3>| {
+            (f[1] * (1.0 + f[2] * libm::tanhf(f[0] / VOICE_GAIN))).max(0.0)
+        });
+    voice_net_at(topology, controls, voice, freq)
+}
+
+/// Build the graph of a voice on the frequency `freq`, whose inputs become
+/// the voice's.
+fn voice_net_at<F: AudioNode<Outputs = U1> + 'static>(
+    topology: &Topology,
+    controls: &Controls,
+    voice: usize,
+    freq: An<F>,
+) -> Net {
+    let gate = &controls.gates[voice];
+    let env = var(gate)
+        >> (amp_env(
+            &controls.env,
+            &controls.attack_scales[voice],
+            &controls.release_scales[voice],
+        ) * VOICE_GAIN
+            * var(&controls.levels[voice])
+            * var(&controls.velocities[voice]));
+    let reset = topology
+        .phase_reset
+        .then_some(&controls.phase_resets[voice]);
+    let osc = match topology.layer {
+        // A layer runs a second oscillator on the same frequency,
+        // crossfaded in by the blend
+        Some(layer) => {
+            let crossfade = (pass() | pass() | var(&controls.osc_blend))
+                >> map(|f: &Frame<f32, U3>| f[0] + (f[1] - f[0]) * f[2]);
+            Net::wrap(Box::new(freq))
+                >> (waveform_osc(pass(), topology.waveforms[voice], controls, reset)
+                    ^ waveform_osc(pass(), layer, controls, reset))
+                >> Net::wrap(Box::new(crossfade))
+        }
+        None => waveform_osc(freq, topology.waveforms[voice], controls, reset),
+    };
+    let osc = if topology.oversampling > 1 {
+        Net::wrap(Box::new(oversample(unit::<F::Inputs, U1>(Box::new(osc)))))
+    } else {
+        osc
+    };
+    let osc = if topology.voice_filters {
+        let cutoff = modulated_cutoff(&controls.filter_cutoff, &controls.lfo_cutoff)
+            * var_fn(&controls.voice_cutoff_offsets[voice], libm::exp2f);
+        osc >> ((pass() | cutoff) >> lowpole::<f32>())
+    } else {
+        osc
+    };
+    let osc = if topology.filter_keytrack {
+        let cutoff = (modulated_cutoff(&controls.filter_cutoff, &controls.lfo_cutoff)
+            * var(&controls.keytrack_ratios[voice]))
+            >> map(|f: &Frame<f32, U1>| f[0].min(FILTER_KEYTRACK_CUTOFF_MAX));
+        osc >> filter_net(topology.filter_slope, cutoff, &controls.filter_resonance)
+    } else {
+        osc
+    };
+    let osc = if topology.voice_highpass {
+        osc >> ((pass() | var(&controls.voice_highpass[voice])) >> highpole::<f32>())
+    } else {
+        osc
+    };
+    osc * Net::wrap(Box::new(env))
+}
+
+// ============================================================================
+// FILTER
+// ============================================================================
+
+/// Slope of the main low-pass filter.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FilterSlope {
+    /// 6 dB/octave one-pole, no resonance (the original gentle filter)
+    OnePole6,
+    /// 12 dB/octave state-variable low-pass with resonance
+    TwoPole12,
+    /// 24 dB/octave Moog ladder emulation with resonance
+    Moog24,
+}
+
+/// An effect in the mono chain after the voice mix, see
+/// `KeyboardSynth::set_fx_order`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum FxStage {
+    /// Overdrive, while `set_drive` is above 0.0
+    Drive,
+    /// Main low-pass filter (in the voices instead with key tracking)
+    Filter,
+    /// Resonator peak
+    Resonator,
+    /// Formant filter, while enabled
+    Formant,
+}
+
+/// Effects chain order by default: the overdrive feeds the filter, as on a
+/// classic monosynth
+pub const FX_ORDER: [FxStage; 4] = [
+    FxStage::Drive,
+    FxStage::Filter,
+    FxStage::Resonator,
+    FxStage::Formant,
+];
+
+/// Note frequency (C4) at which the voice highpass cutoff equals the
+/// tracking amount times the note; it tracks at half rate around it
+const VOICE_HIGHPASS_REFERENCE: f32 = 261.63;
+
+/// Overdrive input gain in octaves at drive 1.0: 64x
+const DRIVE_OCTAVES: f32 = 6.0;
+
+/// Soft-clipping overdrive: the signal times the drive gain through tanh,
+/// scaled back by the gain's square root so that the level stays roughly
+/// the same while the tone gets harsher.
+fn drive_net(drive: &Shared) -> Net {
+    Net::wrap(Box::new(
+        (pass() | var_fn(drive, |d| libm::exp2f(d * DRIVE_OCTAVES)))
+            >> map(|f: &Frame<f32, U2>| libm::tanhf(f[0] * f[1]) / libm::sqrtf(f[1])),
+    ))
+}
+
+/// Vowel shape of the formant filter, see `KeyboardSynth::set_formant`.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Vowel {
+    A,
+    E,
+    I,
+    O,
+    U,
+}
+
+/// Bandpass Q of each formant band
+const FORMANT_Q: f32 = 8.0;
+/// Smoothing time of the vowel position in seconds, so stepwise updates
+/// from the ToF sensor morph instead of jumping
+const FORMANT_SMOOTHING: f32 = 0.05;
+/// Makeup gain bringing a chord back to about its unfiltered level
+const FORMANT_GAIN: f32 = 1.5;
+
+/// First three formants (Hz, linear gain) of each vowel, bass voice, in
+/// `Vowel` order.
+const VOWEL_FORMANTS: [[(f32, f32); 3]; 5] = [
+    [(600.0, 1.0), (1040.0, 0.447), (2250.0, 0.355)],
+    [(400.0, 1.0), (1620.0, 0.251), (2400.0, 0.355)],
+    [(250.0, 1.0), (1750.0, 0.032), (2600.0, 0.158)],
+    [(400.0, 1.0), (750.0, 0.282), (2400.0, 0.089)],
+    [(350.0, 1.0), (600.0, 0.1), (2400.0, 0.025)],
+];
+
+/// Formants at a vowel position, 0.0 (A) to 4.0 (U), interpolated linearly
+/// between neighbouring vowels.
+fn vowel_formants(position: f32) -> [(f32, f32); 3] {
+    let position = position.clamp(0.0, (VOWEL_FORMANTS.len() - 1) as f32);
+    let lower = core::cmp::min(position as usize, VOWEL_FORMANTS.len() - 2);
+    let t = position - lower as f32;
+    let (a, b) = (VOWEL_FORMANTS[lower], VOWEL_FORMANTS[lower + 1]);
+    let mix = |x: f32, y: f32| x * (1.0 - t) + y * t;
+    core::array::from_fn(|i| (mix(a[i].0, b[i].0), mix(a[i].1, b[i].1)))
+}
+
+/// One formant band: a bandpass following the smoothed vowel position.
+fn formant_band(
+    position: &Shared,
+    band: usize,
+) -> An<impl AudioNode<Inputs = U1, Outputs = U1> + use<>> {
+    let smoothed = var(position) >> follow(FORMANT_SMOOTHING);
+    let freq = smoothed.clone() >> map(move |p: &Frame<f32, U1>| vowel_formants(p[0])[band].0);
+    let gain = smoothed >> map(move |p: &Frame<f32, U1>| vowel_formants(p[0])[band].1);
+    ((pass() | freq | dc(FORMANT_Q)) >> bandpass::<f32>()) * gain
+}
+
+/// Formant filter: three parallel bandpasses tuned to the vowel position.
+fn formant_net(position: &Shared) -> Net {
+    Net::wrap(Box::new(
+        split::<U3>()
+            >> (formant_band(position, 0) | formant_band(position, 1) | formant_band(position, 2))
+            >> (join::<U3>() * FORMANT_GAIN),
+    ))
+}
+
+/// Main cutoff times the smoothed LFO cutoff ratio.
+fn modulated_cutoff(
+    cutoff: &Shared,
+    lfo_ratio: &Shared,
+) -> An<impl AudioNode<Inputs = U0, Outputs = U1> + use<>> {
+    var(cutoff) * (var(lfo_ratio) >> follow(LFO_SMOOTHING))
+}
+
+/// Build the main filter with its cutoff from `cutoff` (usually
+/// `modulated_cutoff`). Resonance (0.0-1.0) is ignored by the one-pole.
+fn filter_net(
+    slope: FilterSlope,
+    cutoff: An<impl AudioNode<Inputs = U0, Outputs = U1> + 'static>,
+    resonance: &Shared,
+) -> Net {
+    match slope {
+        FilterSlope::OnePole6 => Net::wrap(Box::new((pass() | cutoff) >> lowpole::<f32>())),
+        FilterSlope::TwoPole12 => Net::wrap(Box::new(
+            (pass()
+                | cutoff
+                | var_fn(resonance, |r| {
+                    FILTER_Q_MIN + r * (FILTER_Q_MAX - FILTER_Q_MIN)
+                }))
+                >> lowpass::<f32>(),
+        )),
+        FilterSlope::Moog24 => Net::wrap(Box::new(
+            (pass() | cutoff | var(resonance)) >> moog::<f32>(),
+        )),
+    }
+}
+
+// ============================================================================
+// GRAPH
+// ============================================================================
+
+/// Graph topology settings that require rebuilding the net when changed.
+#[derive(Clone, Copy)]
+struct Topology {
+    waveforms: [Waveform; VOICE_COUNT],
+    filter_slope: FilterSlope,
+    /// Stereo chorus on the effects bus
+    chorus: bool,
+    /// Per-voice filters for velocity brightness
+    voice_filters: bool,
+    /// Per-voice key-tracking highpass
+    voice_highpass: bool,
+    /// Per-voice panning (stereo mix and filter chain)
+    auto_pan: bool,
+    /// Feedback delay on the effects bus: None = off, Some(ping-pong)
+    delay: Option<bool>,
+    /// Oscillator oversampling factor, 1 or 2
+    oversampling: u8,
+    /// Chorus voices and modulation, used while `chorus` is on
+    chorus_settings: ChorusSettings,
+    /// Formant (vowel) filter after the main filter
+    formant: bool,
+    /// Oscillators restart at phase 0 on each note
+    phase_reset: bool,
+    /// Main filter moved into the voices, its cutoff tracking the note
+    filter_keytrack: bool,
+    /// Overdrive stage in the chain
+    drive: bool,
+    /// Order of the effect stages after the voice mix
+    fx_order: [FxStage; FX_ORDER.len()],
+    /// Second oscillator waveform of every voice, blended in by `osc_blend`
+    layer: Option<Waveform>,
+    /// Voice (source, destination) whose output modulates the other's
+    /// frequency
+    cross_mod: Option<(usize, usize)>,
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Self {
+            waveforms: [Waveform::Saw; VOICE_COUNT],
+            filter_slope: FilterSlope::OnePole6,
+            chorus: false,
+            voice_filters: false,
+            voice_highpass: false,
+            auto_pan: false,
+            delay: None,
+            oversampling: 1,
+            chorus_settings: ChorusSettings::default(),
+            formant: false,
+            phase_reset: false,
+            filter_keytrack: false,
+            drive: false,
+            fx_order: FX_ORDER,
+            layer: None,
+            cross_mod: None,
+        }
+    }
+}
+
+/// Sound parameters held by the performance lock
+const LOCKED_CONTROLS: usize = 13;
+
+/// Shared values read by the audio graph. Cloning shares the underlying
+/// values, so the synth writes them while the graph (or a task) reads them.
+#[derive(Clone)]
+struct Controls {
+    freqs: [Shared; VOICE_COUNT],
+    gates: [Shared; VOICE_COUNT],
+    /// Per-voice volume, set from the zone that owns the voice
+    levels: [Shared; VOICE_COUNT],
+    /// Per-voice note velocity gain (1.0 = full velocity)
+    velocities: [Shared; VOICE_COUNT],
+    /// Per-voice filter cutoff offset from the main cutoff, in octaves
+    voice_cutoff_offsets: [Shared; VOICE_COUNT],
+    /// Per-voice key tracking factor on the main filter cutoff
+    keytrack_ratios: [Shared; VOICE_COUNT],
+    /// Per-voice highpass cutoff in Hz, tracking the note
+    voice_highpass: [Shared; VOICE_COUNT],
+    /// Per-voice 1.0 while the voice graph runs, 0.0 once it has fully
+    /// released and is skipped
+    voice_active: [Shared; VOICE_COUNT],
+    /// Per-voice attack time multiplier, from the note-on velocity
+    attack_scales: [Shared; VOICE_COUNT],
+    /// Per-voice release time multiplier, from the note-off velocity
+    release_scales: [Shared; VOICE_COUNT],
+    /// Per-voice pan position, -1.0 (left) to 1.0 (right)
+    pans: [Shared; VOICE_COUNT],
+    filter_cutoff: Shared,
+    filter_resonance: Shared,
+    /// Amplitude envelope parameters
+    env: EnvControls,
+    /// Pitch slide start offset in semitones
+    pitch_env_offset: Shared,
+    /// Pitch slide duration in seconds (0 = off)
+    pitch_env_time: Shared,
+    /// Per-voice glide start, as a ratio to the new note's frequency
+    glide_ratios: [Shared; VOICE_COUNT],
+    /// Per-voice glide counter, bumped to start a glide
+    glide_triggers: [Shared; VOICE_COUNT],
+    /// Per-voice counter, bumped to restart the oscillator phase
+    phase_resets: [Shared; VOICE_COUNT],
+    /// Glide duration in seconds (0 = off)
+    glide_time: Shared,
+    /// `GlideCurve` as f32
+    glide_curve: Shared,
+    resonator_freq: Shared,
+    /// Formant filter vowel position, 0.0 (A) to 4.0 (U)
+    formant_position: Shared,
+    /// Overdrive amount, 0.0-1.0
+    drive: Shared,
+    /// Share of the layered oscillator in each voice, 0.0 (A) to 1.0 (B)
+    osc_blend: Shared,
+    /// Frequency deviation of the cross-modulated voice at full source
+    /// level, as a fraction of its frequency
+    cross_mod_depth: Shared,
+    /// Mid/side width of the stereo bus (1.0 = unchanged)
+    stereo_width: Shared,
+    /// Master gain target: 1.0 normally, 0.0 when muted
+    master_gain: Shared,
+    delay_feedback: Shared,
+    /// LFO and sensor sample-and-hold cutoff ratio (1.0 = unmodulated)
+    lfo_cutoff: Shared,
+    /// LFO tremolo gain (1.0 = unmodulated)
+    lfo_gain: Shared,
+    /// Width of `Waveform::Pulse`, 0.0-1.0
+    pulse_width: Shared,
+    /// LFO swing added to the pulse width
+    lfo_pulse_width: Shared,
+}
+
+impl Controls {
+    fn new() -> Self {
+        Self {
+            freqs: arr![|_| Shared::new(0.0)],
+            gates: arr![|_| Shared::new(0.0)],
+            levels: arr![|_| Shared::new(1.0)],
+            velocities: arr![|_| Shared::new(1.0)],
+            voice_active: arr![|_| Shared::new(1.0)],
+            attack_scales: arr![|_| Shared::new(1.0)],
+            release_scales: arr![|_| Shared::new(1.0)],
+            voice_cutoff_offsets: arr![|_| Shared::new(0.0)],
+            keytrack_ratios: arr![|_| Shared::new(1.0)],
+            voice_highpass: arr![|_| Shared::new(0.0)],
+            pans: arr![|_| Shared::new(0.0)],
+            filter_cutoff: Shared::new(FILTER_CUTOFF),
+            filter_resonance: Shared::new(0.0),
+            env: EnvControls::new(ENV_ATTACK, ENV_DECAY, ENV_SUSTAIN, ENV_RELEASE),
+            pitch_env_offset: Shared::new(0.0),
+            pitch_env_time: Shared::new(0.0),
+            glide_ratios: arr![|_| Shared::new(1.0)],
+            glide_triggers: arr![|_| Shared::new(0.0)],
+            phase_resets: arr![|_| Shared::new(0.0)],
+            glide_time: Shared::new(0.0),
+            glide_curve: Shared::new(GlideCurve::default() as u8 as f32),
+            resonator_freq: Shared::new(880.0),
+            formant_position: Shared::new(0.0),
+            drive: Shared::new(0.0),
+            osc_blend: Shared::new(0.0),
+            cross_mod_depth: Shared::new(0.0),
+            stereo_width: Shared::new(1.0),
+            master_gain: Shared::new(1.0),
+            delay_feedback: Shared::new(DELAY_FEEDBACK),
+            lfo_cutoff: Shared::new(1.0),
+            lfo_gain: Shared::new(1.0),
+            pulse_width: Shared::new(PULSE_WIDTH),
+            lfo_pulse_width: Shared::new(0.0),
+        }
+    }
+
+    /// The sound parameters a performance lock holds, see
+    /// `KeyboardSynth::set_performance_lock`.
+    fn lockable(&self) -> [&Shared; LOCKED_CONTROLS] {
+        [
+            &self.filter_cutoff,
+            &self.filter_resonance,
+            &self.resonator_freq,
+            &self.formant_position,
+            &self.drive,
+            &self.osc_blend,
+            &self.stereo_width,
+            &self.delay_feedback,
+            &self.glide_time,
+            &self.env.attack,
+            &self.env.decay,
+            &self.env.sustain,
+            &self.env.release,
+        ]
+    }
+}
+
+/// Mid/side width matrix on a stereo pair. 0.0 collapses to mono, 1.0 passes
+/// the signal through untouched, larger values boost the side signal.
+fn stereo_width(width: &Shared) -> An<impl AudioNode<Inputs = U2, Outputs = U2> + use<>> {
+    (pass() | pass() | var(width))
+        >> map(|f: &Frame<f32, U3>| {
+            if f[2] == 1.0 {
+                return (f[0], f[1]);
+            }
+            let mid = (f[0] + f[1]) * 0.5;
+            let side = (f[0] - f[1]) * 0.5 * f[2];
+            (mid + side, mid - side)
+        })
+}
+
+/// Stereo effects bus: chorus, delay, then the width matrix. The chorus runs
+/// one instance per channel with different seeds, which is where the stereo
+/// image comes from; without it (and without auto-pan or ping-pong) both
+/// channels carry the same signal.
+fn stereo_bus(topology: &Topology, controls: &Controls) -> (Net, Option<[NodeId; 2]>) {
+    let (spread, chorus_ids) = if topology.chorus {
+        let (left, left_id) = Net::wrap_id(chorus_net(CHORUS_SEED, topology.chorus_settings));
+        let (right, right_id) = Net::wrap_id(chorus_net(CHORUS_SEED + 1, topology.chorus_settings));
+        (left | right, Some([left_id, right_id]))
+    } else {
+        (Net::wrap(Box::new(multipass::<U2>())), None)
+    };
+    let spread = match topology.delay {
+        Some(pingpong) => {
+            spread
+                >> (Net::wrap(Box::new(multipass::<U2>()))
+                    & delay_net(pingpong, &controls.delay_feedback))
+        }
+        None => spread,
+    };
+    (
+        spread >> Net::wrap(Box::new(stereo_width(&controls.stereo_width))),
+        chorus_ids,
+    )
+}
+
+/// Chorus voice count and modulation, see `KeyboardSynth::set_chorus`.
+#[derive(Clone, Copy, PartialEq)]
+struct ChorusSettings {
+    voices: u8,
+    separation: f32,
+    variation: f32,
+    mod_freq: f32,
+}
+
+impl Default for ChorusSettings {
+    fn default() -> Self {
+        Self {
+            voices: CHORUS_VOICES,
+            separation: CHORUS_SEPARATION,
+            variation: CHORUS_VARIATION,
+            mod_freq: CHORUS_MOD_FREQ,
+        }
+    }
+}
+
+/// Mono chorus: the dry signal plus `N` copies from one delay line, copy
+/// `i` delayed by (i + 1) x separation plus up to `variation` of fractal
+/// noise modulation. Like fundsp's `chorus`, with a variable voice count.
+fn chorus_taps<N>(seed: u64, settings: ChorusSettings) -> Box<dyn AudioUnit>
+where
+    N: Size<f32> + Add<U1>,
+    <N as Add<U1>>::Output: Size<f32>,
+{
+    let ChorusSettings {
+        voices,
+        separation,
+        variation,
+        mod_freq,
+    } = settings;
+    let delays = lfo(move |t: f32| {
+        Frame::<f32, N>::generate(|i| {
+            let base = separation * (i + 1) as f32;
+            let speed = mod_freq + 0.02 * i as f32;
+            lerp11(
+                base,
+                base + variation,
+                fractal_noise(hash1(seed ^ i as u64), 8, 0.45, t * speed),
+            )
+        })
+    })
+    .interval(0.01);
+    let max_delay = separation * voices as f32 + variation;
+    let wet = (Net::wrap(Box::new(pass())) | Net::wrap(Box::new(delays)))
+        >> Net::wrap(Box::new(multitap::<N>(separation, max_delay)));
+    Box::new((Net::wrap(Box::new(pass())) & wet) * dc(1.0 / (voices as f32 + 1.0)))
+}
+
+/// Mono chorus for `settings.voices` (1 to `CHORUS_VOICES_MAX`) copies.
+fn chorus_net(seed: u64, settings: ChorusSettings) -> Box<dyn AudioUnit> {
+    match settings.voices {
+        1 => chorus_taps::<U1>(seed, settings),
+        2 => chorus_taps::<U2>(seed, settings),
+        3 => chorus_taps::<U3>(seed, settings),
+        4 => chorus_taps::<U4>(seed, settings),
+        5 => chorus_taps::<U5>(seed, settings),
+        _ => chorus_taps::<U6>(seed, settings),
+    }
+}
+
+/// Stereo feedback delay of `DELAY_TIME`, repeats only (no dry signal).
+///
+/// Normal mode delays each channel on its own. Ping-pong feeds the sum of
+/// both channels into the left line and cross-couples the lines, so each
+/// repeat lands on the opposite side: right, left, right, ... Every pass
+/// through the loop is soft-clipped and scaled by the feedback, which keeps
+/// the repeats below full scale even at maximum feedback.
+///
+/// Each line holds `DELAY_TIME` of f32 samples: 4411 x 4 bytes = ~17.6 kB,
+/// ~35 kB of heap for the pair.
+fn delay_net(pingpong: bool, feedback_amount: &Shared) -> Net {
+    let lines = delay(DELAY_TIME) | delay(DELAY_TIME) | var(feedback_amount);
+    if pingpong {
+        Net::wrap(Box::new(
+            ((pass() + pass()) | zero())
+                >> feedback(
+                    lines
+                        >> map(|f: &Frame<f32, U3>| {
+                            (libm::tanhf(f[1]) * f[2], libm::tanhf(f[0]) * f[2])
+                        }),
+                ),
+        ))
+    } else {
+        Net::wrap(Box::new(feedback(
+            lines >> map(|f: &Frame<f32, U3>| (libm::tanhf(f[0]) * f[2], libm::tanhf(f[1]) * f[2])),
+        )))
+    }
+}
+
+/// Mono filter chain after the voice mix: the effect stages in
+/// `fx_order`, leaving out those switched off, then the LFO tremolo.
+fn filter_chain(topology: &Topology, controls: &Controls) -> Net {
+    let mut chain = Net::wrap(Box::new(pass()));
+    for stage in topology.fx_order {
+        chain = match stage {
+            FxStage::Drive if topology.drive => chain >> drive_net(&controls.drive),
+            // With key tracking the main filter runs in each voice instead
+            FxStage::Filter if !topology.filter_keytrack => {
+                let cutoff = modulated_cutoff(&controls.filter_cutoff, &controls.lfo_cutoff);
+                chain >> filter_net(topology.filter_slope, cutoff, &controls.filter_resonance)
+            }
+            FxStage::Resonator => {
+                // Efficient peaking filter (Q=2.0)
+                chain
+                    >> Net::wrap(Box::new(
+                        (pass() | var(&controls.resonator_freq) | dc(1.0)) >> peak::<f32>(),
+                    ))
+            }
+            FxStage::Formant if topology.formant => {
+                chain >> formant_net(&controls.formant_position)
+            }
+            _ => chain,
+        };
+    }
+    // LFO tremolo
+    chain
+        >> Net::wrap(Box::new(
+            pass() * (var(&controls.lfo_gain) >> follow(LFO_SMOOTHING)),
+        ))
+}
+
+/// Build the complete synth graph: all voices mixed, then the filter chain
+/// and the stereo effects bus. With auto-pan each voice is panned before
+/// the mix and the filter chain runs once per channel.
+fn build_net(topology: &Topology, controls: &Controls) -> (Net, Option<[NodeId; 2]>) {
+    let voice = |voice: usize| {
+        let net = voice_net(topology, controls, voice);
+        if topology.auto_pan {
+            let net = net >> ((pass() | var(&controls.pans[voice])) >> panner());
+            idle_skip(net, &controls.voice_active[voice], 2)
+        } else {
+            idle_skip(net, &controls.voice_active[voice], 1)
+        }
+    };
+    let mut voices = Net::new(0, 0);
+    for v in 0..VOICE_COUNT {
+        voices = match topology.cross_mod {
+            // The modulated voice runs in the pair below, and always: its
+            // input must keep flowing
+            Some((_, dest)) if v == dest => continue,
+            Some((source, dest)) if v == source => {
+                let pan = |net: Net, voice: usize| {
+                    if topology.auto_pan {
+                        net >> ((pass() | var(&controls.pans[voice])) >> panner())
+                    } else {
+                        net
+                    }
+                };
+                let dest_net = cross_mod_voice_net(topology, controls, dest);
+                voices
+                    | (idle_skip(
+                        voice_net(topology, controls, source),
+                        &controls.voice_active[source],
+                        1,
+                    ) >> split::<U2>()
+                        >> (pan(Net::wrap(Box::new(pass())), source) | pan(dest_net, dest)))
+            }
+            _ => voices | voice(v),
+        };
+    }
+    let mix = if topology.auto_pan {
+        voices
+            >> multijoin::<U2, U7>()
+            >> (filter_chain(topology, controls) | filter_chain(topology, controls))
+    } else {
+        // Summing before the filter runs one filter chain instead of seven
+        voices >> join::<U7>() >> filter_chain(topology, controls) >> split::<U2>()
+    };
+    let (bus, chorus_ids) = stereo_bus(topology, controls);
+    (mix >> bus, chorus_ids)
+}
+
+/// Shared values a custom graph reads to follow the synth's voice
+/// allocation. Cloned from the synth's own controls, so they stay live.
+#[derive(Clone)]
+pub struct VoiceControls {
+    /// Voice frequency in Hz, with pitch bend, tuning and drift applied
+    pub freqs: [Shared; VOICE_COUNT],
+    /// Voice gate: 1.0 while the note is held, 0.0 after release
+    pub gates: [Shared; VOICE_COUNT],
+    /// Zone volume of the voice (1.0 unless split zones set it)
+    pub levels: [Shared; VOICE_COUNT],
+    /// Note velocity gain after the velocity curve (1.0 from the key matrix)
+    pub velocities: [Shared; VOICE_COUNT],
+    /// Main filter cutoff in Hz (also driven by `filter_cutoff_control`)
+    pub filter_cutoff: Shared,
+    /// Resonator frequency in Hz, driven by the ToF sensor and mod pot
+    pub resonator_freq: Shared,
+}
+
+/// Builds a custom audio graph, see `KeyboardSynth::with_net`.
+pub type NetBuilder = fn(&VoiceControls) -> Box<dyn AudioUnit>;
+
+impl VoiceControls {
+    fn from_controls(controls: &Controls) -> Self {
+        Self {
+            freqs: controls.freqs.clone(),
+            gates: controls.gates.clone(),
+            levels: controls.levels.clone(),
+            velocities: controls.velocities.clone(),
+            filter_cutoff: controls.filter_cutoff.clone(),
+            resonator_freq: controls.resonator_freq.clone(),
+        }
+    }
+}
+
+// ============================================================================
+// SYNTHESIZER
+// ============================================================================
+
+/// Free heap `try_new` requires before building the default graph: ~57 KB
+/// on a 64-bit host (less on the Pico, with half-size pointers), plus
+/// headroom for fragmentation and the allocations that follow. A test
+/// measures the graph against it; the firmware logs the graph's actual
+/// size at boot.
+pub const NET_HEAP_RESERVE: usize = 80 * 1024;
+
+/// Heap statistics from the global allocator, in bytes.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct HeapStats {
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Reads the allocator's statistics, see `KeyboardSynth::try_new`.
+pub type HeapProbe = fn() -> HeapStats;
+
+/// Why the synth, or a part of it, could not be set up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum SynthError {
+    /// Less free heap than the graph needs
+    OutOfMemory { needed: usize, free: usize },
+    /// An effects order that isn't each `FxStage` exactly once
+    InvalidFxOrder,
+    /// State bytes that are corrupt or not a state
+    InvalidState,
+}
+
+/// Polyphonic synthesizer with multiplexed 48-key matrix (12 keys × 4 octaves).
+///
+/// Hardware setup:
+/// - 12 physical key inputs (buttons)
+/// - 4 octave select outputs (only one LOW at a time to enable that octave)
+/// - Scanning through octaves rapidly gives us 48 virtual keys
+///
+/// Features:
+/// - Full 4-octave range (C3-B3 up to C6-B6)
+/// - Octave multiplexing: same physical key can trigger different octaves
+/// - Round-robin voice stealing when all 7 voices are busy
+/// - Rapid octave scanning to catch all key presses
+/// - Optional keyboard split with per-zone sound and voice pool
+///
+/// The synth scans through all 4 octaves on each poll, setting one output
+/// LOW at a time and reading the 12 keys for that octave.
+pub struct KeyboardSynth {
+    net: Net,
+    /// Chorus nodes (left, right) in `net`, for crossfading new settings
+    chorus_ids: Option<[NodeId; 2]>,
+    /// Custom graph builder set by `with_net`, replaces `build_net`
+    net_builder: Option<NetBuilder>,
+    controls: Controls,
+    /// Maps voice index -> encoded note (key + octave), or VOICE_UNASSIGNED
+    voice_note: [u8; VOICE_COUNT],
+    /// Base frequencies for each voice (without pitch bend applied)
+    base_freqs: [f32; VOICE_COUNT],
+    /// First key (octave * KEY_COUNT + key) of the upper zone, None = no split
+    split_note: Option<u8>,
+    /// Lower (or only) zone and upper zone
+    zones: [Zone; 2],
+    /// Voices `lo..hi` that allocation and stealing may use
+    voice_range: (usize, usize),
+    /// Previous key states for edge detection (per octave)
+    key_states: [[bool; KEY_COUNT]; OCTAVE_COUNT],
+    /// Latch mode: key presses toggle notes, releases are ignored
+    latch: bool,
+    /// Sostenuto pedal down
+    sostenuto: bool,
+    /// Voices captured by the sostenuto pedal: released keys keep sounding
+    /// until the pedal comes up, and stealing skips them
+    sostenuto_voices: [bool; VOICE_COUNT],
+    /// Stolen voices fading out, and the note each starts when done
+    steal_fades: [Option<StealFade>; VOICE_COUNT],
+    /// Length of the steal fade in samples, 0 to steal at once
+    steal_fade_frames: u64,
+    /// Mono mode: only one note sounds, chosen from the held notes by priority
+    mono: bool,
+    note_priority: NotePriority,
+    /// Held notes in press order (oldest first), used by mono mode
+    held_notes: [u8; HELD_NOTE_MAX],
+    held_count: usize,
+    /// Arpeggiator pattern, None when off; steps through the held notes
+    arp: Option<ArpMode>,
+    /// Arp steps per second while not synced
+    arp_rate: f32,
+    /// Note division the arp steps are synced to, None for `arp_rate`
+    arp_sync: Option<NoteDivision>,
+    /// Fraction of a frame the synced steps so far fell short by
+    arp_step_carry: f32,
+    /// Fraction of each arp step the note sounds
+    arp_gate: f32,
+    /// Octaves the arp pattern spans
+    arp_octaves: u8,
+    /// Position in the arp sequence
+    arp_index: usize,
+    /// Clock of the next arp step
+    arp_next_step: u64,
+    /// Clock at which the current arp note is released, if before the next step
+    arp_note_off: Option<u64>,
+    /// Note-repeat rate, None when off; retriggers the held notes
+    note_repeat: Option<NoteDivision>,
+    /// Clock of the next note-repeat hit, None until a held note rolls
+    note_repeat_next: Option<u64>,
+    /// Clock at which the current hit's gates close
+    note_repeat_off: Option<u64>,
+    topology: Topology,
+    /// Per-voice pitch bend ratios (MPE), applied on top of the global bend
+    voice_bends: [f32; VOICE_COUNT],
+    /// DC offset added to the 16-bit DAC samples
+    output_trim: i16,
+    /// xorshift32 state for the DAC dither, None while dither is off
+    dither: Option<u32>,
+    /// Morph endpoints (patch A and patch B) for `set_morph`
+    morph_presets: (Preset, Preset),
+    /// Frames between auto-save checks, 0 when auto-save is off
+    autosave_interval: u64,
+    /// Clock of the last auto-save check
+    autosave_checked: u64,
+    /// Sound settings as last handed out for saving
+    autosave_saved: SynthState,
+    /// Voice spread in cents, see `set_voice_spread`
+    voice_spread: f32,
+    /// Per-voice detune ratios derived from the voice spread
+    spread_ratios: [f32; VOICE_COUNT],
+    /// Stacked intervals in semitones (0.0 past `stack_len`) and their
+    /// detune in cents, as set with `set_stack`
+    stack_intervals: [f32; STACK_MAX],
+    stack_detune: f32,
+    /// Frequency ratios of the extra voices each poly note stacks, detune
+    /// included
+    stack_ratios: [f32; STACK_MAX],
+    stack_len: usize,
+    pitch_bend: Shared,
+    /// Semitones a full pitch wheel deflection bends, see `set_bend_range`
+    bend_range: f32,
+    /// Global octave shift applied to new notes
+    octave_shift: i8,
+    /// Move sounding voices along when the octave shift changes
+    octave_follow: bool,
+    /// Master tune frequency ratio
+    fine_tune: f32,
+    /// Retune sounding voices when the master tune changes
+    fine_tune_held: bool,
+    backend: RenderBackend,
+    fixed: FixedRenderer,
+    gain_mode: GainMode,
+    /// Smoothed output gain, follows `voice_gain` per sample
+    output_gain: f32,
+    /// Stereo width setting, before the mono-safe limit
+    stereo_width: f32,
+    mono_safe: bool,
+    /// Fraction of the width setting the mono-safe limit allows
+    mono_safe_scale: f32,
+    /// Left/right correlation of the last audible buffer
+    correlation: f32,
+    /// Ramped master gain, follows `Controls::master_gain` per sample
+    master_gain: f32,
+    /// Per-sample master gain step of the startup fade-in, until it is done
+    startup_fade_step: Option<f32>,
+    /// Old graph fading out after a rebuild
+    net_fade: Option<NetFade>,
+    /// Length of the rebuild crossfade in samples, 0 to switch at once
+    topology_fade_frames: usize,
+    /// Samples rendered since startup, the synth's time base
+    clock: u64,
+    /// `clock` when each voice was last allocated or retriggered
+    voice_started: [u64; VOICE_COUNT],
+    /// `clock` up to which each voice's gate was last on
+    voice_gated: [u64; VOICE_COUNT],
+    /// Skip the graphs of fully released voices
+    skip_idle_voices: bool,
+    /// Level (linear) below which a release tail frees its voice, 0.0 to
+    /// keep voices until they are reused
+    release_cutoff: f32,
+    /// `clock` and start ratio of each voice's last glide
+    glide_started: [u64; VOICE_COUNT],
+    glide_start_ratios: [f32; VOICE_COUNT],
+    /// Voices younger than this (in samples) are only stolen as a last resort
+    min_voice_age: u64,
+    steal_mode: StealMode,
+    /// Held voices are gated off after this many samples, None = unlimited
+    max_note_duration: Option<u64>,
+    /// Test tone phase increment per sample (cycles), None when off
+    test_tone: Option<f32>,
+    test_tone_phase: f32,
+    /// Loop recorder mixed into the output
+    looper: Looper,
+    granular: GranularFreeze,
+    drums: DrumMachine,
+    trance_gate: TranceGate,
+    /// Velocity gain (0.0-1.0) of the note event being handled
+    velocity: f32,
+    velocity_curve: VelocityCurve,
+    /// What the keys play
+    keyboard_mode: KeyboardMode,
+    /// Switch drum step editing with `DRUM_EDIT_COMBO`
+    drum_edit_combo: bool,
+    /// Mode to go back to when drum step editing ends
+    mode_before_edit: KeyboardMode,
+    /// Lowest velocity `note_on` plays at
+    velocity_floor: u8,
+    /// Time each dual-contact key passed its first contact, while going
+    /// down
+    contact_times: [[Option<u64>; KEY_COUNT]; OCTAVE_COUNT],
+    /// Microsecond clock for contact timing, None to use the sample clock
+    contact_clock: Option<ContactClock>,
+    /// Time a key must read released before its note ends, in µs
+    release_holdoff_us: u64,
+    /// When each held key reading released may release, on the contact
+    /// clock, while its hold-off runs
+    release_pending: [[Option<u64>; KEY_COUNT]; OCTAVE_COUNT],
+    /// How far velocity darkens the per-voice filter (0.0 = off)
+    velocity_to_cutoff: f32,
+    /// How much note velocity shortens the attack, 0.0-1.0
+    velocity_to_attack: f32,
+    /// Filter key tracking, 0.0 (off) to 1.0 (follows the note)
+    filter_keytrack: f32,
+    /// Voice gain by keyboard octave, for balancing bass and treble
+    octave_gains: [f32; OCTAVE_COUNT],
+    /// Voice highpass tracking amount, 0.0 (off) to 1.0
+    voice_highpass_tracking: f32,
+    /// Scale release times by the `note_off` velocity
+    release_velocity: bool,
+    auto_pan: AutoPanMode,
+    /// Analog drift depth, 0.0 (off) to 1.0
+    drift_amount: f32,
+    drifts: [Drift; VOICE_COUNT],
+    /// Current drift frequency ratio of each voice
+    drift_ratios: [f32; VOICE_COUNT],
+    /// `clock` of the next drift step
+    next_drift: u64,
+    /// Unison drift LFO rate in Hz and depth in cents, 0.0 = off
+    unison_drift_rate: f32,
+    unison_drift_depth: f32,
+    /// Unison drift LFO phase of each voice, 0.0-1.0
+    unison_drift_phases: [f32; VOICE_COUNT],
+    /// Current unison drift frequency ratio of each voice
+    unison_drift_ratios: [f32; VOICE_COUNT],
+    lfo: Lfo,
+    /// LFO depth per `LfoDestination`, 0.0 when not routed
+    lfo_depths: [f32; 5],
+    /// Restart the LFO cycle on every note-on
+    lfo_retrigger: bool,
+    /// Restart the LFO on legato mono notes too
+    legato_filter_retrigger: bool,
+    /// Current LFO vibrato frequency ratio
+    lfo_pitch: f32,
+    /// LFO rate in Hz while not synced
+    lfo_free_rate: f32,
+    /// Values of `Controls::lockable` held while performance-locked
+    performance_lock: Option<[f32; LOCKED_CONTROLS]>,
+    /// Controller number bound to each MIDI CC target
+    cc_map: CcMap,
+    /// Target the next Control Change gets bound to, while learning
+    midi_learn: Option<ControlTarget>,
+    sensor_sh: SensorSh,
+    /// Sensor sample-and-hold pitch ratio, 1.0 when off
+    sensor_pitch: f32,
+    /// Sensor sample-and-hold cutoff ratio, 1.0 when off
+    sensor_cutoff: f32,
+    /// Note division the LFO is synced to, None when free-running
+    lfo_sync: Option<NoteDivision>,
+    /// Tempo in BPM, set directly or from MIDI clock
+    tempo: f32,
+    /// MIDI clock ticks since the last start
+    midi_ticks: u32,
+    /// Clock of the last MIDI clock beat
+    midi_beat_clock: Option<u64>,
+    /// Allocator statistics for `heap_used`, when built with `try_new`
+    heap_probe: Option<HeapProbe>,
+    /// Heap in use before the synth was built
+    heap_baseline: usize,
+}
+
+impl Default for KeyboardSynth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyboardSynth {
+    /// Create a new synthesizer, checking first that the heap has room for
+    /// the audio graph. The allocator aborts on out-of-memory, which on the
+    /// Pico shows up as a hang or an allocation panic deep inside fundsp;
+    /// this returns `SynthError::OutOfMemory` instead when less than
+    /// `NET_HEAP_RESERVE` is free. `heap` reads the allocator's statistics
+    /// and is kept for `heap_used`.
+    ///
+    /// Effects enabled later grow the graph (the chorus and delay add about
+    /// 110 KB) and are not checked; watch `heap_used` when stacking them.
+    pub fn try_new(heap: HeapProbe) -> Result<Self, SynthError> {
+        let before = heap();
+        if before.free < NET_HEAP_RESERVE {
+            return Err(SynthError::OutOfMemory {
+                needed: NET_HEAP_RESERVE,
+                free: before.free,
+            });
+        }
+        let mut synth = Self::new();
+        synth.heap_probe = Some(heap);
+        synth.heap_baseline = before.used;
+        Ok(synth)
+    }
+
+    /// Heap taken since the synth was built with `try_new`, mostly by its
+    /// audio graph; `None` for a synth built with `new`.
+    pub fn heap_used(&self) -> Option<usize> {
+        self.heap_probe
+            .map(|heap| heap().used.saturating_sub(self.heap_baseline))
+    }
+
+    /// Create a new synthesizer with default settings.
+    pub fn new() -> Self {
+        let controls = Controls::new();
+        let pitch_bend = Shared::new(1.0);
+        let topology = Topology::default();
+        let (net, chorus_ids) = build_net(&topology, &controls);
+
+        Self {
+            net,
+            chorus_ids,
+            net_builder: None,
+            controls,
+            voice_note: [VOICE_UNASSIGNED; VOICE_COUNT],
+            base_freqs: [0.0; VOICE_COUNT],
+            split_note: None,
+            zones: [Zone::new(ZoneConfig::default(), 0, VOICE_COUNT); 2],
+            voice_range: (0, VOICE_COUNT),
+            key_states: [[false; KEY_COUNT]; OCTAVE_COUNT],
+            latch: false,
+            sostenuto: false,
+            sostenuto_voices: [false; VOICE_COUNT],
+            steal_fades: [None; VOICE_COUNT],
+            steal_fade_frames: 0,
+            mono: false,
+            note_priority: NotePriority::Last,
+            held_notes: [VOICE_UNASSIGNED; HELD_NOTE_MAX],
+            held_count: 0,
+            arp: None,
+            arp_rate: ARP_RATE,
+            arp_sync: None,
+            arp_step_carry: 0.0,
+            arp_gate: ARP_GATE,
+            arp_octaves: 1,
+            arp_index: 0,
+            arp_next_step: 0,
+            arp_note_off: None,
+            note_repeat: None,
+            note_repeat_next: None,
+            note_repeat_off: None,
+            topology,
+            voice_bends: [1.0; VOICE_COUNT],
+            output_trim: 0,
+            dither: None,
+            morph_presets: (Preset::default(), Preset::default()),
+            autosave_interval: 0,
+            autosave_checked: 0,
+            autosave_saved: SynthState::default(),
+            voice_spread: VOICE_SPREAD_CENTS,
+            spread_ratios: spread_ratios(VOICE_SPREAD_CENTS),
+            stack_intervals: [0.0; STACK_MAX],
+            stack_detune: 0.0,
+            stack_ratios: [1.0; STACK_MAX],
+            stack_len: 0,
+            pitch_bend,
+            bend_range: BEND_RANGE,
+            octave_shift: 0,
+            octave_follow: false,
+            fine_tune: 1.0,
+            fine_tune_held: true,
+            backend: RenderBackend::default(),
+            fixed: FixedRenderer::new(),
+            gain_mode: GainMode::Fixed,
+            output_gain: 1.0,
+            stereo_width: 1.0,
+            mono_safe: false,
+            mono_safe_scale: 1.0,
+            correlation: 1.0,
+            // Fades in from silence, see `set_startup_fade`
+            master_gain: 0.0,
+            startup_fade_step: Some(fade_step(STARTUP_FADE)),
+            net_fade: None,
+            topology_fade_frames: fade_frames(TOPOLOGY_FADE),
+            clock: 0,
+            voice_started: [0; VOICE_COUNT],
+            voice_gated: [0; VOICE_COUNT],
+            skip_idle_voices: true,
+            release_cutoff: 0.0,
+            glide_started: [0; VOICE_COUNT],
+            glide_start_ratios: [1.0; VOICE_COUNT],
+            min_voice_age: 0,
+            steal_mode: StealMode::RoundRobin,
+            max_note_duration: None,
+            test_tone: None,
+            test_tone_phase: 0.0,
+            looper: Looper::default(),
+            granular: GranularFreeze::default(),
+            drums: DrumMachine::default(),
+            trance_gate: TranceGate::default(),
+            velocity: 1.0,
+            velocity_curve: VelocityCurve::Linear,
+            keyboard_mode: KeyboardMode::Chromatic,
+            drum_edit_combo: false,
+            mode_before_edit: KeyboardMode::Chromatic,
+            velocity_floor: VELOCITY_FLOOR,
+            contact_times: [[None; KEY_COUNT]; OCTAVE_COUNT],
+            release_holdoff_us: 0,
+            release_pending: [[None; KEY_COUNT]; OCTAVE_COUNT],
+            contact_clock: None,
+            velocity_to_cutoff: 0.0,
+            velocity_to_attack: 0.0,
+            filter_keytrack: 0.0,
+            octave_gains: [1.0; OCTAVE_COUNT],
+            voice_highpass_tracking: 0.0,
+            release_velocity: false,
+            auto_pan: AutoPanMode::Off,
+            drift_amount: 0.0,
+            drifts: arr![Drift::new],
+            drift_ratios: [1.0; VOICE_COUNT],
+            next_drift: 0,
+            unison_drift_rate: 0.0,
+            unison_drift_depth: 0.0,
+            unison_drift_phases: arr![|voice| voice as f32 / VOICE_COUNT as f32],
+            unison_drift_ratios: [1.0; VOICE_COUNT],
+            lfo: Lfo::new(),
+            lfo_depths: [0.0; 5],
+            lfo_retrigger: false,
+            legato_filter_retrigger: true,
+            lfo_pitch: 1.0,
+            lfo_free_rate: LFO_RATE,
+            performance_lock: None,
+            cc_map: CcMap::default(),
+            midi_learn: None,
+            sensor_sh: SensorSh::new(),
+            sensor_pitch: 1.0,
+            sensor_cutoff: 1.0,
+            lfo_sync: None,
+            tempo: TEMPO,
+            midi_ticks: 0,
+            midi_beat_clock: None,
+            heap_probe: None,
+            heap_baseline: 0,
+        }
+    }
+
+    /// Split the keyboard into a lower and an upper zone.
+    ///
+    /// `split_note` is the first key of the upper zone, counted across the
+    /// whole keyboard (`octave * KEY_COUNT + key`, 0-47). The lower zone gets
+    /// voices `0..lower.voices`, the upper zone the next `upper.voices`, so a
+    /// held bass never steals from the melody and vice versa. `None` removes
+    /// the split and plays the whole keyboard with `lower` on all voices.
+    ///
+    /// This rebuilds the audio graph, so any sounding notes are cut.
+    ///
+    /// ```ignore
+    /// // Sine bass an octave down on C3-B3, quiet square lead on C4 and up
+    /// synth.set_split(
+    ///     Some(12),
+    ///     ZoneConfig { waveform: Waveform::Sine, transpose: -1, volume: 1.0, voices: 2 },
+    ///     ZoneConfig { waveform: Waveform::Square, transpose: 0, volume: 0.7, voices: 5 },
+    /// );
+    /// ```
+    pub fn set_split(&mut self, split_note: Option<u8>, lower: ZoneConfig, upper: ZoneConfig) {
+        let waveforms = &mut self.topology.waveforms;
+        waveforms.fill(lower.waveform);
+        match split_note {
+            Some(split) => {
+                assert!(
+                    (split as usize) < KEY_COUNT * OCTAVE_COUNT,
+                    "Split note out of range"
+                );
+                assert!(
+                    lower.voices >= 1 && upper.voices >= 1,
+                    "Each zone needs at least one voice"
+                );
+                assert!(
+                    lower.voices + upper.voices <= VOICE_COUNT,
+                    "Zones reserve more voices than available"
+                );
+                let upper_hi = lower.voices + upper.voices;
+                self.zones = [
+                    Zone::new(lower, 0, lower.voices),
+                    Zone::new(upper, lower.voices, upper_hi),
+                ];
+                waveforms[lower.voices..upper_hi].fill(upper.waveform);
+            }
+            None => {
+                self.zones = [Zone::new(lower, 0, VOICE_COUNT); 2];
+            }
+        }
+        self.split_note = split_note;
+
+        for zone in &self.zones {
+            for voice in zone.lo..zone.hi {
+                self.controls.levels[voice].set_value(zone.config.volume);
+            }
+        }
+        for voice in 0..VOICE_COUNT {
+            self.controls.gates[voice].set_value(0.0);
+        }
+        self.voice_note = [VOICE_UNASSIGNED; VOICE_COUNT];
+        self.sostenuto_voices = [false; VOICE_COUNT];
+        self.steal_fades = [None; VOICE_COUNT];
+        self.held_count = 0;
+        self.rebuild_net();
+    }
+
+    /// Create a synth that renders through a custom fundsp graph while
+    /// keeping the voice allocation, key handling and control logic.
+    ///
+    /// `builder` receives the voice Shareds and is called again whenever
+    /// the synth rebuilds its graph (e.g. after `set_filter_slope`). The graph
+    /// must have no inputs and one (mono, sent to both channels) or two
+    /// (left, right) outputs; its envelopes should follow the gates.
+    /// Everything the built-in graph does after the oscillators is up to
+    /// the custom graph: waveforms, filter slope, chorus, delay, auto-pan
+    /// and velocity brightness settings have no effect on it. Gain modes,
+    /// mute, test tone and output trim still apply.
+    ///
+    /// ```ignore
+    /// fn sines(voices: &VoiceControls) -> Box<dyn AudioUnit> {
+    ///     let mut net = Net::new(0, 1);
+    ///     for v in 0..VOICE_COUNT {
+    ///         let gate = var(&voices.gates[v]) >> follow(0.01);
+    ///         net = net + ((var(&voices.freqs[v]) >> sine::<f32>()) * gate);
+    ///     }
+    ///     Box::new(net * VOICE_GAIN)
+    /// }
+    /// let synth = KeyboardSynth::with_net(sines);
+    /// ```
+    pub fn with_net(builder: NetBuilder) -> Self {
+        let mut synth = Self::new();
+        synth.net_builder = Some(builder);
+        synth.rebuild_net();
+        synth
+    }
+
+    /// Rebuild the audio graph from the current topology.
+    /// The voice/control Shareds are reused, so their values carry over.
+    fn rebuild_net(&mut self) {
+        let (net, chorus_ids) = match self.net_builder {
+            Some(builder) => {
+                let net = builder(&VoiceControls::from_controls(&self.controls));
+                assert!(
+                    net.inputs() == 0 && (1..=2).contains(&net.outputs()),
+                    "Custom net must have 0 inputs and 1 or 2 outputs"
+                );
+                (Net::wrap(net), None)
+            }
+            None => build_net(&self.topology, &self.controls),
+        };
+        self.crossfade_net(net);
+        self.chorus_ids = chorus_ids;
+    }
+
+    /// Swap in `net` as the audio graph, fading from the old one over the
+    /// topology fade. Both graphs render meanwhile; a rebuild during a
+    /// fade cuts the older graph, which is already mostly faded out.
+    fn crossfade_net(&mut self, net: Net) {
+        let old = core::mem::replace(&mut self.net, net);
+        self.net_fade = (self.topology_fade_frames > 0 && self.backend == RenderBackend::Float)
+            .then(|| NetFade {
+                net: old,
+                remaining: self.topology_fade_frames,
+                frames: self.topology_fade_frames,
+            });
+    }
+
+    /// Length of the crossfade from the old audio graph to the new one when
+    /// a setting rebuilds it (`TOPOLOGY_FADE` by default), so switching the
+    /// waveform, filter slope, effects order and the like doesn't click.
+    /// Sounding notes still restart their envelopes in the new graph, so
+    /// they fade out and attack again; the fade only removes the step.
+    /// It runs both graphs meanwhile, up to twice the render cost for the
+    /// length of the fade. Zero switches at once.
+    pub fn set_topology_fade(&mut self, fade: Duration) {
+        self.topology_fade_frames = fade_frames(fade);
+        if self.topology_fade_frames == 0 {
+            self.net_fade = None;
+        }
+    }
+
+    /// Select the slope of the main low-pass filter.
+    ///
+    /// Rebuilds the audio graph, so sounding notes restart their envelopes.
+    /// Sweep `filter_cutoff_control()` with `FilterSlope::Moog24` and some
+    /// resonance for the classic resonant ladder sweep; `OnePole6` is the
+    /// original flat 6 dB filter.
+    pub fn set_filter_slope(&mut self, slope: FilterSlope) {
+        if slope == self.topology.filter_slope {
+            return;
+        }
+        self.topology.filter_slope = slope;
+        self.rebuild_net();
+    }
+
+    /// Run the oscillators at `factor` times the sample rate (1 or 2, higher
+    /// values are clamped to 2) and decimate, trading CPU for less aliasing.
+    ///
+    /// The band-limited saw and square still fold some energy back at the
+    /// top octave, more so when bent up; 2x pushes those partials past the
+    /// decimation filter. It roughly doubles the oscillator cost and adds
+    /// a half-band filter per voice, so it is off (1x) by default.
+    /// Rebuilds the audio graph, so sounding notes restart their envelopes.
+    pub fn set_oversampling(&mut self, factor: u8) {
+        let factor = factor.clamp(1, 2);
+        if factor == self.topology.oversampling {
+            return;
+        }
+        self.topology.oversampling = factor;
+        self.rebuild_net();
+    }
+
+    /// Restart the oscillator at phase 0 on every new note, so repeated
+    /// notes all attack the same way: tighter and punchier for bass and
+    /// percussive sounds. Off by default, when the oscillators run free
+    /// and each note starts wherever the waveform happens to be. Legato
+    /// mono notes keep their phase so they join smoothly.
+    /// Rebuilds the audio graph.
+    pub fn set_phase_reset(&mut self, on: bool) {
+        if on != self.topology.phase_reset {
+            self.topology.phase_reset = on;
+            self.rebuild_net();
+        }
+    }
+
+    /// Index of the zone a key belongs to.
+    #[inline(always)]
+    fn zone_index(&self, key: usize, octave: u8) -> usize {
+        match self.split_note {
+            Some(split) if octave as usize * KEY_COUNT + key >= split as usize => 1,
+            _ => 0,
+        }
+    }
+
+    /// Restrict voice allocation and stealing to voices `lo..hi`.
+    /// Voices outside the range keep sounding until released but are never
+    /// (re)assigned, e.g. to keep a pad on voices 0-3 safe from notes
+    /// limited to 4..7. With a split, each zone's pool is narrowed to the
+    /// range; a zone without overlap has no voices, and its notes are
+    /// dropped.
+    pub fn set_voice_range(&mut self, lo: usize, hi: usize) {
+        assert!(lo < hi && hi <= VOICE_COUNT, "Invalid voice range");
+        self.voice_range = (lo, hi);
+    }
+
+    /// Protect freshly allocated voices from stealing for `ms` milliseconds,
+    /// so fast runs don't cut off notes that have only just started. If
+    /// every voice in the pool is that young, round-robin stealing applies
+    /// as usual. Ages are counted in rendered audio, at buffer granularity.
+    pub fn set_min_voice_age_ms(&mut self, ms: u32) {
+        self.min_voice_age = ms as u64 * DEFAULT_SR as u64 / 1000;
+    }
+
+    /// Fade a stolen voice out over `ms` milliseconds before the new note
+    /// starts on it, instead of cutting the old note off with a click. The
+    /// new note starts that much later; notes already near silence are
+    /// taken at once. 0 (the default) steals immediately.
+    pub fn set_steal_crossfade_ms(&mut self, ms: u32) {
+        self.steal_fade_frames = ms as u64 * DEFAULT_SR as u64 / 1000;
+    }
+
+    /// Choose the voice a note steals when its pool is full,
+    /// `StealMode::RoundRobin` by default. The minimum voice age and the
+    /// sostenuto pedal protect voices in every mode.
+    pub fn set_steal_mode(&mut self, mode: StealMode) {
+        self.steal_mode = mode;
+    }
+
+    /// Estimated current loudness of a voice, 0.0-1.0: its velocity (with
+    /// the octave gain) times its envelope level, worked out from the
+    /// note's start and release times as rendered so far.
+    fn voice_loudness(&self, voice: usize) -> f32 {
+        let env = &self.controls.env;
+        let seconds = |frames: u64| frames as f32 / DEFAULT_SR as f32;
+        let gated = self.controls.gates[voice].value() > 0.0;
+        let min_ramp = env.min_ramp.value();
+        let level = env_level(
+            EnvCurve::from_code(env.curve.value()),
+            (env.attack.value() * self.controls.attack_scales[voice].value()).max(min_ramp),
+            env.decay.value(),
+            env.sustain.value(),
+            (env.release.value() * self.controls.release_scales[voice].value()).max(min_ramp),
+            seconds(self.clock - self.voice_started[voice]),
+            (!gated).then(|| seconds(self.clock.saturating_sub(self.voice_gated[voice]))),
+        );
+        self.controls.velocities[voice].value() * level
+    }
+
+    /// Release notes automatically `ms` milliseconds after they start, even
+    /// while the key is still down; `None` (the default) holds them for as
+    /// long as the key is. A safety net against stuck keys and endless
+    /// drones, on top of the debouncing. The key has to be released and
+    /// pressed again to replay the note. Checked once per buffer.
+    pub fn set_max_note_duration(&mut self, ms: Option<u32>) {
+        self.max_note_duration = ms.map(|ms| ms as u64 * DEFAULT_SR as u64 / 1000);
+    }
+
+    /// Gate off voices held longer than the maximum note duration.
+    fn release_expired_voices(&mut self) {
+        let Some(limit) = self.max_note_duration else {
+            return;
+        };
+        for voice in 0..VOICE_COUNT {
+            let gate = &self.controls.gates[voice];
+            if gate.value() > 0.0 && self.clock - self.voice_started[voice] >= limit {
+                gate.set_value(0.0);
+            }
+        }
+    }
+
+    /// Voices a zone may allocate from: its pool narrowed to the voice range,
+    /// empty (`lo == hi`) when the two don't overlap.
+    #[inline(always)]
+    fn voice_pool(&self, zone_idx: usize) -> (usize, usize) {
+        let zone = &self.zones[zone_idx];
+        let lo = core::cmp::max(zone.lo, self.voice_range.0);
+        let hi = core::cmp::min(zone.hi, self.voice_range.1);
+        (lo, core::cmp::max(lo, hi))
+    }
+
+    /// The voice mono mode and the arpeggiator play on: the first of the
+    /// lower zone's pool, `None` when the pool is empty.
+    fn mono_voice(&self) -> Option<usize> {
+        let (lo, hi) = self.voice_pool(0);
+        (lo < hi).then_some(lo)
+    }
+
+    /// Frequency of a key in its zone under the keyboard mode, without
+    /// pitch bend.
+    #[inline(always)]
+    fn note_freq(&self, key: usize, octave: u8) -> f32 {
+        let zone = &self.zones[self.zone_index(key, octave)];
+        let (pitch, pitch_octave) = self
+            .keyboard_mode
+            .pitch(key, octave)
+            .unwrap_or((key, octave));
+        let octaves = pitch_octave as i32 + zone.config.transpose as i32 + self.octave_shift as i32;
+        SEMITONE_FREQS[pitch] * libm::exp2f(octaves as f32) * self.fine_tune
+    }
+
+    /// Master tune in cents (clamped to ±100), for matching other
+    /// instruments. Composes with transpose, octave shift and pitch bend.
+    pub fn set_fine_tune_cents(&mut self, cents: f32) {
+        let fine_tune = libm::exp2f(cents.clamp(-100.0, 100.0) / 1200.0);
+        let ratio = fine_tune / self.fine_tune;
+        self.fine_tune = fine_tune;
+        if !self.fine_tune_held {
+            return;
+        }
+        for voice in 0..VOICE_COUNT {
+            if self.voice_note[voice] != VOICE_UNASSIGNED {
+                self.base_freqs[voice] *= ratio;
+                self.update_voice_freq(voice);
+            }
+        }
+    }
+
+    /// Whether master tune changes retune sounding notes immediately (the
+    /// default) or only apply from the next key press.
+    pub fn set_fine_tune_held(&mut self, immediate: bool) {
+        self.fine_tune_held = immediate;
+    }
+
+    /// Shift the whole keyboard by `shift` octaves (e.g. from octave up/down
+    /// buttons), clamped to -3..=3. Sounding notes keep their pitch unless
+    /// octave follow is on.
+    pub fn set_octave_shift(&mut self, shift: i8) {
+        let shift = shift.clamp(-3, 3);
+        let delta = shift - self.octave_shift;
+        self.octave_shift = shift;
+        if !self.octave_follow || delta == 0 {
+            return;
+        }
+        let ratio = libm::exp2f(delta as f32);
+        for voice in 0..VOICE_COUNT {
+            if self.voice_note[voice] != VOICE_UNASSIGNED {
+                self.base_freqs[voice] *= ratio;
+                self.update_voice_freq(voice);
+            }
+        }
+    }
+
+    /// Make held notes jump along with octave shift changes. Voices keep
+    /// their physical key as note identity, so releasing the key still
+    /// finds the shifted voice.
+    pub fn set_octave_follow(&mut self, on: bool) {
+        self.octave_follow = on;
+    }
+
+    /// Switch between polyphonic and mono mode.
+    /// Mono mode plays on the first voice of the lower zone's pool; switching
+    /// releases any sounding notes.
+    pub fn set_mono(&mut self, on: bool) {
+        if on == self.mono {
+            return;
+        }
+        self.mono = on;
+        for voice in 0..VOICE_COUNT {
+            self.controls.gates[voice].set_value(0.0);
+            self.voice_note[voice] = VOICE_UNASSIGNED;
+        }
+        self.sostenuto_voices = [false; VOICE_COUNT];
+        self.steal_fades = [None; VOICE_COUNT];
+        self.held_count = 0;
+    }
+
+    /// Reinterpret the key grid (see `layout.rs`): a chromatic keyboard
+    /// (the default), an isomorphic Wicki-Hayden layout on which every
+    /// chord shape plays the same in any key, drum pads hitting the drum
+    /// machine's sounds, or the drum machine's step grid to edit. Sounding
+    /// notes are cut, as a key may play something else after the switch.
+    ///
+    /// ```ignore
+    /// // Major triads anywhere on the grid: a key, two to its right and
+    /// // the one above it
+    /// synth.set_keyboard_mode(KeyboardMode::Isomorphic);
+    /// ```
+    pub fn set_keyboard_mode(&mut self, mode: KeyboardMode) {
+        if mode == self.keyboard_mode {
+            return;
+        }
+        self.keyboard_mode = mode;
+        for voice in 0..VOICE_COUNT {
+            self.controls.gates[voice].set_value(0.0);
+            self.voice_note[voice] = VOICE_UNASSIGNED;
+        }
+        self.sostenuto_voices = [false; VOICE_COUNT];
+        self.steal_fades = [None; VOICE_COUNT];
+        self.held_count = 0;
+    }
+
+    pub fn keyboard_mode(&self) -> KeyboardMode {
+        self.keyboard_mode
+    }
+
+    /// Let the keys edit the drum pattern: pressing both `DRUM_EDIT_COMBO`
+    /// keys switches to `KeyboardMode::DrumEdit` and starts the drums, so
+    /// each step is heard as it is set, and pressing them again goes back
+    /// to the mode before. The drums play on after editing, unless every
+    /// step was cleared. The combo's own step presses are undone.
+    pub fn set_drum_edit_combo(&mut self, enabled: bool) {
+        self.drum_edit_combo = enabled;
+    }
+
+    /// Whether a key press completes the drum edit combo.
+    fn is_drum_edit_combo(&self, key: usize, octave: u8) -> bool {
+        let [a, b] = DRUM_EDIT_COMBO;
+        let held = |(key, octave): (usize, u8)| self.key_states[octave as usize][key];
+        self.drum_edit_combo && ((key, octave) == a && held(b) || (key, octave) == b && held(a))
+    }
+
+    /// Go into drum step editing, or back out of it to the previous mode.
+    fn toggle_drum_edit(&mut self, key: usize, octave: u8) {
+        if self.keyboard_mode == KeyboardMode::DrumEdit {
+            // The other combo key switched its step on the way in
+            let [a, b] = DRUM_EDIT_COMBO;
+            let (other_key, other_octave) = if (key, octave) == a { b } else { a };
+            if let Some((track, step)) = self.keyboard_mode.drum_step(other_key, other_octave) {
+                let on = self.drums.step(track, step);
+                self.drums.set_step(track, step, !on);
+            }
+            self.set_keyboard_mode(self.mode_before_edit);
+            if self.drums.is_empty() {
+                self.set_drums_playing(false);
+            }
+        } else {
+            self.mode_before_edit = self.keyboard_mode;
+            self.set_keyboard_mode(KeyboardMode::DrumEdit);
+            self.set_drums_playing(true);
+        }
+    }
+
+    /// Enable latch mode: tapping a key turns its note on, tapping it again
+    /// turns it off, and key releases are ignored, so chords can be built up
+    /// one key at a time. Latched notes are stolen like held ones when all
+    /// voices are busy. Applies to polyphonic mode only; turning latch off
+    /// leaves latched notes sounding until `all_notes_off`.
+    pub fn set_latch(&mut self, on: bool) {
+        self.latch = on;
+    }
+
+    /// Sostenuto pedal: pressing it captures the notes whose keys are held
+    /// at that moment, which then keep sounding after their keys are
+    /// released until the pedal comes up. Notes played while the pedal is
+    /// down play and release normally, and stealing never takes a captured
+    /// voice (a note that would need one is not played). Applies to
+    /// polyphonic mode only.
+    ///
+    /// ```ignore
+    /// // Hold a bass note under a moving melody
+    /// synth.update_key(0, 0, true); // C2
+    /// synth.set_sostenuto(true);
+    /// synth.update_key(0, 0, false); // the C keeps sounding
+    /// // ... play the melody in the upper octaves ...
+    /// synth.set_sostenuto(false); // the C releases
+    /// ```
+    pub fn set_sostenuto(&mut self, on: bool) {
+        if on == self.sostenuto {
+            return;
+        }
+        self.sostenuto = on;
+        for voice in 0..VOICE_COUNT {
+            let note = self.voice_note[voice];
+            let gated = note != VOICE_UNASSIGNED && self.controls.gates[voice].value() > 0.0;
+            if on {
+                self.sostenuto_voices[voice] =
+                    gated && !self.mono && self.arp.is_none() && self.key_held(note);
+            } else if self.sostenuto_voices[voice] {
+                self.sostenuto_voices[voice] = false;
+                if !self.latch && !self.key_held(note) {
+                    self.controls.gates[voice].set_value(0.0);
+                }
+            }
+        }
+    }
+
+    /// Whether the key for an encoded note is down.
+    fn key_held(&self, note: u8) -> bool {
+        let (key, octave) = decode_note(note);
+        self.key_states[octave as usize][key]
+    }
+
+    /// Cross-check the keys held down against the sounding voices and fix
+    /// the difference, in case an edge was missed: a voice still gated for
+    /// a key that is up is released (unless the sostenuto pedal holds it),
+    /// and a held key without any voice gets a free one from its pool, at
+    /// full velocity like `update_key`. Returns the number of fixes. Call
+    /// it every so many scans; it costs a pass over the voices and keys.
+    ///
+    /// Only plain polyphonic playing is checked: latch, mono and the arp
+    /// sound notes independently of the keys. A held key whose voice was
+    /// stolen or timed out by `set_max_note_duration` isn't a discrepancy,
+    /// it has no free voice or keeps its note respectively. Switching mono
+    /// or the arp off with keys down leaves them silent until reconciled.
+    pub fn reconcile_voices(&mut self) -> usize {
+        if self.latch || self.mono || self.arp.is_some() {
+            return 0;
+        }
+        let mut fixes = 0;
+        for voice in 0..VOICE_COUNT {
+            let note = self.voice_note[voice];
+            let gate = &self.controls.gates[voice];
+            if note != VOICE_UNASSIGNED
+                && gate.value() > 0.0
+                && !self.sostenuto_voices[voice]
+                && !self.key_held(note)
+            {
+                gate.set_value(0.0);
+                fixes += 1;
+            }
+        }
+        for octave in 0..OCTAVE_COUNT as u8 {
+            for key in 0..KEY_COUNT {
+                let note = encode_note(key as u8, octave);
+                if !self.key_states[octave as usize][key]
+                    || self.voice_note.contains(&note)
+                    || self.keyboard_mode.pitch(key, octave).is_none()
+                {
+                    continue;
+                }
+                let (lo, hi) = self.voice_pool(self.zone_index(key, octave));
+                if let Some(voice) = (lo..hi).find(|&v| self.voice_note[v] == VOICE_UNASSIGNED) {
+                    self.velocity = 1.0;
+                    let freq = self.note_freq(key, octave);
+                    self.allocate_voice(voice, note, freq);
+                    fixes += 1;
+                }
+            }
+        }
+        fixes
+    }
+
+    /// Release every sounding note (latched, held or sostenuto) and clear
+    /// the mono held-note stack. Voices play out their release tails.
+    pub fn all_notes_off(&mut self) {
+        for voice in 0..VOICE_COUNT {
+            self.controls.gates[voice].set_value(0.0);
+        }
+        self.sostenuto_voices = [false; VOICE_COUNT];
+        self.steal_fades = [None; VOICE_COUNT];
+        self.held_count = 0;
+    }
+
+    /// Select which held note sounds in mono mode.
+    /// Takes effect immediately if several keys are already held.
+    pub fn set_note_priority(&mut self, priority: NotePriority) {
+        self.note_priority = priority;
+        if self.mono && self.held_count > 0 {
+            self.play_mono_note();
+        }
+    }
+
+    /// Pick the held note that should sound according to the note priority.
+    fn priority_note(&self) -> Option<u8> {
+        let held = &self.held_notes[..self.held_count];
+        match self.note_priority {
+            NotePriority::Last => held.last().copied(),
+            NotePriority::Low => held.iter().min().copied(),
+            NotePriority::High => held.iter().max().copied(),
+        }
+    }
+
+    /// Handle a key event in mono mode using the held-note stack.
+    fn handle_mono_key(&mut self, note: u8, pressed: bool) {
+        self.update_held_notes(note, pressed);
+        if self.held_count == 0 {
+            if let Some(voice) = self.mono_voice() {
+                self.controls.gates[voice].set_value(0.0);
+            }
+        } else {
+            self.play_mono_note();
+        }
+    }
+
+    /// Push a pressed note onto the held-note stack or remove a released one.
+    fn update_held_notes(&mut self, note: u8, pressed: bool) {
+        let held = &self.held_notes[..self.held_count];
+        let pos = held.iter().position(|&n| n == note);
+        if pressed {
+            if pos.is_none() && self.held_count < HELD_NOTE_MAX {
+                self.held_notes[self.held_count] = note;
+                self.held_count += 1;
+            }
+        } else if let Some(pos) = pos {
+            self.held_notes.copy_within(pos + 1..self.held_count, pos);
+            self.held_count -= 1;
+        }
+    }
+
+    /// Retune the mono voice to the priority note without retriggering it
+    /// when it is already gated (legato).
+    fn play_mono_note(&mut self) {
+        let (Some(note), Some(voice)) = (self.priority_note(), self.mono_voice()) else {
+            return;
+        };
+        if self.voice_note[voice] == note && self.controls.gates[voice].value() > 0.0 {
+            return;
+        }
+        let (key, octave) = decode_note(note);
+        let freq = self.note_freq(key, octave);
+        let legato =
+            self.voice_note[voice] != VOICE_UNASSIGNED && self.controls.gates[voice].value() > 0.0;
+        // Glide from wherever the previous glide has got to
+        let from = self.base_freqs[voice] * self.glide_position(voice);
+        self.allocate_voice(voice, note, freq);
+        // A new glide, or a fresh start on pitch that cuts off any glide
+        let start_ratio = if legato {
+            from / self.base_freqs[voice]
+        } else {
+            1.0
+        };
+        self.glide_started[voice] = self.clock;
+        self.glide_start_ratios[voice] = start_ratio;
+        self.controls.glide_ratios[voice].set_value(start_ratio);
+        let trigger = &self.controls.glide_triggers[voice];
+        trigger.set_value(trigger.value() + 1.0);
+    }
+
+    /// Current glide ratio of a voice, as far as the synth's clock tells.
+    fn glide_position(&self, voice: usize) -> f32 {
+        let time = self.controls.glide_time.value() * DEFAULT_SR as f32;
+        let elapsed = (self.clock - self.glide_started[voice]) as f32;
+        if time <= 0.0 || elapsed >= time {
+            return 1.0;
+        }
+        let curve = GlideCurve::from_code(self.controls.glide_curve.value());
+        glide_ratio(curve, self.glide_start_ratios[voice], elapsed / time)
+    }
+
+    /// Portamento time in seconds for mono legato: while a key is held, the
+    /// next note glides there from the current pitch instead of jumping.
+    /// Notes played after a release start on pitch. 0.0 (the default)
+    /// disables glide. Mono mode only.
+    pub fn set_glide_time(&mut self, seconds: f32) {
+        self.controls.glide_time.set_value(seconds.max(0.0));
+    }
+
+    /// Select the glide path, `GlideCurve::Exponential` by default.
+    pub fn set_glide_curve(&mut self, curve: GlideCurve) {
+        self.controls.glide_curve.set_value(curve as u8 as f32);
+    }
+
+    /// Turn the arpeggiator on with a pattern, or off with `None`.
+    /// While it runs, held keys don't sound directly: the first voice of the
+    /// lower zone's pool steps through them at the arp rate. Switching
+    /// releases any sounding notes.
+    pub fn set_arpeggiator(&mut self, mode: Option<ArpMode>) {
+        if mode == self.arp {
+            return;
+        }
+        self.arp = mode;
+        for voice in 0..VOICE_COUNT {
+            self.controls.gates[voice].set_value(0.0);
+            self.voice_note[voice] = VOICE_UNASSIGNED;
+        }
+        self.sostenuto_voices = [false; VOICE_COUNT];
+        self.steal_fades = [None; VOICE_COUNT];
+        self.held_count = 0;
+        self.arp_note_off = None;
+    }
+
+    /// Arp speed in steps per second (clamped to 0.5..50), while the arp
+    /// isn't synced (`set_arp_sync`). Takes effect from the next step.
+    pub fn set_arp_rate(&mut self, steps_per_second: f32) {
+        self.arp_rate = steps_per_second.clamp(0.5, 50.0);
+    }
+
+    /// Step the arp once per note division of the tempo (`set_tempo`, tap
+    /// tempo or MIDI clock) instead of at the arp rate, or go back to the
+    /// rate with `None`. Synced, the steps keep in time with the drums,
+    /// note repeat and the trance gate at any tempo, where a free rate only
+    /// starts with them. Takes effect from the next step.
+    ///
+    /// ```ignore
+    /// // An arpeggio in 16ths, locked to the drum steps
+    /// synth.set_arp_sync(Some(NoteDivision::Sixteenth));
+    /// ```
+    pub fn set_arp_sync(&mut self, division: Option<NoteDivision>) {
+        self.arp_sync = division;
+        self.arp_step_carry = 0.0;
+    }
+
+    /// Frames to the arp step after the one playing. Synced steps carry
+    /// the fraction of a frame over to the next, so they don't drift from
+    /// the drums' fractional steps.
+    fn arp_step_frames(&mut self) -> u64 {
+        match self.arp_sync {
+            Some(division) => {
+                let exact = DEFAULT_SR as f32 * 60.0 / (self.tempo * division.per_beat())
+                    + self.arp_step_carry;
+                let step = exact as u64;
+                self.arp_step_carry = exact - step as f32;
+                step
+            }
+            None => (DEFAULT_SR as f32 / self.arp_rate) as u64,
+        }
+    }
+
+    /// Fraction of each arp step the note sounds, from staccato (0.05) to
+    /// 1.0, where the notes run into each other legato without retriggering
+    /// the envelope. Note-repeat hits sound for the same fraction, though
+    /// at 1.0 they still retrigger, after a ~3 ms gap.
+    pub fn set_arp_gate(&mut self, fraction: f32) {
+        self.arp_gate = fraction.clamp(ARP_GATE_MIN, 1.0);
+    }
+
+    /// Octaves the arp pattern spans (1 to `ARP_OCTAVES_MAX`): the held notes
+    /// repeat transposed up an octave for each extra octave.
+    pub fn set_arp_octaves(&mut self, n: u8) {
+        self.arp_octaves = n.clamp(1, ARP_OCTAVES_MAX);
+    }
+
+    /// Handle a key event while the arpeggiator runs: only the held notes
+    /// change, the steps are played from `render`.
+    fn handle_arp_key(&mut self, note: u8, pressed: bool) {
+        let idle = self.held_count == 0;
+        self.update_held_notes(note, pressed);
+        if self.held_count == 0 {
+            if let Some(voice) = self.mono_voice() {
+                self.controls.gates[voice].set_value(0.0);
+            }
+            self.arp_note_off = None;
+        } else if idle {
+            // The first key starts the pattern right away, or on the next
+            // drum step to stay in time with the drums
+            self.arp_index = 0;
+            self.arp_next_step = self.clock;
+            self.arp_step_carry = 0.0;
+            if self.drums.is_playing() {
+                self.arp_next_step += self.drums.frames_to_step();
+            }
+            self.arp_note_off = None;
+        }
+    }
+
+    /// Key, octave and octaves up of an arp step: the sorted (or
+    /// press-ordered) held notes, repeated an octave up for each octave of
+    /// the span. The octaves up stay apart from the key's own octave, which
+    /// in isomorphic mode is a row of the grid rather than 12 semitones.
+    fn arp_step_note(&self, mode: ArpMode, step: usize) -> (usize, u8, u8) {
+        let held = self.held_count;
+        let len = held * self.arp_octaves as usize;
+        let pos = match mode {
+            ArpMode::Up | ArpMode::Played => step % len,
+            ArpMode::Down => len - 1 - step % len,
+            ArpMode::UpDown => {
+                let period = core::cmp::max(2 * len - 2, 1);
+                let pos = step % period;
+                if pos < len { pos } else { period - pos }
+            }
+        };
+        let mut notes = self.held_notes;
+        if mode != ArpMode::Played {
+            notes[..held].sort_unstable();
+        }
+        let (key, octave) = decode_note(notes[pos % held]);
+        (key, octave, (pos / held) as u8)
+    }
+
+    /// Play any arp step or note-off due at the current clock.
+    fn update_arp(&mut self) {
+        let Some(mode) = self.arp else {
+            return;
+        };
+        if self.held_count == 0 {
+            return;
+        }
+        // Without a voice the steps still count on, silently
+        let voice = self.mono_voice();
+        if self.arp_note_off.is_some_and(|off| self.clock >= off) {
+            if let Some(voice) = voice {
+                self.controls.gates[voice].set_value(0.0);
+            }
+            self.arp_note_off = None;
+        }
+        if self.clock < self.arp_next_step {
+            return;
+        }
+        let (key, octave, up) = self.arp_step_note(mode, self.arp_index);
+        let freq = self.note_freq(key, octave) * libm::exp2f(up as f32);
+        if let Some(voice) = voice {
+            self.allocate_voice(voice, encode_note(key as u8, octave + up), freq);
+        }
+        self.arp_index = self.arp_index.wrapping_add(1);
+        let step = self.arp_step_frames();
+        self.arp_note_off = (self.arp_gate < 1.0)
+            .then(|| self.arp_next_step + core::cmp::max((step as f32 * self.arp_gate) as u64, 1));
+        self.arp_next_step += step;
+    }
+
+    /// Roll held notes: with a division, every held key's note retriggers
+    /// its envelope once per division at the tempo (`set_tempo`) for as
+    /// long as the key is down, each hit sounding for the arp gate fraction
+    /// (`set_arp_gate`): short for staccato hi-hat rolls, long for
+    /// sustained stutters. The first hit is the key press itself; the rest
+    /// keep to the drums' steps while they play. Releasing the key stops
+    /// the roll at once. `None` (the default) turns it off; the
+    /// arpeggiator, when on, takes over the held notes instead.
+    ///
+    /// ```ignore
+    /// // 16th-note rolls, each hit a quarter of a step
+    /// synth.set_arp_gate(0.25);
+    /// synth.set_note_repeat(Some(NoteDivision::Sixteenth));
+    /// ```
+    pub fn set_note_repeat(&mut self, division: Option<NoteDivision>) {
+        if division.is_none() {
+            // Held notes caught between hits sound on
+            for voice in 0..VOICE_COUNT {
+                if self.note_repeats(voice) && self.controls.gates[voice].value() <= 0.0 {
+                    self.controls.gates[voice].set_value(1.0);
+                }
+            }
+        }
+        self.note_repeat = division;
+        self.note_repeat_next = None;
+        self.note_repeat_off = None;
+    }
+
+    /// Whether a voice plays a held key's note, which note repeat rolls.
+    fn note_repeats(&self, voice: usize) -> bool {
+        let note = self.voice_note[voice];
+        note != VOICE_UNASSIGNED && self.steal_fades[voice].is_none() && self.key_held(note)
+    }
+
+    /// Clock at which a note-repeat hit starting at `start` closes its
+    /// gates, leaving at least `NOTE_REPEAT_GAP` before the next.
+    fn note_repeat_gate_off(&self, start: u64, step: u64) -> u64 {
+        let length = core::cmp::min(
+            (step as f32 * self.arp_gate) as u64,
+            step.saturating_sub(NOTE_REPEAT_GAP),
+        );
+        start + core::cmp::max(length, 1)
+    }
+
+    /// Play any note-repeat hit or gate-off due at the current clock.
+    fn update_note_repeat(&mut self) {
+        let Some(division) = self.note_repeat.filter(|_| self.arp.is_none()) else {
+            return;
+        };
+        if !(0..VOICE_COUNT).any(|voice| self.note_repeats(voice)) {
+            self.note_repeat_next = None;
+            self.note_repeat_off = None;
+            return;
+        }
+        let step = (DEFAULT_SR as f32 * 60.0 / (self.tempo * division.per_beat())) as u64;
+        let next = match self.note_repeat_next {
+            Some(next) => next,
+            None => {
+                // The press was the first hit
+                let next = self.clock
+                    + if self.drums.is_playing() {
+                        self.drums.frames_to_step()
+                    } else {
+                        step
+                    };
+                self.note_repeat_off =
+                    Some(self.note_repeat_gate_off(self.clock, step)).filter(|&off| off < next);
+                self.note_repeat_next = Some(next);
+                next
+            }
+        };
+        if self.note_repeat_off.is_some_and(|off| self.clock >= off) {
+            for voice in 0..VOICE_COUNT {
+                if self.note_repeats(voice) {
+                    self.controls.gates[voice].set_value(0.0);
+                }
+            }
+            self.note_repeat_off = None;
+        }
+        if self.clock < next {
+            return;
+        }
+        for voice in 0..VOICE_COUNT {
+            if self.note_repeats(voice) {
+                self.voice_started[voice] = self.clock;
+                self.reset_phase(voice);
+                self.controls.gates[voice].set_value(1.0);
+            }
+        }
+        self.retrigger_lfo();
+        self.note_repeat_off = Some(self.note_repeat_gate_off(next, step));
+        self.note_repeat_next = Some(next + step);
+    }
+
+    /// Frames until the next note-repeat event, at most `limit`.
+    fn note_repeat_frames(&self, limit: usize) -> usize {
+        let Some(next) = self.note_repeat_next else {
+            return limit;
+        };
+        let next = match self.note_repeat_off {
+            Some(off) => core::cmp::min(off, next),
+            None => next,
+        };
+        let frames = core::cmp::max(next.saturating_sub(self.clock), 1);
+        core::cmp::min(frames, limit as u64) as usize
+    }
+
+    /// Frames until the next arp event, at most `limit`.
+    fn arp_frames(&self, limit: usize) -> usize {
+        if self.arp.is_none() || self.held_count == 0 {
+            return limit;
+        }
+        let next = match self.arp_note_off {
+            Some(off) => core::cmp::min(off, self.arp_next_step),
+            None => self.arp_next_step,
+        };
+        let frames = core::cmp::max(next.saturating_sub(self.clock), 1);
+        core::cmp::min(frames, limit as u64) as usize
+    }
+
+    /// Scan all octaves and handle key detection.
+    /// Update key state and handle press/release events.
+    /// This should be called on every scan with the current key state.
+    /// It will detect edge changes and trigger note on/off accordingly.
+    /// With a release hold-off, a held key must read released for the
+    /// hold-off before its note ends (see `set_release_holdoff_ms`).
+    /// Returns true if the key changed state.
+    #[inline]
+    pub fn update_key(&mut self, key: usize, octave: u8, pressed: bool) -> bool {
+        let octave_idx = octave as usize;
+        let held = self.key_states[octave_idx][key];
+        if pressed {
+            // A contact dropout shorter than the hold-off: the note plays on
+            self.release_pending[octave_idx][key] = None;
+        } else if held && self.release_holdoff_us > 0 {
+            let now = self.now_us();
+            let due =
+                *self.release_pending[octave_idx][key].get_or_insert(now + self.release_holdoff_us);
+            if now < due {
+                return false;
+            }
+        }
+        if pressed == held {
+            return false;
+        }
+        self.set_key(key, octave, pressed);
+        true
+    }
+
+    /// Release a key at once, without the release hold-off, e.g. for keys
+    /// that won't be read again. Returns true if it was down.
+    pub fn release_key_now(&mut self, key: usize, octave: u8) -> bool {
+        if !self.key_states[octave as usize][key] {
+            return false;
+        }
+        self.set_key(key, octave, false);
+        true
+    }
+
+    /// Change a key's state and play the press or release, at full level
+    /// as the key matrix can't sense velocity.
+    fn set_key(&mut self, key: usize, octave: u8, pressed: bool) {
+        self.key_states[octave as usize][key] = pressed;
+        self.release_pending[octave as usize][key] = None;
+        self.velocity = 1.0;
+        if pressed && self.is_drum_edit_combo(key, octave) {
+            self.toggle_drum_edit(key, octave);
+            return;
+        }
+        self.handle_key_change(key, octave, pressed);
+    }
+
+    /// Require a held key to read released for `ms` milliseconds before its
+    /// note ends, so a momentary loss of contact on a worn or dirty key
+    /// doesn't cut a sustained note and restart it. Releases come that much
+    /// later. Unlike press debouncing this only filters dropouts during a
+    /// hold; presses still play at once. Measured on the contact clock (see
+    /// `set_contact_clock`), or the sample clock without one, which makes
+    /// it at least one buffer. 0 (the default) releases at once. `note_off`
+    /// and `release_key_now` are not held off.
+    pub fn set_release_holdoff_ms(&mut self, ms: u32) {
+        self.release_holdoff_us = ms as u64 * 1000;
+        if ms == 0 {
+            self.release_pending = [[None; KEY_COUNT]; OCTAVE_COUNT];
+        }
+    }
+
+    /// Time for key timing in µs: the contact clock, or else the sample
+    /// clock.
+    fn now_us(&self) -> u64 {
+        match self.contact_clock {
+            Some(clock) => clock(),
+            None => self.clock * 1_000_000 / DEFAULT_SR as u64,
+        }
+    }
+
+    /// Update a key with two contacts, as on velocity-sensing keybeds, and
+    /// play it with the velocity its travel time gives. `break_` is the
+    /// upper contact, reached first as the key goes down, and `make` the
+    /// lower one at the bottom of the travel; each is true while the key is
+    /// past it. The note starts when `make` closes, its velocity from the
+    /// time since `break_` did (see `contact_velocity`: 2 ms or faster is
+    /// full velocity, 60 ms or slower the softest), and ends when the key
+    /// comes back up past `break_`. A key seen with both contacts at once,
+    /// or a single-contact key passed as both, plays at full velocity like
+    /// `update_key`. Returns true if the note started or ended.
+    ///
+    /// Wiring: each contact is a switch like the single-contact keys, so a
+    /// dual-contact board needs a second set of 12 key inputs (or a second
+    /// strobe per octave), with a diode per switch for the matrix. Scan
+    /// both contacts of a key together, at least every millisecond or so:
+    /// a faster scan resolves the loud end better. Time comes from
+    /// `set_contact_clock`, or with none set from the synth's sample clock,
+    /// which only advances per rendered block and is too coarse for this.
+    pub fn update_key_dual(&mut self, key: usize, octave: u8, make: bool, break_: bool) -> bool {
+        let octave_idx = octave as usize;
+        let now = self.now_us();
+        let pressed = self.key_states[octave_idx][key];
+        if !break_ && !make {
+            self.contact_times[octave_idx][key] = None;
+            if pressed {
+                self.key_states[octave_idx][key] = false;
+                self.handle_key_change(key, octave, false);
+                return true;
+            }
+            return false;
+        }
+        if pressed {
+            return false;
+        }
+        let started = *self.contact_times[octave_idx][key].get_or_insert(now);
+        if !make {
+            return false;
+        }
+        let velocity = if started == now {
+            VELOCITY_MAX
+        } else {
+            contact_velocity(now - started)
+        };
+        self.note_on(key, octave, velocity);
+        true
+    }
+
+    /// Time source for `update_key_dual` and the release hold-off, e.g. the
+    /// firmware's microsecond timer.
+    pub fn set_contact_clock(&mut self, clock: ContactClock) {
+        self.contact_clock = Some(clock);
+    }
+
+    /// Press a key with a velocity (1-127), for velocity-sensing inputs.
+    /// Velocity scales the voice volume through the velocity curve; full
+    /// velocity plays at the same level as `update_key`.
+    pub fn note_on(&mut self, key: usize, octave: u8, velocity: u8) {
+        self.key_states[octave as usize][key] = true;
+        self.velocity = self
+            .velocity_curve
+            .gain(core::cmp::max(velocity, self.velocity_floor));
+        self.handle_key_change(key, octave, true);
+    }
+
+    /// Reshape `note_on` velocities to suit the controller. The key matrix
+    /// has no velocity and always plays at full level.
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        self.velocity_curve = curve;
+    }
+
+    /// Play `note_on` velocities below `min` (1-127) at `min`, so the
+    /// softest touches still sound instead of feeling like missed presses;
+    /// velocities above it keep their full dynamics. Applies before the
+    /// velocity curve, to played and dual-contact velocities alike.
+    /// `VELOCITY_FLOOR` by default, 1 turns it off.
+    pub fn set_velocity_floor(&mut self, min: u8) {
+        self.velocity_floor = min.clamp(1, VELOCITY_MAX);
+    }
+
+    /// Release a key pressed with `note_on`. With release velocity enabled,
+    /// `release_velocity` (1-127) sets how long the note rings out: 64 uses
+    /// the envelope's release time, a fast 127 shortens it to a quarter and
+    /// a slow 1 stretches it about four times. Otherwise it is ignored.
+    pub fn note_off(&mut self, key: usize, octave: u8, release_velocity: u8) {
+        if self.release_velocity {
+            let note = encode_note(key as u8, octave);
+            let scale = release_scale(release_velocity);
+            for voice in 0..VOICE_COUNT {
+                if self.voice_note[voice] == note {
+                    self.controls.release_scales[voice].set_value(scale);
+                }
+            }
+        }
+        self.release_key_now(key, octave);
+    }
+
+    /// Let `note_off` velocities scale each note's release time, for
+    /// controllers that send them. Off by default: every note releases with
+    /// the envelope's release time.
+    pub fn set_release_velocity(&mut self, on: bool) {
+        self.release_velocity = on;
+    }
+
+    /// Let velocity open the filter: with `amount` > 0 every voice gets its
+    /// own one-pole filter at the main cutoff, lowered by up to
+    /// `amount * 3` octaves for the softest notes, so hard notes sound
+    /// bright and soft ones mellow. Applies to notes played from now on.
+    ///
+    /// Switching between zero and non-zero rebuilds the audio graph; at 0.0
+    /// the voice filters are removed and velocity only affects volume.
+    pub fn set_velocity_to_cutoff(&mut self, amount: f32) {
+        self.velocity_to_cutoff = amount.max(0.0);
+        let voice_filters = self.velocity_to_cutoff > 0.0;
+        if voice_filters != self.topology.voice_filters {
+            self.topology.voice_filters = voice_filters;
+            self.rebuild_net();
+        }
+    }
+
+    /// Let velocity speed up the attack, so hard notes hit percussively and
+    /// soft ones swell in: each note's attack time is divided by up to
+    /// `16^(amount * velocity)` (`amount` 0.0-1.0), so at 1.0 a full
+    /// velocity note attacks 16x faster than the envelope setting while the
+    /// softest keeps nearly all of it. At 0.0 (the default) every note uses
+    /// the envelope's attack. Key matrix notes play at full velocity.
+    /// Applies to notes played from now on.
+    pub fn set_velocity_to_attack(&mut self, amount: f32) {
+        self.velocity_to_attack = amount.clamp(0.0, 1.0);
+    }
+
+    /// Set the volume of notes from one keyboard octave (0 lowest), to
+    /// balance bass and treble for the speaker: e.g. 0.5 on octave 0 tames
+    /// boomy bass on a small one. `gain` is clamped to 0.0-2.0 and all
+    /// octaves start at 1.0 (flat). Applies to notes played from now on.
+    pub fn set_octave_gain(&mut self, octave: u8, gain: f32) {
+        if let Some(octave_gain) = self.octave_gains.get_mut(octave as usize) {
+            *octave_gain = gain.clamp(0.0, OCTAVE_GAIN_MAX);
+        }
+    }
+
+    /// Tilt the octave gains by `db_per_octave` (-6.0 to 6.0) around the
+    /// middle of the keyboard: negative values cut the low octaves and lift
+    /// the high ones, 0.0 is flat. Like `set_octave_gain`, each gain is
+    /// capped at 2.0 (+6 dB), so steep tilts flatten out at the lifted end.
+    /// Replaces any `set_octave_gain` settings; applies to notes played from
+    /// now on.
+    pub fn set_tilt(&mut self, db_per_octave: f32) {
+        let db_per_octave = db_per_octave.clamp(-TILT_MAX, TILT_MAX);
+        let center = (OCTAVE_COUNT - 1) as f32 / 2.0;
+        for (octave, gain) in self.octave_gains.iter_mut().enumerate() {
+            let db = db_per_octave * (center - octave as f32);
+            *gain = libm::powf(10.0, db / 20.0).min(OCTAVE_GAIN_MAX);
+        }
+    }
+
+    /// Let the main filter cutoff follow each note's pitch, so high notes
+    /// stay as bright as low ones: the cutoff is the setting at C4 and
+    /// moves `amount` octaves per octave of the note (0.0 fixed, the
+    /// default; 1.0 tracks the note fully, keeping the same harmonics in
+    /// every register). Applies immediately.
+    ///
+    /// With tracking the main filter moves from the mix into every voice,
+    /// so it costs up to seven filters instead of one; heavy with the
+    /// Moog slope. Switching between zero and non-zero rebuilds the graph.
+    pub fn set_filter_keytrack(&mut self, amount: f32) {
+        self.filter_keytrack = amount.clamp(0.0, 1.0);
+        for voice in 0..VOICE_COUNT {
+            self.update_voice_freq(voice);
+        }
+        let keytrack = self.filter_keytrack > 0.0;
+        if keytrack != self.topology.filter_keytrack {
+            self.topology.filter_keytrack = keytrack;
+            self.rebuild_net();
+        }
+    }
+
+    /// Thin out low notes so dense low chords stay clear: with `amount` > 0
+    /// (up to 1.0) every voice gets a one-pole highpass whose cutoff follows
+    /// its note at half the rate of the pitch, `amount` times the note
+    /// frequency at C4 (so one octave down it sits above the fundamental,
+    /// and high notes keep theirs). Applies immediately.
+    ///
+    /// Switching between zero and non-zero rebuilds the audio graph.
+    pub fn set_voice_highpass_tracking(&mut self, amount: f32) {
+        self.voice_highpass_tracking = amount.clamp(0.0, 1.0);
+        for voice in 0..VOICE_COUNT {
+            self.update_voice_freq(voice);
+        }
+        let voice_highpass = self.voice_highpass_tracking > 0.0;
+        if voice_highpass != self.topology.voice_highpass {
+            self.topology.voice_highpass = voice_highpass;
+            self.rebuild_net();
+        }
+    }
+
+    /// Spread voices across the stereo field. Anything but `Off` pans each
+    /// voice before the mix, which needs the filter and resonator twice
+    /// (one per channel); `Off` keeps the mono-compatible single chain.
+    /// Switching between `Off` and a panning mode rebuilds the audio graph.
+    ///
+    /// ```ignore
+    /// // A C-E-G chord from empty voices: C center, E left, G right
+    /// synth.set_auto_pan(AutoPanMode::ByVoice);
+    /// ```
+    pub fn set_auto_pan(&mut self, mode: AutoPanMode) {
+        self.auto_pan = mode;
+        for voice in 0..VOICE_COUNT {
+            let note = self.voice_note[voice];
+            let pan = if note == VOICE_UNASSIGNED {
+                0.0
+            } else {
+                auto_pan(mode, voice, note)
+            };
+            self.controls.pans[voice].set_value(pan);
+        }
+        let panned = mode != AutoPanMode::Off;
+        if panned != self.topology.auto_pan {
+            self.topology.auto_pan = panned;
+            self.rebuild_net();
+        }
+    }
+
+    /// Apply the current note velocity and the octave gain to a voice.
+    #[inline(always)]
+    fn apply_velocity(&mut self, voice: usize) {
+        let velocity = self.velocity;
+        let (_, octave) = decode_note(self.voice_note[voice]);
+        let octave_gain = self.octave_gains[core::cmp::min(octave as usize, OCTAVE_COUNT - 1)];
+        self.controls.velocities[voice].set_value(velocity * octave_gain);
+        self.controls.attack_scales[voice].set_value(libm::exp2f(
+            -VELOCITY_ATTACK_OCTAVES * self.velocity_to_attack * velocity,
+        ));
+        // A new note releases at the normal rate until note_off says otherwise
+        self.controls.release_scales[voice].set_value(1.0);
+        self.controls.voice_cutoff_offsets[voice]
+            .set_value(self.velocity_to_cutoff * (velocity - 1.0) * VELOCITY_CUTOFF_OCTAVES);
+    }
+
+    /// Handle a key press or release event.
+    /// This is called internally when a state change is detected.
+    #[inline]
+    fn handle_key_change(&mut self, key: usize, octave: u8, pressed: bool) {
+        let note = encode_note(key as u8, octave);
+
+        if let Some(track) = self.keyboard_mode.drum(key) {
+            if pressed {
+                self.drums.trigger(track);
+            }
+            return;
+        }
+        if let Some((track, step)) = self.keyboard_mode.drum_step(key, octave) {
+            if pressed {
+                let on = self.drums.step(track, step);
+                self.drums.set_step(track, step, !on);
+            }
+            return;
+        }
+
+        if self.arp.is_some() {
+            self.handle_arp_key(note, pressed);
+            return;
+        }
+
+        if self.mono {
+            self.handle_mono_key(note, pressed);
+            return;
+        }
+
+        if self.latch {
+            // Latch: a press toggles the note, releases are ignored
+            if !pressed {
+                return;
+            }
+            let mut released = false;
+            for voice in 0..VOICE_COUNT {
+                if self.voice_note[voice] == note && self.controls.gates[voice].value() > 0.0 {
+                    self.controls.gates[voice].set_value(0.0);
+                    released = true;
+                }
+            }
+            if released {
+                return;
+            }
+        }
+
+        if pressed {
+            // Check if this exact note (key + octave) already has its
+            // voices (several with a stack)
+            let mut found = false;
+            for voice in 0..VOICE_COUNT {
+                if self.voice_note[voice] != note {
+                    continue;
+                }
+                found = true;
+                if self.steal_fades[voice].is_some() {
+                    // Starts when its stolen voice has faded out
+                    continue;
+                }
+                self.voice_started[voice] = self.clock;
+                self.apply_velocity(voice);
+                self.reset_phase(voice);
+                self.controls.gates[voice].set_value(1.0);
+            }
+            if found {
+                self.retrigger_lfo();
+                return;
+            }
+
+            let zone_idx = self.zone_index(key, octave);
+            let (lo, hi) = self.voice_pool(zone_idx);
+            let freq = self.note_freq(key, octave);
+            self.claim_voice(zone_idx, note, freq);
+            // The stack's voices, as many as the pool has room for
+            for layer in 0..core::cmp::min(self.stack_len, (hi - lo).saturating_sub(1)) {
+                self.claim_voice(zone_idx, note, freq * self.stack_ratios[layer]);
+            }
+        } else {
+            // Key released - find the voices with this exact note; sostenuto
+            // holds them until the pedal comes up. A note still waiting for
+            // its stolen voice never starts.
+            for voice in 0..VOICE_COUNT {
+                if self.voice_note[voice] == note && !self.sostenuto_voices[voice] {
+                    self.controls.gates[voice].set_value(0.0);
+                    if self.steal_fades[voice].take().is_some() {
+                        self.voice_note[voice] = VOICE_UNASSIGNED;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Start `note` at `freq` on a free voice of the zone's pool, or steal
+    /// one when the pool is full.
+    fn claim_voice(&mut self, zone_idx: usize, note: u8, freq: f32) {
+        let (lo, hi) = self.voice_pool(zone_idx);
+        // Find first free voice in the pool
+        for voice in lo..hi {
+            if self.voice_note[voice] == VOICE_UNASSIGNED {
+                self.allocate_voice(voice, note, freq);
+                return;
+            }
+        }
+
+        // All voices busy - steal within the pool per the steal mode,
+        // skipping voices younger than the minimum age if possible and
+        // never taking one held by the sostenuto pedal or already playing
+        // this note (in its stack)
+        let mut voice = self.zones[zone_idx].next_voice;
+        if voice < lo || voice >= hi {
+            voice = lo;
+        }
+        let candidates = (0..hi - lo)
+            .map(|i| lo + (voice - lo + i) % (hi - lo))
+            .filter(|&v| !self.sostenuto_voices[v] && self.voice_note[v] != note);
+        let old_enough = |&v: &usize| self.clock - self.voice_started[v] >= self.min_voice_age;
+        let Some(first) = candidates.clone().next() else {
+            return;
+        };
+        voice = match self.steal_mode {
+            StealMode::RoundRobin => candidates.clone().find(old_enough).unwrap_or(first),
+            StealMode::OldestReleased => candidates
+                .clone()
+                .filter(old_enough)
+                .filter(|&v| self.controls.gates[v].value() <= 0.0)
+                .min_by_key(|&v| self.voice_gated[v])
+                .or_else(|| candidates.clone().find(old_enough))
+                .unwrap_or(first),
+            StealMode::Quietest => {
+                let quietest = |voices: &mut dyn Iterator<Item = usize>| {
+                    voices
+                        .min_by(|&a, &b| self.voice_loudness(a).total_cmp(&self.voice_loudness(b)))
+                };
+                quietest(&mut candidates.clone().filter(old_enough))
+                    .unwrap_or_else(|| quietest(&mut candidates.clone()).unwrap_or(first))
+            }
+        };
+        let next = voice + 1;
+        self.zones[zone_idx].next_voice = if next >= hi { lo } else { next };
+        if self.steal_fade_frames > 0 && self.voice_loudness(voice) > STEAL_FADE_LEVEL {
+            self.fade_out_stolen(voice, note, freq);
+        } else {
+            self.allocate_voice(voice, note, freq);
+        }
+    }
+
+    /// Fade a stolen voice out over the steal fade, then start `note` on it
+    /// (see `start_due_steals`). The voice takes the note right away, so
+    /// lookups by note find it meanwhile.
+    fn fade_out_stolen(&mut self, voice: usize, note: u8, freq: f32) {
+        let fade = self.steal_fade_frames as f32 / DEFAULT_SR as f32;
+        let release = self.controls.env.release.value();
+        // The release from the current level, shortened to the fade
+        let scale = if release > 0.0 { fade / release } else { 1.0 };
+        self.controls.release_scales[voice].set_value(scale);
+        self.controls.gates[voice].set_value(0.0);
+        self.voice_note[voice] = note;
+        self.voice_started[voice] = self.clock;
+        // The shortened release runs from here, which keeps the voice out
+        // of the idle skip until it has faded
+        self.voice_gated[voice] = self.clock;
+        self.steal_fades[voice] = Some(StealFade {
+            due: self.clock + self.steal_fade_frames,
+            freq,
+            velocity: self.velocity,
+        });
+    }
+
+    /// Start the notes whose stolen voices have faded out.
+    fn start_due_steals(&mut self) {
+        for voice in 0..VOICE_COUNT {
+            let Some(fade) = self.steal_fades[voice] else {
+                continue;
+            };
+            if fade.due > self.clock {
+                continue;
+            }
+            self.steal_fades[voice] = None;
+            let velocity = core::mem::replace(&mut self.velocity, fade.velocity);
+            self.allocate_voice(voice, self.voice_note[voice], fade.freq);
+            self.velocity = velocity;
+        }
+    }
+
+    /// Frames until the next stolen voice has faded out, at most `limit`.
+    fn steal_frames(&self, limit: usize) -> usize {
+        let next = self.steal_fades.iter().flatten().map(|fade| fade.due).min();
+        match next {
+            Some(due) => due.saturating_sub(self.clock).clamp(1, limit as u64) as usize,
+            None => limit,
+        }
+    }
+
+    /// Allocate a voice to a note and trigger the envelope.
+    #[inline(always)]
+    fn allocate_voice(&mut self, voice: usize, note: u8, base_freq: f32) {
+        let legato = self.mono && self.controls.gates[voice].value() > 0.0;
+        if !legato {
+            self.reset_phase(voice);
+        }
+        self.voice_note[voice] = note;
+        self.voice_started[voice] = self.clock;
+        self.steal_fades[voice] = None;
+        let base_freq = base_freq * self.spread_ratios[voice];
+        self.base_freqs[voice] = base_freq;
+        // A new note starts without per-voice bend
+        self.voice_bends[voice] = 1.0;
+        self.update_voice_freq(voice);
+        self.apply_velocity(voice);
+        self.controls.pans[voice].set_value(auto_pan(self.auto_pan, voice, note));
+        self.controls.gates[voice].set_value(1.0);
+        if !legato || self.legato_filter_retrigger {
+            self.retrigger_lfo();
+        }
+    }
+
+    /// Restart a voice's oscillator at phase 0 if phase reset is on.
+    #[inline(always)]
+    fn reset_phase(&mut self, voice: usize) {
+        if self.topology.phase_reset {
+            let reset = &self.controls.phase_resets[voice];
+            reset.set_value(reset.value() + 1.0);
+        }
+    }
+
+    /// Write a voice's frequency: base frequency with global and per-voice
+    /// pitch bend, drift and LFO vibrato applied.
+    #[inline(always)]
+    fn update_voice_freq(&mut self, voice: usize) {
+        let bent_freq = self.base_freqs[voice]
+            * self.pitch_bend.value()
+            * self.voice_bends[voice]
+            * self.drift_ratios[voice]
+            * self.unison_drift_ratios[voice]
+            * self.lfo_pitch
+            * self.sensor_pitch;
+        self.controls.freqs[voice].set_value(bent_freq);
+        if self.filter_keytrack > 0.0 {
+            let ratio = libm::powf(
+                self.base_freqs[voice] / FILTER_KEYTRACK_REFERENCE,
+                self.filter_keytrack,
+            );
+            self.controls.keytrack_ratios[voice].set_value(ratio);
+        }
+        if self.voice_highpass_tracking > 0.0 {
+            let cutoff = self.voice_highpass_tracking
+                * libm::sqrtf(self.base_freqs[voice] * VOICE_HIGHPASS_REFERENCE);
+            self.controls.voice_highpass[voice].set_value(cutoff);
+        }
+    }
+
+    /// Generate next audio sample (for single-sample processing).
+    #[inline(always)]
+    pub fn get_sample(&mut self) -> f32 {
+        self.net.get_mono()
+    }
+
+    /// Process a block of audio samples efficiently, mixed down to mono.
+    /// Uses SIMD acceleration when available.
+    #[inline]
+    pub fn process_block(&mut self, output: &mut [f32], buffer_size: usize) {
+        self.render(buffer_size, |i, left, right| {
+            output[i] = (left + right) * 0.5
+        });
+    }
+
+    /// Process a block of stereo audio into separate left/right buffers.
+    #[inline]
+    pub fn process_block_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let buffer_size = core::cmp::min(left.len(), right.len());
+        self.render(buffer_size, |i, l, r| {
+            left[i] = l;
+            right[i] = r;
+        });
+    }
+
+    /// Select the rendering backend. `Fixed` trades most of the sound
+    /// design features for a much cheaper integer hot loop; see
+    /// [`crate::fixed`] for what it leaves out.
+    pub fn set_render_backend(&mut self, backend: RenderBackend) {
+        self.backend = backend;
+        self.net_fade = None;
+    }
+
+    /// Select how the output gain follows the number of sounding voices.
+    /// Normalized gain changes are smoothed over a few milliseconds.
+    pub fn set_voice_gain_mode(&mut self, mode: GainMode) {
+        self.gain_mode = mode;
+    }
+
+    /// Play a pure sine reference at `freq_hz` (e.g. `TEST_TONE_FREQ`) on
+    /// both channels, for tuning or checking the audio path. It bypasses
+    /// voice allocation, the filter and effects, and sits at a fixed
+    /// -6 dBFS on top of whatever the keyboard plays.
+    pub fn play_test_tone(&mut self, freq_hz: f32, on: bool) {
+        self.test_tone = on.then(|| freq_hz / DEFAULT_SR as f32);
+    }
+
+    /// Start recording a loop of the output (up to `LOOPER_SECONDS`), or
+    /// overdub onto the recorded one; see `looper.rs` for the states. The
+    /// first recording allocates the loop buffer, `LOOPER_BYTES` of heap,
+    /// and fails if there isn't that much free.
+    ///
+    /// ```ignore
+    /// // Record a chord loop, then solo over it
+    /// synth.looper_record()?;
+    /// // ... play the chords ...
+    /// synth.looper_play(); // the loop length is set here
+    /// // ... play the solo; looper_record() again to overdub it ...
+    /// ```
+    pub fn looper_record(&mut self) -> Result<(), SynthError> {
+        self.looper
+            .record()
+            .map_err(|needed| SynthError::OutOfMemory {
+                needed,
+                free: self.heap_probe.map_or(0, |heap| heap().free),
+            })
+    }
+
+    /// Play the loop under live playing, ending a recording or overdub.
+    pub fn looper_play(&mut self) {
+        self.looper.play();
+    }
+
+    /// Silence the loop, ending a recording or overdub. It is kept for
+    /// `looper_play`.
+    pub fn looper_stop(&mut self) {
+        self.looper.stop();
+    }
+
+    /// Drop the loop and free its buffer.
+    pub fn looper_clear(&mut self) {
+        self.looper.clear();
+    }
+
+    pub fn looper_state(&self) -> LooperState {
+        self.looper.state()
+    }
+
+    /// Length of the output's fade-in from silence when rendering starts
+    /// (`STARTUP_FADE` by default), so switching on doesn't pop through
+    /// the DAC and amp. Set it before the first buffer; zero starts at full
+    /// level.
+    pub fn set_startup_fade(&mut self, fade: Duration) {
+        if fade.is_zero() {
+            self.startup_fade_step = None;
+            self.master_gain = self.controls.master_gain.value();
+        } else if self.startup_fade_step.is_some() {
+            self.startup_fade_step = Some(fade_step(fade));
+        }
+    }
+
+    /// Mute or unmute the output with a ~10 ms ramp. Unlike
+    /// `all_notes_off` the voices keep running, so unmuting brings back
+    /// whatever is still sounding, mid-note.
+    pub fn set_output_mute(&mut self, muted: bool) {
+        self.controls
+            .master_gain
+            .set_value(if muted { 0.0 } else { 1.0 });
+    }
+
+    /// Set the global LFO's waveform and rate (clamped to 0.01..50 Hz). It
+    /// modulates nothing until routed with `set_lfo_depth`.
+    ///
+    /// ```ignore
+    /// // Gated filter: cutoff jumps between 300 Hz and 2.4 kHz 8 times a second
+    /// synth.filter_cutoff_control().set(300.0);
+    /// synth.set_lfo(LfoShape::Square, 8.0);
+    /// synth.set_lfo_depth(LfoDestination::Filter, 1.5);
+    /// ```
+    pub fn set_lfo(&mut self, shape: LfoShape, rate_hz: f32) {
+        self.lfo.shape = shape;
+        self.lfo_free_rate = rate_hz.clamp(0.01, 50.0);
+        self.update_lfo_rate();
+        self.apply_lfo();
+    }
+
+    /// Lock the LFO to a note division of the tempo (`set_tempo` or MIDI
+    /// clock), or free-run at the `set_lfo` rate with `None`. Switching
+    /// keeps the LFO's phase, so the modulation continues without a jump,
+    /// and tempo changes only change the speed from there on.
+    pub fn set_lfo_sync(&mut self, division: Option<NoteDivision>) {
+        self.lfo_sync = division;
+        self.update_lfo_rate();
+    }
+
+    /// Tempo in BPM (clamped to 20..300) for synced LFOs, the drum
+    /// machine and the trance gate.
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.tempo = bpm.clamp(20.0, 300.0);
+        self.drums.set_tempo(self.tempo);
+        self.trance_gate.set_tempo(self.tempo);
+        self.update_lfo_rate();
+    }
+
+    /// Current tempo in BPM.
+    pub fn tempo(&self) -> f32 {
+        self.tempo
+    }
+
+    /// Handle a MIDI Start (0xFA): the next clock tick begins a beat.
+    pub fn midi_clock_start(&mut self) {
+        self.midi_ticks = 0;
+        self.midi_beat_clock = None;
+    }
+
+    /// Handle a MIDI timing clock tick (0xF8, 24 per quarter note). Each
+    /// beat sets the tempo from the beat's length and restarts a synced LFO
+    /// cycle, so it stays locked to the clock source. Ticks are timed by
+    /// the buffer they arrive in, so single beats jitter by up to a buffer;
+    /// the tempo is smoothed over several beats.
+    pub fn midi_clock_tick(&mut self) {
+        let beat = self.midi_ticks.is_multiple_of(MIDI_CLOCK_PPQ);
+        self.midi_ticks = self.midi_ticks.wrapping_add(1);
+        if !beat {
+            return;
+        }
+        if let Some(last) = self.midi_beat_clock {
+            let frames = (self.clock - last) as f32;
+            if frames > 0.0 {
+                let bpm = (60.0 * DEFAULT_SR as f32 / frames).clamp(20.0, 300.0);
+                self.set_tempo(self.tempo + (bpm - self.tempo) * 0.5);
+            }
+        }
+        self.midi_beat_clock = Some(self.clock);
+        // Every division fits a whole number of cycles into a beat
+        if self.lfo_sync.is_some() {
+            self.lfo.phase = 0.0;
+        }
+    }
+
+    fn update_lfo_rate(&mut self) {
+        self.lfo.rate = match self.lfo_sync {
+            Some(division) => self.tempo / 60.0 * division.per_beat(),
+            None => self.lfo_free_rate,
+        };
+    }
+
+    /// Route the LFO to a destination with a depth; 0.0 removes the route.
+    /// The LFO swings the destination both ways around its setting by the
+    /// depth (pitch in semitones, filter cutoff in octaves, pan 0.0-1.0,
+    /// pulse width as a fraction of the cycle);
+    /// amplitude dips from full level by up to the depth (0.0-1.0). Pan
+    /// moves the auto-pan positions, so it needs `set_auto_pan` on.
+    pub fn set_lfo_depth(&mut self, destination: LfoDestination, depth: f32) {
+        let depth = match destination {
+            LfoDestination::Pitch => depth.clamp(-12.0, 12.0),
+            LfoDestination::Filter => depth.clamp(-4.0, 4.0),
+            LfoDestination::Amplitude | LfoDestination::Pan => depth.clamp(0.0, 1.0),
+            LfoDestination::PulseWidth => depth.clamp(0.0, LFO_PULSE_WIDTH_MAX),
+        };
+        self.lfo_depths[destination as usize] = depth;
+        self.apply_lfo();
+        if destination == LfoDestination::Pan && depth == 0.0 {
+            self.update_lfo_pans(0.0);
+        }
+    }
+
+    /// Restart the LFO cycle on every note-on, so sweeps sound the same on
+    /// each note instead of picking up wherever the free-running LFO is.
+    pub fn set_lfo_retrigger(&mut self, on: bool) {
+        self.lfo_retrigger = on;
+    }
+
+    /// Whether legato notes in mono mode restart the filter sweep. The
+    /// synth's filter envelope is the LFO routed to the filter with
+    /// `set_lfo_retrigger` on, e.g. a ramp restarting on every note; off,
+    /// notes played legato keep it running from where it is, so a line
+    /// glides under a single sweep started by its first note. Since there
+    /// is one LFO this holds for its other destinations too. Notes played
+    /// after a release always retrigger. On by default.
+    pub fn set_legato_filter_retrigger(&mut self, on: bool) {
+        self.legato_filter_retrigger = on;
+    }
+
+    fn retrigger_lfo(&mut self) {
+        if self.lfo_retrigger {
+            self.lfo.restart();
+        }
+    }
+
+    fn lfo_active(&self) -> bool {
+        self.lfo_depths.iter().any(|&depth| depth != 0.0)
+    }
+
+    /// Write the LFO's current level to the routed destinations.
+    fn apply_lfo(&mut self) {
+        let value = self.lfo.value();
+        let [pitch, filter, amplitude, pan, pulse_width] = self.lfo_depths;
+        let ratio = libm::exp2f(pitch * value / 12.0);
+        if ratio != self.lfo_pitch {
+            self.lfo_pitch = ratio;
+            for voice in 0..VOICE_COUNT {
+                if self.voice_note[voice] != VOICE_UNASSIGNED {
+                    self.update_voice_freq(voice);
+                }
+            }
+        }
+        self.controls
+            .lfo_cutoff
+            .set_value(libm::exp2f(filter * value) * self.sensor_cutoff);
+        self.controls
+            .lfo_gain
+            .set_value(1.0 - amplitude * (1.0 - value) * 0.5);
+        if pan != 0.0 {
+            self.update_lfo_pans(pan * value);
+        }
+        self.controls.lfo_pulse_width.set_value(pulse_width * value);
+    }
+
+    /// Lock the sound for the stage: the filter, resonator, formant,
+    /// drive, layer blend, stereo width, delay feedback, glide and envelope
+    /// settings hold their current values, and sensor readings and patch
+    /// loads are ignored, until unlocked. Notes, pitch bend, the LFO and
+    /// mute keep working. Writes through the `*_control` Shareds, as the
+    /// ToF and pot tasks make, are undone at the start of each buffer, so
+    /// those tasks need not know about the lock; once unlocked their next
+    /// reading applies again. Off by default.
+    pub fn set_performance_lock(&mut self, locked: bool) {
+        self.performance_lock =
+            locked.then(|| self.controls.lockable().map(|control| control.value()));
+    }
+
+    pub fn performance_locked(&self) -> bool {
+        self.performance_lock.is_some()
+    }
+
+    /// Put the locked parameters back to their values at the lock.
+    fn hold_performance_lock(&self) {
+        if let Some(values) = &self.performance_lock {
+            for (control, &value) in self.controls.lockable().into_iter().zip(values) {
+                control.set_value(value);
+            }
+        }
+    }
+
+    /// Arm MIDI learn for `target`: the next Control Change passed to
+    /// `control_change` binds its controller to `target`, replacing any
+    /// earlier binding, and is applied. Arming another target first, or
+    /// `cancel_midi_learn`, leaves the bindings as they were.
+    pub fn midi_learn(&mut self, target: ControlTarget) {
+        self.midi_learn = Some(target);
+    }
+
+    pub fn cancel_midi_learn(&mut self) {
+        self.midi_learn = None;
+    }
+
+    /// Target MIDI learn is armed for, if any.
+    pub fn midi_learning(&self) -> Option<ControlTarget> {
+        self.midi_learn
+    }
+
+    /// Controller number bound to `target`, if any.
+    pub fn cc_mapping(&self, target: ControlTarget) -> Option<u8> {
+        self.cc_map.cc(target)
+    }
+
+    /// Bind `target` to controller `cc` (0-119) directly, or unbind it with
+    /// `None`.
+    pub fn set_cc_mapping(&mut self, target: ControlTarget, cc: Option<u8>) {
+        self.cc_map.bind(target, cc);
+    }
+
+    /// Handle a SysEx message for this synth (see `sysex::SysExParser`):
+    /// a received patch is applied as by `apply_state`, and a dump request
+    /// returns the current state as the dump to send back.
+    pub fn handle_sysex(
+        &mut self,
+        message: SysExMessage,
+    ) -> Option<heapless::Vec<u8, PATCH_DUMP_BYTES_MAX>> {
+        match message {
+            SysExMessage::DumpRequest => Some(patch_dump(&self.state())),
+            SysExMessage::PatchLoad(state) => {
+                self.apply_state(&state);
+                None
+            }
+        }
+    }
+
+    /// Handle a MIDI Control Change (see `midi::CcParser`): completes an
+    /// armed MIDI learn, then sets every target bound to `cc` from `value`
+    /// (0-127), scaled per `ControlTarget::scale`. Returns true if a
+    /// target was set. Ignored while the performance lock is on, learning
+    /// included.
+    pub fn control_change(&mut self, cc: u8, value: u8) -> bool {
+        if self.performance_locked() {
+            return false;
+        }
+        if let Some(target) = self.midi_learn.take() {
+            self.cc_map.bind(target, Some(cc));
+        }
+        let mut applied = false;
+        let map = self.cc_map;
+        for target in map.targets(cc) {
+            let value = target.scale(value);
+            match target {
+                ControlTarget::FilterCutoff => self.controls.filter_cutoff.set_value(value),
+                ControlTarget::FilterResonance => self.controls.filter_resonance.set_value(value),
+                ControlTarget::ResonatorFreq => self.controls.resonator_freq.set_value(value),
+                ControlTarget::FormantPosition => self.controls.formant_position.set_value(value),
+                ControlTarget::Drive => self.set_drive(value),
+                ControlTarget::DelayFeedback => self.set_delay_feedback(value),
+                ControlTarget::GlideTime => self.set_glide_time(value),
+                ControlTarget::StereoWidth => self.set_stereo_width(value),
+            }
+            applied = true;
+        }
+        applied
+    }
+
+    /// Turn the sensor into a stepped random "robot" control: its range is
+    /// split into `steps` zones (2 to `SENSOR_SH_STEPS_MAX`) and every time
+    /// the hand crosses into another zone a new random level is held for
+    /// the `set_sensor_sh_target` destination, by default a random
+    /// semitone, so moving a hand over the sensor arpeggiates random
+    /// pitches. Crossing back needs the hand a quarter zone past the edge.
+    /// Off (the default) clears the held level.
+    ///
+    /// Positions come in through `set_sensor_position`.
+    pub fn set_sensor_sh(&mut self, on: bool, steps: u8) {
+        self.sensor_sh.enabled = on;
+        self.sensor_sh.steps = steps.clamp(2, SENSOR_SH_STEPS_MAX);
+        self.sensor_sh.zone = None;
+        self.apply_sensor_sh();
+    }
+
+    /// Choose what the sensor sample-and-hold steps, pitch by default.
+    pub fn set_sensor_sh_target(&mut self, target: SensorShTarget) {
+        self.sensor_sh.target = target;
+        self.apply_sensor_sh();
+    }
+
+    /// Feed a sensor reading, 0.0 (near) to 1.0 (far), to the
+    /// sample-and-hold. Ignored while it is off or the performance lock is
+    /// on.
+    pub fn set_sensor_position(&mut self, position: f32) {
+        if self.performance_locked() {
+            return;
+        }
+        if self.sensor_sh.enabled && self.sensor_sh.update(position) {
+            self.apply_sensor_sh();
+        }
+    }
+
+    /// Write the held sensor level to its target and neutralize the other.
+    fn apply_sensor_sh(&mut self) {
+        let sh = &self.sensor_sh;
+        let active = sh.enabled && sh.zone.is_some();
+        self.sensor_pitch = if active && sh.target == SensorShTarget::Pitch {
+            let semitone = core::cmp::min((sh.held * 13.0) as u8, 12);
+            libm::exp2f(semitone as f32 / 12.0)
+        } else {
+            1.0
+        };
+        self.sensor_cutoff = if active && sh.target == SensorShTarget::Filter {
+            libm::exp2f((sh.held - 0.5) * SENSOR_SH_FILTER_OCTAVES)
+        } else {
+            1.0
+        };
+        for voice in 0..VOICE_COUNT {
+            if self.voice_note[voice] != VOICE_UNASSIGNED {
+                self.update_voice_freq(voice);
+            }
+        }
+        self.apply_lfo();
+    }
+
+    /// Move the sounding voices' auto-pan positions by `offset`.
+    fn update_lfo_pans(&mut self, offset: f32) {
+        for voice in 0..VOICE_COUNT {
+            let note = self.voice_note[voice];
+            if note != VOICE_UNASSIGNED {
+                let base = auto_pan(self.auto_pan, voice, note);
+                self.controls.pans[voice].set_value((base + offset).clamp(-1.0, 1.0));
+            }
+        }
+    }
+
+    /// Emulate analog oscillator instability: each voice's pitch wanders
+    /// in a slow random walk of at most `amount * 3` cents (`amount`
+    /// 0.0-1.0, 0.0 = off). The walk is seeded per voice index, so a
+    /// performance drifts the same way every time, and steps about four
+    /// times a second.
+    pub fn set_analog_drift(&mut self, amount: f32) {
+        self.drift_amount = amount.clamp(0.0, 1.0);
+        self.apply_drift();
+    }
+
+    /// Advance the drift walks when due.
+    #[inline]
+    fn update_drift(&mut self) {
+        if self.drift_amount <= 0.0 || self.clock < self.next_drift {
+            return;
+        }
+        self.next_drift = self.clock + DRIFT_INTERVAL;
+        for drift in self.drifts.iter_mut() {
+            drift.step();
+        }
+        self.apply_drift();
+    }
+
+    /// Freeze the sound into a pad: on captures the next `FREEZE_WINDOW_MS`
+    /// of the output (hold the chord meanwhile), then keeps replaying
+    /// randomly placed, detuned and panned grains of `grain_ms` (10-150)
+    /// from it on top of live playing, so it rings on when the keys are
+    /// released; see `granular.rs`. Short grains buzz, long ones blur.
+    /// Calling it on again only changes the grain length. Off fades the
+    /// grains out.
+    ///
+    /// Turning it on allocates `FREEZE_BYTES` of heap and fails if there
+    /// isn't that much free.
+    ///
+    /// ```ignore
+    /// // Freeze a chord into a shimmering pad, then play over it
+    /// synth.set_granular_freeze(true, 80.0)?;
+    /// // ... hold the chord for a moment, then let go ...
+    /// ```
+    pub fn set_granular_freeze(&mut self, on: bool, grain_ms: f32) -> Result<(), SynthError> {
+        if !on {
+            self.granular.stop();
+            return Ok(());
+        }
+        self.granular
+            .start(grain_ms)
+            .map_err(|needed| SynthError::OutOfMemory {
+                needed,
+                free: self.heap_probe.map_or(0, |heap| heap().free),
+            })
+    }
+
+    pub fn granular_freeze_state(&self) -> FreezeState {
+        self.granular.state()
+    }
+
+    /// How recorded audio is read back between its samples: the granular
+    /// freeze's detuned and octave-up grains and the looper's half-rate
+    /// buffer. `Cubic` keeps the pitch-shifted grains clean at about twice
+    /// the cost of `Linear` (the default), `Nearest` is cheapest and gritty.
+    /// The oscillators are fundsp's and don't depend on it.
+    pub fn set_interp_quality(&mut self, quality: InterpQuality) {
+        self.granular.set_interp_quality(quality);
+        self.looper.set_interp_quality(quality);
+    }
+
+    /// The drum machine, to edit its patterns and level; start and stop it
+    /// with `set_drums_playing`. It plays 16th-note steps at the tempo
+    /// (`set_tempo`) and mixes into the output without taking any voices.
+    pub fn drums_mut(&mut self) -> &mut DrumMachine {
+        &mut self.drums
+    }
+
+    /// Start the drum pattern from its first step, or stop it. A running
+    /// arpeggio keeps the drums in time: they start on its next step, and
+    /// an arpeggio started while the drums play waits for their next step.
+    /// Synced to the tempo (`set_arp_sync`) the two stay locked whatever
+    /// the tempo; at a free arp rate they only start together.
+    ///
+    /// ```ignore
+    /// // A four-on-the-floor beat to play over
+    /// synth.drums_mut().four_on_the_floor();
+    /// synth.set_drums_playing(true);
+    /// ```
+    pub fn set_drums_playing(&mut self, on: bool) {
+        let delay = if self.arp.is_some() && self.held_count > 0 {
+            self.arp_next_step.saturating_sub(self.clock)
+        } else {
+            0
+        };
+        self.drums.set_playing(on, delay as usize);
+    }
+
+    /// Chop the voices into a rhythm: with `enabled`, the output follows
+    /// `pattern`, one level (0.0 closed to 1.0 open) per step of `division`
+    /// at the tempo, looping over up to `TRANCE_GATE_STEPS` steps. Step
+    /// edges are smoothed over `TRANCE_GATE_SMOOTHING` so they don't click.
+    /// The gate runs on the shared clock: it starts from its first step on
+    /// the drums' next step if they play, or else on a running arpeggio's.
+    /// Only the voices are gated; the drums and the test tone aren't. Edit
+    /// the steps live with `trance_gate_mut`.
+    ///
+    /// ```ignore
+    /// // Held pads chopped in 16ths: on, off, on, on
+    /// synth.set_trance_gate(true, &[1.0, 0.0, 1.0, 1.0], NoteDivision::Sixteenth);
+    /// ```
+    pub fn set_trance_gate(&mut self, enabled: bool, pattern: &[f32], division: NoteDivision) {
+        let delay = if self.drums.is_playing() {
+            self.drums.frames_to_step()
+        } else if self.arp.is_some() && self.held_count > 0 {
+            self.arp_next_step.saturating_sub(self.clock)
+        } else {
+            0
+        };
+        self.trance_gate.set_pattern(pattern);
+        self.trance_gate.set_division(division, self.tempo);
+        self.trance_gate.set_enabled(enabled, delay as usize);
+    }
+
+    /// The trance gate, to change its steps while it plays.
+    pub fn trance_gate_mut(&mut self) -> &mut TranceGate {
+        &mut self.trance_gate
+    }
+
+    /// Animate the per-voice detune (see `set_voice_spread`) for a living,
+    /// supersaw-style stack: each voice's pitch swings around its spread
+    /// offset on its own slow sine LFO, at `rate` Hz (up to 2.0) times a
+    /// fixed per-voice factor between 0.67 and 1.41 so the voices keep
+    /// shifting against each other. `depth` is the swing in cents either
+    /// way, at most `UNISON_DRIFT_MAX_CENTS`, so the voices stay in tune;
+    /// 0.0 (the default) turns it off. Retunes at block rate.
+    pub fn set_unison_drift(&mut self, rate: f32, depth: f32) {
+        self.unison_drift_rate = rate.clamp(0.0, UNISON_DRIFT_RATE_MAX);
+        self.unison_drift_depth = depth.clamp(0.0, UNISON_DRIFT_MAX_CENTS);
+        self.update_unison_drift(0);
+    }
+
+    /// Advance the unison drift LFOs by `frames` and retune the sounding
+    /// voices.
+    fn update_unison_drift(&mut self, frames: usize) {
+        if self.unison_drift_depth <= 0.0 && self.unison_drift_ratios == [1.0; VOICE_COUNT] {
+            return;
+        }
+        let cycles = self.unison_drift_rate * frames as f32 / DEFAULT_SR as f32;
+        for (voice, rate) in UNISON_DRIFT_RATES.into_iter().enumerate() {
+            let phase = &mut self.unison_drift_phases[voice];
+            *phase = libm::fmodf(*phase + cycles * rate, 1.0);
+            let cents = self.unison_drift_depth * libm::sinf(core::f32::consts::TAU * *phase);
+            self.unison_drift_ratios[voice] = libm::exp2f(cents / 1200.0);
+            if self.voice_note[voice] != VOICE_UNASSIGNED {
+                self.update_voice_freq(voice);
+            }
+        }
+    }
+
+    /// Recompute the drift ratios and retune the sounding voices.
+    fn apply_drift(&mut self) {
+        for voice in 0..VOICE_COUNT {
+            let cents = self.drifts[voice].position * self.drift_amount * DRIFT_MAX_CENTS;
+            self.drift_ratios[voice] = libm::exp2f(cents / 1200.0);
+            if self.voice_note[voice] != VOICE_UNASSIGNED {
+                self.update_voice_freq(voice);
+            }
+        }
+    }
+
+    /// Skip the audio graph of voices that are silent: gate off and release
+    /// finished. On by default; with few keys held it saves most of the
+    /// per-voice cost (oscillator, envelope, voice filters), which is the
+    /// bulk of the graph. Releasing tails keep running until their release
+    /// time has passed, and nothing is skipped while envelopes are frozen.
+    /// Custom graphs from `with_net` always run every voice.
+    ///
+    /// Measured on the host, a 640-frame block with one note held takes
+    /// ~25 µs with skipping against ~55 µs without (seven notes: ~57 µs
+    /// either way); on the Pico, compare the CPU load report.
+    pub fn set_skip_idle_voices(&mut self, on: bool) {
+        self.skip_idle_voices = on;
+        self.update_voice_activity(0);
+    }
+
+    /// Free released voices once their envelope has decayed below `db`
+    /// (e.g. -60.0): the voice is unassigned, so a new note takes it before
+    /// any voice has to be stolen, and its graph stops running. The tail is
+    /// measured from full level, whatever the sustain, so it is never cut
+    /// early. 0 dB or above turns it off (the default), keeping released
+    /// voices assigned until they are reused.
+    pub fn set_release_cutoff_db(&mut self, db: f32) {
+        self.release_cutoff = if db < 0.0 {
+            libm::powf(10.0, db / 20.0)
+        } else {
+            0.0
+        };
+    }
+
+    /// Refresh the voice-active mask before rendering the next `frames`,
+    /// through which the gates stay as they are, and free the voices whose
+    /// release has fallen below the cutoff.
+    fn update_voice_activity(&mut self, frames: usize) {
+        let env = &self.controls.env;
+        // Frozen envelopes hold their level whatever the gate says
+        let frozen = env.freeze.value() > 0.0;
+        let cutoff = (self.release_cutoff > 0.0 && !frozen)
+            .then(|| EnvCurve::from_code(env.curve.value()).fall_time(self.release_cutoff));
+        for voice in 0..VOICE_COUNT {
+            let gated = self.controls.gates[voice].value() > 0.0;
+            if gated || frozen {
+                self.voice_gated[voice] = self.clock + frames as u64;
+            }
+            let release_frames = self.release_frames(voice);
+            let released_for = self.clock.saturating_sub(self.voice_gated[voice]);
+            // Held notes keep their voices through the gaps of a note-repeat
+            // roll, as stolen voices do through their fades
+            let rolling =
+                self.note_repeat.is_some() && self.arp.is_none() && self.note_repeats(voice);
+            // A tail cut below the cutoff is inaudible, so it ends without
+            // the idle margin
+            let faded = !gated
+                && !rolling
+                && self.steal_fades[voice].is_none()
+                && cutoff.is_some_and(|fraction| {
+                    released_for as f32 >= release_frames * fraction + ENVELOPE_LAG
+                });
+            if faded {
+                self.voice_note[voice] = VOICE_UNASSIGNED;
+            }
+            let tail = release_frames as u64 + IDLE_VOICE_MARGIN;
+            let active = !faded && (!self.skip_idle_voices || gated || released_for <= tail);
+            self.controls.voice_active[voice].set_value(if active { 1.0 } else { 0.0 });
+        }
+    }
+
+    /// Length of a voice's release in frames, its release velocity included.
+    fn release_frames(&self, voice: usize) -> f32 {
+        let env = &self.controls.env;
+        let release = env.release.value() * self.controls.release_scales[voice].value();
+        release.max(env.min_ramp.value()) * DEFAULT_SR as f32
+    }
+
+    /// Whether a voice is gated or its release tail may still be heard: a
+    /// voice never played or freed by the release cutoff is silent.
+    fn voice_ringing(&self, voice: usize) -> bool {
+        let tail = self.release_frames(voice) as u64 + IDLE_VOICE_MARGIN;
+        self.controls.gates[voice].value() > 0.0
+            || (self.voice_note[voice] != VOICE_UNASSIGNED
+                && self.clock.saturating_sub(self.voice_gated[voice]) <= tail)
+    }
+
+    /// Render `buffer_size` frames, handing each stereo frame to `write`.
+    #[inline]
+    fn render(&mut self, buffer_size: usize, mut write: impl FnMut(usize, f32, f32)) {
+        self.hold_performance_lock();
+        self.release_expired_voices();
+        self.update_drift();
+        self.update_unison_drift(buffer_size);
+        // Release tails count too, so a released chord isn't boosted
+        let sounding = (0..VOICE_COUNT)
+            .filter(|&voice| self.voice_ringing(voice))
+            .count();
+        let target = voice_gain(self.gain_mode, sounding);
+        let mut gain = self.output_gain;
+        let master_target = self.controls.master_gain.value();
+        let mut master = self.master_gain;
+        let master_step = self.startup_fade_step.unwrap_or(MUTE_RAMP_STEP);
+        let tone = self.test_tone;
+        let mut phase = self.test_tone_phase;
+        // Moved out for the frame loop, which can't borrow self
+        let mut looper = core::mem::take(&mut self.looper);
+        let mut granular = core::mem::take(&mut self.granular);
+        let mut drums = core::mem::take(&mut self.drums);
+        let mut trance_gate = core::mem::take(&mut self.trance_gate);
+        let (mut lr, mut ll, mut rr) = (0.0, 0.0, 0.0);
+        let mut write = |i, left: f32, right: f32| {
+            gain += (target - gain) * GAIN_SMOOTHING;
+            master += (master_target - master).clamp(-master_step, master_step);
+            let tone = match tone {
+                Some(inc) => {
+                    phase = libm::fmodf(phase + inc, 1.0);
+                    TEST_TONE_LEVEL * libm::sinf(core::f32::consts::TAU * phase)
+                }
+                None => 0.0,
+            };
+            let voices = gain * trance_gate.process();
+            let (left, right) = (left * voices + tone, right * voices + tone);
+            let (grain_left, grain_right) = granular.process((left + right) * 0.5);
+            let (left, right) = (left + grain_left, right + grain_right);
+            let looped = looper.process((left + right) * 0.5);
+            // The drums keep out of the loop, which would double them
+            let looped = looped + drums.process();
+            let (left, right) = ((left + looped) * master, (right + looped) * master);
+            lr += left * right;
+            ll += left * left;
+            rr += right * right;
+            write(i, left, right);
+        };
+        // Render in pieces split at arp events, so steps land on time
+        let mut done = 0;
+        while done < buffer_size {
+            self.update_arp();
+            self.update_note_repeat();
+            self.start_due_steals();
+            let mut frames =
+                self.steal_frames(self.note_repeat_frames(self.arp_frames(buffer_size - done)));
+            if self.lfo_active() {
+                self.apply_lfo();
+                frames = core::cmp::min(frames, LFO_INTERVAL);
+            }
+            self.update_voice_activity(frames);
+            let write = |i, left, right| write(done + i, left, right);
+            match self.backend {
+                RenderBackend::Float => self.render_float(frames, write),
+                RenderBackend::Fixed => self.render_fixed(frames, write),
+            }
+            self.clock += frames as u64;
+            self.lfo.advance(frames);
+            done += frames;
+        }
+        self.output_gain = gain;
+        self.master_gain = master;
+        if master == master_target {
+            self.startup_fade_step = None;
+        }
+        self.test_tone_phase = phase;
+        self.looper = looper;
+        self.granular = granular;
+        self.drums = drums;
+        self.trance_gate = trance_gate;
+        self.update_correlation(lr, ll, rr);
+    }
+
+    #[inline]
+    fn render_fixed(&mut self, buffer_size: usize, mut write: impl FnMut(usize, f32, f32)) {
+        let controls = &self.controls;
+        let mut chunk = [0.0f32; 64];
+        let mut processed = 0;
+        while processed < buffer_size {
+            let chunk_size = core::cmp::min(buffer_size - processed, 64);
+            let params = FixedParams {
+                waveforms: &self.topology.waveforms,
+                layer: self
+                    .topology
+                    .layer
+                    .map(|waveform| (waveform, controls.osc_blend.value())),
+                freqs: arr![|v| controls.freqs[v].value()],
+                gates: arr![|v| controls.gates[v].value() > 0.0],
+                levels: arr![|v| controls.levels[v].value() * controls.velocities[v].value()],
+                phase_resets: arr![|v| controls.phase_resets[v].value()],
+                attack: controls
+                    .env
+                    .attack
+                    .value()
+                    .max(controls.env.min_ramp.value()),
+                decay: controls.env.decay.value(),
+                sustain: controls.env.sustain.value(),
+                release: controls
+                    .env
+                    .release
+                    .value()
+                    .max(controls.env.min_ramp.value()),
+                cutoff: controls.filter_cutoff.value(),
+                resonator: controls.resonator_freq.value(),
+                pulse_width: (controls.pulse_width.value() + controls.lfo_pulse_width.value())
+                    .clamp(PULSE_WIDTH_MIN, PULSE_WIDTH_MAX),
+            };
+            self.fixed.render(&params, &mut chunk[..chunk_size]);
+            for (i, &sample) in chunk[..chunk_size].iter().enumerate() {
+                write(processed + i, sample, sample);
+            }
+            processed += chunk_size;
+        }
+    }
+
+    /// Run the net for `buffer_size` frames, handing each stereo frame to
+    /// `write`, crossfaded from the old net while a rebuild fades.
+    #[inline]
+    fn render_float(&mut self, buffer_size: usize, mut write: impl FnMut(usize, f32, f32)) {
+        let mut frames = [(0.0, 0.0); MAX_BUFFER_SIZE];
+        let mut old = [(0.0, 0.0); MAX_BUFFER_SIZE];
+
+        // Process in chunks of MAX_BUFFER_SIZE (64 samples) for optimal SIMD usage
+        let mut processed = 0;
+        while processed < buffer_size {
+            let chunk_size = core::cmp::min(buffer_size - processed, MAX_BUFFER_SIZE);
+            process_net(&mut self.net, chunk_size, &mut frames);
+            if let Some(fade) = &mut self.net_fade {
+                process_net(&mut fade.net, chunk_size, &mut old);
+                for (frame, old) in frames.iter_mut().zip(old).take(chunk_size) {
+                    let mix = fade.remaining as f32 / fade.frames as f32;
+                    frame.0 += (old.0 - frame.0) * mix;
+                    frame.1 += (old.1 - frame.1) * mix;
+                    fade.remaining = fade.remaining.saturating_sub(1);
+                }
+                if fade.remaining == 0 {
+                    self.net_fade = None;
+                }
+            }
+
+            // Copy to output buffer
+            for (i, &(left, right)) in frames[..chunk_size].iter().enumerate() {
+                write(processed + i, left, right);
+            }
+
+            processed += chunk_size;
+        }
+    }
+
+    /// Set the stereo width of the effects bus: 0.0 collapses to mono, 1.0
+    /// leaves the signal untouched, up to 2.0 widens. Only the chorus makes
+    /// the channels differ, so this has no audible effect with it disabled.
+    pub fn set_stereo_width(&mut self, width: f32) {
+        self.stereo_width = width.clamp(0.0, 2.0);
+        self.apply_stereo_width();
+    }
+
+    fn apply_stereo_width(&mut self) {
+        self.controls
+            .stereo_width
+            .set_value(self.stereo_width * self.mono_safe_scale);
+    }
+
+    /// Correlation of the left and right output over the last buffer with
+    /// sound in it: 1.0 when both carry the same signal (mono), around 0.0
+    /// for unrelated channels and negative when they are out of phase, so
+    /// that summing to mono cancels and thins the sound. Silent buffers
+    /// leave it as it was; it starts at 1.0.
+    pub fn stereo_correlation(&self) -> f32 {
+        self.correlation
+    }
+
+    /// Keep the output mono-compatible: while the stereo correlation is
+    /// below 0.2 the width is narrowed quickly, and once it is back above
+    /// 0.5 it returns gradually to the `set_stereo_width` setting. Off by
+    /// default, when the width is left alone.
+    pub fn set_mono_safe(&mut self, on: bool) {
+        self.mono_safe = on;
+        if !on {
+            self.mono_safe_scale = 1.0;
+            self.apply_stereo_width();
+        }
+    }
+
+    /// Update the correlation reading from a buffer's sums of left x right
+    /// and the squares, and let the mono-safe limit react to it.
+    fn update_correlation(&mut self, lr: f32, ll: f32, rr: f32) {
+        if ll <= f32::EPSILON || rr <= f32::EPSILON {
+            return;
+        }
+        self.correlation = (lr / libm::sqrtf(ll * rr)).clamp(-1.0, 1.0);
+        if !self.mono_safe {
+            return;
+        }
+        if self.correlation < MONO_SAFE_MIN {
+            self.mono_safe_scale *= MONO_SAFE_NARROW;
+        } else if self.correlation > MONO_SAFE_RECOVER {
+            self.mono_safe_scale = (self.mono_safe_scale + MONO_SAFE_WIDEN).min(1.0);
+        }
+        self.apply_stereo_width();
+    }
+
+    /// Enable or disable the feedback delay. Rebuilds the graph.
+    pub fn set_delay_enabled(&mut self, on: bool) {
+        let delay = on.then_some(self.topology.delay.unwrap_or(false));
+        if delay != self.topology.delay {
+            self.topology.delay = delay;
+            self.rebuild_net();
+        }
+    }
+
+    /// Bounce the delay repeats between left and right instead of
+    /// repeating each channel in place. Turns the delay on; rebuilds the
+    /// graph when the mode changes.
+    pub fn set_delay_pingpong(&mut self, on: bool) {
+        if self.topology.delay != Some(on) {
+            self.topology.delay = Some(on);
+            self.rebuild_net();
+        }
+    }
+
+    /// Delay feedback, 0.0 to `DELAY_FEEDBACK_MAX`. Also sets the level of
+    /// the first repeat.
+    pub fn set_delay_feedback(&mut self, feedback: f32) {
+        self.controls
+            .delay_feedback
+            .set_value(feedback.clamp(0.0, DELAY_FEEDBACK_MAX));
+    }
+
+    /// Shape the stereo chorus: `voices` delayed copies per channel
+    /// (1 to `CHORUS_VOICES_MAX`), spaced `separation` seconds apart (up to
+    /// `CHORUS_SEPARATION_MAX`) and each wandering by up to `variation`
+    /// seconds (up to `CHORUS_VARIATION_MAX`) at roughly `mod_freq` Hz.
+    /// Two voices with 5 ms separation and 2 ms variation give a subtle
+    /// doubling; four or more with 10 ms and 50 ms (the defaults) a lush
+    /// ensemble.
+    ///
+    /// Each voice costs one cubic-interpolated delay read per sample and a
+    /// fractal noise LFO evaluated every 10 ms, per channel. Each channel
+    /// has one delay line of `voices x separation + variation` seconds, 4
+    /// bytes per sample: 16 KB at the defaults, 30 KB at the maximum.
+    ///
+    /// Takes effect while the chorus is enabled (`set_chorus_enabled`). A
+    /// playing chorus crossfades to the new settings over 100 ms, so notes
+    /// keep sounding; the new delay line fills during the fade.
+    pub fn set_chorus(&mut self, voices: u8, separation: f32, variation: f32, mod_freq: f32) {
+        let settings = ChorusSettings {
+            voices: voices.clamp(1, CHORUS_VOICES_MAX),
+            separation: separation.clamp(0.0, CHORUS_SEPARATION_MAX),
+            variation: variation.clamp(0.0, CHORUS_VARIATION_MAX),
+            mod_freq: mod_freq.max(0.0),
+        };
+        if settings == self.topology.chorus_settings {
+            return;
+        }
+        self.topology.chorus_settings = settings;
+        if let Some(ids) = self.chorus_ids {
+            for (seed, id) in (CHORUS_SEED..).zip(ids) {
+                self.net.crossfade(
+                    id,
+                    Fade::Smooth,
+                    CHORUS_FADE_TIME,
+                    chorus_net(seed, settings),
+                );
+            }
+        }
+    }
+
+    /// Enable or disable the stereo chorus. Rebuilds the graph.
+    pub fn set_chorus_enabled(&mut self, on: bool) {
+        if on == self.topology.chorus {
+            return;
+        }
+        self.topology.chorus = on;
+        self.rebuild_net();
+    }
+
+    /// Enable or disable the formant filter, three parallel bandpasses after
+    /// the main filter that shape the mix into a vowel. Off by default.
+    /// Rebuilds the graph.
+    pub fn set_formant_enabled(&mut self, on: bool) {
+        if on == self.topology.formant {
+            return;
+        }
+        self.topology.formant = on;
+        self.rebuild_net();
+    }
+
+    /// Overdrive the mix before or after the filter (see `set_fx_order`):
+    /// `amount` 0.0-1.0 raises the gain into a soft clipper up to 64x, with
+    /// the level roughly kept. Applies immediately; switching between zero
+    /// and non-zero rebuilds the graph, at 0.0 the stage is removed.
+    pub fn set_drive(&mut self, amount: f32) {
+        let amount = amount.clamp(0.0, 1.0);
+        self.controls.drive.set_value(amount);
+        let drive = amount > 0.0;
+        if drive != self.topology.drive {
+            self.topology.drive = drive;
+            self.rebuild_net();
+        }
+    }
+
+    /// Layer two oscillators in every voice, `wave_a` and `wave_b` on the
+    /// same pitch (bend, glide, detune and drift apply to both), crossfaded
+    /// by `blend` from 0.0 (only A) to 1.0 (only B); e.g. saw and square at
+    /// 0.5 for a hollow lead. Sets the waveform of all voices and zones,
+    /// like a preset. The default is a single saw, blend 0.0.
+    ///
+    /// The blend applies immediately, but moving it between zero and
+    /// non-zero rebuilds the graph: at 0.0 the second oscillator is
+    /// removed. While it runs, each sounding voice costs nearly twice as
+    /// much: measured on the host, a 640-frame block with every voice held
+    /// takes ~105 µs against ~57 µs with one oscillator. If that overloads
+    /// the Pico (see the CPU load report), limit the polyphony with
+    /// `set_voice_range`.
+    pub fn set_osc_blend(&mut self, wave_a: Waveform, wave_b: Waveform, blend: f32) {
+        let blend = blend.clamp(0.0, 1.0);
+        self.controls.osc_blend.set_value(blend);
+        let waveforms = [wave_a; VOICE_COUNT];
+        let layer = (blend > 0.0).then_some(wave_b);
+        if waveforms != self.topology.waveforms || layer != self.topology.layer {
+            for zone in &mut self.zones {
+                zone.config.waveform = wave_a;
+            }
+            self.topology.waveforms = waveforms;
+            self.topology.layer = layer;
+            self.rebuild_net();
+        }
+    }
+
+    /// Width of `Waveform::Pulse` as the fraction of each cycle spent high,
+    /// clamped to `PULSE_WIDTH_MIN..=PULSE_WIDTH_MAX`: 0.5 (the default)
+    /// is a square, narrower widths thin the tone towards a nasal buzz.
+    /// The polyBLEP pulse is bandlimited like the saw, so it stays clean
+    /// across the keyboard. For the classic PWM pad shimmer, sweep the
+    /// width with the LFO as well, `set_lfo_depth(LfoDestination::PulseWidth,
+    /// depth)`; the route swings it both ways around this width.
+    ///
+    /// ```ignore
+    /// // A slowly breathing PWM pad
+    /// synth.set_osc_blend(Waveform::Pulse, Waveform::Pulse, 0.0);
+    /// synth.set_pulse_width(0.5);
+    /// synth.set_lfo(LfoShape::Triangle, 0.3);
+    /// synth.set_lfo_depth(LfoDestination::PulseWidth, 0.4);
+    /// ```
+    pub fn set_pulse_width(&mut self, pw: f32) {
+        self.controls
+            .pulse_width
+            .set_value(pw.clamp(PULSE_WIDTH_MIN, PULSE_WIDTH_MAX));
+    }
+
+    /// Let voice `source_voice`'s output modulate voice `dest_voice`'s
+    /// frequency at audio rate, for FM-like clangorous, inharmonic tones
+    /// between two held notes. `amount` (up to `CROSS_MOD_MAX`) is the
+    /// frequency deviation at a full-level source note, as a fraction of
+    /// the destination's frequency; 0.0 (the default) turns cross-mod off.
+    /// One pair at a time, so there are no feedback loops: a voice can't
+    /// modulate itself, and such a call (or one with a voice out of range)
+    /// turns cross-mod off too.
+    ///
+    /// The amount applies immediately; switching cross-mod on or off or to
+    /// another pair rebuilds the graph. It costs a tanh per sample, and the
+    /// destination voice no longer skips rendering while idle.
+    pub fn set_cross_mod(&mut self, source_voice: usize, dest_voice: usize, amount: f32) {
+        let amount = amount.clamp(0.0, CROSS_MOD_MAX);
+        let valid =
+            source_voice != dest_voice && source_voice < VOICE_COUNT && dest_voice < VOICE_COUNT;
+        let pair = (valid && amount > 0.0).then_some((source_voice, dest_voice));
+        self.controls.cross_mod_depth.set_value(amount);
+        if pair != self.topology.cross_mod {
+            self.topology.cross_mod = pair;
+            self.rebuild_net();
+        }
+    }
+
+    /// Reorder the effects after the voice mix: `order` lists every
+    /// `FxStage` once, first to last (`FX_ORDER` by default). The order
+    /// shapes the tone, e.g. overdrive into the filter sounds warm, the
+    /// filter into overdrive gritty and bright. Stages that are off (drive
+    /// at 0.0, formant disabled, the filter while key tracking) keep their
+    /// place for when they come on. Anything but a permutation of the
+    /// stages returns `SynthError::InvalidFxOrder` and changes nothing.
+    /// Rebuilds the graph.
+    ///
+    /// ```ignore
+    /// // Distort after the filter
+    /// synth.set_fx_order(&[FxStage::Filter, FxStage::Drive, FxStage::Resonator, FxStage::Formant])?;
+    /// ```
+    pub fn set_fx_order(&mut self, order: &[FxStage]) -> Result<(), SynthError> {
+        let fx_order: [FxStage; FX_ORDER.len()] =
+            order.try_into().map_err(|_| SynthError::InvalidFxOrder)?;
+        if !FX_ORDER.iter().all(|stage| fx_order.contains(stage)) {
+            return Err(SynthError::InvalidFxOrder);
+        }
+        if fx_order != self.topology.fx_order {
+            self.topology.fx_order = fx_order;
+            self.rebuild_net();
+        }
+        Ok(())
+    }
+
+    /// Shape the formant filter into a vowel. In-between vowels come from
+    /// `formant_control`, which a sensor can sweep for smooth morphs; this
+    /// sets the same position. Takes effect while the formant filter is
+    /// enabled (`set_formant_enabled`).
+    pub fn set_formant(&mut self, vowel: Vowel) {
+        self.controls.formant_position.set_value(vowel as u8 as f32);
+    }
+
+    /// Set the amplitude envelope: attack, decay and release in seconds,
+    /// sustain level 0.0-1.0. Applies immediately, also to sounding notes.
+    pub fn set_envelope(&mut self, attack: f32, decay: f32, sustain: f32, release: f32) {
+        let env = &self.controls.env;
+        env.attack.set_value(attack.max(0.0));
+        env.decay.set_value(decay.max(0.0));
+        env.sustain.set_value(sustain.clamp(0.0, 1.0));
+        env.release.set_value(release.max(0.0));
+    }
+
+    /// Freeze the sound into an infinite drone: every sounding voice holds
+    /// its current envelope level, regardless of sustain level, and key
+    /// releases are ignored. Turning freeze off resumes normal envelopes;
+    /// notes whose keys were let go meanwhile release from the held level.
+    /// Notes played while frozen sound normally over the drone.
+    /// Unlike latch (which is about key state) this captures the envelopes,
+    /// so an evolving chord is caught mid-swell.
+    pub fn freeze(&mut self, on: bool) {
+        self.controls
+            .env
+            .freeze
+            .set_value(if on { 1.0 } else { 0.0 });
+    }
+
+    /// Keep every attack and release at least `CLICK_RAMP` (3 ms) long, so
+    /// a zero attack or release ramps instead of stepping the level, which
+    /// clicks. On by default; off lets the envelope times go all the way
+    /// to 0 for deliberate clicky transients. Applies immediately, also on
+    /// the fixed backend.
+    pub fn set_click_suppression(&mut self, on: bool) {
+        let min_ramp = if on { CLICK_RAMP } else { 0.0 };
+        self.controls.env.min_ramp.set_value(min_ramp);
+    }
+
+    /// Select the envelope curve shape. `EnvCurve::Linear` is the original
+    /// sound; `Exponential` gives natural-sounding plucks and pad tails.
+    /// Applies immediately, also to sounding notes.
+    pub fn set_envelope_curve(&mut self, curve: EnvCurve) {
+        self.controls.env.set_curve(curve);
+    }
+
+    /// Apply a complete preset. Changing the waveform or filter slope
+    /// rebuilds the audio graph; everything else is applied live.
+    /// The waveform applies to all voices (and all zones of a split).
+    /// Ignored while the performance lock is on.
+    pub fn apply_preset(&mut self, preset: &Preset) {
+        if self.performance_locked() {
+            return;
+        }
+        self.controls.filter_cutoff.set_value(preset.filter_cutoff);
+        self.controls
+            .filter_resonance
+            .set_value(preset.filter_resonance);
+        self.controls
+            .resonator_freq
+            .set_value(preset.resonator_freq);
+        self.set_envelope(preset.attack, preset.decay, preset.sustain, preset.release);
+
+        let waveforms = [preset.waveform; VOICE_COUNT];
+        if waveforms != self.topology.waveforms || preset.filter_slope != self.topology.filter_slope
+        {
+            for zone in &mut self.zones {
+                zone.config.waveform = preset.waveform;
+            }
+            self.topology.waveforms = waveforms;
+            self.topology.filter_slope = preset.filter_slope;
+            self.rebuild_net();
+        }
+    }
+
+    /// The current sound settings as a preset. The waveform is that of the
+    /// first voice.
+    pub fn preset(&self) -> Preset {
+        let env = &self.controls.env;
+        Preset {
+            waveform: self.topology.waveforms[0],
+            filter_slope: self.topology.filter_slope,
+            filter_cutoff: self.controls.filter_cutoff.value(),
+            filter_resonance: self.controls.filter_resonance.value(),
+            resonator_freq: self.controls.resonator_freq.value(),
+            attack: env.attack.value(),
+            decay: env.decay.value(),
+            sustain: env.sustain.value(),
+            release: env.release.value(),
+        }
+    }
+
+    /// Every tunable sound parameter, for a companion app or editor. See
+    /// `serialize_state` for the bytes.
+    pub fn state(&self) -> SynthState {
+        let chorus = self.topology.chorus_settings;
+        SynthState {
+            preset: self.preset(),
+            env_curve: EnvCurve::from_code(self.controls.env.curve.value()),
+            filter_keytrack: self.filter_keytrack,
+            drive: self.controls.drive.value(),
+            fx_order: self.topology.fx_order,
+            formant: self.topology.formant,
+            formant_position: self.controls.formant_position.value(),
+            chorus: self.topology.chorus,
+            delay: self.topology.delay.is_some(),
+            delay_pingpong: self.topology.delay == Some(true),
+            delay_feedback: self.controls.delay_feedback.value(),
+            glide_time: self.controls.glide_time.value(),
+            glide_curve: GlideCurve::from_code(self.controls.glide_curve.value()),
+            lfo_shape: self.lfo.shape,
+            lfo_rate: self.lfo_free_rate,
+            lfo_depths: core::array::from_fn(|route| self.lfo_depths[route]),
+            pitch_env_offset: self.controls.pitch_env_offset.value(),
+            pitch_env_time: self.controls.pitch_env_time.value(),
+            bend_range: self.bend_range,
+            octave_gains: self.octave_gains,
+            velocity_to_cutoff: self.velocity_to_cutoff,
+            velocity_to_attack: self.velocity_to_attack,
+            voice_highpass_tracking: self.voice_highpass_tracking,
+            analog_drift: self.drift_amount,
+            stereo_width: self.stereo_width,
+            mono_safe: self.mono_safe,
+            auto_pan: self.auto_pan,
+            cc_map: self.cc_map,
+            chorus_voices: chorus.voices,
+            chorus_separation: chorus.separation,
+            chorus_variation: chorus.variation,
+            chorus_mod_freq: chorus.mod_freq,
+            velocity_curve: self.velocity_curve,
+            velocity_floor: self.velocity_floor,
+            release_velocity: self.release_velocity,
+            voice_spread: self.voice_spread,
+            unison_drift_rate: self.unison_drift_rate,
+            unison_drift_depth: self.unison_drift_depth,
+            oversampling: self.topology.oversampling,
+            phase_reset: self.topology.phase_reset,
+            click_suppression: self.controls.env.min_ramp.value() > 0.0,
+            gain_mode: self.gain_mode,
+            tempo: self.tempo,
+            lfo_sync: self.lfo_sync,
+            lfo_retrigger: self.lfo_retrigger,
+            legato_filter_retrigger: self.legato_filter_retrigger,
+            mono: self.mono,
+            note_priority: self.note_priority,
+            lfo_pulse_width: self.lfo_depths[LfoDestination::PulseWidth as usize],
+            pulse_width: self.controls.pulse_width.value(),
+            osc_layer: self.topology.layer,
+            osc_blend: self.controls.osc_blend.value(),
+            stack_len: self.stack_len as u8,
+            stack_intervals: self.stack_intervals,
+            stack_detune: self.stack_detune,
+            cross_mod: self.topology.cross_mod,
+            cross_mod_amount: self.controls.cross_mod_depth.value(),
+        }
+    }
+
+    /// Set every parameter in `state`, as the individual setters would.
+    /// An effects order that isn't a permutation keeps the current one.
+    /// Ignored while the performance lock is on.
+    pub fn apply_state(&mut self, state: &SynthState) {
+        if self.performance_locked() {
+            return;
+        }
+        self.apply_preset(&state.preset);
+        self.set_envelope_curve(state.env_curve);
+        self.set_filter_keytrack(state.filter_keytrack);
+        self.set_drive(state.drive);
+        let _ = self.set_fx_order(&state.fx_order);
+        self.set_formant_enabled(state.formant);
+        self.controls
+            .formant_position
+            .set_value(state.formant_position);
+        self.set_chorus_enabled(state.chorus);
+        if state.delay {
+            self.set_delay_pingpong(state.delay_pingpong);
+        } else {
+            self.set_delay_enabled(false);
+        }
+        self.set_delay_feedback(state.delay_feedback);
+        self.set_glide_time(state.glide_time);
+        self.set_glide_curve(state.glide_curve);
+        self.set_lfo(state.lfo_shape, state.lfo_rate);
+        let destinations = [
+            LfoDestination::Pitch,
+            LfoDestination::Filter,
+            LfoDestination::Amplitude,
+            LfoDestination::Pan,
+        ];
+        for (destination, depth) in destinations.into_iter().zip(state.lfo_depths) {
+            self.set_lfo_depth(destination, depth);
+        }
+        self.set_pitch_envelope(state.pitch_env_offset, state.pitch_env_time);
+        self.set_bend_range(state.bend_range);
+        for (octave, gain) in state.octave_gains.into_iter().enumerate() {
+            self.set_octave_gain(octave as u8, gain);
+        }
+        self.set_velocity_to_cutoff(state.velocity_to_cutoff);
+        self.set_velocity_to_attack(state.velocity_to_attack);
+        self.set_voice_highpass_tracking(state.voice_highpass_tracking);
+        self.set_analog_drift(state.analog_drift);
+        self.set_stereo_width(state.stereo_width);
+        self.set_mono_safe(state.mono_safe);
+        self.set_auto_pan(state.auto_pan);
+        self.cc_map = state.cc_map;
+        self.set_chorus(
+            state.chorus_voices,
+            state.chorus_separation,
+            state.chorus_variation,
+            state.chorus_mod_freq,
+        );
+        self.set_velocity_curve(state.velocity_curve);
+        self.set_velocity_floor(state.velocity_floor);
+        self.set_release_velocity(state.release_velocity);
+        self.set_voice_spread(state.voice_spread);
+        self.set_unison_drift(state.unison_drift_rate, state.unison_drift_depth);
+        self.set_oversampling(state.oversampling);
+        self.set_phase_reset(state.phase_reset);
+        self.set_click_suppression(state.click_suppression);
+        self.set_voice_gain_mode(state.gain_mode);
+        self.set_tempo(state.tempo);
+        self.set_lfo_sync(state.lfo_sync);
+        self.set_lfo_retrigger(state.lfo_retrigger);
+        self.set_legato_filter_retrigger(state.legato_filter_retrigger);
+        self.set_mono(state.mono);
+        self.set_note_priority(state.note_priority);
+        self.set_lfo_depth(LfoDestination::PulseWidth, state.lfo_pulse_width);
+        self.set_pulse_width(state.pulse_width);
+        let waveform = state.preset.waveform;
+        match state.osc_layer {
+            Some(layer) => self.set_osc_blend(waveform, layer, state.osc_blend),
+            None => self.set_osc_blend(waveform, waveform, 0.0),
+        }
+        let stack_len = core::cmp::min(state.stack_len as usize, STACK_MAX);
+        self.set_stack(&state.stack_intervals[..stack_len], state.stack_detune);
+        // A voice modulating itself turns cross-mod off
+        let (source, dest) = state.cross_mod.unwrap_or((0, 0));
+        self.set_cross_mod(source, dest, state.cross_mod_amount);
+    }
+
+    /// The full parameter set as versioned, checksummed bytes (see
+    /// `crate::state` for the format), for sending to a host.
+    pub fn serialize_state(&self) -> heapless::Vec<u8, STATE_BYTES_MAX> {
+        self.state().to_bytes()
+    }
+
+    /// Apply bytes from `serialize_state`, possibly of an older or newer
+    /// firmware: parameters they lack keep their defaults. Corrupt bytes
+    /// return `SynthError::InvalidState` and change nothing.
+    pub fn deserialize_state(&mut self, bytes: &[u8]) -> Result<(), SynthError> {
+        let state = SynthState::from_bytes(bytes).ok_or(SynthError::InvalidState)?;
+        self.apply_state(&state);
+        Ok(())
+    }
+
+    /// Check the live sound settings for auto-saving every `interval` of
+    /// rendered audio; `Duration::ZERO` (the default) turns auto-save off.
+    /// The current settings count as saved, so call this after restoring
+    /// the last state at boot.
+    pub fn set_autosave(&mut self, interval: Duration) {
+        let frames = interval.as_secs_f64() * DEFAULT_SR;
+        self.autosave_interval = frames as u64;
+        self.autosave_checked = self.clock;
+        self.autosave_saved = self.state();
+    }
+
+    /// The settings to write to the last-state slot, if an auto-save is due:
+    /// the interval has passed, something changed since the last save, and
+    /// no voice sounds, held or in its release tail (flash writes stall the
+    /// CPU, so they should land in a quiet moment). A check that finds
+    /// nothing to save restarts the interval.
+    pub fn take_autosave(&mut self) -> Option<SynthState> {
+        if self.autosave_interval == 0
+            || self.clock - self.autosave_checked < self.autosave_interval
+            || (0..VOICE_COUNT).any(|voice| self.voice_ringing(voice))
+        {
+            return None;
+        }
+        self.autosave_checked = self.clock;
+        let state = self.state();
+        let dirty = state != self.autosave_saved;
+        self.autosave_saved = state;
+        dirty.then_some(state)
+    }
+
+    /// Store the two patches that `set_morph` blends between.
+    pub fn set_morph_presets(&mut self, a: Preset, b: Preset) {
+        self.morph_presets = (a, b);
+    }
+
+    /// Morph between patch A (`t` = 0.0) and patch B (`t` = 1.0), e.g. from
+    /// the ToF sensor or a knob to sweep from a soft pad to a bright lead in
+    /// one gesture. See `Preset::lerp` for how each parameter is blended.
+    pub fn set_morph(&mut self, t: f32) {
+        let (a, b) = &self.morph_presets;
+        let preset = Preset::lerp(a, b, t);
+        self.apply_preset(&preset);
+    }
+
+    /// Slide every new note into its pitch from `start_offset_semitones`
+    /// away over `time` seconds (e.g. -12.0 over 0.3 s for a "dive bomb"
+    /// from below). Independent of glide between notes and composes with
+    /// pitch bend. A time of 0.0 disables the slide.
+    pub fn set_pitch_envelope(&mut self, start_offset_semitones: f32, time: f32) {
+        self.controls
+            .pitch_env_offset
+            .set_value(start_offset_semitones);
+        self.controls.pitch_env_time.set_value(time.max(0.0));
+    }
+
+    /// Stack every poly note with extra voices `intervals` semitones from it
+    /// (e.g. `&[12.0, 7.0, 19.0]` for the octave, fifth and octave-fifth),
+    /// alternately `detune` cents sharp and flat (up to
+    /// `STACK_DETUNE_MAX_CENTS`), for huge hoover and trance sounds from one
+    /// key. The stack plays and releases as one note. An empty stack turns
+    /// it off; at most `STACK_MAX` intervals are used. Mono and the
+    /// arpeggiator play single voices. Applies from the next note on.
+    ///
+    /// Each note takes `1 + intervals.len()` voices from its pool, which
+    /// divides the polyphony: with 7 voices, a 3-interval stack fits one
+    /// note, and a second already steals a voice from it. A pool smaller
+    /// than the stack drops its last intervals.
+    pub fn set_stack(&mut self, intervals: &[f32], detune: f32) {
+        let detune = detune.clamp(0.0, STACK_DETUNE_MAX_CENTS);
+        self.stack_len = core::cmp::min(intervals.len(), STACK_MAX);
+        self.stack_detune = detune;
+        self.stack_intervals = [0.0; STACK_MAX];
+        self.stack_intervals[..self.stack_len].copy_from_slice(&intervals[..self.stack_len]);
+        for (layer, interval) in intervals.iter().take(STACK_MAX).enumerate() {
+            let cents = if layer % 2 == 0 { detune } else { -detune };
+            self.stack_ratios[layer] = libm::exp2f((interval * 100.0 + cents) / 1200.0);
+        }
+    }
+
+    /// Set the per-voice detune spread in cents (0.0 disables it).
+    /// Each voice gets a fixed offset of up to ±cents so identical pitches on
+    /// different voices don't phase-cancel. Applies from the next note on.
+    pub fn set_voice_spread(&mut self, cents: f32) {
+        self.voice_spread = cents;
+        self.spread_ratios = spread_ratios(cents);
+    }
+
+    /// Set a DC offset (in 16-bit LSBs) added to every DAC sample.
+    ///
+    /// Use it to center the output on DACs/amps with an analog offset or to
+    /// trim out a turn-on thump. PCM5102 modules are ground-centered and
+    /// normally need 0, though a small trim can null the offset of a
+    /// DC-coupled amp behind them; PT8211 boards sit around mid-rail with a
+    /// part-to-part offset that typically needs tens to a few hundred LSB.
+    /// The result is clamped, so a large trim clips instead of wrapping.
+    pub fn set_output_trim(&mut self, offset: i16) {
+        self.output_trim = offset;
+    }
+
+    /// Add TPDF dither (±1 LSB, triangular) before `to_dac_sample` rounds
+    /// to 16 bits. Without it, quiet material a few LSB in size (reverb and
+    /// release tails, soft pads) is quantized into a grainy, distorted
+    /// staircase; with it the error becomes a steady hiss at about
+    /// -96 dBFS and the tail fades smoothly into it. Off by default, since
+    /// only the 16-bit I2S DAC benefits: the SPI DAC and PWM outputs drop
+    /// the low bits anyway.
+    pub fn set_dither(&mut self, on: bool) {
+        self.dither = on.then_some(0x2F6B_4E3D);
+    }
+
+    /// Convert a synth sample (-1.0..1.0) to a 16-bit DAC sample with the
+    /// output trim applied, dithered and rounded with `set_dither`.
+    #[inline(always)]
+    pub fn to_dac_sample(&mut self, sample: f32) -> i16 {
+        let mut value = sample * 32767.0 + self.output_trim as f32;
+        if let Some(rng) = &mut self.dither {
+            *rng ^= *rng << 13;
+            *rng ^= *rng >> 17;
+            *rng ^= *rng << 5;
+            // Two 16-bit uniform values summed: triangular over ±1 LSB
+            let sum = (*rng & 0xFFFF) + (*rng >> 16);
+            value = libm::floorf(value + sum as f32 / 65536.0 - 0.5);
+        }
+        value.clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+
+    /// Convert a synth sample (-1.0..1.0) to a full-scale 32-bit DAC
+    /// sample with the output trim applied (still in 16-bit LSBs), for
+    /// 24- and 32-bit DACs. Never dithered: f32 rounding is far below what
+    /// those resolve.
+    #[inline(always)]
+    pub fn to_dac_sample_wide(&self, sample: f32) -> i32 {
+        let value = (sample + self.output_trim as f32 / 32768.0) * 2_147_483_648.0;
+        // Saturates at the ends like the clamp in `to_dac_sample`
+        value as i32
+    }
+
+    /// Set pitch bend. Input range: -12.0 to 12.0 (semitones).
+    /// Uses cheap linear approximation: ratio ≈ 1 + bend * ln(2)/12
+    #[inline]
+    pub fn set_pitch_bend(&mut self, bend: f32) {
+        self.pitch_bend.set_value(bend_ratio(bend));
+        // Update all active voices with new pitch bend
+        for voice in 0..VOICE_COUNT {
+            if self.voice_note[voice] != VOICE_UNASSIGNED {
+                self.update_voice_freq(voice);
+            }
+        }
+    }
+
+    /// Semitones a full pitch wheel deflection bends either way, for
+    /// `set_pitch_wheel`. Clamped to 0..`BEND_RANGE_MAX`; default
+    /// `BEND_RANGE` (a whole step).
+    pub fn set_bend_range(&mut self, semitones: f32) {
+        self.bend_range = semitones.clamp(0.0, BEND_RANGE_MAX);
+    }
+
+    /// Set pitch bend from a 14-bit MIDI pitch wheel value, -8192 to 8191
+    /// (the two data bytes combined, minus the 8192 center). Both extremes
+    /// map to the full bend range.
+    pub fn set_pitch_wheel(&mut self, value: i16) {
+        let value = value.clamp(-8192, 8191) as f32;
+        let deflection = if value < 0.0 {
+            value / 8192.0
+        } else {
+            value / 8191.0
+        };
+        self.set_pitch_bend(deflection * self.bend_range);
+    }
+
+    /// Bend a single voice (MPE-style), on top of the global pitch bend.
+    /// Same range and approximation as `set_pitch_bend`. The bend is reset
+    /// when the voice is given a new note.
+    #[inline]
+    pub fn set_voice_pitch_bend(&mut self, voice: usize, bend: f32) {
+        self.voice_bends[voice] = bend_ratio(bend);
+        if self.voice_note[voice] != VOICE_UNASSIGNED {
+            self.update_voice_freq(voice);
+        }
+    }
+
+    /// Bend only the voice playing `key` in `octave`, e.g. for per-channel
+    /// MPE pitch bend. Returns false if the note has no voice.
+    pub fn set_note_pitch_bend(&mut self, key: usize, octave: u8, bend: f32) -> bool {
+        let note = encode_note(key as u8, octave);
+        match self.voice_note.iter().position(|&n| n == note) {
+            Some(voice) => {
+                self.set_voice_pitch_bend(voice, bend);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get a clone of the pitch bend Shared for external control
+    #[inline]
+    pub fn pitch_bend_control(&self) -> Shared {
+        self.pitch_bend.clone()
+    }
+    #[inline]
+    pub fn resonator_freq_control(&self) -> Shared {
+        self.controls.resonator_freq.clone()
+    }
+
+    /// Get a clone of the formant vowel position Shared for external
+    /// control: 0.0 is A, 1.0 E, 2.0 I, 3.0 O and 4.0 U, with fractional
+    /// values morphing between neighbours
+    #[inline]
+    pub fn formant_control(&self) -> Shared {
+        self.controls.formant_position.clone()
+    }
+
+    /// Get a clone of the filter cutoff (Hz) Shared for external control
+    #[inline]
+    pub fn filter_cutoff_control(&self) -> Shared {
+        self.controls.filter_cutoff.clone()
+    }
+
+    /// Get a clone of the filter resonance (0.0-1.0) Shared for external control
+    #[inline]
+    pub fn filter_resonance_control(&self) -> Shared {
+        self.controls.filter_resonance.clone()
+    }
+
+    /// Current pitch bend in semitones, from `set_pitch_bend` or the pitch
+    /// wheel, e.g. for a display. Read from the Shared, so a bend written
+    /// through `pitch_bend_control` shows too.
+    pub fn pitch_bend_value(&self) -> f32 {
+        bend_semitones(self.pitch_bend.value())
+    }
+
+    /// Current filter cutoff in Hz, before the LFO and sensor modulate it.
+    pub fn cutoff_value(&self) -> f32 {
+        self.controls.filter_cutoff.value()
+    }
+
+    /// Current filter resonance, 0.0-1.0.
+    pub fn resonance_value(&self) -> f32 {
+        self.controls.filter_resonance.value()
+    }
+
+    /// Output level the master gain is heading for: 1.0, or 0.0 while
+    /// muted (`set_output_mute`).
+    pub fn volume_value(&self) -> f32 {
+        self.controls.master_gain.value()
+    }
+
+    /// Current value of a parameter a MIDI controller can drive, in the
+    /// units of `ControlTarget::scale`, e.g. to show where a learned knob
+    /// has left it.
+    pub fn control_value(&self, target: ControlTarget) -> f32 {
+        match target {
+            ControlTarget::FilterCutoff => self.cutoff_value(),
+            ControlTarget::FilterResonance => self.resonance_value(),
+            ControlTarget::ResonatorFreq => self.controls.resonator_freq.value(),
+            ControlTarget::FormantPosition => self.controls.formant_position.value(),
+            ControlTarget::Drive => self.controls.drive.value(),
+            ControlTarget::DelayFeedback => self.controls.delay_feedback.value(),
+            ControlTarget::GlideTime => self.controls.glide_time.value(),
+            ControlTarget::StereoWidth => self.stereo_width,
+        }
+    }
+
+    /// Which keys are held down, lowest octave first, e.g. for the key
+    /// LEDs (see `leds.rs`).
+    pub fn held_keys(&self) -> &[[bool; KEY_COUNT]; OCTAVE_COUNT] {
+        &self.key_states
+    }
+
+    /// Name of the chord formed by the keys held down, e.g. for a display
+    /// (`ChordName` formats as "Cmaj7", "Dm", "C/E"). Inversions name the
+    /// root with the lowest note as the bass; `None` when fewer than two
+    /// keys are held or they form no known chord (see `chord.rs`), and on
+    /// the drum pads.
+    pub fn current_chord(&self) -> Option<ChordName> {
+        let mut pitch_classes = 0u16;
+        let mut bass = None;
+        for (octave, keys) in self.key_states.iter().enumerate() {
+            for key in (0..KEY_COUNT).filter(|&key| keys[key]) {
+                let (key, octave) = self.keyboard_mode.pitch(key, octave as u8)?;
+                pitch_classes |= 1 << key;
+                if bass.is_none_or(|bass| (octave, key) < bass) {
+                    bass = Some((octave, key));
+                }
+            }
+        }
+        recognize(pitch_classes, bass?.1 as u8)
+    }
+}
+
+// ============================================================================
+// TEST ACCESSORS
+// ============================================================================
+
+#[cfg(test)]
+impl KeyboardSynth {
+    /// Encoded note assigned to a voice, or None if unassigned
+    fn voice_note(&self, voice: usize) -> Option<u8> {
+        let note = self.voice_note[voice];
+        (note != VOICE_UNASSIGNED).then_some(note)
+    }
+
+    /// Current gate value of a voice
+    fn gate(&self, voice: usize) -> f32 {
+        self.controls.gates[voice].value()
+    }
+
+    /// Current (bent) frequency of a voice
+    fn freq(&self, voice: usize) -> f32 {
+        self.controls.freqs[voice].value()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Press a key given as (key, octave)
+    fn press(synth: &mut KeyboardSynth, key: usize, octave: u8) {
+        synth.update_key(key, octave, true);
+    }
+
+    fn release(synth: &mut KeyboardSynth, key: usize, octave: u8) {
+        synth.update_key(key, octave, false);
+    }
+
+    /// Mean square of `samples`
+    fn power(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        libm::sqrtf(power(samples))
+    }
+
+    /// A synth with the envelope's attack, decay and release in seconds and
+    /// its sustain level, at full level from the first frame
+    fn synth_with_envelope(attack: f32, decay: f32, sustain: f32, release: f32) -> KeyboardSynth {
+        let mut synth = KeyboardSynth::new();
+        synth.set_startup_fade(Duration::ZERO);
+        synth.set_envelope(attack, decay, sustain, release);
+        synth
+    }
+
+    /// Render `frames` frames in blocks of up to `block`, as the firmware's
+    /// buffers do
+    fn render(synth: &mut KeyboardSynth, frames: usize, block: usize) -> alloc::vec::Vec<f32> {
+        let mut samples = alloc::vec![0.0f32; frames];
+        for chunk in samples.chunks_mut(block) {
+            let len = chunk.len();
+            synth.process_block(chunk, len);
+        }
+        samples
+    }
+
+    #[test]
+    fn fresh_allocation_uses_first_free_voice() {
+        let mut synth = KeyboardSynth::new();
+        press(&mut synth, 9, 0); // A3
+        assert_eq!(synth.voice_note(0), Some(encode_note(9, 0)));
+        assert_eq!(synth.gate(0), 1.0);
+        // Voice 0 has no spread offset, so A3 is exact
+        assert!((synth.freq(0) - 220.0).abs() < 0.01);
+
+        press(&mut synth, 0, 1);
+        assert_eq!(synth.voice_note(1), Some(encode_note(0, 1)));
+        assert_eq!(synth.voice_note(2), None);
+    }
+
+    #[test]
+    fn repressed_note_reuses_its_voice() {
+        let mut synth = KeyboardSynth::new();
+        press(&mut synth, 0, 0);
+        press(&mut synth, 4, 0);
+        release(&mut synth, 0, 0);
+        assert_eq!(synth.gate(0), 0.0);
+
+        press(&mut synth, 0, 0);
+        assert_eq!(synth.gate(0), 1.0);
+        assert_eq!(synth.voice_note(0), Some(encode_note(0, 0)));
+        assert_eq!(synth.voice_note(2), None);
+    }
+
+    #[test]
+    fn stealing_is_round_robin_and_wraps() {
+        let mut synth = KeyboardSynth::new();
+        for key in 0..VOICE_COUNT {
+            press(&mut synth, key, 0);
+        }
+        // Every further note steals the next voice in turn
+        for (i, key) in (0..VOICE_COUNT + 2).enumerate() {
+            press(&mut synth, key, 1);
+            assert_eq!(
+                synth.voice_note(i % VOICE_COUNT),
+                Some(encode_note(key as u8, 1))
+            );
+        }
+    }
+
+    #[test]
+    fn voice_range_confines_allocation_and_stealing() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_voice_range(4, 7);
+        for key in 0..KEY_COUNT {
+            press(&mut synth, key, 0);
+            for voice in 0..4 {
+                assert_eq!(synth.voice_note(voice), None);
+            }
+        }
+        // Stealing wrapped within 4..7: keys 9, 10, 11 hold the last steals
+        assert_eq!(synth.voice_note(4), Some(encode_note(9, 0)));
+        assert_eq!(synth.voice_note(5), Some(encode_note(10, 0)));
+        assert_eq!(synth.voice_note(6), Some(encode_note(11, 0)));
+    }
+
+    #[test]
+    fn voice_range_outside_a_zone_drops_its_notes() {
+        let mut synth = KeyboardSynth::new();
+        let zone = |voices| ZoneConfig {
+            waveform: Waveform::Saw,
+            transpose: 0,
+            volume: 1.0,
+            voices,
+        };
+        synth.set_split(Some(12), zone(3), zone(4));
+        // The upper zone's voices 3..7 are all outside the range
+        synth.set_voice_range(0, 3);
+        press(&mut synth, 0, 1);
+        assert!((0..VOICE_COUNT).all(|v| synth.voice_note(v).is_none()));
+        press(&mut synth, 0, 0);
+        assert_eq!(synth.voice_note(0), Some(encode_note(0, 0)));
+        assert!((1..VOICE_COUNT).all(|v| synth.voice_note(v).is_none()));
+
+        // Mono mode likewise drops notes with no voice for its zone
+        synth.set_voice_range(4, 7);
+        synth.set_mono(true);
+        press(&mut synth, 2, 0);
+        assert!((0..VOICE_COUNT).all(|v| synth.voice_note(v) != Some(encode_note(2, 0))));
+    }
+
+    #[test]
+    fn latch_toggles_notes_and_all_notes_off_clears_them() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_latch(true);
+        // Build a 5-note drone with single taps
+        for key in [0, 4, 7, 11, 2] {
+            press(&mut synth, key, 0);
+            release(&mut synth, key, 0);
+        }
+        for voice in 0..5 {
+            assert_eq!(synth.gate(voice), 1.0);
+        }
+
+        // Tapping a latched note again turns it off
+        press(&mut synth, 7, 0);
+        release(&mut synth, 7, 0);
+        assert_eq!(synth.gate(2), 0.0);
+        assert_eq!(synth.gate(3), 1.0);
+
+        synth.all_notes_off();
+        for voice in 0..VOICE_COUNT {
+            assert_eq!(synth.gate(voice), 0.0);
+        }
+    }
+
+    #[test]
+    fn voice_pitch_bend_leaves_other_voices_unbent() {
+        let mut synth = KeyboardSynth::new();
+        for key in [0, 4, 7] {
+            press(&mut synth, key, 0);
+        }
+        let before: [f32; 3] = core::array::from_fn(|voice| synth.freq(voice));
+
+        assert!(synth.set_note_pitch_bend(4, 0, 2.0));
+        assert!((synth.freq(1) - before[1] * bend_ratio(2.0)).abs() < 0.001);
+        assert_eq!(synth.freq(0), before[0]);
+        assert_eq!(synth.freq(2), before[2]);
+
+        // Global bend composes with the per-voice bend
+        synth.set_pitch_bend(1.0);
+        assert!((synth.freq(1) - before[1] * bend_ratio(2.0) * bend_ratio(1.0)).abs() < 0.001);
+        assert!((synth.freq(0) - before[0] * bend_ratio(1.0)).abs() < 0.001);
+    }
+
+    #[test]
+    fn release_gates_off_only_the_matching_voice() {
+        let mut synth = KeyboardSynth::new();
+        press(&mut synth, 0, 0);
+        press(&mut synth, 0, 1); // same key, other octave
+        press(&mut synth, 7, 0);
+
+        release(&mut synth, 0, 1);
+        assert_eq!(synth.gate(0), 1.0);
+        assert_eq!(synth.gate(1), 0.0);
+        assert_eq!(synth.gate(2), 1.0);
+    }
+
+    #[test]
+    fn stereo_width_is_transparent_at_one_and_mono_at_zero() {
+        let width = Shared::new(1.0);
+        let mut node = stereo_width(&width);
+        assert_eq!(node.filter_stereo(0.75, -0.25), (0.75, -0.25));
+
+        width.set_value(0.0);
+        let (left, right) = node.filter_stereo(0.75, -0.25);
+        assert_eq!(left, right);
+        assert!((left - 0.25).abs() < 1e-6);
+
+        width.set_value(2.0);
+        let (left, right) = node.filter_stereo(0.75, -0.25);
+        assert!((left - right - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fixed_backend_tracks_float_level() {
+        fn level(backend: RenderBackend) -> f32 {
+            let mut synth = KeyboardSynth::new();
+            synth.set_render_backend(backend);
+            press(&mut synth, 9, 0); // A3
+            // Skip the attack and decay
+            render(&mut synth, 88200, 4410);
+            rms(&render(&mut synth, 4410, 4410))
+        }
+        let float = level(RenderBackend::Float);
+        let fixed = level(RenderBackend::Fixed);
+        assert!(fixed > 0.0 && (fixed / float - 1.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn octave_follow_moves_held_notes() {
+        let mut synth = KeyboardSynth::new();
+        press(&mut synth, 9, 0); // A3
+        synth.set_octave_shift(1);
+        assert!((synth.freq(0) - 220.0).abs() < 0.01);
+        press(&mut synth, 9, 1); // A4, shifted to A5
+        assert!((synth.freq(1) - 880.0 * synth.spread_ratios[1]).abs() < 0.01);
+
+        synth.set_octave_follow(true);
+        synth.set_octave_shift(2);
+        assert!((synth.freq(0) - 440.0).abs() < 0.01);
+        assert!((synth.freq(1) - 1760.0 * synth.spread_ratios[1]).abs() < 0.1);
+
+        // Release still matches the physical key
+        release(&mut synth, 9, 0);
+        assert_eq!(synth.gate(0), 0.0);
+        assert_eq!(synth.gate(1), 1.0);
+    }
+
+    #[test]
+    fn normalized_gain_scales_with_active_voices() {
+        assert_eq!(voice_gain(GainMode::Fixed, 1), 1.0);
+        assert!((voice_gain(GainMode::Normalized, VOICE_COUNT) - 1.0).abs() < 1e-6);
+        assert!(
+            (voice_gain(GainMode::Normalized, 1) - libm::sqrtf(VOICE_COUNT as f32)).abs() < 1e-6
+        );
+        // No voices: hold the single-voice gain so release tails don't jump
+        assert_eq!(
+            voice_gain(GainMode::Normalized, 0),
+            voice_gain(GainMode::Normalized, 1)
+        );
+    }
+
+    #[test]
+    fn normalized_gain_holds_a_released_chord_level() {
+        let mut synth = synth_with_envelope(0.01, 0.1, 1.0, 0.5);
+        synth.set_voice_gain_mode(GainMode::Normalized);
+        let keys = [0, 2, 4, 5, 7, 9, 11];
+        for key in keys {
+            press(&mut synth, key, 1);
+        }
+        let held = render(&mut synth, 8820, 4410).split_off(4410);
+        for key in keys {
+            release(&mut synth, key, 1);
+        }
+        // The tails only fade: the gain stays at the full chord's
+        let released = render(&mut synth, 4410, 4410);
+        let loudest = |block: &[f32]| block.chunks(441).map(rms).fold(0.0, f32::max);
+        assert!(loudest(&released) < loudest(&held) * 1.05);
+    }
+
+    #[test]
+    fn min_voice_age_skips_young_voices_when_stealing() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_min_voice_age_ms(50);
+        for key in 0..VOICE_COUNT {
+            press(&mut synth, key, 0);
+        }
+        let mut block = [0.0f32; 2646]; // 60 ms
+        synth.process_block(&mut block, 2646);
+
+        // Steal voice 0 within a one-voice range, leaving round-robin on it
+        synth.set_voice_range(0, 1);
+        press(&mut synth, 0, 1);
+        assert_eq!(synth.voice_note(0), Some(encode_note(0, 1)));
+
+        // Voice 0 is now too young, so the next steal takes voice 1
+        synth.set_voice_range(0, VOICE_COUNT);
+        press(&mut synth, 1, 1);
+        assert_eq!(synth.voice_note(0), Some(encode_note(0, 1)));
+        assert_eq!(synth.voice_note(1), Some(encode_note(1, 1)));
+
+        // Once every voice is young, plain round-robin takes over
+        for key in 2..VOICE_COUNT {
+            press(&mut synth, key, 1);
+        }
+        press(&mut synth, 7, 1);
+        assert_eq!(synth.voice_note(0), Some(encode_note(7, 1)));
+    }
+
+    #[test]
+    fn velocity_to_cutoff_makes_soft_notes_darker() {
+        /// High-frequency share of a note: RMS of the sample differences
+        /// relative to the RMS of the signal
+        fn brightness(velocity: u8) -> f32 {
+            let mut synth = KeyboardSynth::new();
+            synth.set_velocity_to_cutoff(1.0);
+            synth.note_on(9, 0, velocity);
+            let mut block = [0.0f32; 4410];
+            synth.process_block(&mut block, 4410);
+            let energy: f32 = block.iter().map(|s| s * s).sum();
+            let diff: f32 = block
+                .windows(2)
+                .map(|w| (w[1] - w[0]) * (w[1] - w[0]))
+                .sum();
+            libm::sqrtf(diff / energy)
+        }
+        assert!(brightness(20) < brightness(VELOCITY_MAX) * 0.8);
+    }
+
+    #[test]
+    fn fine_tune_shifts_notes_by_cents() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_fine_tune_cents(50.0);
+        press(&mut synth, 9, 0); // A3
+        assert!((synth.freq(0) - 226.45).abs() < 0.05);
+
+        // Held notes follow by default, or keep their pitch until re-pressed
+        synth.set_fine_tune_cents(0.0);
+        assert!((synth.freq(0) - 220.0).abs() < 0.01);
+        synth.set_fine_tune_held(false);
+        synth.set_fine_tune_cents(50.0);
+        assert!((synth.freq(0) - 220.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn auto_pan_places_chord_notes_apart() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_auto_pan(AutoPanMode::ByVoice);
+        for key in [0, 4, 7] {
+            press(&mut synth, key, 1); // C4 E4 G4
+        }
+        let mut left = [0.0f32; 4410];
+        let mut right = [0.0f32; 4410];
+        synth.process_block_stereo(&mut left, &mut right);
+        let energy = |block: &[f32]| block.iter().map(|s| s * s).sum::<f32>();
+        assert!(energy(&left) > 0.0 && energy(&right) > 0.0);
+        assert!(left.iter().zip(&right).any(|(l, r)| (l - r).abs() > 1e-4));
+
+        let pans: std::vec::Vec<f32> = (0..3).map(|v| synth.controls.pans[v].value()).collect();
+        assert_eq!(pans, [0.0, -0.5, 0.5]);
+
+        synth.set_auto_pan(AutoPanMode::ByPitch);
+        // C4 (even key) right, E4 (even) right and further out than C4
+        assert!(synth.controls.pans[0].value() > 0.0);
+        assert!(synth.controls.pans[1].value() > synth.controls.pans[0].value());
+    }
+
+    #[test]
+    fn analog_drift_stays_within_a_few_cents() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_analog_drift(1.0);
+        press(&mut synth, 9, 0); // A3 on voice 0, no spread offset
+        let mut block = [0.0f32; 640];
+        let mut moved = false;
+        let limit = libm::exp2f(DRIFT_MAX_CENTS / 1200.0) * 220.0;
+        // About 70 seconds of playing
+        for _ in 0..5000 {
+            synth.process_block(&mut block, 640);
+            let freq = synth.freq(0);
+            assert!(freq <= limit + 1e-3 && freq >= 220.0 * 220.0 / limit - 1e-3);
+            moved |= (freq - 220.0).abs() > 0.01;
+        }
+        assert!(moved);
+
+        // Deterministic: a fresh synth drifts the same way
+        let mut other = KeyboardSynth::new();
+        other.set_analog_drift(1.0);
+        press(&mut other, 9, 0);
+        for _ in 0..5000 {
+            other.process_block(&mut block, 640);
+        }
+        assert_eq!(synth.freq(0), other.freq(0));
+    }
+
+    #[test]
+    fn output_mute_ramps_and_keeps_voices_running() {
+        let mut synth = KeyboardSynth::new();
+        for key in [0, 4, 7] {
+            press(&mut synth, key, 1);
+        }
+        let mut block = [0.0f32; 4410];
+        synth.process_block(&mut block, 4410);
+
+        synth.set_output_mute(true);
+        synth.process_block(&mut block, 4410);
+        // No jump to zero: the first samples of the ramp still carry signal
+        assert!(block[..100].iter().any(|s| s.abs() > 1e-3));
+        assert!(block[441..].iter().all(|&s| s == 0.0));
+        assert_eq!(synth.gate(0), 1.0);
+
+        synth.set_output_mute(false);
+        synth.process_block(&mut block, 4410);
+        let steps = block.windows(2).map(|w| (w[1] - w[0]).abs());
+        assert!(steps.fold(0.0f32, f32::max) < 0.05);
+        assert!(block[441..].iter().any(|s| s.abs() > 1e-3));
+    }
+
+    #[test]
+    fn velocity_curves_map_mid_velocity() {
+        let expected = [
+            (VelocityCurve::Linear, 64.0 / 127.0),
+            (VelocityCurve::Soft, libm::sqrtf(64.0 / 127.0)),
+            (VelocityCurve::Hard, (64.0 / 127.0) * (64.0 / 127.0)),
+            (VelocityCurve::Fixed(100), 100.0 / 127.0),
+        ];
+        for (curve, gain) in expected {
+            let mut synth = KeyboardSynth::new();
+            synth.set_velocity_curve(curve);
+            synth.note_on(9, 0, 64);
+            assert!((synth.controls.velocities[0].value() - gain).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn pingpong_delay_bounces_between_channels() {
+        let feedback = Shared::new(DELAY_FEEDBACK_MAX);
+        let mut delay = delay_net(true, &feedback);
+        let period = (DELAY_TIME * DEFAULT_SR) as usize;
+        let mut left = std::vec::Vec::new();
+        let mut right = std::vec::Vec::new();
+        for i in 0..period * 4 + 10 {
+            let input = if i == 0 { 1.0 } else { 0.0 };
+            let (l, r) = delay.filter_stereo(input, 0.0);
+            left.push(l);
+            right.push(r);
+        }
+        let peak = |channel: &[f32], repeat: usize| {
+            channel[repeat * period - 2..repeat * period + 3]
+                .iter()
+                .fold(0.0f32, |a, s| a.max(s.abs()))
+        };
+        // First repeat right, then left, then right
+        assert!(peak(&right, 1) > 0.5 && peak(&left, 1) < 1e-6);
+        assert!(peak(&left, 2) > 0.4 && peak(&right, 2) < 1e-6);
+        assert!(peak(&right, 3) > 0.3 && peak(&left, 3) < 1e-6);
+    }
+
+    #[test]
+    fn delay_stays_bounded_at_max_feedback() {
+        let feedback = Shared::new(DELAY_FEEDBACK_MAX);
+        for pingpong in [false, true] {
+            let mut delay = delay_net(pingpong, &feedback);
+            for _ in 0..DEFAULT_SR as usize * 5 {
+                let (l, r) = delay.filter_stereo(1.0, 1.0);
+                assert!(l.abs() <= DELAY_FEEDBACK_MAX && r.abs() <= DELAY_FEEDBACK_MAX);
+            }
+        }
+    }
+
+    #[test]
+    fn custom_net_follows_voice_gates() {
+        fn gated_sines(voices: &VoiceControls) -> Box<dyn AudioUnit> {
+            let mut net = Net::new(0, 1);
+            for v in 0..VOICE_COUNT {
+                net = net + ((var(&voices.freqs[v]) >> sine::<f32>()) * var(&voices.gates[v]));
+            }
+            Box::new(net)
+        }
+
+        let mut synth = KeyboardSynth::with_net(gated_sines);
+        let mut left = [0.0f32; 640];
+        let mut right = [0.0f32; 640];
+        synth.process_block_stereo(&mut left, &mut right);
+        assert!(left.iter().all(|s| *s == 0.0));
+
+        press(&mut synth, 9, 1);
+        synth.process_block_stereo(&mut left, &mut right);
+        assert!(left.iter().any(|s| s.abs() > 0.1));
+        // Mono net goes to both channels
+        assert_eq!(left, right);
+
+        // Survives topology rebuilds, still following the gates
+        synth.set_chorus_enabled(true);
+        synth.set_chorus_enabled(false);
+        synth.process_block_stereo(&mut left, &mut right);
+        assert!(left.iter().any(|s| s.abs() > 0.1));
+        release(&mut synth, 9, 1);
+        synth.process_block_stereo(&mut left, &mut right);
+        assert!(left.iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn oversampling_reduces_aliasing() {
+        // 2 kHz saw over 0.1 s: harmonics land on every 200th DFT bin,
+        // aliases (folded around 44.1 kHz) 10 bins off them
+        const FREQ: f32 = 2000.0;
+        const N: usize = 4410;
+        fn alias_ratio(factor: u8) -> f32 {
+            let mut synth = KeyboardSynth::new();
+            synth.set_oversampling(factor);
+            synth.set_envelope(0.001, 0.001, 1.0, 0.5);
+            synth.filter_cutoff_control().set(20_000.0);
+            press(&mut synth, 11, 3);
+            for freq in &synth.controls.freqs {
+                freq.set(FREQ);
+            }
+            let mut block = [0.0f32; N];
+            synth.process_block(&mut block, N);
+            synth.process_block(&mut block, N);
+
+            let total: f32 = block.iter().map(|s| s * s).sum();
+            let step = FREQ as usize * N / DEFAULT_SR as usize;
+            let harmonic: f32 = (0..=N / 2)
+                .step_by(step)
+                .map(|bin| {
+                    let (mut re, mut im) = (0.0f32, 0.0f32);
+                    for (n, s) in block.iter().enumerate() {
+                        let phase = core::f32::consts::TAU * ((bin * n) % N) as f32 / N as f32;
+                        re += s * libm::cosf(phase);
+                        im -= s * libm::sinf(phase);
+                    }
+                    let scale = if bin == 0 { 1.0 } else { 2.0 };
+                    scale * (re * re + im * im) / N as f32
+                })
+                .sum();
+            (total - harmonic).max(0.0) / total
+        }
+
+        let plain = alias_ratio(1);
+        let oversampled = alias_ratio(2);
+        assert!(
+            oversampled < plain / 20.0,
+            "alias energy 1x {plain:e}, 2x {oversampled:e}"
+        );
+    }
+
+    #[test]
+    fn max_note_duration_releases_held_keys() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_max_note_duration(Some(2000));
+        let mut block = [0.0f32; 640];
+        press(&mut synth, 0, 1);
+        let voice = synth
+            .voice_note
+            .iter()
+            .position(|n| *n == encode_note(0, 1))
+            .unwrap();
+
+        // Key held for 10 s
+        let mut released_at = None;
+        for _ in 0..10 * DEFAULT_SR as usize / 640 {
+            synth.process_block(&mut block, 640);
+            if released_at.is_none() && synth.controls.gates[voice].value() == 0.0 {
+                released_at = Some(synth.clock);
+            }
+        }
+        let released_at = released_at.expect("held note was never released") as f64 / DEFAULT_SR;
+        assert!(
+            (2.0..2.05).contains(&released_at),
+            "released at {released_at} s"
+        );
+        assert_eq!(synth.controls.gates[voice].value(), 0.0);
+
+        // Releasing and pressing again replays the note
+        release(&mut synth, 0, 1);
+        press(&mut synth, 0, 1);
+        synth.process_block(&mut block, 640);
+        assert_eq!(synth.controls.gates[voice].value(), 1.0);
+    }
+
+    #[test]
+    fn chorus_voices_and_spacing_set_the_spread() {
+        // Last sample where an impulse is still echoing
+        fn tail(settings: ChorusSettings) -> f64 {
+            let mut chorus = chorus_net(CHORUS_SEED, settings);
+            let mut last = 0;
+            for i in 0..DEFAULT_SR as usize / 5 {
+                let out = chorus.filter_mono(if i == 0 { 1.0 } else { 0.0 });
+                if out.abs() > 1e-4 {
+                    last = i;
+                }
+            }
+            last as f64 / DEFAULT_SR
+        }
+        let subtle = ChorusSettings {
+            voices: 2,
+            separation: 0.005,
+            variation: 0.002,
+            mod_freq: 0.5,
+        };
+        let lush = ChorusSettings {
+            voices: 4,
+            ..ChorusSettings::default()
+        };
+        assert!(tail(subtle) <= 0.0125, "subtle tail {} s", tail(subtle));
+        assert!(tail(lush) > 0.04, "lush tail {} s", tail(lush));
+    }
+
+    #[test]
+    fn chorus_settings_change_keeps_notes_sounding() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_envelope(0.01, 0.01, 1.0, 0.5);
+        synth.set_chorus_enabled(true);
+        press(&mut synth, 0, 1);
+        press(&mut synth, 4, 1);
+        let mut block = [0.0f32; 640];
+        for _ in 0..20 {
+            synth.process_block(&mut block, 640);
+        }
+        let before = rms(&block);
+
+        synth.set_chorus(2, 0.005, 0.002, 0.5);
+        for _ in 0..10 {
+            synth.process_block(&mut block, 640);
+            // Envelopes carry on, the level only moves with the wet mix
+            assert!(rms(&block) > before * 0.5, "{} vs {before}", rms(&block));
+        }
+    }
+
+    #[test]
+    fn release_velocity_sets_tail_length() {
+        // Seconds until the note falls silent after note_off
+        fn tail(release_velocity: u8) -> f32 {
+            let mut synth = KeyboardSynth::new();
+            synth.set_release_velocity(true);
+            synth.set_envelope(0.01, 0.01, 1.0, 0.5);
+            synth.note_on(9, 1, 100);
+            let mut block = [0.0f32; 441];
+            for _ in 0..20 {
+                synth.process_block(&mut block, 441);
+            }
+            synth.note_off(9, 1, release_velocity);
+            for i in 0..1000 {
+                synth.process_block(&mut block, 441);
+                if block.iter().all(|s| s.abs() < 1e-4) {
+                    return i as f32 * 0.01;
+                }
+            }
+            panic!("note never ended");
+        }
+
+        let fast = tail(127);
+        let normal = tail(RELEASE_VELOCITY_CENTER);
+        let slow = tail(1);
+        assert!((0.1..0.15).contains(&fast), "fast release {fast} s");
+        assert!((0.45..0.55).contains(&normal), "normal release {normal} s");
+        assert!((1.9..2.1).contains(&slow), "slow release {slow} s");
+    }
+
+    #[test]
+    fn exponential_glide_passes_the_geometric_midpoint() {
+        // Glide 220 -> 440 Hz over 1 s, read the frequency halfway
+        fn midpoint(curve: GlideCurve) -> f32 {
+            let start_ratio = Shared::new(220.0 / 440.0);
+            let trigger = Shared::new(0.0);
+            let time = Shared::new(1.0);
+            let curve = Shared::new(curve as u8 as f32);
+            let mut glide = glide_env(&start_ratio, &trigger, &time, &curve);
+            trigger.set_value(1.0);
+            for _ in 0..DEFAULT_SR as usize / 2 {
+                glide.get_mono();
+            }
+            440.0 * glide.get_mono()
+        }
+        let exponential = midpoint(GlideCurve::Exponential);
+        let linear = midpoint(GlideCurve::Linear);
+        assert!(
+            (exponential - 311.13).abs() < 1.0,
+            "exponential {exponential} Hz"
+        );
+        assert!((linear - 330.0).abs() < 1.0, "linear {linear} Hz");
+    }
+
+    #[test]
+    fn mono_note_priority_picks_and_falls_back() {
+        let (e3, g3, c4) = ((4, 0), (7, 0), (0, 1));
+        // Sounding note with E3, C4 and G3 held, then with the sounding one
+        // released
+        for (priority, sounding, fallback) in [
+            (NotePriority::Last, g3, c4),
+            (NotePriority::Low, e3, g3),
+            (NotePriority::High, c4, g3),
+        ] {
+            let mut synth = KeyboardSynth::new();
+            synth.set_mono(true);
+            synth.set_note_priority(priority);
+            let voice = synth.voice_pool(0).0;
+            for (key, octave) in [e3, c4, g3] {
+                press(&mut synth, key, octave);
+            }
+            let note = |(key, octave): (usize, u8)| Some(encode_note(key as u8, octave));
+            assert_eq!(synth.voice_note(voice), note(sounding));
+            release(&mut synth, sounding.0, sounding.1);
+            assert_eq!(synth.voice_note(voice), note(fallback));
+        }
+    }
+
+    #[test]
+    fn mono_legato_glides_from_the_current_pitch() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_mono(true);
+        synth.set_glide_time(0.2);
+        let mut block = [0.0f32; 441];
+        press(&mut synth, 9, 0);
+        synth.process_block(&mut block, 441);
+        assert_eq!(synth.glide_position(0), 1.0);
+
+        // Legato A3 -> A4: a glide up from half the target frequency
+        press(&mut synth, 9, 1);
+        assert!((synth.glide_start_ratios[0] - 0.5).abs() < 1e-6);
+        for _ in 0..10 {
+            synth.process_block(&mut block, 441);
+        }
+        assert!((synth.glide_position(0) - libm::powf(0.5, 0.5)).abs() < 1e-3);
+
+        // Back down halfway through: starts from the midpoint, not from A4
+        release(&mut synth, 9, 1);
+        assert!((synth.glide_start_ratios[0] - libm::sqrtf(2.0)).abs() < 1e-3);
+
+        // After a release the next note starts on pitch
+        release(&mut synth, 9, 0);
+        press(&mut synth, 0, 1);
+        assert_eq!(synth.glide_position(0), 1.0);
+    }
+
+    #[test]
+    fn arp_spans_octaves_with_staccato_gates() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_arpeggiator(Some(ArpMode::Up));
+        synth.set_arp_rate(10.0);
+        synth.set_arp_gate(0.25);
+        synth.set_arp_octaves(2);
+        press(&mut synth, 4, 1); // E4
+        press(&mut synth, 0, 1); // C4
+        let voice = synth.voice_pool(0).0;
+        let mut block = [0.0f32; 441];
+        // C4 E4 C5 E5, then around again; 4410-frame steps
+        for expected in [261.63, 329.63, 523.25, 659.26, 261.63] {
+            synth.process_block(&mut block, 441);
+            let freq = synth.controls.freqs[voice].value();
+            assert!((freq / expected - 1.0).abs() < 0.01, "{freq} != {expected}");
+            assert!(synth.controls.gates[voice].value() > 0.0);
+            // Gate 0.25: released by 1102 frames into the step
+            for _ in 0..2 {
+                synth.process_block(&mut block, 441);
+            }
+            assert_eq!(synth.controls.gates[voice].value(), 0.0);
+            for _ in 0..7 {
+                synth.process_block(&mut block, 441);
+            }
+        }
+        // Letting go stops the pattern
+        release(&mut synth, 0, 1);
+        release(&mut synth, 4, 1);
+        synth.process_block(&mut block, 441);
+        assert_eq!(synth.controls.gates[voice].value(), 0.0);
+    }
+
+    #[test]
+    fn autosave_only_hands_out_changed_settings_when_quiet() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_autosave(Duration::from_millis(100));
+        let mut block = [0.0f32; 4410];
+        synth.process_block(&mut block, 4410);
+        // Nothing changed
+        assert!(synth.take_autosave().is_none());
+
+        synth.filter_cutoff_control().set(2500.0);
+        press(&mut synth, 0, 1);
+        synth.process_block(&mut block, 4410);
+        // Waits while a key is down, and then for the 0.5 s release tail
+        assert!(synth.take_autosave().is_none());
+        release(&mut synth, 0, 1);
+        synth.process_block(&mut block, 4410);
+        assert!(synth.take_autosave().is_none());
+        for _ in 0..5 {
+            synth.process_block(&mut block, 4410);
+        }
+        let saved = synth.take_autosave().expect("cutoff changed");
+        assert_eq!(saved.preset.filter_cutoff, 2500.0);
+        // Saved once, and not again before the next interval
+        synth.filter_cutoff_control().set(3000.0);
+        assert!(synth.take_autosave().is_none());
+        synth.process_block(&mut block, 4410);
+        assert_eq!(synth.take_autosave().unwrap().preset.filter_cutoff, 3000.0);
+        // Parameters outside the preset count as changes too
+        synth.set_pulse_width(0.3);
+        synth.process_block(&mut block, 4410);
+        assert_eq!(synth.take_autosave().unwrap().pulse_width, 0.3);
+        synth.process_block(&mut block, 4410);
+        assert!(synth.take_autosave().is_none());
+    }
+
+    /// Power of `samples` at `freq` (Goertzel)
+    fn tone_power(samples: &[f32], freq: f32) -> f32 {
+        let coeff = 2.0 * libm::cosf(core::f32::consts::TAU * freq / DEFAULT_SR as f32);
+        let (mut s1, mut s2) = (0.0f32, 0.0f32);
+        for &x in samples {
+            let s0 = x + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s0;
+        }
+        s1 * s1 + s2 * s2 - coeff * s1 * s2
+    }
+
+    #[test]
+    fn formant_vowels_move_the_first_formant() {
+        let mid = vowel_formants(0.5);
+        assert_eq!(mid[0].0, 500.0);
+        assert_eq!(mid[1].0, 1330.0);
+        assert!(vowel_formants(9.0) == VOWEL_FORMANTS[4]);
+
+        // C3: 2nd harmonic (262 Hz) near the I formant, 5th (654 Hz) near A's
+        let ratio = |vowel: Vowel| {
+            let mut synth = KeyboardSynth::new();
+            synth.set_formant_enabled(true);
+            synth.set_formant(vowel);
+            synth.filter_cutoff_control().set(20_000.0);
+            synth.set_envelope(0.001, 0.001, 1.0, 0.5);
+            press(&mut synth, 0, 0);
+            let mut block = [0.0f32; 8820];
+            synth.process_block(&mut block, 8820);
+            synth.process_block(&mut block, 8820);
+            tone_power(&block, 5.0 * 130.81) / tone_power(&block, 2.0 * 130.81)
+        };
+        let a = ratio(Vowel::A);
+        let i = ratio(Vowel::I);
+        assert!(a > 10.0 * i, "A {a} vs I {i}");
+    }
+
+    #[test]
+    fn square_lfo_gates_the_filter() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_envelope(0.001, 0.001, 1.0, 0.5);
+        synth.filter_cutoff_control().set(300.0);
+        synth.set_lfo(LfoShape::Square, 5.0);
+        synth.set_lfo_depth(LfoDestination::Filter, 3.0);
+        synth.set_lfo_retrigger(true);
+        let mut block = [0.0f32; 4410];
+        // Let the free-running LFO drift off phase before the note
+        synth.process_block(&mut block, 1234);
+        press(&mut synth, 0, 1);
+        // Retriggered: open (2.4 kHz) for 0.1 s, then closed (300 Hz)
+        synth.process_block(&mut block, 4410);
+        let open = rms(&block[441..4410]);
+        synth.process_block(&mut block, 4410);
+        let closed = rms(&block[441..4410]);
+        assert!(open > 2.0 * closed, "open {open} closed {closed}");
+        assert!(synth.controls.lfo_cutoff.value() < 1.0);
+
+        // Unrouted: cutoff back to its setting
+        synth.set_lfo_depth(LfoDestination::Filter, 0.0);
+        assert_eq!(synth.controls.lfo_cutoff.value(), 1.0);
+    }
+
+    #[test]
+    fn lfo_vibrato_and_sample_hold_stay_in_range() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_lfo(LfoShape::SampleHold, 20.0);
+        synth.set_lfo_depth(LfoDestination::Pitch, 1.0);
+        press(&mut synth, 9, 1); // A4
+        let mut block = [0.0f32; 64];
+        let mut levels = [0.0f32; 40];
+        for level in levels.iter_mut() {
+            synth.process_block(&mut block, 64);
+            *level = synth.controls.freqs[0].value();
+        }
+        // Within a semitone of A4, and stepping
+        assert!(levels.iter().all(|&f| f > 415.0 && f < 466.2));
+        assert!(levels.iter().any(|&f| f != levels[0]));
+    }
+
+    /// Counts each test thread's live heap bytes, for measuring the graph,
+    /// and their high-water mark
+    struct CountingAlloc;
+
+    std::thread_local! {
+        static ALLOCATED: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+        static PEAK: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+        /// Heap size `limited_heap` reports
+        static LIMIT: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+    }
+
+    /// Statistics of a heap of `LIMIT` bytes, of which the thread's live
+    /// bytes are used
+    fn limited_heap() -> HeapStats {
+        let used = ALLOCATED.with(|bytes| bytes.get());
+        HeapStats {
+            used,
+            free: LIMIT.with(|limit| limit.get()).saturating_sub(used),
+        }
+    }
+
+    /// Leave `free` bytes in the limited heap and restart the high-water
+    /// mark from what is allocated now.
+    fn limit_heap(free: usize) {
+        let used = ALLOCATED.with(|bytes| bytes.get());
+        LIMIT.with(|limit| limit.set(used + free));
+        PEAK.with(|peak| peak.set(used));
+    }
+
+    unsafe impl core::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+            let used = ALLOCATED.with(|bytes| {
+                bytes.set(bytes.get().wrapping_add(layout.size()));
+                bytes.get()
+            });
+            PEAK.with(|peak| peak.set(core::cmp::max(peak.get(), used)));
+            unsafe { std::alloc::System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+            ALLOCATED.with(|bytes| bytes.set(bytes.get().wrapping_sub(layout.size())));
+            unsafe { std::alloc::System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    #[test]
+    fn net_heap_reserve_covers_the_default_graph() {
+        fn heap() -> HeapStats {
+            HeapStats {
+                used: ALLOCATED.with(|bytes| bytes.get()),
+                free: usize::MAX / 2,
+            }
+        }
+        let synth = KeyboardSynth::try_new(heap).unwrap();
+        let used = synth.heap_used().unwrap();
+        assert!(used > 0 && used <= NET_HEAP_RESERVE, "{used} bytes");
+    }
+
+    #[test]
+    fn try_new_refuses_a_short_heap() {
+        fn short() -> HeapStats {
+            HeapStats {
+                used: 380 * 1024,
+                free: 4 * 1024,
+            }
+        }
+        fn roomy() -> HeapStats {
+            HeapStats {
+                used: 1000,
+                free: 300 * 1024,
+            }
+        }
+        assert_eq!(
+            KeyboardSynth::try_new(short).err(),
+            Some(SynthError::OutOfMemory {
+                needed: NET_HEAP_RESERVE,
+                free: 4 * 1024
+            })
+        );
+        let synth = KeyboardSynth::try_new(roomy).unwrap();
+        assert_eq!(synth.heap_used(), Some(0));
+        assert_eq!(KeyboardSynth::new().heap_used(), None);
+    }
+
+    #[test]
+    fn try_new_stays_within_a_small_heap() {
+        // A byte short of the reserve: refused before anything is allocated
+        limit_heap(NET_HEAP_RESERVE - 1);
+        let before = ALLOCATED.with(|bytes| bytes.get());
+        assert!(matches!(
+            KeyboardSynth::try_new(limited_heap),
+            Err(SynthError::OutOfMemory { .. })
+        ));
+        assert_eq!(PEAK.with(|peak| peak.get()), before);
+
+        // Exactly the reserve: the synth is built without the heap ever
+        // running past it, transient allocations included
+        limit_heap(NET_HEAP_RESERVE);
+        let synth = KeyboardSynth::try_new(limited_heap).unwrap();
+        let peak = PEAK.with(|peak| peak.get());
+        assert!(
+            peak <= LIMIT.with(|limit| limit.get()),
+            "{} bytes over",
+            peak - LIMIT.with(|limit| limit.get())
+        );
+        assert!(synth.heap_used().unwrap() > 0);
+    }
+
+    #[test]
+    fn sixteenth_tremolo_locks_to_midi_clock() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_lfo(LfoShape::Square, 1.0);
+        synth.set_lfo_depth(LfoDestination::Amplitude, 1.0);
+        synth.set_lfo_sync(Some(NoteDivision::Sixteenth));
+        // 100 BPM from the default 120: 8 -> 6.67 Hz
+        assert_eq!(synth.lfo.rate, 8.0);
+
+        // 100 BPM clock: a tick every 1102.5 frames, rendered in 64-frame blocks
+        let mut block = [0.0f32; 64];
+        synth.midi_clock_start();
+        let mut ticks = 0;
+        while ticks <= 8 * 24 {
+            if synth.clock as f32 >= ticks as f32 * 1102.5 {
+                synth.midi_clock_tick();
+                ticks += 1;
+            }
+            synth.process_block(&mut block, 64);
+        }
+        assert!((synth.tempo() - 100.0).abs() < 1.0, "{}", synth.tempo());
+        assert!((synth.lfo.rate - 6.667).abs() < 0.1);
+        // A beat just passed: the cycle restarted, the gain is in its high half
+        assert!(synth.lfo.phase < 0.5);
+        assert_eq!(synth.controls.lfo_gain.value(), 1.0);
+
+        // Back to free-running at the set_lfo rate, phase kept
+        let phase = synth.lfo.phase;
+        synth.set_lfo_sync(None);
+        assert_eq!((synth.lfo.rate, synth.lfo.phase), (1.0, phase));
+    }
+
+    #[test]
+    fn voice_highpass_thins_low_notes_more() {
+        // Fundamental power of a note with and without tracking
+        let fundamental = |key: usize, octave: u8, tracking: f32| {
+            let mut synth = KeyboardSynth::new();
+            synth.set_envelope(0.001, 0.001, 1.0, 0.5);
+            synth.filter_cutoff_control().set(20_000.0);
+            synth.set_voice_highpass_tracking(tracking);
+            synth.set_octave_shift(-1);
+            press(&mut synth, key, octave);
+            let mut block = [0.0f32; 8820];
+            synth.process_block(&mut block, 8820);
+            synth.process_block(&mut block, 8820);
+            let freq = synth.controls.freqs[0].value();
+            tone_power(&block, freq)
+        };
+        // C2 (65 Hz) loses far more of its fundamental than C5 (523 Hz)
+        let low = fundamental(0, 0, 0.0) / fundamental(0, 0, 1.0);
+        let high = fundamental(0, 3, 0.0) / fundamental(0, 3, 1.0);
+        assert!(low > 4.0, "low {low}");
+        assert!(high < 2.0, "high {high}");
+
+        // A six-note low chord keeps less energy below 150 Hz
+        let low_energy = |tracking: f32| {
+            let mut synth = KeyboardSynth::new();
+            synth.set_envelope(0.001, 0.001, 1.0, 0.5);
+            synth.set_voice_highpass_tracking(tracking);
+            synth.set_octave_shift(-1);
+            for key in [0, 4, 7, 11] {
+                press(&mut synth, key, 0);
+            }
+            press(&mut synth, 2, 1);
+            press(&mut synth, 7, 1);
+            let mut block = [0.0f32; 8820];
+            synth.process_block(&mut block, 8820);
+            synth.process_block(&mut block, 8820);
+            [65.41, 82.41, 98.0, 123.47]
+                .iter()
+                .map(|&f| tone_power(&block, f))
+                .sum::<f32>()
+        };
+        assert!(low_energy(0.0) > 3.0 * low_energy(1.0));
+    }
+
+    #[test]
+    fn pitch_wheel_maps_through_the_bend_range() {
+        let mut synth = KeyboardSynth::new();
+        press(&mut synth, 9, 1); // A4
+        synth.set_pitch_wheel(8191);
+        // A whole step up (within the linear approximation)
+        let ratio = synth.controls.freqs[0].value() / 440.0;
+        assert!(
+            (ratio / libm::exp2f(2.0 / 12.0) - 1.0).abs() < 0.01,
+            "{ratio}"
+        );
+        synth.set_pitch_wheel(-8192);
+        let down = synth.controls.freqs[0].value() / 440.0;
+        assert!((ratio - 1.0 + down - 1.0).abs() < 1e-4);
+        synth.set_pitch_wheel(0);
+        assert_eq!(synth.controls.freqs[0].value(), 440.0);
+
+        // An octave range, and clamping beyond the maximum
+        synth.set_bend_range(12.0);
+        synth.set_pitch_wheel(4096);
+        let half = synth.controls.freqs[0].value() / 440.0;
+        synth.set_bend_range(48.0);
+        synth.set_pitch_wheel(8191);
+        assert_eq!(synth.bend_range, BEND_RANGE_MAX);
+        assert!(half > 1.3 && half < 1.5);
+    }
+
+    #[test]
+    fn sostenuto_holds_a_bass_note_under_a_moving_melody() {
+        let mut synth = KeyboardSynth::new();
+        press(&mut synth, 0, 0);
+        synth.set_sostenuto(true);
+        release(&mut synth, 0, 0);
+        assert_eq!(synth.gate(0), 1.0);
+
+        // A melody long enough to cycle through every voice twice: each
+        // note releases normally and none takes the bass voice
+        for step in 0..2 * VOICE_COUNT {
+            let key = [0, 2, 4, 5, 7, 9, 11][step % 7];
+            press(&mut synth, key, 2);
+            let voice = (0..VOICE_COUNT)
+                .find(|&v| synth.voice_note(v) == Some(encode_note(key as u8, 2)))
+                .unwrap();
+            assert_ne!(voice, 0);
+            release(&mut synth, key, 2);
+            assert_eq!(synth.gate(voice), 0.0);
+        }
+        // With every other voice held, a new note steals one of them, never
+        // the captured bass voice
+        for key in 0..VOICE_COUNT - 1 {
+            press(&mut synth, key, 3);
+        }
+        press(&mut synth, 11, 3);
+        assert_eq!(synth.voice_note(0), Some(encode_note(0, 0)));
+        assert_eq!(synth.gate(0), 1.0);
+        assert!((1..VOICE_COUNT).any(|v| synth.voice_note(v) == Some(encode_note(11, 3))));
+
+        synth.set_sostenuto(false);
+        assert_eq!(synth.gate(0), 0.0);
+        assert_eq!(synth.gate(1), 1.0);
+    }
+
+    #[test]
+    fn sensor_sample_and_hold_steps_with_hysteresis() {
+        let mut synth = KeyboardSynth::new();
+        press(&mut synth, 9, 1); // A4
+        synth.set_sensor_position(0.3);
+        assert_eq!(synth.controls.freqs[0].value(), 440.0);
+
+        // A hand sweeping away: one random semitone per zone
+        synth.set_sensor_sh(true, 8);
+        let mut levels = 0;
+        let mut last = 0.0;
+        for step in 0..=100 {
+            synth.set_sensor_position(step as f32 / 100.0);
+            let freq = synth.controls.freqs[0].value();
+            let semitones = 12.0 * libm::log2f(freq / 440.0);
+            assert!((semitones - libm::roundf(semitones)).abs() < 1e-3);
+            assert!((0.0..=12.0).contains(&semitones));
+            if freq != last {
+                levels += 1;
+                last = freq;
+            }
+        }
+        assert!(levels >= 6, "{levels}");
+        assert_eq!(synth.sensor_sh.zone, Some(7));
+
+        // Jitter around a zone edge doesn't flicker
+        synth.set_sensor_position(0.51);
+        let held = synth.controls.freqs[0].value();
+        for position in [0.49, 0.52, 0.48, 0.51, 0.47] {
+            synth.set_sensor_position(position);
+            assert_eq!(synth.controls.freqs[0].value(), held);
+        }
+        synth.set_sensor_position(0.45);
+        assert_eq!(synth.sensor_sh.zone, Some(3));
+
+        synth.set_sensor_sh_target(SensorShTarget::Filter);
+        assert_eq!(synth.controls.freqs[0].value(), 440.0);
+        let ratio = synth.controls.lfo_cutoff.value();
+        assert!((0.25..=4.0).contains(&ratio) && ratio != 1.0);
+        synth.set_sensor_sh(false, 8);
+        assert_eq!(synth.controls.lfo_cutoff.value(), 1.0);
+    }
+
+    #[test]
+    fn velocity_to_attack_swells_soft_notes_and_snaps_hard_ones() {
+        /// Level of a note 40 ms and 300 ms into a 400 ms attack
+        fn levels(velocity: u8) -> (f32, f32) {
+            // Measured from the first buffer, so without the fade-in
+            let mut synth = synth_with_envelope(0.4, 0.1, 1.0, 0.1);
+            synth.set_velocity_to_attack(1.0);
+            synth.note_on(9, 1, velocity);
+            let mut early = [0.0f32; 1764];
+            synth.process_block(&mut early, 1764);
+            let mut late = [0.0f32; 11466];
+            synth.process_block(&mut late, 11466);
+            // Normalized by velocity, so only the envelope shape differs
+            let gain = VelocityCurve::Linear.gain(velocity);
+            (rms(&early[1323..]) / gain, rms(&late[11466 - 441..]) / gain)
+        }
+        let (soft_early, soft_late) = levels(20);
+        let (hard_early, hard_late) = levels(VELOCITY_MAX);
+        // The hard note is already near full level, the soft one still rising
+        assert!(hard_early > 3.0 * soft_early, "{hard_early} {soft_early}");
+        assert!(soft_late > 2.0 * soft_early);
+        assert!((hard_late - hard_early).abs() < 0.2 * hard_late);
+    }
+
+    #[test]
+    fn looper_plays_a_chord_loop_under_a_solo() {
+        let mut synth = KeyboardSynth::new();
+        let mut block = [0.0f32; 22050];
+        synth.looper_record().unwrap();
+        assert_eq!(synth.looper_state(), LooperState::Recording);
+        for key in [0, 4, 7] {
+            press(&mut synth, key, 0); // C3 chord
+        }
+        synth.process_block(&mut block, 22050);
+        for key in [0, 4, 7] {
+            release(&mut synth, key, 0);
+        }
+        synth.looper_play();
+        assert_eq!(synth.looper.len_frames(), 22050);
+
+        // Let the release die out while the loop comes round once
+        synth.process_block(&mut block, 22050);
+        synth.process_block(&mut block, 22050);
+        let c3 = 130.81;
+        assert!(tone_power(&block, c3) > 0.01, "{}", tone_power(&block, c3));
+
+        // A solo note on top: both sound
+        press(&mut synth, 9, 2); // A5
+        synth.process_block(&mut block, 22050);
+        assert!(tone_power(&block[11025..], 880.0) > 0.01);
+        assert!(tone_power(&block[11025..], c3) > 0.01);
+
+        // Overdub the solo, then stop and clear
+        synth.looper_record().unwrap();
+        synth.process_block(&mut block, 22050);
+        release(&mut synth, 9, 2);
+        synth.looper_play();
+        for _ in 0..3 {
+            synth.process_block(&mut block, 22050);
+        }
+        assert!(tone_power(&block, 880.0) > 0.01);
+        synth.looper_stop();
+        synth.process_block(&mut block, 22050);
+        assert!(block.iter().all(|s| s.abs() < 1e-3));
+        synth.looper_clear();
+        assert_eq!(synth.looper_state(), LooperState::Empty);
+        assert_eq!(crate::looper::LOOPER_BYTES, 176_400);
+    }
+
+    #[test]
+    fn idle_voices_are_skipped_after_their_release() {
+        let mut skipping = KeyboardSynth::new();
+        let mut full = KeyboardSynth::new();
+        full.set_skip_idle_voices(false);
+        let mut a = [0.0f32; 441];
+        let mut b = [0.0f32; 441];
+        for synth in [&mut skipping, &mut full] {
+            synth.set_envelope(0.01, 0.1, 0.7, 0.2);
+            // Past the first release time, when every voice counts as idle
+            for _ in 0..25 {
+                synth.process_block(&mut a, 441);
+            }
+            press(synth, 0, 1);
+        }
+
+        // Held, then released: the tail plays out as without skipping, and
+        // the voice stops running once it has decayed
+        for block in 0..40 {
+            if block == 10 {
+                release(&mut skipping, 0, 1);
+                release(&mut full, 0, 1);
+            }
+            skipping.process_block(&mut a, 441);
+            full.process_block(&mut b, 441);
+            // Same level after the attack; the oscillator phase and the
+            // envelope's update steps differ, the idle voice having stood
+            // still before the press
+            let (rms_a, rms_b) = (rms(&a), rms(&b));
+            if block > 0 {
+                assert!(
+                    (rms_a - rms_b).abs() <= 0.05 * rms_b + 1e-4,
+                    "block {block}"
+                );
+            }
+            if block == 0 {
+                assert_eq!(skipping.controls.voice_active[0].value(), 1.0);
+                assert_eq!(skipping.controls.voice_active[1].value(), 0.0);
+            }
+        }
+        assert_eq!(skipping.controls.voice_active[0].value(), 0.0);
+        assert_eq!(full.controls.voice_active[0].value(), 1.0);
+    }
+
+    #[test]
+    fn dither_turns_tail_distortion_into_noise() {
+        /// A note's last moments, a 1 kHz sine fading from 4 LSB to
+        /// nothing, through the DAC conversion: the 1 kHz power and the
+        /// 3rd harmonic relative to it
+        fn fade_out(dither: bool) -> (f32, f32) {
+            let mut synth = KeyboardSynth::new();
+            synth.set_dither(dither);
+            let n = 8820;
+            let mut out = [0.0f32; 8820];
+            for (i, sample) in out.iter_mut().enumerate() {
+                let level = 4.0 / 32767.0 * (1.0 - i as f32 / n as f32);
+                let x = level * libm::sinf(core::f32::consts::TAU * 1000.0 * i as f32 / 44100.0);
+                *sample = synth.to_dac_sample(x) as f32 / 32767.0;
+            }
+            let fundamental = tone_power(&out, 1000.0);
+            (fundamental, tone_power(&out, 3000.0) / fundamental)
+        }
+        let (plain, plain_h3) = fade_out(false);
+        let (dithered, dithered_h3) = fade_out(true);
+        // Truncating the tail leaves a strong harmonic; dither removes it
+        // and keeps the note's level
+        assert!(dithered_h3 < plain_h3 / 10.0, "{dithered_h3} {plain_h3}");
+        assert!(dithered > plain, "{dithered} {plain}");
+
+        let mut synth = KeyboardSynth::new();
+        synth.set_dither(true);
+        synth.set_output_trim(100);
+        let samples: [i16; 64] = core::array::from_fn(|_| synth.to_dac_sample(0.0));
+        assert!(samples.iter().all(|&s| (99..=101).contains(&s)));
+        assert_eq!(synth.to_dac_sample(2.0), i16::MAX);
+
+        // Wide samples carry the trim in the same 16-bit steps and keep
+        // detail far below the 16-bit LSB
+        assert_eq!(synth.to_dac_sample_wide(0.0), 100 << 16);
+        synth.set_output_trim(0);
+        assert_eq!(synth.to_dac_sample_wide(1e-6), 2147);
+        assert_eq!(synth.to_dac_sample_wide(-2.0), i32::MIN);
+    }
+
+    #[test]
+    fn dual_contact_velocity_follows_key_speed() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        fn now() -> u64 {
+            NOW.load(Ordering::Relaxed)
+        }
+        let mut synth = KeyboardSynth::new();
+        synth.set_contact_clock(now);
+        // The raw velocities, without the floor lifting the slowest
+        synth.set_velocity_floor(1);
+        // Press a key with `travel_us` between the contacts, return its gain
+        let mut strike = |key: usize, travel_us: u64| {
+            NOW.fetch_add(100_000, Ordering::Relaxed);
+            assert!(!synth.update_key_dual(key, 1, false, true));
+            NOW.fetch_add(travel_us, Ordering::Relaxed);
+            assert!(synth.update_key_dual(key, 1, true, true));
+            let voice = (0..VOICE_COUNT)
+                .find(|&v| synth.voice_note(v) == Some(encode_note(key as u8, 1)))
+                .unwrap();
+            let gain = synth.controls.velocities[voice].value();
+            // Up past the lower contact only: still sounding
+            assert!(!synth.update_key_dual(key, 1, false, true));
+            assert!(synth.update_key_dual(key, 1, false, false));
+            assert_eq!(synth.gate(voice), 0.0);
+            gain
+        };
+        let hard = strike(0, 1_500);
+        let medium = strike(2, 12_000);
+        let soft = strike(4, 80_000);
+        assert_eq!(hard, 1.0);
+        assert!(
+            soft < 0.02 && soft < medium && medium < hard,
+            "{soft} {medium}"
+        );
+        assert_eq!(contact_velocity(CONTACT_TIME_SLOW / 2), 27);
+
+        // A single-contact key reports both at once: full velocity
+        assert!(synth.update_key_dual(5, 1, true, true));
+        assert_eq!(synth.voice_note(3), Some(encode_note(5, 1)));
+        assert_eq!(synth.controls.velocities[3].value(), 1.0);
+    }
+
+    #[test]
+    fn current_chord_names_the_held_keys() {
+        let mut synth = KeyboardSynth::new();
+        assert_eq!(synth.current_chord(), None);
+        // D minor in first inversion: F3 A3 D4
+        for (key, octave) in [(5, 0), (9, 0), (2, 1)] {
+            press(&mut synth, key, octave);
+        }
+        let chord = synth.current_chord().unwrap();
+        assert_eq!(chord.quality, crate::chord::ChordQuality::Minor);
+        assert_eq!((chord.root, chord.bass), (2, 5));
+        release(&mut synth, 5, 0);
+        assert_eq!(synth.current_chord().map(|c| c.bass), Some(9));
+    }
+
+    #[test]
+    fn octave_gain_tames_the_bass() {
+        let mut synth = KeyboardSynth::new();
+        // Flat by default
+        press(&mut synth, 0, 0);
+        press(&mut synth, 0, 3);
+        assert_eq!(synth.controls.velocities[0].value(), 1.0);
+        assert_eq!(synth.controls.velocities[1].value(), 1.0);
+        release(&mut synth, 0, 0);
+        release(&mut synth, 0, 3);
+
+        // Halve octave 0; the others are untouched
+        synth.set_octave_gain(0, 0.5);
+        synth.set_octave_gain(9, 0.1);
+        press(&mut synth, 2, 0);
+        press(&mut synth, 2, 1);
+        let gain = |synth: &KeyboardSynth, note| {
+            let voice = synth.voice_note.iter().position(|&n| n == note).unwrap();
+            synth.controls.velocities[voice].value()
+        };
+        assert_eq!(gain(&synth, encode_note(2, 0)), 0.5);
+        assert_eq!(gain(&synth, encode_note(2, 1)), 1.0);
+
+        // A -3 dB/octave tilt pivots on the middle of the keyboard
+        synth.set_tilt(-3.0);
+        press(&mut synth, 4, 0);
+        press(&mut synth, 4, 3);
+        let low = 20.0 * libm::log10f(gain(&synth, encode_note(4, 0)));
+        let high = 20.0 * libm::log10f(gain(&synth, encode_note(4, 3)));
+        assert!((low + 4.5).abs() < 0.01, "{low}");
+        assert!((high - 4.5).abs() < 0.01, "{high}");
+        // The steepest tilt would lift the top octave 9 dB; it stops at 2.0
+        synth.set_tilt(-TILT_MAX);
+        assert_eq!(synth.octave_gains[3], OCTAVE_GAIN_MAX);
+    }
+
+    #[test]
+    fn granular_freeze_holds_a_released_chord() {
+        let mut synth = KeyboardSynth::new();
+        let mut block = [0.0f32; 22050];
+        for key in [0, 4, 7] {
+            press(&mut synth, key, 1); // C4 chord
+        }
+        synth.process_block(&mut block, 4410);
+        synth.set_granular_freeze(true, 80.0).unwrap();
+        assert_eq!(synth.granular_freeze_state(), FreezeState::Capturing);
+        synth.process_block(&mut block, 22050);
+        synth.process_block(&mut block, 4410);
+        assert_eq!(synth.granular_freeze_state(), FreezeState::Frozen);
+
+        // Let go and wait out the release: the chord rings on in the grains
+        for key in [0, 4, 7] {
+            release(&mut synth, key, 1);
+        }
+        for _ in 0..4 {
+            synth.process_block(&mut block, 22050);
+        }
+        assert!(synth.controls.gates.iter().all(|gate| gate.value() == 0.0));
+        let c4 = 261.63;
+        let e4 = 329.63;
+        assert!(tone_power(&block, c4) > 0.001, "{}", tone_power(&block, c4));
+        assert!(tone_power(&block, e4) > 0.001, "{}", tone_power(&block, e4));
+
+        // Off fades out and frees the buffer
+        synth.set_granular_freeze(false, 0.0).unwrap();
+        synth.process_block(&mut block, 22050);
+        assert_eq!(synth.granular_freeze_state(), FreezeState::Off);
+        assert!(block[4410..].iter().all(|s| s.abs() < 1e-3));
+        assert_eq!(crate::granular::FREEZE_BYTES, 52_920);
+    }
+
+    #[test]
+    fn freeze_holds_an_evolving_chord() {
+        // A C4 chord swelling in over 2 s, frozen halfway up
+        let mut synth = synth_with_envelope(2.0, 0.0, 1.0, 0.5);
+        for key in [0, 4, 7] {
+            press(&mut synth, key, 1);
+        }
+        render(&mut synth, 44100, 64);
+        synth.freeze(true);
+        // Past the envelope steps already under way
+        render(&mut synth, 441, 64);
+        let frozen = render(&mut synth, 44100, 64);
+        // Each chord note's level against the frozen one, over a second so
+        // the other notes' partials don't leak in
+        let unchanged = |samples: &[f32]| {
+            [261.63, 329.63, 392.0].iter().all(|&freq| {
+                let change = tone_power(samples, freq) / tone_power(&frozen, freq);
+                (change - 1.0).abs() < 0.05
+            })
+        };
+
+        // Holding on, the swell stops where it was
+        render(&mut synth, 22050, 64);
+        assert!(unchanged(&render(&mut synth, 44100, 64)));
+
+        // Letting the keys go releases nothing
+        for key in [0, 4, 7] {
+            release(&mut synth, key, 1);
+        }
+        render(&mut synth, 44100, 64);
+        assert!(unchanged(&render(&mut synth, 44100, 64)));
+
+        // A new note plays over the drone without changing it
+        press(&mut synth, 9, 1);
+        render(&mut synth, 22050, 64);
+        let over = render(&mut synth, 44100, 64);
+        assert!(tone_power(&over, 440.0) > 0.1 * tone_power(&frozen, 261.63));
+        assert!(unchanged(&over));
+
+        // Unfrozen, the chord releases from the held level
+        release(&mut synth, 9, 1);
+        synth.freeze(false);
+        render(&mut synth, 44100, 64);
+        assert!(rms(&render(&mut synth, 44100, 64)) < 1e-4);
+    }
+
+    #[test]
+    fn phase_reset_repeats_identical_attacks() {
+        /// Two C2 notes played one after the other
+        fn notes(synth: &mut KeyboardSynth) -> ([f32; 2205], [f32; 2205]) {
+            let mut first = [0.0f32; 2205];
+            let mut second = [0.0f32; 2205];
+            let mut gap = [0.0f32; 22050];
+            press(synth, 0, 0);
+            synth.process_block(&mut first, 2205);
+            release(synth, 0, 0);
+            // Long enough apart for the release to finish and the phase to
+            // have wandered
+            synth.process_block(&mut gap, 22050);
+            synth.process_block(&mut gap[..1234], 1234);
+            press(synth, 0, 0);
+            synth.process_block(&mut second, 2205);
+            release(synth, 0, 0);
+            synth.process_block(&mut gap, 22050);
+            (first, second)
+        }
+        /// Same waveform within 2% of the peak, past the first 10 ms where
+        /// the envelope's jittered 2 ms steps land differently
+        fn same((first, second): ([f32; 2205], [f32; 2205])) -> bool {
+            let peak = first.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            assert!(peak > 0.005);
+            first[441..]
+                .iter()
+                .zip(&second[441..])
+                .all(|(a, b)| (a - b).abs() < 0.02 * peak)
+        }
+        let bass = |synth: &mut KeyboardSynth| synth.set_envelope(0.002, 0.3, 0.5, 0.05);
+
+        let mut synth = KeyboardSynth::new();
+        bass(&mut synth);
+        assert!(!same(notes(&mut synth)));
+        synth.set_phase_reset(true);
+        assert!(same(notes(&mut synth)));
+
+        let mut fixed = KeyboardSynth::new();
+        fixed.set_render_backend(RenderBackend::Fixed);
+        bass(&mut fixed);
+        assert!(!same(notes(&mut fixed)));
+        fixed.set_phase_reset(true);
+        assert!(same(notes(&mut fixed)));
+    }
+
+    #[test]
+    fn drums_play_four_on_the_floor_under_live_playing() {
+        use crate::drums::DrumTrack;
+
+        let mut synth = KeyboardSynth::new();
+        let drums = synth.drums_mut();
+        drums.four_on_the_floor();
+        assert!(drums.step(DrumTrack::Kick, 4));
+        assert!(!drums.step(DrumTrack::Kick, 5));
+        assert!(drums.step(DrumTrack::Snare, 12));
+        synth.set_drums_playing(true);
+        for key in [0, 4, 7] {
+            press(&mut synth, key, 1);
+        }
+        // Two bars at 120 BPM, a beat every 22050 frames
+        let bars = render(&mut synth, 4 * 22050, 640);
+        // The drums use none of the voices: all three notes still sound
+        assert_eq!(
+            synth
+                .controls
+                .gates
+                .iter()
+                .filter(|g| g.value() > 0.0)
+                .count(),
+            3
+        );
+        assert!(tone_power(&bars[66150..], 261.63) > 0.001);
+        // A kick opens every beat, well above the end of the beat
+        for beat in 0..4 {
+            let start = beat * 22050;
+            let hit = power(&bars[start..start + 2205]);
+            let tail = power(&bars[start + 19845..start + 22050]);
+            assert!(hit > 4.0 * tail, "beat {beat}: {hit} {tail}");
+        }
+
+        // Stopped, the last hits ring out and the chord is left
+        synth.set_drums_playing(false);
+        let mut block = [0.0f32; 22050];
+        synth.process_block(&mut block, 22050);
+        synth.process_block(&mut block, 22050);
+        for key in [0, 4, 7] {
+            release(&mut synth, key, 1);
+        }
+        synth.process_block(&mut block, 22050);
+        synth.process_block(&mut block, 22050);
+        assert!(block.iter().all(|s| s.abs() < 1e-3));
+    }
+
+    #[test]
+    fn drum_edit_combo_sets_steps_from_the_keys() {
+        use crate::drums::DrumTrack;
+
+        let mut synth = KeyboardSynth::new();
+        synth.set_drum_edit_combo(true);
+        let [(low, low_octave), (high, high_octave)] = DRUM_EDIT_COMBO;
+        press(&mut synth, low, low_octave);
+        press(&mut synth, high, high_octave);
+        // The combo cuts the note its first key played and starts the drums
+        assert_eq!(synth.keyboard_mode(), KeyboardMode::DrumEdit);
+        assert!((0..VOICE_COUNT).all(|v| synth.voice_note(v).is_none()));
+        assert!(synth.drums.is_playing());
+        release(&mut synth, low, low_octave);
+        release(&mut synth, high, high_octave);
+
+        // Kick step 4, snare step 4 on the next row, hat step 2 on the
+        // third; a second press clears a step again
+        for (key, octave) in [(4, 0), (8, 1), (10, 2), (5, 0), (5, 0)] {
+            press(&mut synth, key, octave);
+            release(&mut synth, key, octave);
+        }
+        assert!(synth.drums.step(DrumTrack::Kick, 4));
+        assert!(synth.drums.step(DrumTrack::Snare, 4));
+        assert!(synth.drums.step(DrumTrack::Hat, 2));
+        assert!(!synth.drums.step(DrumTrack::Kick, 5));
+        assert!((0..VOICE_COUNT).all(|v| synth.voice_note(v).is_none()));
+
+        // The combo again leaves the mode without its keys' steps set, and
+        // the beat plays on under the keys
+        press(&mut synth, low, low_octave);
+        press(&mut synth, high, high_octave);
+        release(&mut synth, low, low_octave);
+        release(&mut synth, high, high_octave);
+        assert_eq!(synth.keyboard_mode(), KeyboardMode::Chromatic);
+        assert!(!synth.drums.step(DrumTrack::Kick, 0));
+        assert!(!synth.drums.step(DrumTrack::Hat, 15));
+        assert!(synth.drums.is_playing());
+        press(&mut synth, 9, 1);
+        assert!(synth.voice_note(0).is_some());
+        release(&mut synth, 9, 1);
+
+        // Leaving with every step cleared stops the drums
+        press(&mut synth, low, low_octave);
+        press(&mut synth, high, high_octave);
+        release(&mut synth, low, low_octave);
+        release(&mut synth, high, high_octave);
+        for (key, octave) in [(4, 0), (8, 1), (10, 2)] {
+            press(&mut synth, key, octave);
+            release(&mut synth, key, octave);
+        }
+        press(&mut synth, low, low_octave);
+        press(&mut synth, high, high_octave);
+        assert_eq!(synth.keyboard_mode(), KeyboardMode::Chromatic);
+        assert!(!synth.drums.is_playing());
+    }
+
+    #[test]
+    fn synced_arp_stays_locked_to_the_drums() {
+        /// Frames between the arp's next step and the drums' after 16 bars
+        /// at a tempo whose 16ths aren't a whole number of frames
+        fn offset(sync: bool) -> u64 {
+            let mut synth = KeyboardSynth::new();
+            synth.set_tempo(130.0);
+            if sync {
+                synth.set_arp_sync(Some(NoteDivision::Sixteenth));
+            } else {
+                synth.set_arp_rate(130.0 / 15.0);
+            }
+            synth.set_arpeggiator(Some(ArpMode::Up));
+            synth.drums_mut().four_on_the_floor();
+            synth.set_drums_playing(true);
+            press(&mut synth, 0, 1);
+            press(&mut synth, 7, 1);
+            let mut block = [0.0f32; 640];
+            // 16 bars of 16 steps of ~5088.5 frames
+            for _ in 0..16 * 16 * 5088 / 640 {
+                synth.process_block(&mut block, 640);
+            }
+            let arp = synth.arp_next_step - synth.clock;
+            arp.abs_diff(synth.drums.frames_to_step())
+        }
+        assert!(offset(true) <= 1, "{}", offset(true));
+        // A free rate at the same speed drifts half a frame a step
+        assert!(offset(false) > 50, "{}", offset(false));
+    }
+
+    #[test]
+    fn filter_keytrack_keeps_a_lead_bright_across_octaves() {
+        /// Power of the 4th harmonic over the fundamental for C in each of
+        /// three octaves
+        fn brightness(keytrack: f32) -> [f32; 3] {
+            let mut synth = KeyboardSynth::new();
+            synth.set_filter_keytrack(keytrack);
+            synth.controls.filter_cutoff.set_value(600.0);
+            let mut block = [0.0f32; 8820];
+            core::array::from_fn(|octave| {
+                press(&mut synth, 0, octave as u8);
+                synth.process_block(&mut block, 8820);
+                synth.process_block(&mut block, 8820);
+                release(&mut synth, 0, octave as u8);
+                let c = 130.81 * (1 << octave) as f32;
+                tone_power(&block, 4.0 * c) / tone_power(&block, c)
+            })
+        }
+        let fixed = brightness(0.0);
+        let tracked = brightness(1.0);
+        // A fixed cutoff dulls the top octave to under a tenth of the bottom's
+        // harmonics (the resonator peak keeps the rest)
+        assert!(fixed[2] < 0.1 * fixed[0], "{fixed:?}");
+        // Tracked, the three octaves stay within a few times of each other
+        assert!(tracked[2] > 0.25 * tracked[0], "{tracked:?}");
+        assert!(tracked[2] > 3.0 * fixed[2], "{tracked:?} {fixed:?}");
+    }
+
+    #[test]
+    fn output_fades_in_from_silence_at_startup() {
+        /// Peak level of each 5 ms of the first 100 ms, with a note held
+        fn envelope(synth: &mut KeyboardSynth) -> [f32; 20] {
+            synth.set_envelope(0.0, 0.0, 1.0, 0.1);
+            press(synth, 9, 1);
+            let mut block = [0.0f32; 4410];
+            synth.process_block(&mut block, 4410);
+            core::array::from_fn(|i| {
+                block[i * 220..(i + 1) * 220]
+                    .iter()
+                    .fold(0.0f32, |peak, s| peak.max(s.abs()))
+            })
+        }
+        let mut synth = KeyboardSynth::new();
+        let faded = envelope(&mut synth);
+        let mut synth = KeyboardSynth::new();
+        synth.set_startup_fade(Duration::ZERO);
+        let full = envelope(&mut synth);
+
+        // Rises steadily over the default 50 ms, then matches full level
+        assert!(faded[0] < 0.1 * full[0], "{faded:?}");
+        for i in 1..10 {
+            assert!(faded[i] > faded[i - 1], "{faded:?}");
+        }
+        assert!((faded[15] - full[15]).abs() < 0.01 * full[15]);
+        // Only once: muting later uses the normal short ramp
+        synth.set_output_mute(true);
+        let mut block = [0.0f32; 441];
+        synth.process_block(&mut block, 441);
+        synth.process_block(&mut block, 441);
+        assert!(block.iter().all(|s| s.abs() < 1e-6));
+    }
+
+    #[test]
+    fn mono_safe_brings_a_wide_chorus_back_into_phase() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_startup_fade(Duration::ZERO);
+        let mut left = [0.0f32; 640];
+        let mut right = [0.0f32; 640];
+        let mut play = |synth: &mut KeyboardSynth, buffers: usize| {
+            for _ in 0..buffers {
+                synth.process_block_stereo(&mut left, &mut right);
+            }
+        };
+        // Same signal on both sides without the chorus
+        press(&mut synth, 0, 1);
+        play(&mut synth, 20);
+        assert!(synth.stereo_correlation() > 0.99);
+
+        // A wide chorus pulls the channels apart: low correlation
+        synth.set_chorus(4, 0.015, 0.01, 0.5);
+        synth.set_chorus_enabled(true);
+        synth.set_stereo_width(2.0);
+        press(&mut synth, 4, 1);
+        press(&mut synth, 7, 1);
+        let mut lowest: f32 = 1.0;
+        for _ in 0..50 {
+            play(&mut synth, 1);
+            lowest = lowest.min(synth.stereo_correlation());
+        }
+        assert!(lowest < 0.2, "{lowest}");
+
+        // Mono-safe narrows it until the correlation stays positive
+        synth.set_mono_safe(true);
+        play(&mut synth, 50);
+        for _ in 0..100 {
+            play(&mut synth, 1);
+            assert!(
+                synth.stereo_correlation() > 0.0,
+                "{}",
+                synth.stereo_correlation()
+            );
+        }
+        assert!(synth.controls.stereo_width.value() < 2.0);
+        synth.set_mono_safe(false);
+        assert_eq!(synth.controls.stereo_width.value(), 2.0);
+    }
+
+    #[test]
+    fn fx_order_changes_the_drive_tone() {
+        /// Power of the 5th harmonic over the fundamental of a driven A3
+        fn brightness(order: &[FxStage]) -> f32 {
+            let mut synth = KeyboardSynth::new();
+            synth.set_drive(1.0);
+            synth.set_fx_order(order).unwrap();
+            synth.set_filter_slope(FilterSlope::TwoPole12);
+            synth.controls.filter_cutoff.set_value(400.0);
+            press(&mut synth, 9, 0);
+            let mut block = [0.0f32; 8820];
+            synth.process_block(&mut block, 8820);
+            synth.process_block(&mut block, 8820);
+            tone_power(&block, 220.0 * 5.0) / tone_power(&block, 220.0)
+        }
+        let drive_first = brightness(&FX_ORDER);
+        let filter_first = brightness(&[
+            FxStage::Filter,
+            FxStage::Drive,
+            FxStage::Resonator,
+            FxStage::Formant,
+        ]);
+        // Clipping after the filter puts back the highs it took out
+        assert!(
+            filter_first > 4.0 * drive_first,
+            "{filter_first} {drive_first}"
+        );
+
+        // Only permutations of every stage
+        let mut synth = KeyboardSynth::new();
+        let err = Err(SynthError::InvalidFxOrder);
+        assert_eq!(synth.set_fx_order(&[FxStage::Filter]), err);
+        let doubled = [
+            FxStage::Filter,
+            FxStage::Filter,
+            FxStage::Drive,
+            FxStage::Formant,
+        ];
+        assert_eq!(synth.set_fx_order(&doubled), err);
+        assert_eq!(synth.topology.fx_order, FX_ORDER);
+    }
+
+    #[test]
+    fn state_bytes_reproduce_every_parameter() {
+        assert!(KeyboardSynth::new().state() == SynthState::default());
+
+        let mut synth = KeyboardSynth::new();
+        synth.apply_preset(&Preset {
+            waveform: Waveform::Triangle,
+            filter_slope: FilterSlope::TwoPole12,
+            filter_cutoff: 900.0,
+            filter_resonance: 0.4,
+            resonator_freq: 660.0,
+            attack: 0.2,
+            decay: 0.3,
+            sustain: 0.6,
+            release: 1.2,
+        });
+        synth.set_envelope_curve(EnvCurve::Exponential);
+        synth.set_filter_keytrack(0.5);
+        synth.set_drive(0.7);
+        synth
+            .set_fx_order(&[
+                FxStage::Filter,
+                FxStage::Drive,
+                FxStage::Formant,
+                FxStage::Resonator,
+            ])
+            .unwrap();
+        synth.set_formant_enabled(true);
+        synth.set_formant(Vowel::O);
+        synth.set_chorus_enabled(true);
+        synth.set_delay_pingpong(true);
+        synth.set_delay_feedback(0.5);
+        synth.set_glide_time(0.15);
+        synth.set_glide_curve(GlideCurve::Linear);
+        synth.set_lfo(LfoShape::Triangle, 3.0);
+        synth.set_lfo_depth(LfoDestination::Pitch, 0.3);
+        synth.set_lfo_depth(LfoDestination::Filter, -1.5);
+        synth.set_lfo_depth(LfoDestination::Amplitude, 0.2);
+        synth.set_lfo_depth(LfoDestination::Pan, 0.8);
+        synth.set_pitch_envelope(-12.0, 0.05);
+        synth.set_bend_range(7.0);
+        synth.set_tilt(-2.0);
+        synth.set_octave_gain(3, 0.4);
+        synth.set_velocity_to_cutoff(1.5);
+        synth.set_velocity_to_attack(0.6);
+        synth.set_voice_highpass_tracking(0.3);
+        synth.set_analog_drift(0.25);
+        synth.set_stereo_width(1.6);
+        synth.set_mono_safe(true);
+        synth.set_auto_pan(AutoPanMode::ByVoice);
+
+        let state = synth.state();
+        assert!(state != SynthState::default());
+        let bytes = synth.serialize_state();
+        let mut restored = KeyboardSynth::new();
+        restored.deserialize_state(&bytes).unwrap();
+        assert!(restored.state() == state);
+        assert_eq!(restored.topology.delay, Some(true));
+
+        assert_eq!(
+            restored.deserialize_state(&bytes[..8]),
+            Err(SynthError::InvalidState)
+        );
+        assert!(restored.state() == state);
+    }
+
+    #[test]
+    fn reconcile_voices_fixes_missed_key_edges() {
+        let mut synth = KeyboardSynth::new();
+        press(&mut synth, 0, 1);
+        press(&mut synth, 4, 1);
+        assert_eq!(synth.reconcile_voices(), 0);
+
+        // A missed release of E and a missed press of G
+        synth.key_states[1][4] = false;
+        synth.key_states[1][7] = true;
+        assert_eq!(synth.reconcile_voices(), 2);
+        let gated = |synth: &KeyboardSynth, key: u8| {
+            (0..VOICE_COUNT).any(|v| {
+                synth.voice_note[v] == encode_note(key, 1) && synth.controls.gates[v].value() > 0.0
+            })
+        };
+        assert!(gated(&synth, 0) && !gated(&synth, 4) && gated(&synth, 7));
+        assert_eq!(synth.reconcile_voices(), 0);
+
+        // Keys held through mono mode come back when it is switched off
+        synth.set_mono(true);
+        synth.set_mono(false);
+        assert!(!gated(&synth, 0));
+        assert_eq!(synth.reconcile_voices(), 2);
+        assert!(gated(&synth, 0) && gated(&synth, 7));
+
+        // A timed-out note stays released
+        synth.set_max_note_duration(Some(10));
+        let mut block = [0.0f32; 882];
+        synth.process_block(&mut block, 882);
+        synth.process_block(&mut block, 882);
+        assert!(!gated(&synth, 0));
+        assert_eq!(synth.reconcile_voices(), 0);
+    }
+
+    #[test]
+    fn click_suppression_ramps_a_zero_attack_pluck() {
+        /// Largest sample-to-sample step of a zero-attack, zero-release C3
+        /// sine pluck through an open filter, as a fraction of its peak
+        fn largest_step(backend: RenderBackend, suppression: bool) -> f32 {
+            let mut synth = KeyboardSynth::new();
+            synth.set_startup_fade(Duration::ZERO);
+            synth.set_render_backend(backend);
+            synth.set_click_suppression(suppression);
+            synth.apply_preset(&Preset {
+                waveform: Waveform::Sine,
+                attack: 0.0,
+                decay: 0.0,
+                sustain: 1.0,
+                release: 0.0,
+                filter_cutoff: 18000.0,
+                ..Preset::default()
+            });
+            let mut block = [0.0f32; 4410];
+            let mut samples = alloc::vec::Vec::new();
+            synth.process_block(&mut block, 1000);
+            samples.extend_from_slice(&block[..1000]);
+            press(&mut synth, 0, 0);
+            synth.process_block(&mut block, 4410);
+            samples.extend_from_slice(&block);
+            release(&mut synth, 0, 0);
+            synth.process_block(&mut block, 4410);
+            samples.extend_from_slice(&block);
+            let peak = samples.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+            let step = samples
+                .windows(2)
+                .fold(0.0f32, |step, w| step.max((w[1] - w[0]).abs()));
+            step / peak
+        }
+        // A C3 sine moves at most 0.019 of its peak per sample by itself
+        assert!(largest_step(RenderBackend::Float, true) < 0.025);
+        assert!(largest_step(RenderBackend::Fixed, true) < 0.025);
+        // The fixed backend's linear envelope steps without it
+        assert!(largest_step(RenderBackend::Fixed, false) > 0.2);
+    }
+
+    #[test]
+    fn unison_drift_moves_a_held_note_within_bounds() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_voice_spread(0.0);
+        press(&mut synth, 9, 1);
+        let voice = (0..VOICE_COUNT)
+            .find(|&v| synth.voice_note[v] == encode_note(9, 1))
+            .unwrap();
+        let mut block = [0.0f32; 441];
+        let mut cents = alloc::vec::Vec::new();
+        let mut render = |synth: &mut KeyboardSynth, cents: &mut alloc::vec::Vec<f32>| {
+            for _ in 0..400 {
+                synth.process_block(&mut block, 441);
+                let ratio = synth.controls.freqs[voice].value() / 440.0;
+                cents.push(1200.0 * libm::log2f(ratio));
+            }
+        };
+        // Off: the note holds its pitch
+        render(&mut synth, &mut cents);
+        assert!(cents.iter().all(|c| c.abs() < 0.01));
+
+        // Four seconds of a held note sweeping up and down, never further
+        // than the depth
+        synth.set_unison_drift(0.5, 10.0);
+        cents.clear();
+        render(&mut synth, &mut cents);
+        let highest = cents.iter().fold(f32::MIN, |a, &b| a.max(b));
+        let lowest = cents.iter().fold(f32::MAX, |a, &b| a.min(b));
+        assert!(highest > 8.0 && lowest < -8.0, "{highest} {lowest}");
+        assert!(highest <= 10.01 && lowest >= -10.01);
+
+        synth.set_unison_drift(0.5, 0.0);
+        assert!((synth.controls.freqs[voice].value() - 440.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn release_cutoff_frees_a_released_voice() {
+        let mut synth = synth_with_envelope(0.01, 0.1, 0.7, 0.2);
+        synth.set_release_cutoff_db(-60.0);
+        let mut block = [0.0f32; 441];
+        press(&mut synth, 0, 1);
+        synth.process_block(&mut block, 441);
+        let voice = (0..VOICE_COUNT)
+            .find(|&v| synth.voice_note(v).is_some())
+            .expect("no voice for the note");
+        release(&mut synth, 0, 1);
+
+        // Still assigned while the tail is audible, free once the release
+        // time and the envelope's lag have passed
+        synth.process_block(&mut block, 441);
+        assert!(synth.voice_note(voice).is_some());
+        for _ in 0..21 {
+            synth.process_block(&mut block, 441);
+        }
+        assert_eq!(synth.voice_note(voice), None);
+        assert_eq!(synth.controls.voice_active[voice].value(), 0.0);
+        synth.process_block(&mut block, 441);
+        assert!(block.iter().all(|s| s.abs() < 1e-3));
+
+        // Without the cutoff the voice stays assigned until reused
+        synth.set_release_cutoff_db(0.0);
+        press(&mut synth, 0, 1);
+        synth.process_block(&mut block, 441);
+        release(&mut synth, 0, 1);
+        for _ in 0..40 {
+            synth.process_block(&mut block, 441);
+        }
+        assert!((0..VOICE_COUNT).any(|v| synth.voice_note(v).is_some()));
+    }
+
+    #[test]
+    fn release_cutoff_waits_for_the_click_ramp() {
+        // A zero-release C3 sine, as in the click suppression test, freed by
+        // the cutoff while rendered in short blocks
+        let mut synth = KeyboardSynth::new();
+        synth.set_startup_fade(Duration::ZERO);
+        synth.apply_preset(&Preset {
+            waveform: Waveform::Sine,
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.0,
+            filter_cutoff: 18000.0,
+            ..Preset::default()
+        });
+        synth.set_release_cutoff_db(-60.0);
+        let mut samples = [0.0f32; 64 * 40];
+        press(&mut synth, 0, 0);
+        for (i, chunk) in samples.chunks_mut(64).enumerate() {
+            if i == 20 {
+                release(&mut synth, 0, 0);
+            }
+            synth.process_block(chunk, 64);
+        }
+        // The voice ramps down over CLICK_RAMP before it is freed, so the
+        // note ends no steeper than the sine moves by itself
+        let peak = samples.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        let step = samples
+            .windows(2)
+            .fold(0.0f32, |step, w| step.max((w[1] - w[0]).abs()));
+        assert!(step < 0.025 * peak, "{step} {peak}");
+        assert!((0..VOICE_COUNT).all(|v| synth.voice_note(v).is_none()));
+    }
+
+    #[test]
+    fn saw_square_blend_makes_a_hollow_lead() {
+        /// Second-harmonic to fundamental power of a held A4, before and
+        /// after bending it up a whole tone
+        fn even_to_fundamental(backend: RenderBackend, blend: f32) -> (f32, f32) {
+            let mut synth = KeyboardSynth::new();
+            synth.set_render_backend(backend);
+            synth.apply_preset(&Preset {
+                filter_cutoff: 18000.0,
+                ..Preset::default()
+            });
+            synth.set_osc_blend(Waveform::Saw, Waveform::Square, blend);
+            let mut block = [0.0f32; 4410];
+            press(&mut synth, 9, 1);
+            synth.process_block(&mut block, 4410);
+            synth.process_block(&mut block, 4410);
+            let ratio = tone_power(&block, 880.0) / tone_power(&block, 440.0);
+            // Both oscillators follow the bend
+            synth.set_pitch_bend(2.0);
+            synth.process_block(&mut block, 4410);
+            synth.process_block(&mut block, 4410);
+            let bent = 440.0 * bend_ratio(2.0);
+            let fundamental = tone_power(&block, bent);
+            assert!(fundamental > tone_power(&block, 440.0) * 10.0);
+            (ratio, tone_power(&block, 2.0 * bent) / fundamental)
+        }
+
+        for backend in [RenderBackend::Float, RenderBackend::Fixed] {
+            let (saw, saw_bent) = even_to_fundamental(backend, 0.0);
+            let (lead, lead_bent) = even_to_fundamental(backend, 0.5);
+            // Saw harmonics fall as 1/n; the square adds only odd ones, so
+            // the blend hollows out the even harmonics
+            assert!(lead < saw / 4.0, "{saw} {lead}");
+            assert!(lead_bent < saw_bent / 4.0, "{saw_bent} {lead_bent}");
+        }
+    }
+
+    #[test]
+    fn performance_lock_ignores_the_sensor_until_unlocked() {
+        let mut synth = KeyboardSynth::new();
+        // What the ToF task writes to
+        let resonator = synth.resonator_freq_control();
+        let mut block = [0.0f32; 441];
+        resonator.set_value(600.0);
+        synth.process_block(&mut block, 441);
+        assert!(!synth.performance_locked());
+
+        synth.set_performance_lock(true);
+        assert!(synth.performance_locked());
+        // A bumped sensor and a stray patch load change nothing
+        resonator.set_value(1400.0);
+        synth.apply_preset(&Preset {
+            filter_cutoff: 300.0,
+            ..Preset::default()
+        });
+        synth.process_block(&mut block, 441);
+        assert_eq!(synth.controls.resonator_freq.value(), 600.0);
+        assert_eq!(synth.controls.filter_cutoff.value(), FILTER_CUTOFF);
+        // Notes and bend still play
+        press(&mut synth, 9, 1);
+        synth.set_pitch_bend(1.0);
+        for _ in 0..10 {
+            synth.process_block(&mut block, 441);
+        }
+        assert!(block.iter().any(|s| s.abs() > 1e-3));
+
+        // Unlocked, the next reading applies
+        synth.set_performance_lock(false);
+        resonator.set_value(1400.0);
+        synth.process_block(&mut block, 441);
+        assert_eq!(synth.controls.resonator_freq.value(), 1400.0);
+    }
+
+    #[test]
+    fn midi_learn_binds_cc21_to_the_filter_cutoff() {
+        use crate::midi::{CcParser, ControlTarget};
+        let mut synth = KeyboardSynth::new();
+        let mut parser = CcParser::new();
+        let mut feed = |synth: &mut KeyboardSynth, bytes: &[u8]| {
+            for &byte in bytes {
+                if let Some(cc) = parser.push(byte) {
+                    synth.control_change(cc.cc, cc.value);
+                }
+            }
+        };
+        // Nothing is bound yet
+        feed(&mut synth, &[0xB0, 21, 0]);
+        assert_eq!(synth.controls.filter_cutoff.value(), FILTER_CUTOFF);
+
+        // Learn armed and cancelled: the knob still does nothing
+        synth.midi_learn(ControlTarget::FilterCutoff);
+        synth.cancel_midi_learn();
+        feed(&mut synth, &[21, 0]);
+        assert_eq!(synth.cc_mapping(ControlTarget::FilterCutoff), None);
+        assert_eq!(synth.controls.filter_cutoff.value(), FILTER_CUTOFF);
+
+        // Armed, the first CC to come in is learned and applied
+        synth.midi_learn(ControlTarget::FilterCutoff);
+        assert_eq!(synth.midi_learning(), Some(ControlTarget::FilterCutoff));
+        feed(&mut synth, &[0xB0, 21, 0]);
+        assert_eq!(synth.midi_learning(), None);
+        assert_eq!(synth.cc_mapping(ControlTarget::FilterCutoff), Some(21));
+        assert_eq!(synth.controls.filter_cutoff.value(), 20.0);
+        feed(&mut synth, &[21, 127]);
+        assert_eq!(synth.controls.filter_cutoff.value(), 18_000.0);
+        // Other controllers leave it alone
+        feed(&mut synth, &[0xB0, 22, 0]);
+        assert_eq!(synth.controls.filter_cutoff.value(), 18_000.0);
+
+        // The binding is saved and restored with the state
+        let bytes = synth.serialize_state();
+        let mut restored = KeyboardSynth::new();
+        restored.deserialize_state(&bytes).unwrap();
+        assert_eq!(restored.cc_mapping(ControlTarget::FilterCutoff), Some(21));
+        assert!(restored.control_change(21, 0));
+        assert_eq!(restored.controls.filter_cutoff.value(), 20.0);
+    }
+
+    #[test]
+    fn midi_input_loads_and_dumps_patches_over_sysex() {
+        use crate::midi::MidiInput;
+        // A patch with settings beyond the preset, a CC binding included
+        let mut source = KeyboardSynth::new();
+        source.apply_preset(&Preset {
+            waveform: Waveform::Square,
+            filter_cutoff: 1200.0,
+            release: 0.8,
+            ..Preset::default()
+        });
+        source.set_pulse_width(0.3);
+        source.set_voice_gain_mode(GainMode::Normalized);
+        source.set_cc_mapping(ControlTarget::Drive, Some(21));
+        let patch = source.state();
+        let mut synth = KeyboardSynth::new();
+        let mut input = MidiInput::new();
+        // A patch load with a clock tick in the middle
+        let mut load = alloc::vec::Vec::from(&patch_dump(&patch)[..]);
+        load.insert(10, 0xF8);
+        for byte in load {
+            assert!(input.push(&mut synth, byte).is_none());
+        }
+        assert!(synth.state() == patch);
+
+        // A dump request is answered with the loaded patch
+        let mut reply = None;
+        for byte in [0xF0, 0x7D, 0x32, 0x01, 0xF7] {
+            reply = input.push(&mut synth, byte);
+        }
+        assert_eq!(reply, Some(patch_dump(&patch)));
+    }
+
+    #[test]
+    fn topology_fade_switches_saw_to_square_without_a_click() {
+        // Largest second difference (a click's sharp corner stands out of
+        // the band-limited waveform) before and across a switch from saw
+        // to square in the middle of a held A4
+        let corners = |fade: Duration| {
+            let mut synth = synth_with_envelope(0.005, 0.0, 1.0, 0.05);
+            synth.set_topology_fade(fade);
+            press(&mut synth, 9, 1);
+            let mut block = [0.0f32; 441];
+            let mut samples = alloc::vec::Vec::new();
+            for _ in 0..10 {
+                synth.process_block(&mut block, 441);
+                samples.extend_from_slice(&block);
+            }
+            synth.apply_preset(&Preset {
+                waveform: Waveform::Square,
+                ..synth.preset()
+            });
+            for _ in 0..2 {
+                synth.process_block(&mut block, 441);
+                samples.extend_from_slice(&block);
+            }
+            let corner = |samples: &[f32]| {
+                samples
+                    .windows(3)
+                    .map(|s| (s[2] - 2.0 * s[1] + s[0]).abs())
+                    .fold(0.0f32, f32::max)
+            };
+            let switch = 10 * 441;
+            (
+                corner(&samples[switch - 882..switch]),
+                corner(&samples[switch - 2..switch + 882]),
+            )
+        };
+        // Switched at once, the old graph's output drops out mid-cycle
+        let (steady, hard) = corners(Duration::ZERO);
+        assert!(hard > 3.0 * steady, "{steady} {hard}");
+        let (steady, faded) = corners(TOPOLOGY_FADE);
+        assert!(faded < 1.5 * steady, "{steady} {faded}");
+    }
+
+    #[test]
+    fn quietest_stealing_takes_the_soft_fading_note() {
+        let steal = |mode: StealMode| {
+            let mut synth = KeyboardSynth::new();
+            synth.set_steal_mode(mode);
+            synth.set_voice_range(0, 2);
+            synth.set_envelope(0.01, 0.0, 1.0, 2.0);
+            let mut block = [0.0f32; 441];
+            // A loud held note on voice 0, a soft one on voice 1 fading out
+            synth.note_on(0, 1, 127);
+            synth.process_block(&mut block, 441);
+            synth.note_on(4, 1, 40);
+            synth.process_block(&mut block, 441);
+            synth.note_off(4, 1, 64);
+            for _ in 0..20 {
+                synth.process_block(&mut block, 441);
+            }
+            assert!(synth.voice_loudness(1) < 0.5 * synth.voice_loudness(0));
+            synth.note_on(7, 1, 100);
+            [synth.voice_note(0), synth.voice_note(1)]
+        };
+        let (loud, soft, new) = (encode_note(0, 1), encode_note(4, 1), encode_note(7, 1));
+        // Round-robin comes back round to the loud note
+        assert_eq!(steal(StealMode::RoundRobin), [Some(new), Some(soft)]);
+        assert_eq!(steal(StealMode::Quietest), [Some(loud), Some(new)]);
+    }
+
+    #[test]
+    fn oldest_released_stealing_spares_the_fresh_tails() {
+        let steal = |mode: StealMode| {
+            let mut synth = KeyboardSynth::new();
+            synth.set_steal_mode(mode);
+            synth.set_voice_range(0, 3);
+            synth.set_envelope(0.01, 0.0, 1.0, 2.0);
+            let mut block = [0.0f32; 441];
+            for key in [0, 4, 7] {
+                press(&mut synth, key, 1);
+            }
+            synth.process_block(&mut block, 441);
+            // Voice 1 released first, then voice 0; voice 2 still held
+            release(&mut synth, 4, 1);
+            synth.process_block(&mut block, 441);
+            release(&mut synth, 0, 1);
+            synth.process_block(&mut block, 441);
+            press(&mut synth, 11, 1);
+            [0, 1, 2].map(|v| synth.voice_note(v))
+        };
+        let (first, second, held, new) = (
+            encode_note(0, 1),
+            encode_note(4, 1),
+            encode_note(7, 1),
+            encode_note(11, 1),
+        );
+        assert_eq!(
+            steal(StealMode::RoundRobin),
+            [Some(new), Some(second), Some(held)]
+        );
+        assert_eq!(
+            steal(StealMode::OldestReleased),
+            [Some(first), Some(new), Some(held)]
+        );
+
+        // With every note held it takes each voice in turn
+        let mut synth = KeyboardSynth::new();
+        synth.set_steal_mode(StealMode::OldestReleased);
+        synth.set_voice_range(0, 2);
+        press(&mut synth, 0, 1);
+        press(&mut synth, 4, 1);
+        press(&mut synth, 7, 1);
+        press(&mut synth, 11, 1);
+        assert_eq!(synth.voice_note(0), Some(encode_note(7, 1)));
+        assert_eq!(synth.voice_note(1), Some(encode_note(11, 1)));
+    }
+
+    #[test]
+    fn legato_line_sweeps_the_filter_only_on_its_first_note() {
+        // Sweep position 0.1 s into the second note of a legato line,
+        // under a 2 Hz ramp on the filter retriggered per note
+        let sweep_after_legato_note = |retrigger: bool| {
+            let mut synth = KeyboardSynth::new();
+            synth.set_mono(true);
+            synth.set_glide_time(0.05);
+            synth.set_lfo(LfoShape::Ramp, 2.0);
+            synth.set_lfo_depth(LfoDestination::Filter, 2.0);
+            synth.set_lfo_retrigger(true);
+            synth.set_legato_filter_retrigger(retrigger);
+            let mut block = [0.0f32; 441];
+            press(&mut synth, 0, 1);
+            for _ in 0..15 {
+                synth.process_block(&mut block, 441);
+            }
+            press(&mut synth, 4, 1);
+            for _ in 0..10 {
+                synth.process_block(&mut block, 441);
+            }
+            synth.lfo.phase
+        };
+        // Retriggered, the sweep starts over with the second note
+        let restarted = sweep_after_legato_note(true);
+        assert!((restarted - 0.2).abs() < 0.01, "{restarted}");
+        // Otherwise it carries on from the first, 0.25 s in
+        let continued = sweep_after_legato_note(false);
+        assert!((continued - 0.5).abs() < 0.01, "{continued}");
+    }
+
+    #[test]
+    fn stolen_voice_fades_out_before_the_new_note_starts() {
+        // Loudness of the quietest A4 cycle across a held A4 being
+        // stolen by a C5, relative to the A4
+        let steal = |fade_ms: u32| {
+            let mut synth = synth_with_envelope(0.001, 0.0, 1.0, 0.5);
+            synth.set_voice_range(0, 1);
+            synth.set_steal_crossfade_ms(fade_ms);
+            let mut block = [0.0f32; 441];
+            press(&mut synth, 9, 1);
+            for _ in 0..5 {
+                synth.process_block(&mut block, 441);
+            }
+            let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            let held = peak(&block);
+            press(&mut synth, 0, 2);
+            assert_eq!(synth.voice_note(0), Some(encode_note(0, 2)));
+            let mut samples = alloc::vec::Vec::new();
+            for _ in 0..3 {
+                synth.process_block(&mut block, 441);
+                samples.extend_from_slice(&block);
+            }
+            assert_eq!(synth.controls.gates[0].value(), 1.0);
+            assert!(tone_power(&block, 523.25) > 10.0 * tone_power(&block, 440.0));
+            let dip = samples.chunks(100).map(peak).fold(f32::MAX, f32::min);
+            (dip / held, synth)
+        };
+        // Stolen at once, the voice jumps straight to the new pitch
+        let (dip, _) = steal(0);
+        assert!(dip > 0.5, "{dip}");
+        let (dip, mut synth) = steal(5);
+        assert!(dip < 0.3, "{dip}");
+
+        // A key let go during the fade never starts its note
+        let mut block = [0.0f32; 441];
+        press(&mut synth, 4, 2);
+        assert_eq!(synth.controls.gates[0].value(), 0.0);
+        release(&mut synth, 4, 2);
+        for _ in 0..2 {
+            synth.process_block(&mut block, 441);
+        }
+        assert_eq!(synth.controls.gates[0].value(), 0.0);
+    }
+
+    #[test]
+    fn rapid_notes_steal_without_clicks_or_stuck_voices() {
+        // Twelve sine notes, one every 40 ms and each let go after 30 ms:
+        // with a 0.5 s release every voice still rings when the 8th comes,
+        // so from there on each note steals a tail
+        let mut synth = KeyboardSynth::new();
+        synth.set_startup_fade(Duration::ZERO);
+        synth.apply_preset(&Preset {
+            waveform: Waveform::Sine,
+            attack: 0.005,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.5,
+            filter_cutoff: 18000.0,
+            ..Preset::default()
+        });
+        synth.set_release_cutoff_db(-60.0);
+        synth.set_steal_crossfade_ms(5);
+        // Past the crossfade from the saw voices the preset replaced
+        render(&mut synth, 4410, 64);
+        let mut samples = alloc::vec::Vec::new();
+        for note in 0..12 {
+            let (key, octave) = (note * 5 % KEY_COUNT, 1 + (note / 6) as u8);
+            press(&mut synth, key, octave);
+            samples.extend(render(&mut synth, 1323, 64));
+            release(&mut synth, key, octave);
+            samples.extend(render(&mut synth, 441, 64));
+        }
+        // Largest bend in the waveform against its peak: the sines' own is
+        // about 1.2%, a jump shows as one about its size
+        let peak = samples.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        let bend = samples
+            .windows(3)
+            .fold(0.0f32, |bend, w| bend.max((w[2] - 2.0 * w[1] + w[0]).abs()));
+        assert!(bend / peak < 0.02, "{}", bend / peak);
+
+        // Every note ends: no voice is left fading, gated or assigned
+        let tail = render(&mut synth, 44100, 640);
+        assert!(rms(&tail[22050..]) < 1e-5);
+        assert!((0..VOICE_COUNT).all(|v| synth.voice_note(v).is_none()));
+        assert!(synth.steal_fades.iter().all(Option::is_none));
+        assert!(synth.controls.gates.iter().all(|gate| gate.value() == 0.0));
+    }
+
+    #[test]
+    fn one_key_plays_a_detuned_octave_fifth_stack() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_voice_spread(0.0);
+        synth.set_stack(&[12.0, 7.0, 19.0], 10.0);
+        press(&mut synth, 9, 1);
+        let a4 = encode_note(9, 1);
+        let stack: alloc::vec::Vec<usize> = (0..VOICE_COUNT)
+            .filter(|&v| synth.voice_note(v) == Some(a4))
+            .collect();
+        assert_eq!(stack.len(), 4);
+        let mut freqs: alloc::vec::Vec<f32> = stack.iter().map(|&v| synth.freq(v)).collect();
+        freqs.sort_by(f32::total_cmp);
+        let cents = |freq: f32, target: f32| 1200.0 * libm::log2f(freq / target);
+        assert!(cents(freqs[0], 440.0).abs() < 0.01);
+        // Octave and octave-fifth sharp, fifth flat
+        assert!((cents(freqs[1], 659.26) + 10.0).abs() < 0.1, "{freqs:?}");
+        assert!((cents(freqs[2], 880.0) - 10.0).abs() < 0.1, "{freqs:?}");
+        assert!((cents(freqs[3], 1318.51) - 10.0).abs() < 0.1, "{freqs:?}");
+        let mut block = [0.0f32; 4410];
+        synth.process_block(&mut block, 4410);
+        for freq in &freqs {
+            assert!(tone_power(&block, *freq) > 0.1 * tone_power(&block, 440.0));
+        }
+
+        // A second stacked note takes the three voices left and steals one
+        // from the first; a release lets the whole of its stack go
+        press(&mut synth, 0, 1);
+        let voices = |synth: &KeyboardSynth, note| {
+            (0..VOICE_COUNT)
+                .filter(|&v| synth.voice_note(v) == Some(note))
+                .collect::<alloc::vec::Vec<_>>()
+        };
+        assert_eq!(voices(&synth, encode_note(0, 1)).len(), 4);
+        let stack = voices(&synth, a4);
+        assert_eq!(stack.len(), 3);
+        release(&mut synth, 9, 1);
+        for voice in stack {
+            assert_eq!(synth.controls.gates[voice].value(), 0.0);
+        }
+    }
+
+    #[test]
+    fn cross_mod_rings_two_held_notes_into_a_clang() {
+        // Power at the A4 + A#4 sum tone, which two sines only share under
+        // frequency modulation
+        let clang = |amount: f32| {
+            let mut synth = KeyboardSynth::new();
+            synth.set_startup_fade(Duration::ZERO);
+            synth.set_voice_spread(0.0);
+            synth.apply_preset(&Preset {
+                waveform: Waveform::Sine,
+                filter_cutoff: 18000.0,
+                ..Preset::default()
+            });
+            // A#4 on voice 1 modulates A4 on voice 0
+            synth.set_cross_mod(1, 0, amount);
+            press(&mut synth, 9, 1);
+            press(&mut synth, 10, 1);
+            let mut block = [0.0f32; 8820];
+            synth.process_block(&mut block, 8820);
+            let peak = block.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            (
+                tone_power(&block[4410..], 440.0 + 466.16) / tone_power(&block[4410..], 466.16),
+                peak,
+            )
+        };
+        let (clean, clean_peak) = clang(0.0);
+        let (rung, rung_peak) = clang(0.5);
+        assert!(rung > 100.0 * clean, "{clean} {rung}");
+        // Modulation moves the pitch, not the level
+        assert!(rung_peak < 1.1 * clean_peak, "{clean_peak} {rung_peak}");
+
+        // A voice can't modulate itself; the pair also builds panned
+        let mut synth = KeyboardSynth::new();
+        synth.set_cross_mod(2, 2, 0.5);
+        assert_eq!(synth.topology.cross_mod, None);
+        synth.set_auto_pan(AutoPanMode::ByVoice);
+        synth.set_cross_mod(2, 3, 0.5);
+        press(&mut synth, 9, 1);
+        let mut block = [0.0f32; 441];
+        synth.process_block(&mut block, 441);
+        assert!(block.iter().any(|s| *s != 0.0));
+    }
+
+    #[test]
+    fn velocity_floor_keeps_the_softest_touch_audible() {
+        let gain = |floor: u8, velocity: u8| {
+            let mut synth = KeyboardSynth::new();
+            synth.set_velocity_floor(floor);
+            synth.note_on(9, 1, velocity);
+            synth.controls.velocities[0].value()
+        };
+        let floor_gain = VelocityCurve::Linear.gain(VELOCITY_FLOOR);
+        assert_eq!(gain(VELOCITY_FLOOR, 1), floor_gain);
+        assert!(gain(1, 1) < 0.1 * floor_gain);
+        // Above the floor the dynamics are untouched
+        assert_eq!(gain(VELOCITY_FLOOR, 100), VelocityCurve::Linear.gain(100));
+    }
+
+    #[test]
+    fn isomorphic_layout_plays_one_triad_shape_in_every_key() {
+        use crate::chord::ChordQuality;
+        let mut synth = KeyboardSynth::new();
+        synth.set_voice_spread(0.0);
+        synth.set_keyboard_mode(KeyboardMode::Isomorphic);
+        // The same shape from two places on the grid: a key, two keys to
+        // its right and the key above it
+        for (key, row, root) in [(0, 0, 0), (3, 1, 1)] {
+            let shape = [(key, row), (key + 2, row), (key, row + 1)];
+            for (key, row) in shape {
+                press(&mut synth, key, row);
+            }
+            let chord = synth.current_chord().unwrap();
+            assert_eq!(chord.quality, ChordQuality::Major);
+            assert_eq!((chord.root, chord.bass), (root, root));
+            let freqs = shape.map(|(key, row)| {
+                let note = encode_note(key as u8, row);
+                let voice = (0..VOICE_COUNT).find(|&v| synth.voice_note(v) == Some(note));
+                synth.freq(voice.unwrap())
+            });
+            for (freq, semitones) in freqs.into_iter().zip([0.0, 4.0, 7.0]) {
+                let interval = 12.0 * libm::log2f(freq / freqs[0]);
+                assert!((interval - semitones).abs() < 1e-3, "{freqs:?}");
+            }
+            for (key, row) in shape {
+                release(&mut synth, key, row);
+            }
+        }
+
+        // On the drum pads a key hits a drum and takes no voice
+        synth.set_keyboard_mode(KeyboardMode::DrumPads);
+        press(&mut synth, 0, 0);
+        assert!((0..VOICE_COUNT).all(|v| synth.voice_note(v).is_none()));
+        let mut block = [0.0f32; 441];
+        synth.process_block(&mut block, 441);
+        assert!(block.iter().any(|s| s.abs() > 0.01));
+    }
+
+    #[test]
+    fn release_holdoff_rides_out_a_contact_dropout() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        fn now() -> u64 {
+            NOW.load(Ordering::Relaxed)
+        }
+        let mut synth = KeyboardSynth::new();
+        synth.set_contact_clock(now);
+        synth.set_release_holdoff_ms(5);
+        // A flaky held key: scans every ms, dropping out for 3 ms in the
+        // middle of the hold
+        let scan = |synth: &mut KeyboardSynth, pressed: bool| {
+            NOW.fetch_add(1_000, Ordering::Relaxed);
+            synth.update_key(9, 1, pressed)
+        };
+        assert!(scan(&mut synth, true));
+        let started = synth.voice_started[0];
+        // Move the sample clock on, so a retrigger would show
+        synth.clock += 1_000;
+        for pressed in [true, false, false, false, true, true] {
+            assert!(!scan(&mut synth, pressed));
+            assert_eq!(synth.gate(0), 1.0);
+        }
+        // Not retriggered
+        assert_eq!(synth.voice_started[0], started);
+
+        // Really let go: the note ends once it has read released for 5 ms
+        for _ in 0..5 {
+            assert!(!scan(&mut synth, false));
+            assert_eq!(synth.gate(0), 1.0);
+        }
+        assert!(scan(&mut synth, false));
+        assert_eq!(synth.gate(0), 0.0);
+    }
+
+    #[test]
+    fn trance_gate_chops_a_held_chord_into_sixteenths() {
+        let mut synth = KeyboardSynth::new();
+        for key in [0, 4, 7] {
+            press(&mut synth, key, 1);
+        }
+        let mut block = [0.0f32; 22050];
+        synth.process_block(&mut block, 22050);
+        synth.set_trance_gate(true, &[1.0, 0.0, 1.0, 0.0], NoteDivision::Sixteenth);
+        // Eight 16ths at 120 BPM, 5512.5 frames each
+        let mut steps = render(&mut synth, 44100, 640);
+        let level = |steps: &[f32], step: usize| {
+            let start = step * 5512 + 1000;
+            power(&steps[start..start + 3500])
+        };
+        for step in (0..8).step_by(2) {
+            let (open, closed) = (level(&steps, step), level(&steps, step + 1));
+            assert!(open > 0.001, "step {step}: {open}");
+            assert!(closed < open * 0.001, "step {step}: {open} {closed}");
+        }
+
+        // Opening the second step live lets the chord through there too
+        synth.trance_gate_mut().set_step(1, 1.0);
+        assert_eq!(synth.trance_gate_mut().step(1), Some(1.0));
+        synth.process_block(&mut steps, 44100);
+        for bar in [0, 4] {
+            let open = level(&steps, bar);
+            assert!(level(&steps, bar + 1) > open * 0.5, "step {}", bar + 1);
+            assert!(level(&steps, bar + 3) < open * 0.001, "step {}", bar + 3);
+        }
+    }
+
+    #[test]
+    fn trance_gate_stays_open_until_the_drums_next_step() {
+        let mut synth = KeyboardSynth::new();
+        for key in [0, 4, 7] {
+            press(&mut synth, key, 1);
+        }
+        // Silent drums, only their clock
+        synth.drums_mut().set_level(0.0);
+        synth.set_drums_playing(true);
+        let mut block = [0.0f32; 22050];
+        synth.process_block(&mut block, 22050);
+        synth.process_block(&mut block, 3000);
+        let lead_in = synth.drums.frames_to_step() as usize;
+        assert!(lead_in > 1000 && lead_in < 5512, "{lead_in}");
+
+        // The last step is closed, and must not play before the first
+        synth.set_trance_gate(true, &[1.0, 0.0, 1.0, 0.0], NoteDivision::Sixteenth);
+        let steps = render(&mut synth, 22050, 640);
+        let open = power(&steps[200..lead_in - 200]);
+        assert!(open > 0.001, "lead-in {open}");
+        let step = |n: usize| {
+            let start = lead_in + n * 5512 + 500;
+            power(&steps[start..start + 4500])
+        };
+        assert!(step(0) > open * 0.25, "{} {open}", step(0));
+        assert!(step(1) < open * 0.001, "{} {open}", step(1));
+        assert!(step(2) > open * 0.25, "{} {open}", step(2));
+    }
+
+    #[test]
+    fn note_repeat_rolls_a_held_note_in_sixteenths() {
+        let mut synth = synth_with_envelope(0.001, 0.1, 1.0, 0.02);
+        // The held note keeps its voice between hits, tail cut or not
+        synth.set_release_cutoff_db(-60.0);
+        synth.set_arp_gate(0.25);
+        synth.set_note_repeat(Some(NoteDivision::Sixteenth));
+        press(&mut synth, 9, 1);
+        // Eight 16ths at 120 BPM, 5512.5 frames each
+        let steps = render(&mut synth, 44100, 640);
+        // Each hit sounds for a quarter of its step and dies away before
+        // the next
+        for step in 0..8 {
+            let start = step * 5512;
+            let hit = power(&steps[start + 300..start + 1300]);
+            let gap = power(&steps[start + 3000..start + 5000]);
+            assert!(hit > 1e-4, "step {step}: {hit}");
+            assert!(gap < hit * 1e-4, "step {step}: {hit} {gap}");
+        }
+
+        // Released, the roll stops at once
+        release(&mut synth, 9, 1);
+        let mut tail = [0.0f32; 22050];
+        synth.process_block(&mut tail, 22050);
+        assert!(power(&tail[2000..]) < 1e-9);
+        assert!(synth.controls.gates.iter().all(|gate| gate.value() == 0.0));
+    }
+
+    #[test]
+    fn lfo_sweeps_the_pulse_width_of_a_held_note() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_osc_blend(Waveform::Pulse, Waveform::Pulse, 0.0);
+        press(&mut synth, 9, 1);
+        let mut block = [0.0f32; 4410];
+        synth.process_block(&mut block, 4410);
+        // The 2nd harmonic of a pulse follows sin(2 pi width): none in a
+        // square, strongest at a quarter
+        let second = |synth: &mut KeyboardSynth| {
+            let mut block = [0.0f32; 4410];
+            synth.process_block(&mut block, 4410);
+            tone_power(&block, 880.0) / tone_power(&block, 440.0)
+        };
+        let square = second(&mut synth);
+        synth.set_pulse_width(0.25);
+        second(&mut synth);
+        let quarter = second(&mut synth);
+        assert!(square < 0.01, "{square}");
+        assert!(quarter > 0.2, "{quarter}");
+
+        // A slow LFO sweep from 10% to 90% and back widens and narrows the
+        // pulse, so the 2nd harmonic comes and goes
+        synth.set_pulse_width(0.5);
+        synth.set_lfo(LfoShape::Triangle, 0.5);
+        synth.set_lfo_depth(LfoDestination::PulseWidth, 0.4);
+        let sweep: alloc::vec::Vec<f32> = (0..20).map(|_| second(&mut synth)).collect();
+        let (low, high) = sweep.iter().fold((f32::MAX, 0.0f32), |(low, high), &x| {
+            (low.min(x), high.max(x))
+        });
+        assert!(high > 0.5 && low < 0.05, "{sweep:?}");
+    }
+
+    #[test]
+    fn control_values_read_back_in_user_units() {
+        let mut synth = KeyboardSynth::new();
+        assert_eq!(synth.pitch_bend_value(), 0.0);
+        synth.set_pitch_bend(-3.5);
+        assert!((synth.pitch_bend_value() + 3.5).abs() < 1e-4);
+        // Full wheel over the default whole-step range
+        synth.set_pitch_wheel(8191);
+        assert!((synth.pitch_bend_value() - BEND_RANGE).abs() < 1e-4);
+
+        synth.apply_preset(&Preset {
+            filter_cutoff: 1200.0,
+            filter_resonance: 0.4,
+            ..Preset::default()
+        });
+        assert_eq!(synth.cutoff_value(), 1200.0);
+        assert_eq!(synth.resonance_value(), 0.4);
+        assert_eq!(synth.volume_value(), 1.0);
+        synth.set_output_mute(true);
+        assert_eq!(synth.volume_value(), 0.0);
+
+        // A learned knob reads back in the units it was scaled to
+        synth.midi_learn(ControlTarget::StereoWidth);
+        synth.control_change(74, 127);
+        assert_eq!(synth.control_value(ControlTarget::StereoWidth), 2.0);
+        synth.set_glide_time(0.25);
+        assert_eq!(synth.control_value(ControlTarget::GlideTime), 0.25);
+        assert_eq!(synth.control_value(ControlTarget::FilterCutoff), 1200.0);
+    }
+
+    #[test]
+    fn isomorphic_arp_climbs_octaves_from_the_top_row() {
+        let mut synth = KeyboardSynth::new();
+        synth.set_keyboard_mode(KeyboardMode::Isomorphic);
+        synth.set_arpeggiator(Some(ArpMode::Up));
+        synth.set_arp_rate(10.0);
+        synth.set_arp_octaves(3);
+        press(&mut synth, 0, 3); // A4
+        press(&mut synth, 5, 3); // G5
+        let voice = synth.voice_pool(0).0;
+        let mut block = [0.0f32; 4410];
+        // Whole octaves above the top row, past the grid
+        for expected in [440.0, 783.99, 880.0, 1567.98, 1760.0, 3135.96, 440.0] {
+            synth.process_block(&mut block, 4410);
+            let freq = synth.controls.freqs[voice].value();
+            assert!((freq / expected - 1.0).abs() < 0.01, "{freq} != {expected}");
+        }
+    }
+
+    #[test]
+    fn state_round_trips_each_parameter_alone() {
+        // One change per entry, each of which `state` must see and a fresh
+        // synth restore
+        let changes: &[fn(&mut KeyboardSynth)] = &[
+            |synth| synth.set_envelope(0.2, 0.3, 0.6, 1.2),
+            |synth| synth.set_filter_slope(FilterSlope::Moog24),
+            |synth| synth.set_envelope_curve(EnvCurve::Exponential),
+            |synth| synth.set_filter_keytrack(0.5),
+            |synth| synth.set_drive(0.7),
+            |synth| {
+                let order = [
+                    FxStage::Formant,
+                    FxStage::Filter,
+                    FxStage::Drive,
+                    FxStage::Resonator,
+                ];
+                synth.set_fx_order(&order).unwrap();
+            },
+            |synth| synth.set_formant_enabled(true),
+            |synth| synth.set_formant(Vowel::E),
+            |synth| synth.set_chorus_enabled(true),
+            |synth| synth.set_chorus(6, 0.012, 0.02, 1.5),
+            |synth| synth.set_delay_enabled(true),
+            |synth| synth.set_delay_pingpong(true),
+            |synth| synth.set_delay_feedback(0.6),
+            |synth| synth.set_glide_time(0.1),
+            |synth| synth.set_glide_curve(GlideCurve::Linear),
+            |synth| synth.set_lfo(LfoShape::Square, 2.0),
+            |synth| synth.set_lfo_depth(LfoDestination::Pitch, 0.5),
+            |synth| synth.set_lfo_depth(LfoDestination::Filter, 1.0),
+            |synth| synth.set_lfo_depth(LfoDestination::Amplitude, 0.5),
+            |synth| synth.set_lfo_depth(LfoDestination::Pan, 0.5),
+            |synth| synth.set_lfo_depth(LfoDestination::PulseWidth, 0.3),
+            |synth| synth.set_pitch_envelope(7.0, 0.02),
+            |synth| synth.set_bend_range(12.0),
+            |synth| synth.set_tilt(1.5),
+            |synth| synth.set_velocity_to_cutoff(1.0),
+            |synth| synth.set_velocity_to_attack(0.5),
+            |synth| synth.set_voice_highpass_tracking(0.5),
+            |synth| synth.set_analog_drift(0.5),
+            |synth| synth.set_stereo_width(0.5),
+            |synth| synth.set_mono_safe(true),
+            |synth| synth.set_auto_pan(AutoPanMode::ByPitch),
+            |synth| synth.set_cc_mapping(ControlTarget::FilterCutoff, Some(74)),
+            |synth| synth.set_velocity_curve(VelocityCurve::Fixed(100)),
+            |synth| synth.set_velocity_floor(30),
+            |synth| synth.set_release_velocity(true),
+            |synth| synth.set_voice_spread(5.0),
+            |synth| synth.set_unison_drift(0.5, 4.0),
+            |synth| synth.set_oversampling(2),
+            |synth| synth.set_phase_reset(true),
+            |synth| synth.set_click_suppression(false),
+            |synth| synth.set_voice_gain_mode(GainMode::Normalized),
+            |synth| synth.set_tempo(90.0),
+            |synth| synth.set_lfo_sync(Some(NoteDivision::Eighth)),
+            |synth| synth.set_lfo_retrigger(true),
+            |synth| synth.set_legato_filter_retrigger(false),
+            |synth| synth.set_mono(true),
+            |synth| synth.set_note_priority(NotePriority::Low),
+            |synth| synth.set_pulse_width(0.2),
+            |synth| synth.set_osc_blend(Waveform::Saw, Waveform::Pulse, 0.4),
+            |synth| synth.set_stack(&[12.0, 7.0], 8.0),
+            |synth| synth.set_cross_mod(1, 3, 0.5),
+        ];
+        for (change, apply) in changes.iter().enumerate() {
+            let mut synth = KeyboardSynth::new();
+            apply(&mut synth);
+            let state = synth.state();
+            assert!(state != SynthState::default(), "change {change}");
+            let mut restored = KeyboardSynth::new();
+            restored
+                .deserialize_state(&synth.serialize_state())
+                .unwrap();
+            assert!(restored.state() == state, "change {change}");
+        }
     }
 }
@@ -0,0 +1,165 @@
+//! Last-state slot: the live sound settings, auto-saved to flash so the
+//! synth comes up where it left off.
+//!
+//! The slot is the two 4 KB flash sectors just past the firmware's region
+//! (see `memory.x`), each split into 8 records that are written in turn, so
+//! a sector is erased once per 8 saves rather than on every save. Each
+//! record is a sequence number and a `SynthState::to_bytes` image with its
+//! version and CRC, the format SysEx patch dumps use too; at boot the valid
+//! record with the highest sequence number wins. When a sector fills up the
+//! saves move on to the other one, erasing it first: the sector erased
+//! never holds the newest record, so a save torn by a power cut, whether in
+//! the erase or the write, leaves the previous record to use instead. Blank
+//! or fully corrupt sectors give `None`, i.e. the defaults. Without a valid
+//! record, the 64-byte `Preset` records of older firmware, kept in the
+//! second sector, are read instead.
+//!
+//! Erasing takes tens of milliseconds with the CPU stalled (up to ~400 ms
+//! worst case), so saves should only happen while nothing is sounding
+//! (`KeyboardSynth::take_autosave` waits for that), and the watchdog is
+//! given longer for it.
+
+use embassy_rp::Peri;
+use embassy_rp::flash::{Blocking, ERASE_SIZE, Error, Flash};
+use embassy_rp::peripherals::FLASH;
+use embassy_rp::watchdog::Watchdog;
+use pico2_synth::preset::Preset;
+use pico2_synth::state::{STATE_BYTES_MAX, SynthState};
+
+/// Flash size the driver is told about: the 2 MiB that `memory.x` covers
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// Sectors the saves alternate between
+const SECTOR_COUNT: usize = 2;
+/// Offset of the first last-state sector, the last two of `FLASH_SIZE`
+const SLOT_OFFSET: u32 = (FLASH_SIZE - SECTOR_COUNT * ERASE_SIZE) as u32;
+/// One record: sequence number, state image, erased padding
+const RECORD_SIZE: usize = 512;
+const RECORD_COUNT: usize = ERASE_SIZE / RECORD_SIZE;
+const _: () = assert!(
+    4 + STATE_BYTES_MAX <= RECORD_SIZE,
+    "A record must hold the largest state"
+);
+/// Record of older firmware, a `Preset` image in the second sector
+const LEGACY_RECORD_SIZE: usize = 64;
+/// Sequence number of an erased record
+const BLANK: u32 = u32::MAX;
+/// Watchdog timeout while a sector is erased, above the worst-case erase
+/// time of the QSPI flash
+const ERASE_WATCHDOG_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(1);
+
+pub struct LastState<'d> {
+    flash: Flash<'d, FLASH, Blocking, FLASH_SIZE>,
+    /// Newest valid record found at boot
+    latest: Option<SynthState>,
+    /// Sector and record the next save goes to
+    sector: usize,
+    next: usize,
+    sequence: u32,
+}
+
+impl<'d> LastState<'d> {
+    /// Scan both sectors for the newest valid record.
+    pub fn new(flash: Peri<'d, FLASH>) -> Self {
+        let mut state = Self {
+            flash: Flash::new_blocking(flash),
+            latest: None,
+            sector: 0,
+            next: 0,
+            sequence: 0,
+        };
+        for sector in 0..SECTOR_COUNT {
+            for index in 0..RECORD_COUNT {
+                let mut record = [0u8; RECORD_SIZE];
+                if state
+                    .flash
+                    .blocking_read(record_offset(sector, index), &mut record)
+                    .is_err()
+                {
+                    continue;
+                }
+                let sequence = u32::from_le_bytes(record[..4].try_into().unwrap());
+                if sequence == BLANK || (state.latest.is_some() && sequence < state.sequence) {
+                    continue;
+                }
+                if let Some(saved) = SynthState::from_bytes(&record[4..]) {
+                    state.latest = Some(saved);
+                    state.sequence = sequence;
+                    state.sector = sector;
+                    state.next = index + 1;
+                }
+            }
+        }
+        if state.latest.is_none() {
+            state.load_legacy();
+        }
+        state
+    }
+
+    /// Fall back on the newest `Preset` record of older firmware, the rest
+    /// of the state at its defaults. The saves start over in the first
+    /// sector, so the second one stays as it is until that fills up.
+    fn load_legacy(&mut self) {
+        let legacy_offset = record_offset(SECTOR_COUNT - 1, 0);
+        for index in 0..ERASE_SIZE / LEGACY_RECORD_SIZE {
+            let mut record = [0u8; LEGACY_RECORD_SIZE];
+            let offset = legacy_offset + (index * LEGACY_RECORD_SIZE) as u32;
+            if self.flash.blocking_read(offset, &mut record).is_err() {
+                continue;
+            }
+            let sequence = u32::from_le_bytes(record[..4].try_into().unwrap());
+            if sequence == BLANK || (self.latest.is_some() && sequence < self.sequence) {
+                continue;
+            }
+            if let Some(preset) = Preset::from_bytes(&record[4..]) {
+                self.latest = Some(SynthState {
+                    preset,
+                    ..SynthState::default()
+                });
+                self.sequence = sequence;
+            }
+        }
+    }
+
+    /// Settings saved before the last power-down, `None` when the slot is
+    /// blank or corrupt.
+    pub fn load(&self) -> Option<SynthState> {
+        self.latest
+    }
+
+    /// Write the settings to the next record, moving on to the other sector
+    /// and erasing it when this one is full. The watchdog's timeout is
+    /// raised for the erase and set back to `WATCHDOG_TIMEOUT` after it.
+    pub fn save(&mut self, state: &SynthState, watchdog: &mut Watchdog) -> Result<(), Error> {
+        let mut existing = [0u8; 4];
+        if self.next < RECORD_COUNT {
+            self.flash
+                .blocking_read(record_offset(self.sector, self.next), &mut existing)?;
+        }
+        if self.next >= RECORD_COUNT || u32::from_le_bytes(existing) != BLANK {
+            self.sector = (self.sector + 1) % SECTOR_COUNT;
+            self.next = 0;
+        }
+        if self.next == 0 {
+            let start = record_offset(self.sector, 0);
+            watchdog.start(ERASE_WATCHDOG_TIMEOUT);
+            let erased = self.flash.blocking_erase(start, start + ERASE_SIZE as u32);
+            watchdog.start(crate::WATCHDOG_TIMEOUT);
+            erased?;
+        }
+
+        self.sequence = self.sequence.wrapping_add(1) % BLANK;
+        let mut record = [0xFFu8; RECORD_SIZE];
+        record[..4].copy_from_slice(&self.sequence.to_le_bytes());
+        let bytes = state.to_bytes();
+        record[4..4 + bytes.len()].copy_from_slice(&bytes);
+        self.flash
+            .blocking_write(record_offset(self.sector, self.next), &record)?;
+        self.latest = Some(*state);
+        self.next += 1;
+        Ok(())
+    }
+}
+
+fn record_offset(sector: usize, index: usize) -> u32 {
+    SLOT_OFFSET + (sector * ERASE_SIZE + index * RECORD_SIZE) as u32
+}
@@ -0,0 +1,87 @@
+//! Keyboard modes: what each of the 48 keys plays.
+//!
+//! The key matrix is a grid of `KEY_COUNT` columns by `OCTAVE_COUNT` rows.
+//! Chromatic (the default) reads it as four octaves of a piano keyboard.
+//! Isomorphic lays it out in a Wicki-Hayden pattern: a whole tone per key
+//! along a row and a fifth per row up, so one step up and one left is a
+//! fourth. Every interval, and so every chord shape, has the same shape
+//! wherever it is played: the major triad root-third-fifth is two keys
+//! right, then one row up from the root, in any key. Drum pads play the
+//! drum machine's sounds instead of notes, in three blocks of four keys per
+//! row. Drum step editing turns the grid into the drum machine's pattern:
+//! its 48 keys are the 3 tracks of 16 steps, read like text from the bottom
+//! left, and a press switches its step on or off.
+//!
+//! Keys keep their identity whatever the mode (voices, the split and the
+//! held-key LEDs all go by the physical key); only the pitch a key sounds,
+//! or the drum it hits, comes from the mode here.
+
+use crate::drums::{DRUM_STEPS, DrumTrack};
+use crate::keyboard::KEY_COUNT;
+
+/// How `KeyboardSynth` interprets the key grid, see
+/// `KeyboardSynth::set_keyboard_mode`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, defmt::Format)]
+pub enum KeyboardMode {
+    /// One octave per row, C3 at the bottom left
+    #[default]
+    Chromatic,
+    /// Wicki-Hayden: whole tones along the rows, fifths between them
+    Isomorphic,
+    /// Each key hits a fixed drum, see `DRUM_PADS`
+    DrumPads,
+    /// Each key switches a drum step, see `KeyboardMode::drum_step`
+    DrumEdit,
+}
+
+/// Semitones per key along a row, and per row up, of the isomorphic layout
+const ISOMORPHIC_KEY_STEP: usize = 2;
+const ISOMORPHIC_ROW_STEP: usize = 7;
+
+/// Drum of each key in every row of the drum pads: kicks on the left,
+/// snares in the middle, hats on the right
+pub const DRUM_PADS: [DrumTrack; KEY_COUNT] = {
+    use DrumTrack::{Hat, Kick, Snare};
+    [
+        Kick, Kick, Kick, Kick, Snare, Snare, Snare, Snare, Hat, Hat, Hat, Hat,
+    ]
+};
+
+impl KeyboardMode {
+    /// Pitch of a key as (key, octave) on the chromatic keyboard, or None
+    /// for a drum pad. Octaves past the grid's top row carry on the
+    /// layout.
+    pub fn pitch(self, key: usize, octave: u8) -> Option<(usize, u8)> {
+        match self {
+            Self::Chromatic => Some((key, octave)),
+            Self::Isomorphic => {
+                // C3 to A#4 along the bottom row, up to G6 at the top right
+                let semitone = key * ISOMORPHIC_KEY_STEP + octave as usize * ISOMORPHIC_ROW_STEP;
+                Some((semitone % KEY_COUNT, (semitone / KEY_COUNT) as u8))
+            }
+            Self::DrumPads | Self::DrumEdit => None,
+        }
+    }
+
+    /// Drum a key hits, in drum pad mode.
+    pub fn drum(self, key: usize) -> Option<DrumTrack> {
+        (self == Self::DrumPads).then(|| DRUM_PADS[key])
+    }
+
+    /// Track and step a key switches, in drum step editing: the kick's 16
+    /// steps from the bottom left along the row and on into the next, then
+    /// the snare's and the hat's.
+    pub fn drum_step(self, key: usize, octave: u8) -> Option<(DrumTrack, usize)> {
+        if self != Self::DrumEdit {
+            return None;
+        }
+        let cell = octave as usize * KEY_COUNT + key;
+        let track = match cell / DRUM_STEPS {
+            0 => DrumTrack::Kick,
+            1 => DrumTrack::Snare,
+            2 => DrumTrack::Hat,
+            _ => return None,
+        };
+        Some((track, cell % DRUM_STEPS))
+    }
+}
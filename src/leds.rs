@@ -0,0 +1,164 @@
+//! Brightness of one LED per key, with a scale highlight as a playing aid.
+//!
+//! `KeyLeds::levels` turns the held keys into a brightness per key for an
+//! LED driver to write out: held keys light bright, and with a scale
+//! selected the other keys of that scale glow dim, so the player can see
+//! which keys are in key. The highlight goes by the pitch each key plays in
+//! the keyboard mode (see `layout.rs`), and drum pads have none. Brightness
+//! is 0-255, linear in PWM duty.
+
+use crate::keyboard::{KEY_COUNT, OCTAVE_COUNT};
+use crate::layout::KeyboardMode;
+
+/// Scales the highlight can show.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Scale {
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+}
+
+impl Scale {
+    /// Pitch classes of the scale on C, bit 0 = C
+    fn mask(self) -> u16 {
+        let degrees: &[u8] = match self {
+            Self::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Self::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+            Self::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Self::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Self::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Self::Blues => &[0, 3, 5, 6, 7, 10],
+        };
+        degrees.iter().fold(0, |mask, &degree| mask | 1 << degree)
+    }
+
+    /// Pitch classes of the scale on `root` (0 = C, 11 = B), bit 0 = C.
+    pub fn pitch_classes(self, root: u8) -> u16 {
+        let mask = self.mask() as u32;
+        let root = (root % 12) as u32;
+        ((mask << root | mask >> (12 - root)) & 0xFFF) as u16
+    }
+}
+
+/// Default brightness of held keys and of highlighted scale keys
+pub const LED_HELD: u8 = 255;
+pub const LED_IN_SCALE: u8 = 24;
+
+/// Per-key LED levels, see the module docs.
+pub struct KeyLeds {
+    /// Pitch classes to highlight, 0 for none
+    highlight: u16,
+    mode: KeyboardMode,
+    held: u8,
+    in_scale: u8,
+}
+
+impl Default for KeyLeds {
+    fn default() -> Self {
+        Self {
+            highlight: 0,
+            mode: KeyboardMode::Chromatic,
+            held: LED_HELD,
+            in_scale: LED_IN_SCALE,
+        }
+    }
+}
+
+impl KeyLeds {
+    /// Highlight the keys of `scale` on `root` (0 = C), or none with
+    /// `None`. Applies from the next `levels`.
+    pub fn set_scale(&mut self, scale: Option<(Scale, u8)>) {
+        self.highlight = scale.map_or(0, |(scale, root)| scale.pitch_classes(root));
+    }
+
+    /// Follow the synth's keyboard mode, which decides the pitch of each key
+    /// (see `KeyboardSynth::set_keyboard_mode`).
+    pub fn set_keyboard_mode(&mut self, mode: KeyboardMode) {
+        self.mode = mode;
+    }
+
+    /// Brightness of held keys and of the highlighted keys not held.
+    pub fn set_brightness(&mut self, held: u8, in_scale: u8) {
+        self.held = held;
+        self.in_scale = in_scale;
+    }
+
+    /// Brightness of every key, lowest octave first, for the held keys
+    /// (e.g. `KeyboardSynth::held_keys`).
+    pub fn levels(
+        &self,
+        held: &[[bool; KEY_COUNT]; OCTAVE_COUNT],
+    ) -> [[u8; KEY_COUNT]; OCTAVE_COUNT] {
+        let mut levels = [[0; KEY_COUNT]; OCTAVE_COUNT];
+        for (octave, row) in levels.iter_mut().enumerate() {
+            for (key, level) in row.iter_mut().enumerate() {
+                let in_scale = self
+                    .mode
+                    .pitch(key, octave as u8)
+                    .is_some_and(|(class, _)| self.highlight & 1 << class != 0);
+                *level = if held[octave][key] {
+                    self.held
+                } else if in_scale {
+                    self.in_scale
+                } else {
+                    0
+                };
+            }
+        }
+        levels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c_major_lights_the_white_keys() {
+        let mut leds = KeyLeds::default();
+        let mut held = [[false; KEY_COUNT]; OCTAVE_COUNT];
+        held[1][4] = true; // E4
+        held[2][6] = true; // F#5, out of the scale
+        assert_eq!(leds.levels(&held)[0], [0; KEY_COUNT]);
+
+        leds.set_scale(Some((Scale::Major, 0)));
+        let levels = leds.levels(&held);
+        let (dim, lit) = (LED_IN_SCALE, LED_HELD);
+        let white = [dim, 0, dim, 0, dim, dim, 0, dim, 0, dim, 0, dim];
+        assert_eq!(levels[0], white);
+        assert_eq!(
+            levels[1],
+            [dim, 0, dim, 0, lit, dim, 0, dim, 0, dim, 0, dim]
+        );
+        assert_eq!(levels[2][6], lit);
+
+        // G major adds F# and drops F
+        leds.set_scale(Some((Scale::Major, 7)));
+        let levels = leds.levels(&held);
+        assert_eq!((levels[0][5], levels[0][6]), (0, dim));
+        assert_eq!(Scale::MinorPentatonic.pitch_classes(9), 0b0010_1001_0101);
+    }
+
+    #[test]
+    fn highlight_follows_the_isomorphic_pitches() {
+        let mut leds = KeyLeds::default();
+        leds.set_scale(Some((Scale::Major, 0)));
+        leds.set_keyboard_mode(KeyboardMode::Isomorphic);
+        let held = [[false; KEY_COUNT]; OCTAVE_COUNT];
+        let levels = leds.levels(&held);
+        let dim = LED_IN_SCALE;
+        // Bottom row in whole tones from C: C D E F# G# A# and on up
+        assert_eq!(levels[0], [dim, dim, dim, 0, 0, 0, dim, dim, dim, 0, 0, 0]);
+        // A fifth up, from G: G A B C# D# F
+        assert_eq!(
+            levels[1],
+            [dim, dim, dim, 0, 0, dim, dim, dim, dim, 0, 0, dim]
+        );
+
+        leds.set_keyboard_mode(KeyboardMode::DrumPads);
+        assert_eq!(leds.levels(&held), [[0; KEY_COUNT]; OCTAVE_COUNT]);
+    }
+}
@@ -0,0 +1,33 @@
+//! Hardware-independent synth core shared by the firmware and the host
+//! simulator: voice allocation, key handling and the fundsp audio graph.
+//!
+//! Unit tests run on the host:
+//!
+//! ```text
+//! cargo test --lib --no-default-features --target x86_64-unknown-linux-gnu
+//! ```
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+mod arrayinit_nostd;
+pub mod chord;
+pub mod drums;
+pub mod encoder;
+pub mod envelope;
+pub mod fixed;
+pub mod follower;
+pub mod granular;
+pub mod interp;
+pub mod keyboard;
+pub mod layout;
+pub mod leds;
+pub mod load;
+pub mod looper;
+pub mod midi;
+pub mod preset;
+pub mod scan;
+pub mod state;
+pub mod sysex;
+pub mod trance_gate;
@@ -0,0 +1,128 @@
+//! CPU load meter for the audio loop.
+//!
+//! The load is the fraction of each buffer period spent filling the next
+//! buffer, the time the busy pin is high. Above 1.0 the fill overruns and
+//! the DAC underruns. Single buffers jump around with key scans and
+//! auto-saves, so `CpuLoad` smooths them with an exponential moving average
+//! and also keeps the peak since the last report.
+//!
+//! `simulate_underruns` plays a run of fill times through the firmware's
+//! ring of DMA buffers, to compare buffer counts off the board.
+
+/// EMA weight of each new buffer: about 16 buffers (0.2 s at 640 frames)
+/// to settle
+const LOAD_SMOOTHING: f32 = 1.0 / 16.0;
+
+/// Smoothed busy time / buffer period of the fill loop.
+pub struct CpuLoad {
+    load: f32,
+    peak: f32,
+}
+
+impl Default for CpuLoad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuLoad {
+    pub const fn new() -> Self {
+        Self {
+            load: 0.0,
+            peak: 0.0,
+        }
+    }
+
+    /// Record one buffer: `busy_us` spent filling it out of a period of
+    /// `period_us`.
+    pub fn record(&mut self, busy_us: u64, period_us: u64) {
+        if period_us == 0 {
+            return;
+        }
+        let load = busy_us as f32 / period_us as f32;
+        self.load += (load - self.load) * LOAD_SMOOTHING;
+        self.peak = self.peak.max(load);
+    }
+
+    /// Smoothed load, 0.0 idle to 1.0 at the real-time limit.
+    pub fn cpu_load(&self) -> f32 {
+        self.load
+    }
+
+    /// Highest single-buffer load since the last call, then reset.
+    pub fn take_peak(&mut self) -> f32 {
+        core::mem::take(&mut self.peak)
+    }
+
+    /// The smoothed load as a PWM compare value for a counter wrapping at
+    /// `top`, full brightness at 100% load.
+    pub fn led_duty(&self, top: u16) -> u16 {
+        (self.load.clamp(0.0, 1.0) * (top as f32 + 1.0)) as u16
+    }
+}
+
+/// Underruns of a ring of `buffer_count` DMA buffers, each playing for
+/// `period_us`, filled one after the other in `fill_us` each. Models the
+/// firmware: a fill waits for the previous one and for a buffer the output
+/// has finished with, and the output counts an underrun whenever it
+/// finishes a buffer before the next is filled (see `output_task`).
+pub fn simulate_underruns(
+    buffer_count: usize,
+    period_us: u64,
+    fill_us: impl IntoIterator<Item = u64>,
+) -> usize {
+    // When each of the last `buffer_count` buffers finishes playing, the
+    // oldest first, 0 for none yet
+    let mut play_ends = alloc::collections::VecDeque::from(alloc::vec![0; buffer_count]);
+    let mut filled = 0;
+    let mut underruns = 0;
+    for (index, fill) in fill_us.into_iter().enumerate() {
+        let free = play_ends.pop_front().unwrap_or(0);
+        filled = filled.max(free) + fill;
+        let last_end = play_ends.back().copied().unwrap_or(0);
+        if index > 0 && filled > last_end {
+            underruns += 1;
+        }
+        play_ends.push_back(filled.max(last_end) + period_us);
+    }
+    underruns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_settles_on_the_busy_fraction() {
+        let mut load = CpuLoad::new();
+        load.record(8_000, 0);
+        assert_eq!(load.cpu_load(), 0.0);
+        for _ in 0..200 {
+            load.record(3_600, 14_512);
+        }
+        assert!((load.cpu_load() - 0.248).abs() < 0.001);
+        // One slow buffer barely moves the average but shows as the peak
+        load.record(13_000, 14_512);
+        assert!(load.cpu_load() < 0.3);
+        assert!((load.take_peak() - 0.896).abs() < 0.001);
+        assert_eq!(load.take_peak(), 0.0);
+        assert_eq!(load.led_duty(999), 288);
+    }
+
+    #[test]
+    fn three_buffers_ride_out_slow_fills_that_underrun_two() {
+        // A heavy effects load at 640 frames: fills take 85% of the 14.5 ms
+        // period, and every 16th takes half again as long (a graph rebuild
+        // or a slow scan). Modelled figures, not board measurements.
+        let period = 14_512;
+        let fills = (0..1_000).map(|index| if index % 16 == 15 { 21_000 } else { 12_300 });
+        let two = simulate_underruns(2, period, fills.clone());
+        let three = simulate_underruns(3, period, fills);
+        assert_eq!(two, 1_000 / 16);
+        assert_eq!(three, 0);
+        // Fills that keep up never underrun, and a steady overload
+        // underruns with any count
+        assert_eq!(simulate_underruns(2, period, [period; 100]), 0);
+        assert!(simulate_underruns(4, period, [period + 500; 100]) > 90);
+    }
+}
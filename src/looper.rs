@@ -0,0 +1,200 @@
+//! Loop recorder: captures the synth's output and plays it back under live
+//! playing, for layering parts one at a time.
+//!
+//! The loop is kept in a heap buffer, allocated on the first recording and
+//! freed by `clear`, as mono 16-bit samples at half the sample rate
+//! (~11 kHz bandwidth, fine for a backing layer): `LOOPER_SECONDS` take
+//! `LOOPER_BYTES` (~172 KB) of the 384 KB firmware heap. Flash would hold
+//! more but can't be written fast enough without stalling the audio.
+//!
+//! States, driven by `KeyboardSynth::looper_record`, `looper_play`,
+//! `looper_stop` and `looper_clear`:
+//!
+//! - `Empty`, record: `Recording`. The first pass sets the loop length; it
+//!   ends at `play` (`Playing`) or `stop` (`Stopped`), or by itself at
+//!   `LOOPER_SECONDS`, switching to `Playing`.
+//! - `Playing`, record: `Overdubbing`, which adds the live output to the
+//!   loop as it comes round; `play` keeps the result playing.
+//! - `Stopped`, play or record: `Playing` or `Overdubbing` from the loop
+//!   start.
+//! - `stop` silences any state but `Empty`; `clear` returns to `Empty` from
+//!   any state.
+
+use crate::interp::InterpQuality;
+use alloc::vec::Vec;
+use fundsp::DEFAULT_SR;
+
+/// Longest loop in seconds
+pub const LOOPER_SECONDS: usize = 4;
+/// Frames per stored sample
+const LOOPER_DECIMATION: usize = 2;
+/// Stored samples in the longest loop
+const LOOPER_SAMPLES: usize = LOOPER_SECONDS * DEFAULT_SR as usize / LOOPER_DECIMATION;
+/// Heap the loop buffer takes
+pub const LOOPER_BYTES: usize = LOOPER_SAMPLES * core::mem::size_of::<i16>();
+
+/// What the looper is doing.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, defmt::Format)]
+pub enum LooperState {
+    /// No loop recorded
+    #[default]
+    Empty,
+    /// Recording the first pass, which sets the loop length
+    Recording,
+    Playing,
+    /// Playing and adding the live output to the loop
+    Overdubbing,
+    /// Loop kept but silent
+    Stopped,
+}
+
+/// Loop buffer and transport.
+#[derive(Default)]
+pub struct Looper {
+    state: LooperState,
+    /// Stored samples; the loop length once the first recording ends
+    buffer: Vec<i16>,
+    /// Position in frames, 0 to `buffer.len() * LOOPER_DECIMATION`
+    position: usize,
+    /// Sum of the live frames waiting to be stored as one sample
+    pending: f32,
+    /// How playback fills in the frames between stored samples
+    interp: InterpQuality,
+}
+
+impl Looper {
+    pub fn state(&self) -> LooperState {
+        self.state
+    }
+
+    /// Loop length in frames, 0 while empty or recording the first pass.
+    pub fn len_frames(&self) -> usize {
+        match self.state {
+            LooperState::Empty | LooperState::Recording => 0,
+            _ => self.buffer.len() * LOOPER_DECIMATION,
+        }
+    }
+
+    pub fn set_interp_quality(&mut self, quality: InterpQuality) {
+        self.interp = quality;
+    }
+
+    /// Start recording or overdubbing. Allocating the buffer for a first
+    /// recording can fail: the needed bytes are returned and the looper
+    /// stays empty.
+    pub fn record(&mut self) -> Result<(), usize> {
+        match self.state {
+            LooperState::Empty => {
+                self.buffer.clear();
+                self.buffer
+                    .try_reserve_exact(LOOPER_SAMPLES)
+                    .map_err(|_| LOOPER_BYTES)?;
+                self.state = LooperState::Recording;
+                self.restart();
+            }
+            LooperState::Playing => self.state = LooperState::Overdubbing,
+            LooperState::Stopped => {
+                self.state = LooperState::Overdubbing;
+                self.restart();
+            }
+            LooperState::Recording | LooperState::Overdubbing => {}
+        }
+        Ok(())
+    }
+
+    /// Play the loop, ending a recording or overdub.
+    pub fn play(&mut self) {
+        match self.state {
+            LooperState::Recording => {
+                self.end_recording();
+                self.state = LooperState::Playing;
+            }
+            LooperState::Overdubbing => self.state = LooperState::Playing,
+            LooperState::Stopped => {
+                self.state = LooperState::Playing;
+                self.restart();
+            }
+            LooperState::Empty | LooperState::Playing => {}
+        }
+    }
+
+    /// Silence the loop, ending a recording or overdub; it is kept for
+    /// `play`.
+    pub fn stop(&mut self) {
+        match self.state {
+            LooperState::Recording => {
+                self.end_recording();
+                self.state = LooperState::Stopped;
+            }
+            LooperState::Playing | LooperState::Overdubbing => self.state = LooperState::Stopped,
+            LooperState::Empty | LooperState::Stopped => {}
+        }
+    }
+
+    /// Drop the loop and free its buffer.
+    pub fn clear(&mut self) {
+        self.state = LooperState::Empty;
+        self.buffer = Vec::new();
+    }
+
+    fn end_recording(&mut self) {
+        if self.buffer.is_empty() {
+            // Nothing captured yet: keep one silent sample as the loop
+            self.buffer.push(0);
+        }
+        self.restart();
+    }
+
+    fn restart(&mut self) {
+        self.position = 0;
+        self.pending = 0.0;
+    }
+
+    /// Take one frame of the live output and return the loop's sample to
+    /// mix with it.
+    #[inline]
+    pub fn process(&mut self, live: f32) -> f32 {
+        let index = self.position / LOOPER_DECIMATION;
+        let last_frame = self.position % LOOPER_DECIMATION == LOOPER_DECIMATION - 1;
+        match self.state {
+            LooperState::Empty | LooperState::Stopped => 0.0,
+            LooperState::Recording => {
+                self.pending += live;
+                self.position += 1;
+                if last_frame {
+                    self.buffer.push(to_sample(self.pending));
+                    self.pending = 0.0;
+                    if self.buffer.len() == LOOPER_SAMPLES {
+                        self.end_recording();
+                        self.state = LooperState::Playing;
+                    }
+                }
+                0.0
+            }
+            LooperState::Playing | LooperState::Overdubbing => {
+                // Interpolation up to the full rate, round the loop end
+                let len = self.buffer.len();
+                let s = core::array::from_fn(|k| self.buffer[(index + len + k - 1) % len] as f32);
+                let t = (self.position % LOOPER_DECIMATION) as f32 / LOOPER_DECIMATION as f32;
+                let out = self.interp.interpolate(s, t) / i16::MAX as f32;
+                if self.state == LooperState::Overdubbing {
+                    self.pending += live;
+                    if last_frame {
+                        let stored = self.buffer[index] as f32 / i16::MAX as f32;
+                        self.buffer[index] =
+                            to_sample(stored * LOOPER_DECIMATION as f32 + self.pending);
+                        self.pending = 0.0;
+                    }
+                }
+                self.position = (self.position + 1) % (len * LOOPER_DECIMATION);
+                out
+            }
+        }
+    }
+}
+
+/// Average of `LOOPER_DECIMATION` summed frames as a stored sample.
+fn to_sample(sum: f32) -> i16 {
+    let level = (sum / LOOPER_DECIMATION as f32).clamp(-1.0, 1.0);
+    (level * i16::MAX as f32) as i16
+}
@@ -1,126 +1,536 @@
 //! This example shows generating audio and sending it to a connected i2s DAC using the PIO
 //! module of the RP2040.
 //!
+//! Default wiring (see `pins.rs` to change it).
+//!
 //! Connect the i2s DAC as follows:
 //!   bclk : GPIO 18
 //!   lrc  : GPIO 19
 //!   din  : GPIO 20
 //!
+//! or an MCP4822-style SPI DAC (OUTPUT_BACKEND, see `output.rs`):
+//!   sck  : GPIO 18
+//!   sdi  : GPIO 19
+//!   cs   : GPIO 21
+//!
+//! or, with no DAC, RC-filtered PWM (see `output.rs` for the filter):
+//!   left : GPIO 20
+//!   right: GPIO 21
+//!
 //! I2C device (vl53l0x) connected to:
 //!   sda  : GPIO 26
 //!   scl  : GPIO 27
 //!
+//! Pitch-bend pot (spring-return, center detent) wiper:
+//!   adc2 : GPIO 28
+//!
+//! DIN MIDI, with MIDI_DIN set (see `midi_port.rs`):
+//!   in   : GPIO 17
+//!   out  : GPIO 16
+//!
+//! Keys: 12 key lines on GPIO 0-11, with either 4 octave strobes on GPIO
+//! 12-15 (a 48-key matrix) or, with OCTAVE_ENCODER set, a rotary
+//! encoder on GPIO 12/13 picking the octave (see `octave_select.rs`).
+//!
 //! Then hold down the boot select button to trigger a rising triangle waveform.
 
 #![no_std]
 #![no_main]
-#![allow(static_mut_refs)]
 
 extern crate alloc;
+use core::cell::RefCell;
 use core::mem;
-use core::ops::{Mul, Sub};
+use core::num::NonZeroUsize;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::u16::MAX;
-use embassy_executor::Spawner;
+use embassy_executor::{InterruptExecutor, Spawner};
 
 use linked_list_allocator::LockedHeap;
 
+/// The heap, recording the most of it ever in use for the scan report
+struct PeakHeap {
+    heap: LockedHeap,
+    peak: AtomicUsize,
+}
+
+unsafe impl core::alloc::GlobalAlloc for PeakHeap {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        // SAFETY: forwarded as is from the caller
+        let ptr = unsafe { self.heap.alloc(layout) };
+        if !ptr.is_null() {
+            self.peak
+                .fetch_max(self.heap.lock().used(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        // SAFETY: forwarded as is from the caller
+        unsafe { self.heap.dealloc(ptr, layout) }
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: PeakHeap = PeakHeap {
+    heap: LockedHeap::empty(),
+    peak: AtomicUsize::new(0),
+};
 
 const HEAP_SIZE: usize = 384 * 1024;
-static mut HEAP: [mem::MaybeUninit<u8>; HEAP_SIZE] = [mem::MaybeUninit::uninit(); HEAP_SIZE];
+static HEAP: ConstStaticCell<[mem::MaybeUninit<u8>; HEAP_SIZE]> =
+    ConstStaticCell::new([mem::MaybeUninit::uninit(); HEAP_SIZE]);
+
+/// Hand the heap memory to the allocator, before anything allocates. The
+/// cell gives the memory out only once, so a second call panics instead of
+/// aliasing the live heap.
+fn init_heap() {
+    ALLOCATOR.heap.lock().init_from_slice(HEAP.take());
+}
 
+/// Allocator statistics for the synth's heap guard.
+fn heap_stats() -> keyboard::HeapStats {
+    let heap = ALLOCATOR.heap.lock();
+    keyboard::HeapStats {
+        used: heap.used(),
+        free: heap.free(),
+    }
+}
+
+use embassy_rp::adc::{Adc, Channel, InterruptHandler as AdcInterruptHandler};
 use embassy_rp::bind_interrupts;
-use embassy_rp::gpio::{Input, Pull};
-use embassy_rp::i2c::{Async, I2c, InterruptHandler as I2cInterruptHandler};
-use embassy_rp::peripherals::{I2C1, PIO0};
+use embassy_rp::gpio::{Input, Output, Pull};
+use embassy_rp::i2c::{I2c, InterruptHandler as I2cInterruptHandler};
+use embassy_rp::interrupt;
+use embassy_rp::interrupt::{InterruptExt, Priority};
+use embassy_rp::peripherals::{I2C1, PIO0, UART0};
 use embassy_rp::pio::{InterruptHandler as PioInterruptHandler, Pio};
 use embassy_rp::pio_programs::i2s::{PioI2sOut, PioI2sOutProgram};
-use static_cell::StaticCell;
+use embassy_rp::uart::InterruptHandler as UartInterruptHandler;
+use embassy_rp::watchdog::{ResetReason, Watchdog};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::zerocopy_channel;
+use static_cell::{ConstStaticCell, StaticCell};
 use {defmt_rtt as _, panic_probe as _};
 
 use vl53l0x::VL53L0x;
 
-mod arrayinit_nostd;
-mod keyboard;
+mod analog;
+mod dump;
+mod key_scan;
+mod last_state;
+mod midi_port;
+mod octave_select;
+mod output;
+mod pins;
+mod sensor;
+
+use pico2_synth::keyboard;
+use pico2_synth::load::CpuLoad;
+use pico2_synth::scan::ScanOrder;
 
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => PioInterruptHandler<PIO0>;
     I2C1_IRQ => I2cInterruptHandler<I2C1>;
+    ADC_IRQ_FIFO => AdcInterruptHandler;
+    UART0_IRQ => UartInterruptHandler<UART0>;
 });
 
 const SAMPLE_RATE: u32 = 44_100;
-const BIT_DEPTH: u32 = 16;
-
-// Task to handle VL53L0X interrupts via async GPIO and control pitch bend
-// Distance range: 50mm to 400mm maps to pitch bend -1.0 to 1.0 (±1 semitone)
-#[embassy_executor::task]
-async fn sensor_task(
-    mut tof: VL53L0x<I2c<'static, I2C1, Async>>,
-    mut int_pin: Input<'static>,
-    resonator_freq: fundsp::shared::Shared,
-) {
-    const MIN_DIST: u16 = 30; // mm
-    const MAX_DIST: u16 = 400; // mm
+// DAC to drive, see output.rs for the supported SPI DACs and the I2S
+// formats (24 or 32 bits for DACs with more than 16 bits of range)
+const OUTPUT_BACKEND: output::OutputBackend = output::OutputBackend::I2s(output::I2sFormat::Bits16);
+
+// DMA buffer size in frames. One buffer plays while the other is filled, so
+// with two buffers the output latency is about 2 x frames / SAMPLE_RATE:
+//    256 frames:  5.8 ms per buffer, ~11.6 ms latency
+//    480 frames: 10.9 ms per buffer, ~21.8 ms latency
+//    640 frames: 14.5 ms per buffer, ~29.0 ms latency (default)
+//   1024 frames: 23.2 ms per buffer, ~46.4 ms latency
+// Smaller buffers lower latency but leave less time to absorb slow fills
+// (effects, scans); the PIO TX FIFO only holds 8 frames (~0.18 ms, half
+// that with 32-bit I2S slots), so with two buffers the fill of every
+// buffer must finish within one buffer period.
+const MAX_BUFFER_FRAMES: usize = 1024;
+const MIN_BUFFER_FRAMES: usize = 64;
+const DEFAULT_BUFFER_FRAMES: usize = 640;
+static ACTIVE_BUFFER_FRAMES: AtomicUsize = AtomicUsize::new(DEFAULT_BUFFER_FRAMES);
+
+/// Change how many frames are filled and queued per DMA transfer, from the
+/// next buffer on. Clamped to MIN_BUFFER_FRAMES..=MAX_BUFFER_FRAMES.
+fn set_active_buffer_frames(frames: usize) {
+    let clamped = frames.clamp(MIN_BUFFER_FRAMES, MAX_BUFFER_FRAMES);
+    if clamped != frames {
+        defmt::warn!("Buffer size {} out of range, using {}", frames, clamped);
+    }
+    ACTIVE_BUFFER_FRAMES.store(clamped, Ordering::Relaxed);
+}
 
-    loop {
-        // Wait for falling edge on GPIO1 (measurement ready)
-        int_pin.wait_for_falling_edge().await;
-
-        // Read distance and update pitch bend
-        match tof.read_range_continuous_millimeters() {
-            Ok(distance) => {
-                if distance > 1500 {
-                    continue;
+// Frames to grow the buffer by whenever the output underruns, up to
+// MAX_BUFFER_FRAMES: trades latency for stability once heavy effects
+// overrun the fill deadline. None keeps DEFAULT_BUFFER_FRAMES.
+const BUFFER_GROW_ON_UNDERRUN: Option<NonZeroUsize> = None;
+
+// DMA buffers in the ring (2 to 4). The fill loop keeps up to
+// BUFFER_COUNT - 1 of them filled ahead of the one playing, so a fill may
+// run late by the extra buffers' length without an underrun, and every
+// note waits that much longer to be heard. At 640 frames:
+//   2 buffers: ~29.0 ms latency, each fill within 14.5 ms
+//   3 buffers: ~43.5 ms latency, a fill may take up to 29.0 ms if the
+//              next one catches up
+//   4 buffers: ~58.1 ms latency, up to 43.5 ms
+// Extra buffers only absorb fills that run long now and then, such as a
+// graph rebuild crossfading two graphs, or granular freeze grains on top
+// of 2x oversampling; a steady overload underruns with any count. Every
+// underrun is logged, so raise this only when those logs show occasional
+// slow fills. `load::simulate_underruns` models the ring: with fills at
+// 85% of the period and every 16th half again as long, 2 buffers underrun
+// on each slow fill and 3 never do (modelled, not measured on a board).
+// Alternatively, three smaller buffers give two 640-frame buffers' latency
+// with more of it spent as slack: 3 x 427 frames is ~29 ms too.
+const BUFFER_COUNT: usize = 2;
+// Static RAM for the DMA buffers, 8 KB each. SRAM is 520 KB, of which the
+// heap takes HEAP_SIZE; the rest also holds the stacks and other statics.
+const BUFFER_RAM_BUDGET: usize = 32 * 1024;
+const _: () = assert!(
+    BUFFER_COUNT >= 2 && BUFFER_COUNT * mem::size_of::<output::AudioBuffer>() <= BUFFER_RAM_BUDGET,
+    "BUFFER_COUNT must be 2 or more and fit in BUFFER_RAM_BUDGET"
+);
+const _: () = assert!(
+    HEAP_SIZE + BUFFER_RAM_BUDGET <= 448 * 1024,
+    "Heap and DMA buffers leave too little SRAM for the stacks"
+);
+
+// Runs the output task above the audio loop's thread-mode executor
+static OUTPUT_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+unsafe fn SWI_IRQ_1() {
+    // SAFETY: SWI_IRQ_1 is only pended by this executor.
+    unsafe { OUTPUT_EXECUTOR.on_interrupt() }
+}
+
+// A short A440 test tone at power-up confirms the DAC wiring and amp are
+// working before any key is pressed. Zero disables it.
+const BOOT_TONE_TIME: embassy_time::Duration = embassy_time::Duration::from_millis(300);
+
+// Settling time after asserting an octave strobe before reading its keys.
+// The ~50k internal pull-ups and a few tens of pF of matrix wiring give an
+// RC of a few µs, so without it the first reads can still see the previous
+// octave. 5 µs is enough for typical hand wiring; raise it for long ribbon
+// cables. Costs 4 x settle time per scan (20 µs), well inside the interval.
+const OCTAVE_SETTLE_TIME: embassy_time::Duration = embassy_time::Duration::from_micros(5);
+
+// Select the keys' octave with a rotary encoder instead of the 4 octave
+// strobes: one octave of keys, and the encoder steps it through the
+// keyboard's range (see octave_select.rs for the wiring)
+const OCTAVE_ENCODER: bool = false;
+
+// Scan the keys in their own task every SCAN_INTERVAL instead of once per
+// audio buffer in the audio loop, off the fill's deadline. Keeps the
+// latency at about 2 buffers with partial scans; see key_scan.rs.
+const SCAN_TASK: bool = false;
+
+// Scan the key matrix at boot and report stuck keys over defmt. Takes about
+// 0.2 ms; keys held down during power-up are reported as stuck.
+const KEY_SELFTEST: bool = true;
+// Reads of every key during the self-test; a key is stuck only if it reads
+// pressed every time, so a bouncing contact is not flagged
+const KEY_SELFTEST_PASSES: usize = 8;
+
+// Auto-save the sound settings to the flash last-state slot at most this
+// often, and only when they changed; they are restored at the next boot.
+// Zero disables it.
+const AUTOSAVE_INTERVAL: core::time::Duration = core::time::Duration::from_secs(30);
+
+// Fade the output in from silence over this long after the first buffer,
+// so power-up transients don't pop through the DAC and amp. Zero starts at
+// full level.
+const STARTUP_FADE: core::time::Duration = core::time::Duration::from_millis(50);
+
+// Bass/treble balance in dB per octave across the keyboard; around -2.0
+// tames boomy low octaves on a small speaker, 0.0 is flat.
+const TILT_DB_PER_OCTAVE: f32 = 0.0;
+
+// Overdrive amount, 0.0 (off) to 1.0, and where it sits: before the filter
+// it sounds warm and round, after it gritty and bright.
+const DRIVE: f32 = 0.0;
+const DRIVE_AFTER_FILTER: bool = false;
+
+// Free a released voice once its tail has decayed below this level in dB,
+// so new notes find it free sooner and it stops costing CPU. 0.0 keeps
+// released voices assigned until they are reused.
+const RELEASE_CUTOFF_DB: f32 = -60.0;
+
+// A held key must read released this long before its note ends, riding
+// out momentary contact dropouts on worn keys (see
+// `KeyboardSynth::set_release_holdoff_ms`). 0 releases at once.
+const RELEASE_HOLDOFF_MS: u32 = 5;
+
+// Fade a stolen voice out over this many ms before its new note starts,
+// instead of cutting the old note off with a click. 0 steals at once.
+const STEAL_CROSSFADE_MS: u32 = 5;
+
+// Play a four-on-the-floor drum beat at the synth's tempo from boot, to
+// jam over.
+const DRUM_BEAT: bool = false;
+// Edit the drum pattern from the keys: press the bottom-left and top-right
+// keys together to turn the grid into the 3 x 16 step grid (kick, snare,
+// hat, read from the bottom left) with the drums playing, and again to
+// play notes (see `KeyboardSynth::set_drum_edit_combo`).
+const DRUM_EDIT: bool = true;
+
+// ToF sensor ranges per second and time per range: a longer budget is
+// steadier but caps the rate (see `sensor.rs`). With TOF_ENABLED off the
+// sensor stays in standby, saving power and CPU when it isn't wanted.
+const TOF_ENABLED: bool = true;
+const TOF_RATE_HZ: u32 = sensor::SENSOR_RATE_HZ;
+const TOF_TIMING_BUDGET: embassy_time::Duration = sensor::SENSOR_TIMING_BUDGET;
+
+// Let the ToF sensor sweep the formant filter through the vowels A-E-I-O-U
+// (hand close to far) instead of tuning the resonator. Hold a chord and move
+// your hand over the sensor for a vocal pad.
+const TOF_FORMANT: bool = false;
+// "Robot" mode: the ToF range is split into TOF_SAMPLE_HOLD_STEPS zones and
+// each zone the hand crosses into plays a random semitone above the held
+// notes (see `KeyboardSynth::set_sensor_sh`). Takes over from the above.
+const TOF_SAMPLE_HOLD: bool = false;
+const TOF_SAMPLE_HOLD_STEPS: u8 = 8;
+
+// Dim the onboard LED with the smoothed CPU load (busy time / buffer period),
+// brighter as the fill loop nears the real-time limit. The load and the peak
+// buffer are also logged every CPU_LOAD_REPORT_INTERVAL.
+const CPU_LOAD_LED: bool = true;
+const CPU_LOAD_REPORT_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(5);
+// LED PWM counter wrap, ~150 kHz at 150 MHz: flicker-free
+const LOAD_LED_TOP: u16 = 999;
+
+// Put the output's envelope on GPIO 17 as PWM, for an LED that follows the
+// playing or, RC-filtered, a control voltage for external gear (see
+// output.rs). The attack and release set how fast it follows a rise and a
+// fall; followed in the fill loop, a few µs per buffer.
+const ENV_FOLLOWER_OUT: bool = false;
+const ENV_FOLLOWER_ATTACK: embassy_time::Duration = embassy_time::Duration::from_millis(5);
+const ENV_FOLLOWER_RELEASE: embassy_time::Duration = embassy_time::Duration::from_millis(150);
+
+// DIN MIDI in and out on UART0 (GPIO 17 in, 16 out through the usual
+// optocoupler and 220 ohm resistors, see midi_port.rs): controller knobs
+// (see midi.rs), MIDI clock, and SysEx patch dumps and loads. The port
+// takes the busy and envelope follower pins.
+const MIDI_DIN: bool = false;
+const _: () = assert!(
+    !(MIDI_DIN && ENV_FOLLOWER_OUT),
+    "MIDI_DIN and ENV_FOLLOWER_OUT share GPIO 17"
+);
+
+// Hardware watchdog, fed once per audio buffer (~14.5 ms). A hung DMA await or
+// a runaway fill stops the feed and resets the chip after this timeout.
+const WATCHDOG_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_millis(250);
+// Scratch register recording which audio loop stage was running, so the
+// reason for a watchdog reset can be reported after the reboot
+const WATCHDOG_STAGE_SCRATCH: usize = 0;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u32)]
+enum LoopStage {
+    Scan = 1,
+    Fill = 2,
+    DmaWait = 3,
+}
+
+impl LoopStage {
+    fn from_scratch(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Scan),
+            2 => Some(Self::Fill),
+            3 => Some(Self::DmaWait),
+            _ => None,
+        }
+    }
+}
+
+/// Scan all 48 keys a few times before the audio loop starts and report any
+/// that always read pressed. Also reads the keys with no octave enabled,
+/// which finds key lines shorted to ground rather than through a switch.
+/// Returns the number of stuck keys.
+fn run_key_selftest(
+    inputs: &[Input<'static>; keyboard::KEY_COUNT],
+    octave_enables: &mut [Output<'static>; keyboard::OCTAVE_COUNT],
+) -> usize {
+    // Bit per key, per octave: set while every pass read the key pressed
+    let mut stuck = [(1u16 << keyboard::KEY_COUNT) - 1; keyboard::OCTAVE_COUNT];
+    let mut shorted = (1u16 << keyboard::KEY_COUNT) - 1;
+
+    for _ in 0..KEY_SELFTEST_PASSES {
+        embassy_time::block_for(OCTAVE_SETTLE_TIME);
+        for (key, input) in inputs.iter().enumerate() {
+            if input.is_high() {
+                shorted &= !(1 << key);
+            }
+        }
+        for (octave, enable) in octave_enables.iter_mut().enumerate() {
+            enable.set_low();
+            embassy_time::block_for(OCTAVE_SETTLE_TIME);
+            for (key, input) in inputs.iter().enumerate() {
+                if input.is_high() {
+                    stuck[octave] &= !(1 << key);
                 }
-                defmt::dbg!("VL53L0X: {} mm", distance);
-                resonator_freq
-                    .set_value(distance.clamp(MIN_DIST, MAX_DIST).sub(MIN_DIST).mul(4) as f32);
             }
-            Err(_) => defmt::warn!("VL53L0X read failed"),
+            enable.set_high();
+        }
+    }
+
+    for key in 0..keyboard::KEY_COUNT {
+        if shorted & (1 << key) != 0 {
+            defmt::warn!(
+                "Key self-test: key line {} is low with no octave enabled",
+                key
+            );
         }
     }
+    let mut count = 0;
+    for (octave, keys) in stuck.iter().enumerate() {
+        // A shorted line reads pressed in every octave, reported above
+        for key in 0..keyboard::KEY_COUNT {
+            if keys & !shorted & (1 << key) != 0 {
+                defmt::warn!("Key self-test: key {} octave {} stuck pressed", key, octave);
+                count += 1;
+            }
+        }
+    }
+    let count = count + shorted.count_ones() as usize * keyboard::OCTAVE_COUNT;
+    if count == 0 {
+        defmt::info!("Key self-test: all keys released");
+    } else {
+        defmt::warn!(
+            "Key self-test: {} of {} keys stuck",
+            count,
+            keyboard::KEY_COUNT * keyboard::OCTAVE_COUNT
+        );
+    }
+    count
 }
 
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
+async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
-
-    unsafe {
-        ALLOCATOR.lock().init_from_slice(&mut HEAP);
+    // Run the DWT cycle counter for the scan and fill cost reports
+    let mut core = cortex_m::Peripherals::take().unwrap();
+    core.DCB.enable_trace();
+    core.DWT.enable_cycle_counter();
+    let pins = pins::pin_config!(p);
+
+    let mut watchdog = Watchdog::new(p.WATCHDOG);
+    match watchdog.reset_reason() {
+        Some(ResetReason::TimedOut) => {
+            let stage = LoopStage::from_scratch(watchdog.get_scratch(WATCHDOG_STAGE_SCRATCH));
+            defmt::warn!("Watchdog reset: audio loop hung in stage {}", stage);
+        }
+        Some(ResetReason::Forced) => defmt::info!("Watchdog forced reset"),
+        None => {}
     }
+    watchdog.set_scratch(WATCHDOG_STAGE_SCRATCH, 0);
+
+    init_heap();
 
-    // Setup I2C1 for vl53l0x on GPIO 26 (SDA) and GPIO 27 (SCL)
+    // Setup I2C1 for vl53l0x
     let i2c = I2c::new_async(
         p.I2C1,
-        p.PIN_27,
-        p.PIN_26,
+        pins.i2c_scl,
+        pins.i2c_sda,
         Irqs,
         embassy_rp::i2c::Config::default(),
     );
 
     // Initialize vl53l0x time-of-flight sensor
-    let mut tof = VL53L0x::new(i2c).expect("VL53L0X initialization failed");
+    let tof = VL53L0x::new(i2c).expect("VL53L0X initialization failed");
     defmt::info!("VL53L0X sensor initialized successfully");
 
-    // Configure sensor for high speed mode (20ms timing budget)
-    tof.set_measurement_timing_budget(20000)
-        .expect("Failed to set timing budget");
+    // The sensor task starts ranging at this rate and budget
+    sensor::configure_sensor(TOF_RATE_HZ, TOF_TIMING_BUDGET);
+    sensor::set_sensor_enabled(TOF_ENABLED);
 
-    // Start continuous mode
-    tof.start_continuous(0)
-        .expect("Failed to start continuous mode");
+    // Input for VL53L0X GPIO1 (async interrupt)
+    let tof_int_pin = Input::new(pins.tof_interrupt, Pull::Up);
 
-    // Configure GP22 as input for VL53L0X GPIO1 (async interrupt)
-    let tof_int_pin = Input::new(p.PIN_22, Pull::Up);
-    defmt::info!("VL53L0X interrupt on GP22");
-
-    let mut synth = keyboard::KeyboardSynth::new();
+    static SYNTH: StaticCell<RefCell<keyboard::KeyboardSynth>> = StaticCell::new();
+    let mut synth = keyboard::KeyboardSynth::try_new(heap_stats)
+        .unwrap_or_else(|e| defmt::panic!("Synth init failed: {}", e));
+    defmt::info!(
+        "Audio graph: {} bytes of heap, {} free",
+        synth.heap_used(),
+        heap_stats().free
+    );
+    if synth.heap_used() > Some(keyboard::NET_HEAP_RESERVE) {
+        defmt::warn!(
+            "Audio graph outgrew NET_HEAP_RESERVE ({} bytes)",
+            keyboard::NET_HEAP_RESERVE
+        );
+    }
+    let mut last_state = last_state::LastState::new(p.FLASH);
+    match last_state.load() {
+        Some(state) => {
+            synth.apply_state(&state);
+            defmt::info!("Restored the last state");
+        }
+        None => defmt::info!("No saved state, starting with defaults"),
+    }
+    synth.set_autosave(AUTOSAVE_INTERVAL);
+    synth.set_tilt(TILT_DB_PER_OCTAVE);
+    synth.set_startup_fade(STARTUP_FADE);
+    synth.set_drive(DRIVE);
+    synth.set_release_cutoff_db(RELEASE_CUTOFF_DB);
+    synth.set_steal_crossfade_ms(STEAL_CROSSFADE_MS);
+    synth.set_contact_clock(|| embassy_time::Instant::now().as_micros());
+    synth.set_release_holdoff_ms(RELEASE_HOLDOFF_MS);
+    if DRIVE_AFTER_FILTER {
+        use keyboard::FxStage;
+        let order = [
+            FxStage::Filter,
+            FxStage::Drive,
+            FxStage::Resonator,
+            FxStage::Formant,
+        ];
+        synth.set_fx_order(&order).unwrap();
+    }
+    if DRUM_BEAT {
+        synth.drums_mut().four_on_the_floor();
+        synth.set_drums_playing(true);
+    }
+    synth.set_drum_edit_combo(DRUM_EDIT);
+    // Only the 16-bit I2S DAC resolves the dither
+    synth.set_dither(OUTPUT_BACKEND == output::OutputBackend::I2s(output::I2sFormat::Bits16));
     let resonator_freq = synth.resonator_freq_control();
+    synth.set_formant_enabled(TOF_FORMANT);
+    let formant_position = TOF_FORMANT.then(|| synth.formant_control());
+    synth.set_sensor_sh(TOF_SAMPLE_HOLD, TOF_SAMPLE_HOLD_STEPS);
+    let sample_hold_input = fundsp::shared::Shared::new(0.0);
 
     // Spawn sensor interrupt handler task with pitch bend control
-    _spawner
-        .spawn(sensor_task(tof, tof_int_pin, resonator_freq))
+    spawner
+        .spawn(sensor::sensor_task(
+            tof,
+            tof_int_pin,
+            resonator_freq.clone(),
+            formant_position,
+            TOF_SAMPLE_HOLD.then(|| sample_hold_input.clone()),
+        ))
+        .unwrap();
+
+    // Analog bend pot (GPIO 28/ADC2 by default). To add a mod pot, pass
+    // `Some(Channel::new_pin(p.PIN_29, Pull::None))` (or GPIO 26/27 when the
+    // ToF sensor is not fitted) as the mod channel.
+    let adc = Adc::new(p.ADC, Irqs, embassy_rp::adc::Config::default());
+    let bend_channel = Channel::new_pin(pins.bend, Pull::None);
+    let bend_input = fundsp::shared::Shared::new(0.0);
+    spawner
+        .spawn(analog::analog_task(
+            adc,
+            bend_channel,
+            None,
+            analog::AnalogConfig::default(),
+            bend_input.clone(),
+            resonator_freq,
+        ))
         .unwrap();
 
     // Setup pio state machine for i2s output
@@ -128,121 +538,345 @@ async fn main(_spawner: Spawner) {
         mut common, sm0, ..
     } = Pio::new(p.PIO0, Irqs);
 
-    let bit_clock_pin = p.PIN_18;
-    let left_right_clock_pin = p.PIN_19;
-    let data_pin = p.PIN_20;
-
-    let mut busy_pin = embassy_rp::gpio::Output::new(p.PIN_16, embassy_rp::gpio::Level::Low);
+    // DIN MIDI takes over the busy and envelope follower pins
+    let (midi_uart, busy, env_follower_pin) = if MIDI_DIN {
+        let mut config = embassy_rp::uart::Config::default();
+        config.baudrate = midi_port::MIDI_BAUD_RATE;
+        let uart = embassy_rp::uart::Uart::new(
+            p.UART0,
+            pins.busy,
+            pins.env_follower,
+            Irqs,
+            p.DMA_CH1,
+            p.DMA_CH2,
+            config,
+        );
+        (Some(uart), None, None)
+    } else {
+        (None, Some(pins.busy), Some(pins.env_follower))
+    };
+    let mut busy_pin =
+        busy.map(|pin| embassy_rp::gpio::Output::new(pin, embassy_rp::gpio::Level::Low));
+    let mut cpu_load = CpuLoad::new();
+    let mut load_led = CPU_LOAD_LED.then(|| {
+        let mut config = embassy_rp::pwm::Config::default();
+        config.top = LOAD_LED_TOP;
+        embassy_rp::pwm::Pwm::new_output_b(p.PWM_SLICE4, pins.load_led, config)
+    });
+
+    let mut env_follower = env_follower_pin.filter(|_| ENV_FOLLOWER_OUT).map(|pin| {
+        output::EnvFollowerOutput::new(
+            p.PWM_SLICE0,
+            pin,
+            pico2_synth::follower::EnvelopeFollower::new(
+                ENV_FOLLOWER_ATTACK.into(),
+                ENV_FOLLOWER_RELEASE.into(),
+            ),
+        )
+    });
 
     // 12 keys for full chromatic octave (C, C#, D, D#, E, F, F#, G, G#, A, A#, B)
-    let input0 = Input::new(p.PIN_0, embassy_rp::gpio::Pull::Up);
-    let input1 = Input::new(p.PIN_1, embassy_rp::gpio::Pull::Up);
-    let input2 = Input::new(p.PIN_2, embassy_rp::gpio::Pull::Up);
-    let input3 = Input::new(p.PIN_3, embassy_rp::gpio::Pull::Up);
-    let input4 = Input::new(p.PIN_4, embassy_rp::gpio::Pull::Up);
-    let input5 = Input::new(p.PIN_5, embassy_rp::gpio::Pull::Up);
-    let input6 = Input::new(p.PIN_6, embassy_rp::gpio::Pull::Up);
-    let input7 = Input::new(p.PIN_7, embassy_rp::gpio::Pull::Up);
-    let input8 = Input::new(p.PIN_8, embassy_rp::gpio::Pull::Up);
-    let input9 = Input::new(p.PIN_9, embassy_rp::gpio::Pull::Up);
-    let input10 = Input::new(p.PIN_10, embassy_rp::gpio::Pull::Up);
-    let input11 = Input::new(p.PIN_11, embassy_rp::gpio::Pull::Up);
-
-    let inputs: [&Input<'_>; keyboard::KEY_COUNT] = [
-        &input0, &input1, &input2, &input3, &input4, &input5, &input6, &input7, &input8, &input9,
-        &input10, &input11,
-    ];
-
-    // 4 octave select outputs (only one LOW at a time to enable that octave)
-    let mut octave0_en = embassy_rp::gpio::Output::new(p.PIN_12, embassy_rp::gpio::Level::High);
-    let mut octave1_en = embassy_rp::gpio::Output::new(p.PIN_13, embassy_rp::gpio::Level::High);
-    let mut octave2_en = embassy_rp::gpio::Output::new(p.PIN_14, embassy_rp::gpio::Level::High);
-    let mut octave3_en = embassy_rp::gpio::Output::new(p.PIN_15, embassy_rp::gpio::Level::High);
-
-    let program = PioI2sOutProgram::new(&mut common);
-    let mut i2s = PioI2sOut::new(
-        &mut common,
-        sm0,
-        p.DMA_CH0,
-        data_pin,
-        bit_clock_pin,
-        left_right_clock_pin,
-        SAMPLE_RATE,
-        BIT_DEPTH,
-        &program,
-    );
-
-    // create two audio buffers (back and front) which will take turns being
-    // filled with new audio data and being sent to the pio fifo using dma
-    const BUFFER_SIZE: usize = 640;
-    static DMA_BUFFER: StaticCell<[u32; BUFFER_SIZE * 2]> = StaticCell::new();
-    let dma_buffer = DMA_BUFFER.init_with(|| [0u32; BUFFER_SIZE * 2]);
-    let (mut back_buffer, mut front_buffer) = dma_buffer.split_at_mut(BUFFER_SIZE);
+    let inputs = pins
+        .keys
+        .map(|pin| Input::new(pin, embassy_rp::gpio::Pull::Up));
+
+    let octave_selector = if OCTAVE_ENCODER {
+        // Encoder A/B on the first two enable pins, common to GND
+        let [a, b, ..] = pins.octave_enables;
+        spawner
+            .spawn(octave_select::encoder_task(
+                Input::new(a, Pull::Up),
+                Input::new(b, Pull::Up),
+            ))
+            .unwrap();
+        octave_select::OctaveSelector::Encoder {
+            octave: octave_select::ENCODER_START_OCTAVE,
+        }
+    } else {
+        // 4 octave select outputs (only one LOW at a time to enable that octave)
+        let mut octave_enables = pins
+            .octave_enables
+            .map(|pin| embassy_rp::gpio::Output::new(pin, embassy_rp::gpio::Level::High));
+        if KEY_SELFTEST {
+            run_key_selftest(&inputs, &mut octave_enables);
+        }
+        octave_select::OctaveSelector::Strobes(octave_enables)
+    };
+
+    let output = match OUTPUT_BACKEND {
+        output::OutputBackend::I2s(format) => {
+            let program = PioI2sOutProgram::new(&mut common);
+            output::AudioOutput::I2s(PioI2sOut::new(
+                &mut common,
+                sm0,
+                p.DMA_CH0,
+                pins.i2s_data,
+                pins.i2s_bit_clock,
+                pins.i2s_left_right_clock,
+                SAMPLE_RATE,
+                format.bit_depth(),
+                &program,
+            ))
+        }
+        output::OutputBackend::SpiDac => output::AudioOutput::SpiDac(output::SpiDac::new(
+            p.SPI0,
+            pins.i2s_bit_clock,
+            pins.i2s_left_right_clock,
+            pins.spi_dac_cs,
+            p.DMA_CH0.into(),
+            SAMPLE_RATE,
+        )),
+        output::OutputBackend::Pwm => output::AudioOutput::Pwm(output::PwmOut::new(
+            p.PWM_SLICE2,
+            pins.i2s_data,
+            pins.spi_dac_cs,
+            p.DMA_CH0.into(),
+            SAMPLE_RATE,
+        )),
+    };
+    defmt::info!("Audio output: {}", OUTPUT_BACKEND);
+
+    // create a ring of BUFFER_COUNT audio buffers which take turns being
+    // filled with new audio data and being sent to the pio fifo using dma,
+    // by the output task. They are sized for the largest buffer of two-word
+    // frames; only the active frames are used.
+    static DMA_BUFFERS: ConstStaticCell<[output::AudioBuffer; BUFFER_COUNT]> =
+        ConstStaticCell::new([const { output::AudioBuffer::new() }; BUFFER_COUNT]);
+    static BUFFER_RING: StaticCell<
+        zerocopy_channel::Channel<'static, CriticalSectionRawMutex, output::AudioBuffer>,
+    > = StaticCell::new();
+    let ring = BUFFER_RING.init(zerocopy_channel::Channel::new(DMA_BUFFERS.take()));
+    let (mut free_buffers, filled_buffers) = ring.split();
+    interrupt::SWI_IRQ_1.set_priority(Priority::P2);
+    OUTPUT_EXECUTOR
+        .start(interrupt::SWI_IRQ_1)
+        .spawn(output::output_task(output, filled_buffers))
+        .unwrap();
+    let frame_words = OUTPUT_BACKEND.words_per_frame();
+    defmt::info!("Audio buffers: {}", BUFFER_COUNT);
 
     // start pio state machine
     use embassy_time::Instant;
     let mut last_scan = Instant::now();
-    // Scan at ~1kHz to properly read all 48 keys (12 keys × 4 octaves)
+    // Minimum time between key scans. Inline, the loop runs once per audio
+    // buffer (14.5 ms at 640 frames), so in practice every buffer scans and
+    // this only limits scanning with very small buffers; with SCAN_TASK the
+    // keys are scanned this often.
+    //
+    // Inline, key-to-sound latency is therefore set by the buffer size, not
+    // by this interval: a press waits up to one scan gap (one buffer with a
+    // full scan) to be read, then the buffer it is rendered into waits for
+    // the one that is playing. Worst case (gap + 1) buffers, 29 ms at 640
+    // frames with a full scan; the octave settle time adds a few µs. The
+    // bound is logged per octave at boot and the measured read-to-DAC part
+    // with the scan report, see SCAN_REPORT_INTERVAL.
     const SCAN_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_micros(250);
+    key_scan::set_scan_interval(SCAN_INTERVAL);
+    // Octaves strobed per scan. Each octave costs the settle time plus 12
+    // GPIO reads and key updates, an estimated ~7 µs at 150 MHz, so a full
+    // scan is an estimated ~28 µs, about 0.2% of a 640-frame buffer period.
+    // Those are estimates: the scan's cost has not been measured on a board
+    // yet, before or after the partial scans. Its time and DWT cycle count
+    // are logged at debug level with the scan report (SCAN_REPORT_INTERVAL),
+    // and the fill's cycles with the CPU load report, for taking those
+    // figures. With small buffers or heavy effects set this to 1 to spread
+    // the scan over 4 buffers, at the cost of up to 4 buffers of key
+    // latency (or 4 scan intervals with SCAN_TASK).
+    const OCTAVES_PER_SCAN: usize = keyboard::OCTAVE_COUNT;
+    // Octave read first in every scan, e.g. the one most played. With
+    // OCTAVES_PER_SCAN below 4 it is also read between the others, which
+    // keeps its latency at about 2 buffers (see scan.rs).
+    const SCAN_PRIORITY_OCTAVE: Option<u8> = None;
+    const SCAN_REPORT_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(10);
+    // Cross-check the held keys against the sounding voices this often,
+    // releasing notes whose key is up and playing held keys that have no
+    // voice, in case an edge was missed. Zero disables it.
+    const RECONCILE_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(1);
+    let mut scan_order = ScanOrder::new(OCTAVES_PER_SCAN);
+    scan_order.set_scan_priority_octave(SCAN_PRIORITY_OCTAVE);
+    let scan_interval_frames =
+        (key_scan::scan_interval().as_micros() * SAMPLE_RATE as u64).div_ceil(1_000_000) as usize;
+    let buffer_frames = ACTIVE_BUFFER_FRAMES.load(Ordering::Relaxed);
+    // Buffers queued beyond the one playing while the next is filled
+    let queued_frames = (BUFFER_COUNT - 2) * buffer_frames;
+    for octave in 0..keyboard::OCTAVE_COUNT as u8 {
+        let frames = queued_frames
+            + if SCAN_TASK {
+                scan_order.decoupled_latency_frames(octave, scan_interval_frames, buffer_frames)
+            } else {
+                scan_order.worst_case_latency_frames(octave, buffer_frames)
+            };
+        defmt::info!(
+            "Octave {} key latency: up to {} us",
+            octave,
+            frames as u64 * 1_000_000 / SAMPLE_RATE as u64
+        );
+    }
+    let scanner = key_scan::KeyScanner::new(
+        inputs,
+        octave_selector,
+        scan_order,
+        bend_input,
+        TOF_SAMPLE_HOLD.then_some(sample_hold_input),
+        RECONCILE_INTERVAL,
+    );
+    let synth = SYNTH.init(RefCell::new(synth));
+    // Inline, the audio loop scans; otherwise the scan task does
+    let mut scanner = if SCAN_TASK {
+        spawner.spawn(key_scan::scan_task(scanner, synth)).unwrap();
+        None
+    } else {
+        Some(scanner)
+    };
+    if let Some(uart) = midi_uart {
+        spawner.spawn(midi_port::midi_task(uart, synth)).unwrap();
+    }
+    let mut last_scan_report = Instant::now();
+    let mut press_latency_max = embassy_time::Duration::from_ticks(0);
+    let mut last_load_report = Instant::now();
+    // Most CPU cycles a fill took since the last load report
+    let mut fill_cycles_max = 0u32;
+
+    watchdog.pause_on_debug(true);
+    watchdog.start(WATCHDOG_TIMEOUT);
+
+    let boot_start = Instant::now();
+    let mut boot_tone = BOOT_TONE_TIME > embassy_time::Duration::from_ticks(0);
+    synth
+        .borrow_mut()
+        .play_test_tone(keyboard::TEST_TONE_FREQ, boot_tone);
 
     loop {
-        // trigger transfer of front buffer data to the pio fifo
-        // but don't await the returned future, yet
-        let dma_future = i2s.write(front_buffer);
+        // wait for a buffer the output task has finished playing; with
+        // BUFFER_COUNT - 1 filled ahead, this is where the loop idles
+        watchdog.set_scratch(WATCHDOG_STAGE_SCRATCH, LoopStage::DmaWait as u32);
+        let buffer = free_buffers.send().await;
+        watchdog.feed();
+        if let Some(latency) = key_scan::take_press_read() {
+            press_latency_max = press_latency_max.max(latency);
+        }
+        let frames = ACTIVE_BUFFER_FRAMES.load(Ordering::Relaxed);
+        let fill_start = Instant::now();
+        let fill_start_cycles = cortex_m::peripheral::DWT::cycle_count();
+        // Released before the wait for the next buffer, where the scan task may run
+        let mut synth = synth.borrow_mut();
+
+        if let Some(pin) = &mut busy_pin {
+            pin.set_high();
+        }
 
-        busy_pin.set_high();
+        if boot_tone && boot_start.elapsed() >= BOOT_TONE_TIME {
+            boot_tone = false;
+            synth.play_test_tone(keyboard::TEST_TONE_FREQ, false);
+        }
 
-        // Scan the keyboard matrix at ~1kHz
-        // Each scan cycles through all 4 octaves
-        if last_scan.elapsed() >= SCAN_INTERVAL {
+        // Scan the keyboard matrix, OCTAVES_PER_SCAN octaves at a time
+        if let Some(scanner) = &mut scanner
+            && last_scan.elapsed() >= key_scan::scan_interval()
+        {
             last_scan = Instant::now();
+            watchdog.set_scratch(WATCHDOG_STAGE_SCRATCH, LoopStage::Scan as u32);
+            scanner.scan(&mut synth);
+        }
+        if last_scan_report.elapsed() >= SCAN_REPORT_INTERVAL {
+            defmt::debug!(
+                "Key scan: max {} us ({} cycles), key read to DAC: max {} us, heap high-water {} bytes",
+                key_scan::take_scan_time_max().as_micros(),
+                key_scan::take_scan_cycles_max(),
+                press_latency_max.as_micros(),
+                ALLOCATOR.peak.load(Ordering::Relaxed)
+            );
+            press_latency_max = embassy_time::Duration::from_ticks(0);
+            last_scan_report = Instant::now();
+        }
 
-            // Scan all 4 octaves
-            // For each octave: enable it (set output LOW), read 12 keys, disable it (set HIGH)
-            for octave in 0..keyboard::OCTAVE_COUNT as u8 {
-                // Enable this octave
-                match octave {
-                    0 => octave0_en.set_low(),
-                    1 => octave1_en.set_low(),
-                    2 => octave2_en.set_low(),
-                    3 => octave3_en.set_low(),
-                    _ => {}
-                }
+        watchdog.set_scratch(WATCHDOG_STAGE_SCRATCH, LoopStage::Fill as u32);
 
-                // Read all 12 keys for this octave
-                for key in 0..keyboard::KEY_COUNT {
-                    let pressed = inputs[key].is_low();
-                    synth.update_key(key, octave, pressed);
-                }
+        // fill the free buffer with fresh audio samples using efficient block processing
+        // Process the active frames in blocks for SIMD acceleration
+        let mut left_block: [f32; MAX_BUFFER_FRAMES] = [0.0; MAX_BUFFER_FRAMES];
+        let mut right_block: [f32; MAX_BUFFER_FRAMES] = [0.0; MAX_BUFFER_FRAMES];
+        synth.process_block_stereo(&mut left_block[..frames], &mut right_block[..frames]);
+        dump::dump_block(&left_block[..frames]);
+        if let Some(follower) = &mut env_follower {
+            follower.update(&left_block[..frames], &right_block[..frames]);
+        }
 
-                // Disable this octave
-                match octave {
-                    0 => octave0_en.set_high(),
-                    1 => octave1_en.set_high(),
-                    2 => octave2_en.set_high(),
-                    3 => octave3_en.set_high(),
-                    _ => {}
+        // Convert f32 samples to the DAC's DMA format (one u32 per frame,
+        // two for 32-bit I2S slots)
+        buffer.len = frames * frame_words;
+        let frames_out = buffer.words[..buffer.len].chunks_exact_mut(frame_words);
+        for ((s, &left), &right) in frames_out
+            .zip(&left_block[..frames])
+            .zip(&right_block[..frames])
+        {
+            match OUTPUT_BACKEND {
+                output::OutputBackend::I2s(format) if frame_words == 2 => {
+                    s.copy_from_slice(&format.encode_wide(
+                        synth.to_dac_sample_wide(left),
+                        synth.to_dac_sample_wide(right),
+                    ))
+                }
+                _ => {
+                    s[0] =
+                        OUTPUT_BACKEND.encode(synth.to_dac_sample(left), synth.to_dac_sample(right))
                 }
             }
         }
 
-        // fill back buffer with fresh audio samples using efficient block processing
-        // Process BUFFER_SIZE samples in blocks for SIMD acceleration
-        let mut audio_block: [f32; BUFFER_SIZE] = [0.0; BUFFER_SIZE];
-        synth.process_block(&mut audio_block, BUFFER_SIZE);
+        free_buffers.send_done();
+
+        if let Some(state) = synth.take_autosave()
+            && let Err(e) = last_state.save(&state, &mut watchdog)
+        {
+            defmt::warn!("Auto-save failed: {}", e);
+        }
 
-        // Convert f32 samples to DMA format (stereo u32)
-        for (i, s) in back_buffer.iter_mut().enumerate() {
-            let sample = (audio_block[i] * 32767.0) as i16;
-            // duplicate mono sample into lower and upper half of dma word
-            *s = (sample as u16 as u32) * 0x10001;
+        if let Some(pin) = &mut busy_pin {
+            pin.set_low();
         }
 
-        busy_pin.set_low();
+        // On average the fill has to keep up with the output; the buffers
+        // queued ahead only absorb the odd slow one
+        let buffer_period =
+            embassy_time::Duration::from_micros(frames as u64 * 1_000_000 / SAMPLE_RATE as u64);
+        let fill_time = fill_start.elapsed();
+        fill_cycles_max = fill_cycles_max
+            .max(cortex_m::peripheral::DWT::cycle_count().wrapping_sub(fill_start_cycles));
+        let underruns = output::take_underruns();
+        if underruns > 0 {
+            defmt::warn!(
+                "Audio output underran {} times with {} buffers of {} frames",
+                underruns,
+                BUFFER_COUNT,
+                frames
+            );
+            if let Some(step) = BUFFER_GROW_ON_UNDERRUN
+                && frames < MAX_BUFFER_FRAMES
+            {
+                let grown = (frames + step.get()).min(MAX_BUFFER_FRAMES);
+                defmt::info!("Growing the audio buffer to {} frames", grown);
+                set_active_buffer_frames(grown);
+            }
+        }
+        cpu_load.record(fill_time.as_micros(), buffer_period.as_micros());
+        if let Some(led) = &mut load_led {
+            let mut config = embassy_rp::pwm::Config::default();
+            config.top = LOAD_LED_TOP;
+            config.compare_b = cpu_load.led_duty(LOAD_LED_TOP);
+            led.set_config(&config);
+        }
+        if last_load_report.elapsed() >= CPU_LOAD_REPORT_INTERVAL {
+            defmt::info!(
+                "CPU load: {}%, peak buffer {}% ({} cycles)",
+                (cpu_load.cpu_load() * 100.0) as u32,
+                (cpu_load.take_peak() * 100.0) as u32,
+                fill_cycles_max
+            );
+            fill_cycles_max = 0;
+            last_load_report = Instant::now();
+        }
 
-        // now await the dma future. once the dma finishes, the next buffer needs to be queued
-        // within DMA_DEPTH / SAMPLE_RATE - seconds
-        dma_future.await;
-        mem::swap(&mut back_buffer, &mut front_buffer);
+        drop(synth);
     }
 }
@@ -0,0 +1,246 @@
+//! MIDI Control Change input with MIDI learn, for playing the sound
+//! parameters from any controller's knobs.
+//!
+//! `CcParser` takes the incoming MIDI byte stream one byte at a time, like
+//! `sysex::SysExParser`, and returns the Control Change messages,
+//! running status included. `KeyboardSynth::control_change` then moves
+//! every `ControlTarget` bound to the controller number; which number
+//! drives what is learned rather than fixed: arm learn for a target with
+//! `KeyboardSynth::midi_learn`, turn a knob, and the first CC to arrive is
+//! bound to it. The bindings are part of the `SynthState`, so they are
+//! saved and restored with the rest of the sound.
+//!
+//! `MidiInput` is the one place the MIDI port's bytes go: it runs both
+//! parsers and hands their messages, and the clock, to the synth.
+
+use crate::keyboard::KeyboardSynth;
+use crate::sysex::{PATCH_DUMP_BYTES_MAX, SysExParser};
+
+/// Sound parameters a controller can be bound to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+#[repr(u8)]
+pub enum ControlTarget {
+    /// Main filter cutoff, 20 Hz to 18 kHz on an exponential scale
+    FilterCutoff = 0,
+    FilterResonance = 1,
+    /// Resonator peak, 50 Hz to 3.2 kHz on an exponential scale
+    ResonatorFreq = 2,
+    /// Formant vowel position, A to U
+    FormantPosition = 3,
+    Drive = 4,
+    /// Delay feedback, up to `DELAY_FEEDBACK_MAX`
+    DelayFeedback = 5,
+    /// Glide time, up to `CC_GLIDE_TIME_MAX`
+    GlideTime = 6,
+    /// Stereo width, mono to twice as wide
+    StereoWidth = 7,
+}
+
+/// Number of `ControlTarget`s
+pub const CONTROL_TARGETS: usize = 8;
+/// Glide time at a controller's full travel, in seconds
+pub const CC_GLIDE_TIME_MAX: f32 = 2.0;
+/// Controller numbers 120 and up are channel mode messages (all notes off
+/// and the like), not controllers
+pub const CC_COUNT: u8 = 120;
+
+impl ControlTarget {
+    pub const ALL: [ControlTarget; CONTROL_TARGETS] = [
+        Self::FilterCutoff,
+        Self::FilterResonance,
+        Self::ResonatorFreq,
+        Self::FormantPosition,
+        Self::Drive,
+        Self::DelayFeedback,
+        Self::GlideTime,
+        Self::StereoWidth,
+    ];
+
+    /// The target's parameter value for a controller value, 0 to 127.
+    pub fn scale(self, value: u8) -> f32 {
+        let x = core::cmp::min(value, 127) as f32 / 127.0;
+        match self {
+            Self::FilterCutoff => (20.0 * libm::exp2f(x * 10.0)).min(18_000.0),
+            Self::ResonatorFreq => 50.0 * libm::exp2f(x * 6.0),
+            Self::FormantPosition => x * 4.0,
+            Self::DelayFeedback => x * crate::keyboard::DELAY_FEEDBACK_MAX,
+            Self::GlideTime => x * CC_GLIDE_TIME_MAX,
+            Self::StereoWidth => x * 2.0,
+            Self::FilterResonance | Self::Drive => x,
+        }
+    }
+}
+
+/// Controller number bound to each `ControlTarget`. A controller may drive
+/// several targets, a target follows one controller.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CcMap([Option<u8>; CONTROL_TARGETS]);
+
+impl CcMap {
+    /// Byte for an unbound target in `codes`
+    pub const UNBOUND: u8 = 0xFF;
+
+    /// Bind `target` to controller `cc` (below `CC_COUNT`), or unbind it
+    /// with `None`.
+    pub fn bind(&mut self, target: ControlTarget, cc: Option<u8>) {
+        self.0[target as usize] = cc.filter(|&cc| cc < CC_COUNT);
+    }
+
+    pub fn cc(&self, target: ControlTarget) -> Option<u8> {
+        self.0[target as usize]
+    }
+
+    /// Targets bound to controller `cc`.
+    pub fn targets(&self, cc: u8) -> impl Iterator<Item = ControlTarget> + '_ {
+        ControlTarget::ALL
+            .into_iter()
+            .filter(move |&target| self.cc(target) == Some(cc))
+    }
+
+    /// One byte per target in `ControlTarget` order, `UNBOUND` for none.
+    pub fn codes(&self) -> [u8; CONTROL_TARGETS] {
+        self.0.map(|cc| cc.unwrap_or(Self::UNBOUND))
+    }
+
+    /// Parse `codes` output; `None` for a byte that is neither a controller
+    /// number nor `UNBOUND`.
+    pub fn from_codes(codes: [u8; CONTROL_TARGETS]) -> Option<Self> {
+        let mut map = Self::default();
+        for (target, code) in ControlTarget::ALL.into_iter().zip(codes) {
+            match code {
+                Self::UNBOUND => {}
+                cc if cc < CC_COUNT => map.bind(target, Some(cc)),
+                _ => return None,
+            }
+        }
+        Some(map)
+    }
+}
+
+/// A Control Change message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ControlChange {
+    /// MIDI channel, 0-15
+    pub channel: u8,
+    /// Controller number, 0-127
+    pub cc: u8,
+    pub value: u8,
+}
+
+/// Incremental parser for Control Change messages, on any channel.
+#[derive(Default)]
+pub struct CcParser {
+    /// Channel of the running Control Change status, if that is the status
+    running: Option<u8>,
+    /// Controller number waiting for its value
+    cc: Option<u8>,
+}
+
+impl CcParser {
+    pub const fn new() -> Self {
+        Self {
+            running: None,
+            cc: None,
+        }
+    }
+
+    /// Feed one byte from the MIDI input. Returns the message when a
+    /// Control Change ends with this byte.
+    pub fn push(&mut self, byte: u8) -> Option<ControlChange> {
+        match byte {
+            // Real-time messages can interleave with anything
+            0xF8..=0xFF => None,
+            0xB0..=0xBF => {
+                self.running = Some(byte & 0x0F);
+                self.cc = None;
+                None
+            }
+            // Any other status ends the running status; system common
+            // messages (SysEx included) also cancel it
+            0x80..=0xF7 => {
+                self.running = None;
+                self.cc = None;
+                None
+            }
+            _ => {
+                let channel = self.running?;
+                match self.cc.take() {
+                    None => {
+                        self.cc = Some(byte);
+                        None
+                    }
+                    Some(cc) => Some(ControlChange {
+                        channel,
+                        cc,
+                        value: byte,
+                    }),
+                }
+            }
+        }
+    }
+}
+
+/// The synth's MIDI input: Control Changes, SysEx and the MIDI clock.
+#[derive(Default)]
+pub struct MidiInput {
+    cc: CcParser,
+    sysex: SysExParser,
+}
+
+impl MidiInput {
+    pub const fn new() -> Self {
+        Self {
+            cc: CcParser::new(),
+            sysex: SysExParser::new(),
+        }
+    }
+
+    /// Feed one byte from the MIDI input to `synth`. Returns a SysEx reply
+    /// to send back when the byte completes a dump request.
+    pub fn push(
+        &mut self,
+        synth: &mut KeyboardSynth,
+        byte: u8,
+    ) -> Option<heapless::Vec<u8, PATCH_DUMP_BYTES_MAX>> {
+        match byte {
+            0xF8 => synth.midi_clock_tick(),
+            0xFA => synth.midi_clock_start(),
+            _ => {}
+        }
+        if let Some(cc) = self.cc.push(byte) {
+            synth.control_change(cc.cc, cc.value);
+        }
+        let message = self.sysex.push(byte)?;
+        synth.handle_sysex(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parser_reads_control_changes_with_running_status() {
+        let mut parser = CcParser::new();
+        let mut messages = alloc::vec::Vec::new();
+        // CC 21 = 64 on channel 2, then = 100 by running status with a
+        // clock in between, a note on, and CC 7 on channel 1
+        for byte in [0xB1, 21, 64, 21, 0xF8, 100, 0x90, 60, 100, 0xB0, 7, 127] {
+            messages.extend(parser.push(byte));
+        }
+        let cc = |channel, cc, value| ControlChange { channel, cc, value };
+        assert_eq!(messages, [cc(1, 21, 64), cc(1, 21, 100), cc(0, 7, 127)]);
+
+        let mut map = CcMap::default();
+        map.bind(ControlTarget::FilterCutoff, Some(21));
+        map.bind(ControlTarget::Drive, Some(21));
+        map.bind(ControlTarget::GlideTime, Some(121));
+        assert_eq!(map.cc(ControlTarget::GlideTime), None);
+        assert_eq!(
+            map.targets(21).collect::<alloc::vec::Vec<_>>(),
+            [ControlTarget::FilterCutoff, ControlTarget::Drive]
+        );
+        assert_eq!(CcMap::from_codes(map.codes()), Some(map));
+        assert_eq!(CcMap::from_codes([120; CONTROL_TARGETS]), None);
+    }
+}
@@ -0,0 +1,41 @@
+//! DIN MIDI in and out on UART0, see `MIDI_DIN` in main.
+//!
+//! `midi_task` reads the port a byte at a time into `MidiInput`, which
+//! applies Control Changes, the MIDI clock and SysEx patch loads to the
+//! synth, and sends the reply to a SysEx dump request back out. It shares
+//! the synth with the audio loop as `key_scan::scan_task` does: the borrow
+//! is only held between awaits.
+//!
+//! While a dump goes out (about 0.2 s at 31250 baud for the largest) the
+//! input isn't read; the UART's 32-byte FIFO keeps what arrives meanwhile,
+//! and anything past that is lost, which a librarian waiting for the reply
+//! doesn't send.
+
+use core::cell::RefCell;
+use embassy_rp::uart::{Async, Uart};
+use pico2_synth::keyboard::KeyboardSynth;
+use pico2_synth::midi::MidiInput;
+
+/// MIDI's fixed baud rate
+pub const MIDI_BAUD_RATE: u32 = 31_250;
+
+// Task to feed the DIN MIDI input to the synth and send its SysEx replies.
+#[embassy_executor::task]
+pub async fn midi_task(mut uart: Uart<'static, Async>, synth: &'static RefCell<KeyboardSynth>) {
+    let mut input = MidiInput::new();
+    let mut byte = [0u8];
+    loop {
+        if let Err(e) = uart.read(&mut byte).await {
+            // A framing error or overrun: the parsers pick up again at the
+            // next status byte
+            defmt::warn!("MIDI input error: {}", e);
+            continue;
+        }
+        let reply = input.push(&mut synth.borrow_mut(), byte[0]);
+        if let Some(reply) = reply
+            && let Err(e) = uart.write(&reply).await
+        {
+            defmt::warn!("MIDI output error: {}", e);
+        }
+    }
+}
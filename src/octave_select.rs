@@ -0,0 +1,106 @@
+//! How the firmware tells which octave the 12 key inputs belong to.
+//!
+//! Two wirings, picked with `OCTAVE_ENCODER` in main:
+//!
+//! - Strobes (default): the 48-key matrix. Each octave's 12 switches
+//!   connect the key lines, through a diode each (cathode to the strobe),
+//!   to one octave enable output, GPIO 12-15 lowest octave first. The scan
+//!   drives one enable low at a time and reads the keys, which are pulled
+//!   up, so all four octaves play at once.
+//! - Encoder: one octave of 12 switches from the key lines straight to
+//!   GND, no diodes, and a rotary encoder with its A and B contacts on
+//!   GPIO 12 and 13 (the first two enable pins) and its common pin to GND.
+//!   The keys play the octave the encoder has selected, one octave per
+//!   detent, stopping at the lowest and highest; notes held while it turns
+//!   move to the new octave. GPIO 14 and 15 stay unused.
+//!
+//! Both feed `KeyboardSynth::update_key`, so the synth can't tell them
+//! apart. The encoder is polled every millisecond by `encoder_task`, which
+//! publishes the octave for the scan to pick up.
+
+use crate::OCTAVE_SETTLE_TIME;
+use core::sync::atomic::{AtomicU8, Ordering};
+use embassy_rp::gpio::{Input, Output};
+use embassy_time::{Duration, Ticker};
+use pico2_synth::encoder::EncoderOctave;
+use pico2_synth::keyboard::{KEY_COUNT, KeyboardSynth, OCTAVE_COUNT};
+use pico2_synth::scan::ScanOrder;
+
+/// Octave selected by the encoder, written by `encoder_task`
+static ENCODER_OCTAVE: AtomicU8 = AtomicU8::new(ENCODER_START_OCTAVE);
+/// Octave the encoder wiring starts in: the middle one, C4
+pub const ENCODER_START_OCTAVE: u8 = 1;
+/// Time between encoder polls, short enough to see every contact change
+/// of a knob turned by hand
+const ENCODER_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The octave selection hardware of one of the wirings.
+pub enum OctaveSelector {
+    /// Enable outputs, lowest octave first
+    Strobes([Output<'static>; OCTAVE_COUNT]),
+    /// Octave the keys were last read into
+    Encoder { octave: u8 },
+}
+
+impl OctaveSelector {
+    /// Read the key inputs of the octaves due in this scan (per `order`
+    /// with strobes, the selected one with the encoder) into the synth.
+    /// Returns true if a key went down.
+    pub fn scan(
+        &mut self,
+        inputs: &[Input<'static>; KEY_COUNT],
+        order: &mut ScanOrder,
+        synth: &mut KeyboardSynth,
+    ) -> bool {
+        let mut pressed_any = false;
+        let mut read = |synth: &mut KeyboardSynth, octave: u8| {
+            for (key, input) in inputs.iter().enumerate() {
+                let pressed = input.is_low();
+                if synth.update_key(key, octave, pressed) && pressed {
+                    pressed_any = true;
+                }
+            }
+        };
+        match self {
+            Self::Strobes(enables) => {
+                for _ in 0..order.octaves_per_scan() {
+                    let octave = order.next_octave();
+                    // Enable this octave (LOW), read its keys, disable it
+                    enables[octave as usize].set_low();
+                    embassy_time::block_for(OCTAVE_SETTLE_TIME);
+                    read(synth, octave);
+                    enables[octave as usize].set_high();
+                }
+            }
+            Self::Encoder { octave } => {
+                let selected = ENCODER_OCTAVE.load(Ordering::Relaxed);
+                if selected != *octave {
+                    // Release the old octave's notes, which won't be read
+                    // again, so at once; the read below presses the held
+                    // keys again in the new one
+                    for key in 0..KEY_COUNT {
+                        synth.release_key_now(key, *octave);
+                    }
+                    *octave = selected;
+                }
+                read(synth, selected);
+            }
+        }
+        pressed_any
+    }
+}
+
+// Task to poll the octave encoder's contacts and publish the selected
+// octave to the key scan.
+#[embassy_executor::task]
+pub async fn encoder_task(a: Input<'static>, b: Input<'static>) {
+    let mut encoder = EncoderOctave::new(ENCODER_START_OCTAVE);
+    let mut ticker = Ticker::every(ENCODER_POLL_INTERVAL);
+    loop {
+        if let Some(octave) = encoder.update(a.is_high(), b.is_high()) {
+            ENCODER_OCTAVE.store(octave, Ordering::Relaxed);
+            defmt::debug!("Octave {}", octave);
+        }
+        ticker.next().await;
+    }
+}
@@ -0,0 +1,395 @@
+//! Audio output backends.
+//!
+//! The fill loop renders into a ring of DMA buffers of `u32` words while
+//! `output_task` plays the filled ones, whatever the DAC: one word per
+//! frame, or two with 32-bit I2S slots. `OutputBackend::encode` packs a
+//! stereo frame into the backend's word format and `AudioOutput::write`
+//! starts the DMA transfer of a buffer, so the loop is the same for every
+//! backend.
+//!
+//! `output_task` runs on a higher-priority interrupt executor, so it starts
+//! each transfer as soon as the last one ends, even in the middle of a
+//! fill: a slow fill only underruns the output once every buffer queued
+//! ahead of it has played, see `BUFFER_COUNT` in main. Underruns are
+//! counted for `take_underruns`.
+//!
+//! - `OutputBackend::I2s`: stereo I2S from PIO0 (PCM5102A and the like),
+//!   clocked by the PIO at exactly `SAMPLE_RATE`, in one of the
+//!   `I2sFormat`s: 16-bit by default, or 24 or 32 bits in 32-bit slots.
+//! - `OutputBackend::SpiDac`: a dual-channel SPI DAC with the MCP4822
+//!   command format on SPI0. Supported: MCP4822 (12-bit), MCP4812 (10-bit)
+//!   and MCP4802 (8-bit), internal 2.048 V reference at 1x gain, plus the
+//!   MCP4922/4912/4902 with an external reference (unbuffered). Samples are
+//!   reduced to 12 bits (72 dB) and the lower-resolution parts drop the
+//!   extra bits; silence is mid-scale, so AC-couple the outputs. Channel A
+//!   is left, B is right. Tie LDAC low so each channel updates when its
+//!   word ends.
+//!
+//! - `OutputBackend::Pwm`: no DAC at all, two GPIOs (one PWM slice) with a
+//!   146.5 kHz carrier (clk_sys / 1024) and 10-bit duty cycles (about 60 dB
+//!   of dynamic range). Filter each pin with an RC low-pass, e.g.
+//!   1 kΩ and 10 nF (16 kHz corner), or two such stages in series for a
+//!   cleaner result, then a 10 µF series capacitor to block the DC offset
+//!   before an amplifier or headphones (through a 100 Ω+ resistor).
+//!
+//! Besides the audio, `EnvFollowerOutput` puts the output's envelope on a
+//! PWM pin as a control voltage for external gear.
+//!
+//! The SPI DAC has no word clock of its own: a DMA pacing timer releases one
+//! 16-bit word every half frame and the SPI hardware pulses CS between
+//! words. The timer divides clk_sys by a 16-bit fraction, so the rate is
+//! within about 0.01% of `SAMPLE_RATE` (a fraction of a cent). At
+//! `SPI_DAC_FREQUENCY` a word takes under 2 µs of the 11.3 µs slot, leaving
+//! headroom up to ~250 kHz frame rates; the MCP4822 itself settles in
+//! 4.5 µs, so it is good for audio rates up to ~100 kHz. The PWM backend is
+//! paced the same way, one compare update per frame; the PWM slice latches
+//! it at the end of the current carrier period.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_rp::Peri;
+use embassy_rp::dma::{AnyChannel, Transfer};
+use embassy_rp::pac;
+use embassy_rp::peripherals::{PIO0, PWM_SLICE0, PWM_SLICE2, SPI0};
+use embassy_rp::pio_programs::i2s::PioI2sOut;
+use embassy_rp::pwm::{ChannelAPin, ChannelBPin, Pwm};
+use embassy_rp::spi::{Blocking, ClkPin, CsPin, MosiPin, Spi};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::zerocopy_channel::Receiver;
+use pico2_synth::follower::EnvelopeFollower;
+
+/// Which DAC the firmware drives, chosen at init.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum OutputBackend {
+    /// PIO I2S DAC (default wiring) taking frames in this format
+    I2s(I2sFormat),
+    /// MCP4822-style dual SPI DAC on SPI0
+    #[allow(dead_code)] // selected by editing OUTPUT_BACKEND in main
+    SpiDac,
+    /// RC-filtered PWM on PWM slice 2 (GPIO 20/21)
+    #[allow(dead_code)] // selected by editing OUTPUT_BACKEND in main
+    Pwm,
+}
+
+/// Sample format of the I2S frames.
+///
+/// The PIO program shifts 32-bit words out MSB first, counting the bits of
+/// each channel from the bit depth, so a frame is either one word (16-bit
+/// slots) or one word per channel (32-bit slots); 24-bit slots would split
+/// samples across words. 24-bit audio therefore goes out left-justified in
+/// 32-bit slots, with the low byte zero, which is also what 24-bit I2S DACs
+/// expect at 64 bit clocks per frame. The bit clock doubles to 2.82 MHz at
+/// 44.1 kHz.
+///
+/// The wide formats suit DACs with more than 16 bits of dynamic range,
+/// where 16 bits would set the noise floor at -96 dBFS: the PCM5100/5101/
+/// 5102A (100-112 dB, detect the frame width themselves, no master clock)
+/// and the UDA1334A (24-bit, ~100 dB) take `Bits24In32`; `Bits32` is for
+/// DACs that want full 32-bit words, such as ES9038Q2M or AK4493 boards
+/// with their own master clock. The synth renders in f32, so `Bits32`
+/// carries the same 24 bits of resolution as `Bits24In32`. Dither is left
+/// off with either: the rounding error sits near -144 dBFS, below any DAC.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum I2sFormat {
+    /// 16 bits per channel, one word per frame
+    Bits16,
+    /// 24 bits left-justified in 32-bit slots
+    #[allow(dead_code)] // selected by editing OUTPUT_BACKEND in main
+    Bits24In32,
+    /// 32 bits per channel
+    #[allow(dead_code)] // selected by editing OUTPUT_BACKEND in main
+    Bits32,
+}
+
+impl I2sFormat {
+    /// Bits per channel slot, as the PIO program counts them.
+    pub fn bit_depth(self) -> u32 {
+        match self {
+            Self::Bits16 => 16,
+            Self::Bits24In32 | Self::Bits32 => 32,
+        }
+    }
+
+    /// Pack one stereo frame of full-scale 32-bit samples into the two
+    /// words of a wide frame, left first.
+    #[inline(always)]
+    pub fn encode_wide(self, left: i32, right: i32) -> [u32; 2] {
+        let mask = match self {
+            Self::Bits24In32 => 0xFFFF_FF00,
+            Self::Bits16 | Self::Bits32 => u32::MAX,
+        };
+        [left as u32 & mask, right as u32 & mask]
+    }
+}
+
+impl OutputBackend {
+    /// DMA words per stereo frame.
+    pub fn words_per_frame(self) -> usize {
+        match self {
+            Self::I2s(format) => format.bit_depth() as usize / 16,
+            Self::SpiDac | Self::Pwm => 1,
+        }
+    }
+
+    /// Pack one stereo frame of DAC samples into a DMA word.
+    #[inline(always)]
+    pub fn encode(self, left: i16, right: i16) -> u32 {
+        match self {
+            // left channel in the upper half of the dma word (shifted out first)
+            Self::I2s(_) => ((left as u16 as u32) << 16) | right as u16 as u32,
+            Self::SpiDac => ((mcp_word(right, true) as u32) << 16) | mcp_word(left, false) as u32,
+            // Compare register layout: channel A (left) low, B (right) high
+            Self::Pwm => ((pwm_duty(right) as u32) << 16) | pwm_duty(left) as u32,
+        }
+    }
+}
+
+/// SPI clock for the DAC; the MCP48x2/49x2 accept up to 20 MHz
+const SPI_DAC_FREQUENCY: u32 = 10_000_000;
+/// MCP4822 command bits: channel B select, 1x gain, output enabled
+const MCP_CHANNEL_B: u16 = 1 << 15;
+const MCP_GAIN_1X: u16 = 1 << 13;
+const MCP_ACTIVE: u16 = 1 << 12;
+/// DMA pacing timer for the SPI DAC and PWM backends
+const PACING_DMA_TIMER: usize = 0;
+/// PWM counter wrap: 1024 duty steps (10 bits)
+const PWM_TOP: u16 = 1023;
+
+/// MCP4822 command word for a sample on one channel.
+fn mcp_word(sample: i16, channel_b: bool) -> u16 {
+    // Offset binary, top 12 bits
+    let value = ((sample as i32 + 32768) >> 4) as u16;
+    let channel = if channel_b { MCP_CHANNEL_B } else { 0 };
+    channel | MCP_GAIN_1X | MCP_ACTIVE | value
+}
+
+/// PWM compare value for a sample, 0 to PWM_TOP + 1, silence at half duty.
+fn pwm_duty(sample: i16) -> u16 {
+    // Round to the nearest step rather than truncating
+    let steps = PWM_TOP as i32 + 1;
+    (((sample as i32 + 32768) * steps + 32768) >> 16) as u16
+}
+
+/// Closest clk_sys * x / y to `rate` with 16-bit x and y.
+fn pacing_fraction(clk_sys: u32, rate: u32) -> (u16, u16) {
+    let mut best = (1, u16::MAX);
+    let mut best_error = u64::MAX;
+    for x in 1..=u16::MAX as u64 {
+        let y = (clk_sys as u64 * x + rate as u64 / 2) / rate as u64;
+        if y > u16::MAX as u64 {
+            break;
+        }
+        let error = (clk_sys as u64 * x).abs_diff(rate as u64 * y);
+        if error < best_error {
+            best = (x as u16, y as u16);
+            best_error = error;
+        }
+    }
+    best
+}
+
+/// Set the DMA pacing timer to release `rate` transfers per second.
+fn start_pacing_timer(rate: u32) {
+    let (x, y) = pacing_fraction(embassy_rp::clocks::clk_sys_freq(), rate);
+    pac::DMA.timer(PACING_DMA_TIMER).write(|w| {
+        w.set_x(x);
+        w.set_y(y);
+    });
+}
+
+/// MCP4822-style DAC on SPI0, fed by DMA paced at twice the sample rate.
+pub struct SpiDac<'d> {
+    /// Kept for the pin and clock setup; the DMA writes the data register
+    _spi: Spi<'d, SPI0, Blocking>,
+    dma: Peri<'d, AnyChannel>,
+}
+
+impl<'d> SpiDac<'d> {
+    pub fn new(
+        spi: Peri<'d, SPI0>,
+        clk: Peri<'d, impl ClkPin<SPI0> + 'd>,
+        mosi: Peri<'d, impl MosiPin<SPI0> + 'd>,
+        cs: Peri<'d, impl CsPin<SPI0> + 'd>,
+        dma: Peri<'d, AnyChannel>,
+        sample_rate: u32,
+    ) -> Self {
+        let mut config = embassy_rp::spi::Config::default();
+        config.frequency = SPI_DAC_FREQUENCY;
+        let spi = Spi::new_blocking_txonly(spi, clk, mosi, config);
+
+        let regs = pac::SPI0;
+        // 16-bit frames; in mode 0 the hardware raises CS between frames,
+        // which latches each word into the DAC
+        regs.cr1().modify(|w| w.set_sse(false));
+        regs.cr0().modify(|w| w.set_dss(0b1111));
+        regs.cr1().modify(|w| w.set_sse(true));
+        // The driver has no hardware CS option: hand the pin to SPI0
+        let cs = cs.pin() as usize;
+        pac::IO_BANK0.gpio(cs).ctrl().write(|w| w.set_funcsel(1));
+        pac::PADS_BANK0.gpio(cs).write(|w| {
+            w.set_iso(false);
+            w.set_ie(true);
+            w.set_od(false);
+        });
+
+        start_pacing_timer(sample_rate * 2);
+        Self { _spi: spi, dma }
+    }
+
+    fn write<'b>(&'b mut self, words: &'b [u32]) -> Transfer<'b, AnyChannel> {
+        // Each frame word holds the channel A command in its low half, which
+        // comes first in memory
+        let halves = core::ptr::slice_from_raw_parts(words.as_ptr() as *const u16, words.len() * 2);
+        // SAFETY: the buffer outlives the transfer (borrowed for 'b) and the
+        // data register is a valid DMA target.
+        unsafe {
+            embassy_rp::dma::write(
+                self.dma.reborrow(),
+                halves,
+                pac::SPI0.dr().as_ptr() as *mut u16,
+                pac::dma::vals::TreqSel::TIMER0,
+            )
+        }
+    }
+}
+
+/// Stereo PWM audio on one slice, fed by DMA paced at the sample rate.
+pub struct PwmOut<'d> {
+    /// Kept so the slice and pins stay configured
+    _pwm: Pwm<'d>,
+    dma: Peri<'d, AnyChannel>,
+}
+
+impl<'d> PwmOut<'d> {
+    pub fn new(
+        slice: Peri<'d, PWM_SLICE2>,
+        left: Peri<'d, impl ChannelAPin<PWM_SLICE2>>,
+        right: Peri<'d, impl ChannelBPin<PWM_SLICE2>>,
+        dma: Peri<'d, AnyChannel>,
+        sample_rate: u32,
+    ) -> Self {
+        let mut config = embassy_rp::pwm::Config::default();
+        config.top = PWM_TOP;
+        config.compare_a = pwm_duty(0);
+        config.compare_b = pwm_duty(0);
+        let pwm = Pwm::new_output_ab(slice, left, right, config);
+        start_pacing_timer(sample_rate);
+        Self { _pwm: pwm, dma }
+    }
+
+    fn write<'b>(&'b mut self, words: &'b [u32]) -> Transfer<'b, AnyChannel> {
+        // SAFETY: the buffer outlives the transfer (borrowed for 'b) and the
+        // compare register is a valid DMA target.
+        unsafe {
+            embassy_rp::dma::write(
+                self.dma.reborrow(),
+                words as *const [u32],
+                pac::PWM.ch(2).cc().as_ptr() as *mut u32,
+                pac::dma::vals::TreqSel::TIMER0,
+            )
+        }
+    }
+}
+
+/// The DAC the fill loop writes to, built for the chosen `OutputBackend`.
+pub enum AudioOutput<'d> {
+    I2s(PioI2sOut<'d, PIO0, 0>),
+    SpiDac(SpiDac<'d>),
+    Pwm(PwmOut<'d>),
+}
+
+impl<'d> AudioOutput<'d> {
+    /// Start sending a buffer of encoded frames. The transfer runs until the
+    /// returned future completes.
+    pub fn write<'b>(&'b mut self, words: &'b [u32]) -> Transfer<'b, AnyChannel> {
+        match self {
+            Self::I2s(i2s) => i2s.write(words),
+            Self::SpiDac(dac) => dac.write(words),
+            Self::Pwm(pwm) => pwm.write(words),
+        }
+    }
+}
+
+/// Words in a DMA buffer: the largest buffer of two-word frames
+pub const BUFFER_WORDS: usize = crate::MAX_BUFFER_FRAMES * 2;
+
+/// One DMA buffer of the ring, of which the first `len` words are played.
+pub struct AudioBuffer {
+    pub words: [u32; BUFFER_WORDS],
+    pub len: usize,
+}
+
+impl AudioBuffer {
+    pub const fn new() -> Self {
+        Self {
+            words: [0; BUFFER_WORDS],
+            len: 0,
+        }
+    }
+}
+
+/// Transfers that ended with no filled buffer to play next
+static UNDERRUNS: AtomicU32 = AtomicU32::new(0);
+
+/// Underruns since the last call.
+pub fn take_underruns() -> u32 {
+    UNDERRUNS.swap(0, Ordering::Relaxed)
+}
+
+// Task to play the filled buffers back to back, each handed back to the
+// fill loop as soon as its transfer ends.
+#[embassy_executor::task]
+pub async fn output_task(
+    mut output: AudioOutput<'static>,
+    mut buffers: Receiver<'static, CriticalSectionRawMutex, AudioBuffer>,
+) {
+    let mut playing = false;
+    loop {
+        // The output goes silent until the fill loop catches up
+        if playing && buffers.is_empty() {
+            UNDERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+        let buffer = buffers.receive().await;
+        output.write(&buffer.words[..buffer.len]).await;
+        buffers.receive_done();
+        playing = true;
+    }
+}
+
+/// PWM counter wrap of the envelope follower output: ~150 kHz carrier at
+/// 150 MHz, 1000 steps
+const FOLLOWER_TOP: u16 = 999;
+
+/// The output's envelope as a PWM duty cycle on PWM slice 0 channel B,
+/// 0 V in silence up to 3.3 V at full scale. Drives an LED directly
+/// (through a resistor); for a DC-coupled control voltage filter it with an
+/// RC low-pass, e.g. 10 kΩ and 1 µF (16 Hz corner, a few ms of lag), and
+/// buffer it with an op-amp before a long cable or a low-impedance input.
+pub struct EnvFollowerOutput<'d> {
+    pwm: Pwm<'d>,
+    follower: EnvelopeFollower,
+}
+
+impl<'d> EnvFollowerOutput<'d> {
+    pub fn new(
+        slice: Peri<'d, PWM_SLICE0>,
+        pin: Peri<'d, impl ChannelBPin<PWM_SLICE0>>,
+        follower: EnvelopeFollower,
+    ) -> Self {
+        let mut config = embassy_rp::pwm::Config::default();
+        config.top = FOLLOWER_TOP;
+        Self {
+            pwm: Pwm::new_output_b(slice, pin, config),
+            follower,
+        }
+    }
+
+    /// Follow a rendered buffer and set the duty cycle. A few µs per
+    /// buffer; the PWM slice latches the new duty at the end of its period.
+    pub fn update(&mut self, left: &[f32], right: &[f32]) {
+        self.follower.process(left, right);
+        let mut config = embassy_rp::pwm::Config::default();
+        config.top = FOLLOWER_TOP;
+        config.compare_b = self.follower.duty(FOLLOWER_TOP);
+        self.pwm.set_config(&config);
+    }
+}
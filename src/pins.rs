@@ -0,0 +1,113 @@
+//! Board wiring in one place.
+//!
+//! `PinConfig` collects every GPIO the firmware uses; `pin_config!` moves the
+//! pins out of the `embassy_rp::init` peripherals, so main keeps the rest
+//! (PIO, I2C, ADC, DMA) for itself. To adapt the firmware to another board,
+//! edit the pin numbers in the macro and, for the constrained roles, the type
+//! aliases below.
+//!
+//! Constraints on the RP2350A:
+//! - Keys, octave enables, busy and the ToF interrupt are plain GPIO, any
+//!   free pin works.
+//! - The I2S pins are driven by PIO0 and can be any GPIO 0-29, but the
+//!   program needs LRCLK on the pin directly after BCLK.
+//! - With the SPI DAC backend (see `output.rs`) the BCLK and LRCLK pins
+//!   become SPI0 SCK and MOSI, so either DAC fits the same header. SPI0 SCK
+//!   must be GPIO 2/6/18/22, MOSI 3/7/19/23 and CS 1/5/17/21.
+//! - The PWM backend drives the I2S data and SPI DAC CS pins, channels A
+//!   and B of PWM slice 2. Other pairs need the slice changed in
+//!   `output.rs` (slice n is GPIO 2n and 2n+1).
+//! - The ToF sensor sits on I2C1: SDA must be GPIO 2/6/10/14/18/22/26 and SCL
+//!   the next pin up (3/7/11/.../27).
+//! - The bend pot needs an ADC pin, GPIO 26-29 (29 is VSYS/3 on the Pico 2).
+//! - The load LED is dimmed by PWM slice 4 channel B (GPIO 25, the Pico 2's
+//!   onboard LED); another pin needs the slice changed in main.
+//! - The envelope follower output is PWM slice 0 channel B (GPIO 17); as
+//!   with the load LED, another pin needs the slice changed in main.
+//! - DIN MIDI (`MIDI_DIN` in main) is UART0, TX on the busy pin and RX on
+//!   the envelope follower's, so those two go. UART0 TX must be GPIO
+//!   0/12/16/28 (or 2/14/18 as the RP2350's auxiliary function) and RX
+//!   the pin after it.
+
+use embassy_rp::Peri;
+use embassy_rp::gpio::AnyPin;
+use embassy_rp::peripherals;
+use pico2_synth::keyboard::{KEY_COUNT, OCTAVE_COUNT};
+
+pub type I2sBitClockPin = peripherals::PIN_18;
+pub type I2sLeftRightClockPin = peripherals::PIN_19;
+pub type I2sDataPin = peripherals::PIN_20;
+pub type SpiDacCsPin = peripherals::PIN_21;
+pub type I2cSdaPin = peripherals::PIN_26;
+pub type I2cSclPin = peripherals::PIN_27;
+pub type BendPin = peripherals::PIN_28;
+pub type LoadLedPin = peripherals::PIN_25;
+pub type EnvFollowerPin = peripherals::PIN_17;
+pub type BusyPin = peripherals::PIN_16;
+
+/// Every pin the firmware uses, by role.
+pub struct PinConfig {
+    /// Key inputs, C to B, active low with pull-ups
+    pub keys: [Peri<'static, AnyPin>; KEY_COUNT],
+    /// Octave strobes, lowest octave first, driven low to enable; with the
+    /// octave encoder the first two are its A and B inputs
+    pub octave_enables: [Peri<'static, AnyPin>; OCTAVE_COUNT],
+    /// High while the audio loop is busy filling a buffer (scope timing),
+    /// or the DIN MIDI out with `MIDI_DIN` in main
+    pub busy: Peri<'static, BusyPin>,
+    /// VL53L0X GPIO1 measurement-ready interrupt
+    pub tof_interrupt: Peri<'static, AnyPin>,
+    pub i2s_bit_clock: Peri<'static, I2sBitClockPin>,
+    pub i2s_left_right_clock: Peri<'static, I2sLeftRightClockPin>,
+    pub i2s_data: Peri<'static, I2sDataPin>,
+    /// SPI DAC chip select, or the right channel with PWM output
+    pub spi_dac_cs: Peri<'static, SpiDacCsPin>,
+    pub i2c_sda: Peri<'static, I2cSdaPin>,
+    pub i2c_scl: Peri<'static, I2cSclPin>,
+    pub bend: Peri<'static, BendPin>,
+    /// LED showing the CPU load, see `CPU_LOAD_LED` in main
+    pub load_led: Peri<'static, LoadLedPin>,
+    /// Envelope follower PWM, see `ENV_FOLLOWER_OUT` in main, or the DIN
+    /// MIDI in with `MIDI_DIN`
+    pub env_follower: Peri<'static, EnvFollowerPin>,
+}
+
+/// Build the default `PinConfig` from `embassy_rp::init` peripherals.
+macro_rules! pin_config {
+    ($p:ident) => {
+        $crate::pins::PinConfig {
+            keys: [
+                $p.PIN_0.into(),
+                $p.PIN_1.into(),
+                $p.PIN_2.into(),
+                $p.PIN_3.into(),
+                $p.PIN_4.into(),
+                $p.PIN_5.into(),
+                $p.PIN_6.into(),
+                $p.PIN_7.into(),
+                $p.PIN_8.into(),
+                $p.PIN_9.into(),
+                $p.PIN_10.into(),
+                $p.PIN_11.into(),
+            ],
+            octave_enables: [
+                $p.PIN_12.into(),
+                $p.PIN_13.into(),
+                $p.PIN_14.into(),
+                $p.PIN_15.into(),
+            ],
+            busy: $p.PIN_16,
+            tof_interrupt: $p.PIN_22.into(),
+            i2s_bit_clock: $p.PIN_18,
+            i2s_left_right_clock: $p.PIN_19,
+            i2s_data: $p.PIN_20,
+            spi_dac_cs: $p.PIN_21,
+            i2c_sda: $p.PIN_26,
+            i2c_scl: $p.PIN_27,
+            bend: $p.PIN_28,
+            load_led: $p.PIN_25,
+            env_follower: $p.PIN_17,
+        }
+    };
+}
+pub(crate) use pin_config;
@@ -0,0 +1,185 @@
+//! Complete sound settings that can be stored, recalled and morphed.
+
+use crate::keyboard::{
+    ENV_ATTACK, ENV_DECAY, ENV_RELEASE, ENV_SUSTAIN, FILTER_CUTOFF, FilterSlope, Waveform,
+};
+
+/// Size of a legacy preset image, see `Preset::from_bytes`
+pub const PRESET_BYTES: usize = 36;
+/// Tag at the start of a legacy preset image
+const PRESET_MAGIC: [u8; 2] = *b"P1";
+
+/// One full parameter set of the synth.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Preset {
+    pub waveform: Waveform,
+    pub filter_slope: FilterSlope,
+    /// Main filter cutoff in Hz
+    pub filter_cutoff: f32,
+    /// Main filter resonance (0.0-1.0)
+    pub filter_resonance: f32,
+    /// Center frequency of the peaking resonator in Hz
+    pub resonator_freq: f32,
+    /// Amplitude envelope times in seconds and sustain level (0.0-1.0)
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Default for Preset {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Saw,
+            filter_slope: FilterSlope::OnePole6,
+            filter_cutoff: FILTER_CUTOFF,
+            filter_resonance: 0.0,
+            resonator_freq: 880.0,
+            attack: ENV_ATTACK,
+            decay: ENV_DECAY,
+            sustain: ENV_SUSTAIN,
+            release: ENV_RELEASE,
+        }
+    }
+}
+
+impl Preset {
+    /// Interpolate between two presets (`t` = 0.0 gives `a`, 1.0 gives `b`).
+    /// Continuous parameters are interpolated linearly; discrete ones
+    /// (waveform, filter slope) switch over at `t` = 0.5.
+    pub fn lerp(a: &Preset, b: &Preset, t: f32) -> Preset {
+        let t = t.clamp(0.0, 1.0);
+        let mix = |x: f32, y: f32| x + (y - x) * t;
+        let discrete = if t < 0.5 { a } else { b };
+        Preset {
+            waveform: discrete.waveform,
+            filter_slope: discrete.filter_slope,
+            filter_cutoff: mix(a.filter_cutoff, b.filter_cutoff),
+            filter_resonance: mix(a.filter_resonance, b.filter_resonance),
+            resonator_freq: mix(a.resonator_freq, b.resonator_freq),
+            attack: mix(a.attack, b.attack),
+            decay: mix(a.decay, b.decay),
+            sustain: mix(a.sustain, b.sustain),
+            release: mix(a.release, b.release),
+        }
+    }
+
+    /// Parse the 36-byte image older firmware saved to flash before
+    /// `SynthState` (see `crate::state`) took over: magic, waveform and
+    /// filter slope codes, the continuous parameters as little-endian f32,
+    /// then a CRC-32 of it all. Only read, to carry an old last state over.
+    /// Returns `None` for blank, torn or otherwise corrupt data, so callers
+    /// can fall back to defaults.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Preset> {
+        let bytes: &[u8; PRESET_BYTES] = bytes.get(..PRESET_BYTES)?.try_into().ok()?;
+        let crc = u32::from_le_bytes(bytes[PRESET_BYTES - 4..].try_into().ok()?);
+        if bytes[..2] != PRESET_MAGIC || crc32(&bytes[..PRESET_BYTES - 4]) != crc {
+            return None;
+        }
+        let waveform = waveform_from_code(bytes[2])?;
+        let filter_slope = filter_slope_from_code(bytes[3])?;
+        let value = |i: usize| {
+            let value = f32::from_le_bytes(bytes[4 + i * 4..8 + i * 4].try_into().unwrap());
+            value.is_finite().then_some(value)
+        };
+        Some(Preset {
+            waveform,
+            filter_slope,
+            filter_cutoff: value(0)?,
+            filter_resonance: value(1)?,
+            resonator_freq: value(2)?,
+            attack: value(3)?,
+            decay: value(4)?,
+            sustain: value(5)?,
+            release: value(6)?,
+        })
+    }
+}
+
+/// Decode a serialized `Waveform`.
+pub(crate) fn waveform_from_code(code: u8) -> Option<Waveform> {
+    match code {
+        0 => Some(Waveform::Saw),
+        1 => Some(Waveform::Square),
+        2 => Some(Waveform::Triangle),
+        3 => Some(Waveform::Sine),
+        4 => Some(Waveform::Pulse),
+        _ => None,
+    }
+}
+
+/// Decode a serialized `FilterSlope`.
+pub(crate) fn filter_slope_from_code(code: u8) -> Option<FilterSlope> {
+    match code {
+        0 => Some(FilterSlope::OnePole6),
+        1 => Some(FilterSlope::TwoPole12),
+        2 => Some(FilterSlope::Moog24),
+        _ => None,
+    }
+}
+
+/// CRC-32 (IEEE, reflected), bitwise: presets are saved rarely.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lerp_blends_continuous_and_switches_discrete_at_half() {
+        let pad = Preset {
+            filter_cutoff: 400.0,
+            attack: 1.0,
+            ..Preset::default()
+        };
+        let lead = Preset {
+            waveform: Waveform::Square,
+            filter_cutoff: 4000.0,
+            attack: 0.0,
+            ..Preset::default()
+        };
+
+        let quarter = Preset::lerp(&pad, &lead, 0.25);
+        assert_eq!(quarter.filter_cutoff, 1300.0);
+        assert_eq!(quarter.attack, 0.75);
+        assert!(quarter.waveform == Waveform::Saw);
+
+        let half = Preset::lerp(&pad, &lead, 0.5);
+        assert!(half.waveform == Waveform::Square);
+        assert!(Preset::lerp(&pad, &lead, 1.0) == lead);
+    }
+
+    #[test]
+    fn legacy_bytes_load_and_reject_corruption() {
+        let preset = Preset {
+            waveform: Waveform::Triangle,
+            filter_slope: FilterSlope::Moog24,
+            filter_cutoff: 2500.0,
+            release: 1.5,
+            ..Preset::default()
+        };
+        // As saved by older firmware
+        let bytes = [
+            80, 49, 2, 2, 0, 64, 28, 69, 0, 0, 0, 0, 0, 0, 92, 68, 0, 0, 0, 63, 0, 0, 0, 63, 0, 0,
+            0, 63, 0, 0, 192, 63, 111, 114, 79, 147,
+        ];
+        assert!(Preset::from_bytes(&bytes) == Some(preset));
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        // Erased flash, a flipped bit and a short read all fail
+        assert!(Preset::from_bytes(&[0xFF; PRESET_BYTES]).is_none());
+        let mut corrupt = bytes;
+        corrupt[10] ^= 0x04;
+        assert!(Preset::from_bytes(&corrupt).is_none());
+        assert!(Preset::from_bytes(&bytes[..PRESET_BYTES - 1]).is_none());
+    }
+}
@@ -0,0 +1,158 @@
+//! Key matrix scan order and the latency it implies.
+//!
+//! The firmware strobes `octaves_per_scan` octaves once per audio buffer, so
+//! a key is read every few buffers and its note is heard in the buffer after
+//! the one being filled. `ScanOrder` picks which octaves each scan reads and
+//! can put one octave first: with a full scan that only saves the few µs of
+//! the octaves read before it, but with partial scans the priority octave is
+//! read every other slot, so its worst-case latency drops to about two
+//! buffers instead of `OCTAVE_COUNT / octaves_per_scan + 1`. Scanned from
+//! a task of its own, every octave is read within a few scan intervals and
+//! the latency stays at about two buffers however the scan is split.
+
+use crate::keyboard::OCTAVE_COUNT;
+
+/// Longest octave sequence: the priority octave before each other one
+const CYCLE_MAX: usize = 2 * (OCTAVE_COUNT - 1);
+
+/// Order in which the scan loop strobes the octaves.
+pub struct ScanOrder {
+    octaves_per_scan: usize,
+    priority: Option<u8>,
+    cycle: [u8; CYCLE_MAX],
+    cycle_len: usize,
+    position: usize,
+}
+
+impl ScanOrder {
+    /// Scan order for `octaves_per_scan` octaves per scan (1 to
+    /// `OCTAVE_COUNT`), lowest octave first.
+    pub fn new(octaves_per_scan: usize) -> Self {
+        let mut order = Self {
+            octaves_per_scan: octaves_per_scan.clamp(1, OCTAVE_COUNT),
+            priority: None,
+            cycle: [0; CYCLE_MAX],
+            cycle_len: 0,
+            position: 0,
+        };
+        order.build_cycle();
+        order
+    }
+
+    /// Scan `octave` (0 to `OCTAVE_COUNT - 1`) first, or `None` for the plain
+    /// lowest-to-highest order. When a scan covers fewer than all octaves
+    /// the priority octave is also read between each of the others.
+    pub fn set_scan_priority_octave(&mut self, octave: Option<u8>) {
+        self.priority = octave.map(|octave| octave.min(OCTAVE_COUNT as u8 - 1));
+        self.build_cycle();
+    }
+
+    fn build_cycle(&mut self) {
+        let interleave = self.octaves_per_scan < OCTAVE_COUNT;
+        self.cycle_len = 0;
+        let mut push = |cycle: &mut [u8; CYCLE_MAX], octave: u8| {
+            cycle[self.cycle_len] = octave;
+            self.cycle_len += 1;
+        };
+        match self.priority {
+            None => (0..OCTAVE_COUNT as u8).for_each(|octave| push(&mut self.cycle, octave)),
+            Some(priority) => {
+                push(&mut self.cycle, priority);
+                for (i, octave) in (0..OCTAVE_COUNT as u8)
+                    .filter(|&octave| octave != priority)
+                    .enumerate()
+                {
+                    if interleave && i > 0 {
+                        push(&mut self.cycle, priority);
+                    }
+                    push(&mut self.cycle, octave);
+                }
+            }
+        }
+        self.position = 0;
+    }
+
+    /// Octaves strobed per scan.
+    pub fn octaves_per_scan(&self) -> usize {
+        self.octaves_per_scan
+    }
+
+    /// The octave to strobe next.
+    pub fn next_octave(&mut self) -> u8 {
+        let octave = self.cycle[self.position];
+        self.position = (self.position + 1) % self.cycle_len;
+        octave
+    }
+
+    /// Most scans between two reads of `octave`, i.e. how many scans a
+    /// press made just after its octave was read waits to be seen.
+    pub fn max_scan_gap(&self, octave: u8) -> usize {
+        let cycle = &self.cycle[..self.cycle_len];
+        let mut reads = cycle.iter().enumerate().filter(|(_, o)| **o == octave);
+        let Some((first, _)) = reads.next() else {
+            return usize::MAX;
+        };
+        let mut gap = 0;
+        let mut last = first;
+        for (slot, _) in reads.chain(core::iter::once((first + cycle.len(), &octave))) {
+            gap = gap.max(slot - last);
+            last = slot;
+        }
+        gap.div_ceil(self.octaves_per_scan)
+    }
+
+    /// Worst-case delay in frames from a key press in `octave` to its note
+    /// starting at the DAC, with one scan per buffer of `buffer_frames`:
+    /// waiting for the octave to be read, then the buffer that is playing
+    /// while the new one is filled. Add the envelope attack for the time to
+    /// full level.
+    pub fn worst_case_latency_frames(&self, octave: u8, buffer_frames: usize) -> usize {
+        (self.max_scan_gap(octave) + 1) * buffer_frames
+    }
+
+    /// Worst-case delay in frames from a key press in `octave` to its note
+    /// starting at the DAC, with the scans running every
+    /// `scan_interval_frames` independently of the fills of
+    /// `buffer_frames`: waiting for the octave to be read, then for the
+    /// next fill, then the buffer that is playing while it is filled.
+    pub fn decoupled_latency_frames(
+        &self,
+        octave: u8,
+        scan_interval_frames: usize,
+        buffer_frames: usize,
+    ) -> usize {
+        self.max_scan_gap(octave) * scan_interval_frames + 2 * buffer_frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_octave_is_read_first_and_between_the_others() {
+        let mut full = ScanOrder::new(OCTAVE_COUNT);
+        assert_eq!([0; 4].map(|_| full.next_octave()), [0, 1, 2, 3]);
+        assert_eq!(full.worst_case_latency_frames(2, 640), 1280);
+        full.set_scan_priority_octave(Some(2));
+        assert_eq!([0; 4].map(|_| full.next_octave()), [2, 0, 1, 3]);
+
+        let mut single = ScanOrder::new(1);
+        assert_eq!(single.max_scan_gap(2), 4);
+        single.set_scan_priority_octave(Some(2));
+        assert_eq!([0; 7].map(|_| single.next_octave()), [2, 0, 2, 1, 2, 3, 2]);
+        assert_eq!(single.max_scan_gap(2), 2);
+        assert_eq!(single.max_scan_gap(0), 6);
+        assert_eq!(single.worst_case_latency_frames(2, 640), 1920);
+    }
+
+    #[test]
+    fn decoupled_scans_keep_partial_scan_latency_near_two_buffers() {
+        // 250 µs scans (12 frames) against 640-frame buffers
+        let single = ScanOrder::new(1);
+        assert_eq!(single.worst_case_latency_frames(0, 640), 3200);
+        assert_eq!(single.decoupled_latency_frames(0, 12, 640), 1328);
+        let full = ScanOrder::new(OCTAVE_COUNT);
+        assert_eq!(full.decoupled_latency_frames(0, 12, 640), 1292);
+    }
+}
@@ -0,0 +1,154 @@
+//! VL53L0X time-of-flight sensor: measurement rate, timing budget and
+//! standby.
+//!
+//! The sensor measures continuously, one range every inter-measurement
+//! period, and pulls its GPIO1 line low when a range is ready. Each
+//! measurement takes the timing budget: a longer budget averages more
+//! returns for a steadier, more accurate range, a shorter one allows a
+//! faster rate. Roughly (per ST's datasheet):
+//!
+//! | budget | accuracy      | fastest rate |
+//! |--------|---------------|--------------|
+//! | 20 ms  | ±5%, jittery  | 50 Hz        |
+//! | 33 ms  | ±3% (default) | 30 Hz        |
+//! | 200 ms | ±3%, steady   | 5 Hz         |
+//!
+//! The budget must fit in the period, so `configure_sensor` lowers the rate
+//! to what the budget allows. Each range costs an interrupt and an I2C read
+//! in `sensor_task`, and the sensor draws ~19 mA while ranging against
+//! ~5 µA in standby, so the rate is best kept to what the mapped parameter
+//! needs: 30 Hz is smooth for a hand sweeping the resonator, as the synth
+//! smooths the steps in between. With `set_sensor_enabled(false)` the
+//! sensor stops ranging and sits in standby until enabled again.
+
+use core::ops::{Mul, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embassy_rp::gpio::Input;
+use embassy_rp::i2c::{Async, I2c};
+use embassy_rp::peripherals::I2C1;
+use embassy_time::{Duration, Timer};
+use fundsp::shared::Shared;
+use vl53l0x::VL53L0x;
+
+/// Measurement rate and timing budget from boot
+pub const SENSOR_RATE_HZ: u32 = 30;
+pub const SENSOR_TIMING_BUDGET: Duration = Duration::from_micros(33_000);
+/// Shortest and longest timing budget the sensor accepts
+const TIMING_BUDGET_MIN: Duration = Duration::from_micros(20_000);
+const TIMING_BUDGET_MAX: Duration = Duration::from_millis(1000);
+/// Time between checks for a new configuration while the sensor is off
+const STANDBY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static SENSOR_PERIOD_MS: AtomicU32 = AtomicU32::new(1000 / SENSOR_RATE_HZ);
+static SENSOR_BUDGET_US: AtomicU32 = AtomicU32::new(SENSOR_TIMING_BUDGET.as_micros() as u32);
+static SENSOR_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Set the measurement rate and timing budget, applied by `sensor_task`
+/// after the next range. The budget is clamped to 20 ms..1 s, and the rate
+/// to at most one range per budget.
+pub fn configure_sensor(rate_hz: u32, timing_budget: Duration) {
+    let budget = timing_budget.clamp(TIMING_BUDGET_MIN, TIMING_BUDGET_MAX);
+    let budget_ms = budget.as_micros().div_ceil(1000) as u32;
+    let period_ms = (1000 / rate_hz.max(1)).max(budget_ms);
+    if period_ms != 1000 / rate_hz.max(1) {
+        defmt::warn!(
+            "Sensor rate {} Hz too fast for a {} us budget, ranging every {} ms",
+            rate_hz,
+            budget.as_micros(),
+            period_ms
+        );
+    }
+    SENSOR_BUDGET_US.store(budget.as_micros() as u32, Ordering::Relaxed);
+    SENSOR_PERIOD_MS.store(period_ms, Ordering::Relaxed);
+}
+
+/// Stop ranging and put the sensor in standby, e.g. when it drives nothing,
+/// or start it again.
+pub fn set_sensor_enabled(on: bool) {
+    SENSOR_ENABLED.store(on, Ordering::Relaxed);
+}
+
+/// Inter-measurement period in ms and timing budget in µs, or None for
+/// standby.
+fn sensor_settings() -> Option<(u32, u32)> {
+    SENSOR_ENABLED.load(Ordering::Relaxed).then(|| {
+        (
+            SENSOR_PERIOD_MS.load(Ordering::Relaxed),
+            SENSOR_BUDGET_US.load(Ordering::Relaxed),
+        )
+    })
+}
+
+// Task to run the VL53L0X at the configured rate and map each range to the
+// resonator, the formant vowel or the sample-and-hold position.
+// Distance range: 30mm to 400mm
+#[embassy_executor::task]
+pub async fn sensor_task(
+    mut tof: VL53L0x<I2c<'static, I2C1, Async>>,
+    mut int_pin: Input<'static>,
+    resonator_freq: Shared,
+    formant_position: Option<Shared>,
+    sample_hold_position: Option<Shared>,
+) {
+    const MIN_DIST: u16 = 30; // mm
+    const MAX_DIST: u16 = 400; // mm
+
+    // Settings the sensor runs with, None for standby
+    let mut applied = None;
+    // Force the first pass to start the sensor
+    let mut started = false;
+    loop {
+        let settings = sensor_settings();
+        if settings != applied || !started {
+            started = true;
+            applied = settings;
+            if tof.stop_continuous().is_err() {
+                defmt::warn!("VL53L0X stop failed");
+            }
+            if let Some((period_ms, budget_us)) = settings {
+                if tof.set_measurement_timing_budget(budget_us).is_err()
+                    || tof.start_continuous(period_ms).is_err()
+                {
+                    defmt::warn!("VL53L0X configuration failed");
+                }
+                defmt::info!(
+                    "VL53L0X ranging every {} ms, {} us budget",
+                    period_ms,
+                    budget_us
+                );
+            } else {
+                defmt::info!("VL53L0X in standby");
+            }
+        }
+        if applied.is_none() {
+            Timer::after(STANDBY_POLL_INTERVAL).await;
+            continue;
+        }
+
+        // Wait for falling edge on GPIO1 (measurement ready)
+        int_pin.wait_for_falling_edge().await;
+
+        match tof.read_range_continuous_millimeters() {
+            Ok(distance) => {
+                if distance > 1500 {
+                    continue;
+                }
+                defmt::dbg!("VL53L0X: {} mm", distance);
+                let offset = distance.clamp(MIN_DIST, MAX_DIST).sub(MIN_DIST);
+                if let Some(position) = &sample_hold_position {
+                    // Quantized by the synth, read in the scan loop
+                    position.set_value(offset as f32 / (MAX_DIST - MIN_DIST) as f32);
+                    continue;
+                }
+                match &formant_position {
+                    // The synth smooths the steps between readings
+                    Some(position) => {
+                        position.set_value(offset as f32 * 4.0 / (MAX_DIST - MIN_DIST) as f32)
+                    }
+                    None => resonator_freq.set_value(offset.mul(4) as f32),
+                }
+            }
+            Err(_) => defmt::warn!("VL53L0X read failed"),
+        }
+    }
+}
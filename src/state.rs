@@ -0,0 +1,603 @@
+//! The full tunable parameter set as versioned bytes, the one serializer for
+//! the last-state slot in flash, SysEx patch dumps (`crate::sysex`) and a
+//! companion app or desktop editor talking to the synth over serial/USB.
+//!
+//! `SynthState` is a `Preset`, the core sound, plus every other sound
+//! parameter; `Preset::from_bytes` only reads the last state that older
+//! firmware left in flash. Layout:
+//!
+//! ```text
+//! 'S' <version> <payload length, u16 LE> <payload> <CRC-32 LE>
+//! ```
+//!
+//! The payload is the fields in `SynthState` order: enums and flags one
+//! byte (an optional one 0 for none, else its code plus one; the velocity
+//! curve adds the fixed velocity), numbers little-endian f32, arrays
+//! element by element. The CRC-32 covers everything before it. Fields are
+//! only ever appended, bumping `STATE_VERSION`, so a reader takes the
+//! fields that are there: those missing from an older state keep their
+//! defaults and trailing ones from a newer state are ignored.
+
+use crate::envelope::EnvCurve;
+use crate::keyboard::{
+    AutoPanMode, BEND_RANGE, CHORUS_MOD_FREQ, CHORUS_SEPARATION, CHORUS_VARIATION, CHORUS_VOICES,
+    DELAY_FEEDBACK, FX_ORDER, FxStage, GainMode, GlideCurve, LFO_RATE, LfoShape, NoteDivision,
+    NotePriority, OCTAVE_COUNT, PULSE_WIDTH, STACK_MAX, TEMPO, VELOCITY_FLOOR, VELOCITY_MAX,
+    VOICE_COUNT, VOICE_SPREAD_CENTS, VelocityCurve, Waveform,
+};
+use crate::midi::{CONTROL_TARGETS, CcMap};
+use crate::preset::{Preset, crc32, filter_slope_from_code, waveform_from_code};
+use heapless::Vec;
+
+/// Version written by `SynthState::to_bytes`
+pub const STATE_VERSION: u8 = 3;
+/// Largest serialized state, with room for parameters to come
+pub const STATE_BYTES_MAX: usize = 384;
+const STATE_MAGIC: u8 = b'S';
+/// Magic, version and payload length
+const STATE_HEADER: usize = 4;
+
+/// Every tunable sound parameter, see `KeyboardSynth::state`. Performance
+/// features (arpeggiator, note repeat, trance gate, drums, looper) and the
+/// keyboard setup aren't part of it.
+#[derive(Clone, Copy, PartialEq)]
+pub struct SynthState {
+    pub preset: Preset,
+    pub env_curve: EnvCurve,
+    /// Filter key tracking amount (0.0-1.0)
+    pub filter_keytrack: f32,
+    /// Overdrive amount (0.0-1.0)
+    pub drive: f32,
+    pub fx_order: [FxStage; FX_ORDER.len()],
+    pub formant: bool,
+    /// Formant vowel position, 0.0 (A) to 4.0 (U)
+    pub formant_position: f32,
+    pub chorus: bool,
+    pub delay: bool,
+    pub delay_pingpong: bool,
+    pub delay_feedback: f32,
+    /// Glide time in seconds
+    pub glide_time: f32,
+    pub glide_curve: GlideCurve,
+    pub lfo_shape: LfoShape,
+    /// Free-running LFO rate in Hz
+    pub lfo_rate: f32,
+    /// LFO depths in `LfoDestination` order, up to the pan (the pulse width
+    /// route came later, see `lfo_pulse_width`)
+    pub lfo_depths: [f32; 4],
+    /// Pitch envelope start offset in semitones and time in seconds
+    pub pitch_env_offset: f32,
+    pub pitch_env_time: f32,
+    /// Pitch wheel range in semitones
+    pub bend_range: f32,
+    pub octave_gains: [f32; OCTAVE_COUNT],
+    pub velocity_to_cutoff: f32,
+    pub velocity_to_attack: f32,
+    pub voice_highpass_tracking: f32,
+    pub analog_drift: f32,
+    pub stereo_width: f32,
+    pub mono_safe: bool,
+    pub auto_pan: AutoPanMode,
+    /// MIDI CC bindings, one byte per `ControlTarget` (since version 2)
+    pub cc_map: CcMap,
+    /// Chorus voice count, separation and variation in seconds and
+    /// modulation rate in Hz (this and the rest since version 3)
+    pub chorus_voices: u8,
+    pub chorus_separation: f32,
+    pub chorus_variation: f32,
+    pub chorus_mod_freq: f32,
+    pub velocity_curve: VelocityCurve,
+    pub velocity_floor: u8,
+    pub release_velocity: bool,
+    /// Per-voice detune spread in cents
+    pub voice_spread: f32,
+    /// Unison drift rate in Hz and depth in cents
+    pub unison_drift_rate: f32,
+    pub unison_drift_depth: f32,
+    /// Oscillator oversampling factor, 1 or 2
+    pub oversampling: u8,
+    pub phase_reset: bool,
+    pub click_suppression: bool,
+    pub gain_mode: GainMode,
+    /// Tempo in BPM
+    pub tempo: f32,
+    pub lfo_sync: Option<NoteDivision>,
+    pub lfo_retrigger: bool,
+    pub legato_filter_retrigger: bool,
+    pub mono: bool,
+    pub note_priority: NotePriority,
+    /// Depth of the `LfoDestination::PulseWidth` route
+    pub lfo_pulse_width: f32,
+    pub pulse_width: f32,
+    /// Second oscillator layered on the preset's waveform, and its level
+    pub osc_layer: Option<Waveform>,
+    pub osc_blend: f32,
+    /// Stacked intervals in semitones (the first `stack_len`) and their
+    /// detune in cents
+    pub stack_len: u8,
+    pub stack_intervals: [f32; STACK_MAX],
+    pub stack_detune: f32,
+    /// Cross-modulating voices (source, destination) and amount
+    pub cross_mod: Option<(usize, usize)>,
+    pub cross_mod_amount: f32,
+}
+
+impl Default for SynthState {
+    /// The settings of a new `KeyboardSynth`.
+    fn default() -> Self {
+        Self {
+            preset: Preset::default(),
+            env_curve: EnvCurve::Linear,
+            filter_keytrack: 0.0,
+            drive: 0.0,
+            fx_order: FX_ORDER,
+            formant: false,
+            formant_position: 0.0,
+            chorus: false,
+            delay: false,
+            delay_pingpong: false,
+            delay_feedback: DELAY_FEEDBACK,
+            glide_time: 0.0,
+            glide_curve: GlideCurve::default(),
+            lfo_shape: LfoShape::Sine,
+            lfo_rate: LFO_RATE,
+            lfo_depths: [0.0; 4],
+            pitch_env_offset: 0.0,
+            pitch_env_time: 0.0,
+            bend_range: BEND_RANGE,
+            octave_gains: [1.0; OCTAVE_COUNT],
+            velocity_to_cutoff: 0.0,
+            velocity_to_attack: 0.0,
+            voice_highpass_tracking: 0.0,
+            analog_drift: 0.0,
+            stereo_width: 1.0,
+            mono_safe: false,
+            auto_pan: AutoPanMode::Off,
+            cc_map: CcMap::default(),
+            chorus_voices: CHORUS_VOICES,
+            chorus_separation: CHORUS_SEPARATION,
+            chorus_variation: CHORUS_VARIATION,
+            chorus_mod_freq: CHORUS_MOD_FREQ,
+            velocity_curve: VelocityCurve::Linear,
+            velocity_floor: VELOCITY_FLOOR,
+            release_velocity: false,
+            voice_spread: VOICE_SPREAD_CENTS,
+            unison_drift_rate: 0.0,
+            unison_drift_depth: 0.0,
+            oversampling: 1,
+            phase_reset: false,
+            click_suppression: true,
+            gain_mode: GainMode::Fixed,
+            tempo: TEMPO,
+            lfo_sync: None,
+            lfo_retrigger: false,
+            legato_filter_retrigger: true,
+            mono: false,
+            note_priority: NotePriority::Last,
+            lfo_pulse_width: 0.0,
+            pulse_width: PULSE_WIDTH,
+            osc_layer: None,
+            osc_blend: 0.0,
+            stack_len: 0,
+            stack_intervals: [0.0; STACK_MAX],
+            stack_detune: 0.0,
+            cross_mod: None,
+            cross_mod_amount: 0.0,
+        }
+    }
+}
+
+impl SynthState {
+    /// Serialize as described in the module docs.
+    pub fn to_bytes(&self) -> Vec<u8, STATE_BYTES_MAX> {
+        let mut bytes = Vec::new();
+        // The fields are well under STATE_BYTES_MAX, so pushes can't fail
+        let mut put = |field: &[u8]| bytes.extend_from_slice(field).unwrap();
+        put(&[STATE_MAGIC, STATE_VERSION, 0, 0]);
+        let f32s = |put: &mut dyn FnMut(&[u8]), values: &[f32]| {
+            for value in values {
+                put(&value.to_le_bytes());
+            }
+        };
+
+        let preset = &self.preset;
+        put(&[preset.waveform as u8, preset.filter_slope as u8]);
+        f32s(
+            &mut put,
+            &[
+                preset.filter_cutoff,
+                preset.filter_resonance,
+                preset.resonator_freq,
+                preset.attack,
+                preset.decay,
+                preset.sustain,
+                preset.release,
+            ],
+        );
+        put(&[self.env_curve as u8]);
+        f32s(&mut put, &[self.filter_keytrack, self.drive]);
+        put(&self.fx_order.map(|stage| stage as u8));
+        put(&[self.formant as u8]);
+        f32s(&mut put, &[self.formant_position]);
+        put(&[
+            self.chorus as u8,
+            self.delay as u8,
+            self.delay_pingpong as u8,
+        ]);
+        f32s(&mut put, &[self.delay_feedback, self.glide_time]);
+        put(&[self.glide_curve as u8, self.lfo_shape as u8]);
+        f32s(&mut put, &[self.lfo_rate]);
+        f32s(&mut put, &self.lfo_depths);
+        f32s(
+            &mut put,
+            &[self.pitch_env_offset, self.pitch_env_time, self.bend_range],
+        );
+        f32s(&mut put, &self.octave_gains);
+        f32s(
+            &mut put,
+            &[
+                self.velocity_to_cutoff,
+                self.velocity_to_attack,
+                self.voice_highpass_tracking,
+                self.analog_drift,
+                self.stereo_width,
+            ],
+        );
+        put(&[self.mono_safe as u8, self.auto_pan as u8]);
+        put(&self.cc_map.codes());
+        put(&[self.chorus_voices]);
+        f32s(
+            &mut put,
+            &[
+                self.chorus_separation,
+                self.chorus_variation,
+                self.chorus_mod_freq,
+            ],
+        );
+        put(&match self.velocity_curve {
+            VelocityCurve::Linear => [0, 0],
+            VelocityCurve::Soft => [1, 0],
+            VelocityCurve::Hard => [2, 0],
+            VelocityCurve::Fixed(velocity) => [3, velocity],
+        });
+        put(&[self.velocity_floor, self.release_velocity as u8]);
+        f32s(
+            &mut put,
+            &[
+                self.voice_spread,
+                self.unison_drift_rate,
+                self.unison_drift_depth,
+            ],
+        );
+        put(&[
+            self.oversampling,
+            self.phase_reset as u8,
+            self.click_suppression as u8,
+            self.gain_mode as u8,
+        ]);
+        f32s(&mut put, &[self.tempo]);
+        put(&[
+            self.lfo_sync.map_or(0, |division| division as u8 + 1),
+            self.lfo_retrigger as u8,
+            self.legato_filter_retrigger as u8,
+            self.mono as u8,
+            self.note_priority as u8,
+        ]);
+        f32s(&mut put, &[self.lfo_pulse_width, self.pulse_width]);
+        put(&[self.osc_layer.map_or(0, |waveform| waveform as u8 + 1)]);
+        f32s(&mut put, &[self.osc_blend]);
+        put(&[self.stack_len]);
+        f32s(&mut put, &self.stack_intervals);
+        f32s(&mut put, &[self.stack_detune]);
+        // The same voice twice for no cross-mod
+        let (source, dest) = self.cross_mod.unwrap_or((0, 0));
+        put(&[source as u8, dest as u8]);
+        f32s(&mut put, &[self.cross_mod_amount]);
+
+        let payload = (bytes.len() - STATE_HEADER) as u16;
+        bytes[2..STATE_HEADER].copy_from_slice(&payload.to_le_bytes());
+        let crc = crc32(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes()).unwrap();
+        bytes
+    }
+
+    /// Parse bytes written by `to_bytes` of any version. Returns `None` for
+    /// a bad magic, length or CRC, or a field holding an invalid value.
+    pub fn from_bytes(bytes: &[u8]) -> Option<SynthState> {
+        let header: [u8; STATE_HEADER] = bytes.get(..STATE_HEADER)?.try_into().ok()?;
+        let end = STATE_HEADER + u16::from_le_bytes([header[2], header[3]]) as usize;
+        let crc = u32::from_le_bytes(bytes.get(end..end + 4)?.try_into().ok()?);
+        if header[0] != STATE_MAGIC || header[1] == 0 || crc32(&bytes[..end]) != crc {
+            return None;
+        }
+
+        let mut state = SynthState::default();
+        let mut fields = Fields(&bytes[STATE_HEADER..end]);
+        let preset = &mut state.preset;
+        fields.code(&mut preset.waveform, waveform_from_code)?;
+        fields.code(&mut preset.filter_slope, filter_slope_from_code)?;
+        fields.f32(&mut preset.filter_cutoff)?;
+        fields.f32(&mut preset.filter_resonance)?;
+        fields.f32(&mut preset.resonator_freq)?;
+        fields.f32(&mut preset.attack)?;
+        fields.f32(&mut preset.decay)?;
+        fields.f32(&mut preset.sustain)?;
+        fields.f32(&mut preset.release)?;
+        fields.code(&mut state.env_curve, |code| match code {
+            0 => Some(EnvCurve::Linear),
+            1 => Some(EnvCurve::Exponential),
+            2 => Some(EnvCurve::Logarithmic),
+            _ => None,
+        })?;
+        fields.f32(&mut state.filter_keytrack)?;
+        fields.f32(&mut state.drive)?;
+        let mut fx_order = state.fx_order;
+        for stage in &mut fx_order {
+            fields.code(stage, |code| match code {
+                0 => Some(FxStage::Drive),
+                1 => Some(FxStage::Filter),
+                2 => Some(FxStage::Resonator),
+                3 => Some(FxStage::Formant),
+                _ => None,
+            })?;
+        }
+        if !FX_ORDER.iter().all(|stage| fx_order.contains(stage)) {
+            return None;
+        }
+        state.fx_order = fx_order;
+        fields.flag(&mut state.formant)?;
+        fields.f32(&mut state.formant_position)?;
+        fields.flag(&mut state.chorus)?;
+        fields.flag(&mut state.delay)?;
+        fields.flag(&mut state.delay_pingpong)?;
+        fields.f32(&mut state.delay_feedback)?;
+        fields.f32(&mut state.glide_time)?;
+        fields.code(&mut state.glide_curve, |code| match code {
+            0 => Some(GlideCurve::Linear),
+            1 => Some(GlideCurve::Exponential),
+            _ => None,
+        })?;
+        fields.code(&mut state.lfo_shape, |code| match code {
+            0 => Some(LfoShape::Sine),
+            1 => Some(LfoShape::Triangle),
+            2 => Some(LfoShape::Square),
+            3 => Some(LfoShape::SampleHold),
+            4 => Some(LfoShape::Ramp),
+            _ => None,
+        })?;
+        fields.f32(&mut state.lfo_rate)?;
+        for depth in &mut state.lfo_depths {
+            fields.f32(depth)?;
+        }
+        fields.f32(&mut state.pitch_env_offset)?;
+        fields.f32(&mut state.pitch_env_time)?;
+        fields.f32(&mut state.bend_range)?;
+        for gain in &mut state.octave_gains {
+            fields.f32(gain)?;
+        }
+        fields.f32(&mut state.velocity_to_cutoff)?;
+        fields.f32(&mut state.velocity_to_attack)?;
+        fields.f32(&mut state.voice_highpass_tracking)?;
+        fields.f32(&mut state.analog_drift)?;
+        fields.f32(&mut state.stereo_width)?;
+        fields.flag(&mut state.mono_safe)?;
+        fields.code(&mut state.auto_pan, |code| match code {
+            0 => Some(AutoPanMode::Off),
+            1 => Some(AutoPanMode::ByVoice),
+            2 => Some(AutoPanMode::ByPitch),
+            _ => None,
+        })?;
+        if let Some(codes) = fields.take::<CONTROL_TARGETS>() {
+            state.cc_map = CcMap::from_codes(codes)?;
+        }
+        fields.code(&mut state.chorus_voices, Some)?;
+        fields.f32(&mut state.chorus_separation)?;
+        fields.f32(&mut state.chorus_variation)?;
+        fields.f32(&mut state.chorus_mod_freq)?;
+        if let Some([code, velocity]) = fields.take() {
+            state.velocity_curve = match code {
+                0 => VelocityCurve::Linear,
+                1 => VelocityCurve::Soft,
+                2 => VelocityCurve::Hard,
+                3 if (1..=VELOCITY_MAX).contains(&velocity) => VelocityCurve::Fixed(velocity),
+                _ => return None,
+            };
+        }
+        fields.code(&mut state.velocity_floor, Some)?;
+        fields.flag(&mut state.release_velocity)?;
+        fields.f32(&mut state.voice_spread)?;
+        fields.f32(&mut state.unison_drift_rate)?;
+        fields.f32(&mut state.unison_drift_depth)?;
+        fields.code(&mut state.oversampling, |code| {
+            (1..=2).contains(&code).then_some(code)
+        })?;
+        fields.flag(&mut state.phase_reset)?;
+        fields.flag(&mut state.click_suppression)?;
+        fields.code(&mut state.gain_mode, |code| match code {
+            0 => Some(GainMode::Fixed),
+            1 => Some(GainMode::Normalized),
+            _ => None,
+        })?;
+        fields.f32(&mut state.tempo)?;
+        fields.code(&mut state.lfo_sync, |code| match code {
+            0 => Some(None),
+            1 => Some(Some(NoteDivision::Quarter)),
+            2 => Some(Some(NoteDivision::Eighth)),
+            3 => Some(Some(NoteDivision::EighthTriplet)),
+            4 => Some(Some(NoteDivision::Sixteenth)),
+            _ => None,
+        })?;
+        fields.flag(&mut state.lfo_retrigger)?;
+        fields.flag(&mut state.legato_filter_retrigger)?;
+        fields.flag(&mut state.mono)?;
+        fields.code(&mut state.note_priority, |code| match code {
+            0 => Some(NotePriority::Last),
+            1 => Some(NotePriority::Low),
+            2 => Some(NotePriority::High),
+            _ => None,
+        })?;
+        fields.f32(&mut state.lfo_pulse_width)?;
+        fields.f32(&mut state.pulse_width)?;
+        fields.code(&mut state.osc_layer, |code| match code {
+            0 => Some(None),
+            code => waveform_from_code(code - 1).map(Some),
+        })?;
+        fields.f32(&mut state.osc_blend)?;
+        fields.code(&mut state.stack_len, |len| {
+            (len as usize <= STACK_MAX).then_some(len)
+        })?;
+        for interval in &mut state.stack_intervals {
+            fields.f32(interval)?;
+        }
+        fields.f32(&mut state.stack_detune)?;
+        if let Some([source, dest]) = fields.take() {
+            let (source, dest) = (source as usize, dest as usize);
+            if source >= VOICE_COUNT || dest >= VOICE_COUNT {
+                return None;
+            }
+            state.cross_mod = (source != dest).then_some((source, dest));
+        }
+        fields.f32(&mut state.cross_mod_amount)?;
+        Some(state)
+    }
+}
+
+/// Reads payload fields in order. A field past the end of the payload is
+/// missing and leaves the value as it is; an invalid one returns `None`.
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (field, rest) = self.0.split_first_chunk::<N>()?;
+        self.0 = rest;
+        Some(*field)
+    }
+
+    fn f32(&mut self, value: &mut f32) -> Option<()> {
+        if let Some(field) = self.take() {
+            *value = f32::from_le_bytes(field);
+            if !value.is_finite() {
+                return None;
+            }
+        }
+        Some(())
+    }
+
+    fn code<T>(&mut self, value: &mut T, decode: impl Fn(u8) -> Option<T>) -> Option<()> {
+        if let Some([code]) = self.take() {
+            *value = decode(code)?;
+        }
+        Some(())
+    }
+
+    fn flag(&mut self, value: &mut bool) -> Option<()> {
+        self.code(value, |code| match code {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::{FilterSlope, Waveform};
+    use crate::midi::ControlTarget;
+
+    /// `bytes` with the payload cut to `len` plus `extra`, length and CRC
+    /// redone: what an older or newer firmware would send
+    fn reframe(bytes: &[u8], len: usize, extra: &[u8]) -> alloc::vec::Vec<u8> {
+        let mut framed = bytes[..STATE_HEADER + len].to_vec();
+        framed.extend_from_slice(extra);
+        let payload = (framed.len() - STATE_HEADER) as u16;
+        framed[2..STATE_HEADER].copy_from_slice(&payload.to_le_bytes());
+        let crc = crc32(&framed);
+        framed.extend_from_slice(&crc.to_le_bytes());
+        framed
+    }
+
+    /// Payload bytes added in version 3
+    const V3_BYTES: usize = 66 + 4 * STACK_MAX;
+
+    #[test]
+    fn state_bytes_round_trip_across_versions() {
+        let mut cc_map = CcMap::default();
+        cc_map.bind(ControlTarget::FilterCutoff, Some(21));
+        cc_map.bind(ControlTarget::DelayFeedback, Some(0));
+        let state = SynthState {
+            preset: Preset {
+                waveform: Waveform::Square,
+                filter_slope: FilterSlope::Moog24,
+                filter_cutoff: 800.0,
+                ..Preset::default()
+            },
+            env_curve: EnvCurve::Logarithmic,
+            drive: 0.5,
+            fx_order: [
+                FxStage::Formant,
+                FxStage::Filter,
+                FxStage::Drive,
+                FxStage::Resonator,
+            ],
+            delay: true,
+            delay_pingpong: true,
+            lfo_shape: LfoShape::Ramp,
+            lfo_depths: [0.5, -2.0, 0.0, 1.0],
+            octave_gains: [1.5, 1.0, 0.5, 0.25],
+            stereo_width: 1.8,
+            auto_pan: AutoPanMode::ByPitch,
+            cc_map,
+            velocity_curve: VelocityCurve::Fixed(90),
+            lfo_sync: Some(NoteDivision::EighthTriplet),
+            note_priority: NotePriority::High,
+            osc_layer: Some(Waveform::Pulse),
+            osc_blend: 0.3,
+            stack_len: 2,
+            stack_intervals: [12.0, 7.0, 0.0, 0.0, 0.0, 0.0],
+            cross_mod: Some((2, 0)),
+            cross_mod_amount: 0.4,
+            ..SynthState::default()
+        };
+        let bytes = state.to_bytes();
+        assert!(SynthState::from_bytes(&bytes) == Some(state));
+
+        // An older state lacks the last fields, which keep their defaults
+        let old = reframe(&bytes, 2 + 7 * 4, &[]);
+        let parsed = SynthState::from_bytes(&old).unwrap();
+        assert!(parsed.preset == state.preset);
+        assert!(parsed.env_curve == EnvCurve::Linear && parsed.fx_order == FX_ORDER);
+        // Version 2, before the chorus settings and the rest
+        let payload = bytes.len() - STATE_HEADER - 4;
+        let v2 = reframe(&bytes, payload - V3_BYTES, &[]);
+        let parsed = SynthState::from_bytes(&v2).unwrap();
+        assert!(parsed.cc_map == cc_map && parsed.cross_mod.is_none());
+        assert!(parsed.velocity_curve == VelocityCurve::Linear);
+        // Version 1, before the CC map
+        let v1 = reframe(&bytes, payload - V3_BYTES - CONTROL_TARGETS, &[]);
+        let parsed = SynthState::from_bytes(&v1).unwrap();
+        assert!(parsed.auto_pan == AutoPanMode::ByPitch && parsed.cc_map == CcMap::default());
+        // A newer one has fields this firmware ignores
+        let new = reframe(&bytes, payload, &[1, 2, 3]);
+        assert!(SynthState::from_bytes(&new) == Some(state));
+
+        // Corruption, a short read and invalid fields fail
+        let mut corrupt = bytes.clone();
+        corrupt[12] ^= 0x10;
+        assert!(SynthState::from_bytes(&corrupt).is_none());
+        assert!(SynthState::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(SynthState::from_bytes(&reframe(&bytes, 0, &[9])).is_none());
+        let doubled = reframe(&bytes, 2 + 7 * 4 + 9, &[0, 0, 0, 0]);
+        assert!(SynthState::from_bytes(&doubled).is_none());
+        let bad_cc = reframe(
+            &bytes,
+            payload - V3_BYTES - CONTROL_TARGETS,
+            &[120; CONTROL_TARGETS],
+        );
+        assert!(SynthState::from_bytes(&bad_cc).is_none());
+        let v3 = payload - V3_BYTES;
+        let mut bad_curve = bytes[STATE_HEADER + v3..STATE_HEADER + v3 + 13].to_vec();
+        bad_curve.extend_from_slice(&[3, 0]);
+        assert!(SynthState::from_bytes(&reframe(&bytes, v3, &bad_curve)).is_none());
+    }
+}
@@ -0,0 +1,254 @@
+//! MIDI SysEx patch dump and load, for backing up and sharing sounds with a
+//! desktop patch librarian.
+//!
+//! Message layout (hex):
+//!
+//! ```text
+//! F0 7D 32 01 F7                  dump request
+//! F0 7D 32 02 <data> <cs> F7      patch dump (sent) / patch load (received)
+//! ```
+//!
+//! - `7D` is the MIDI non-commercial manufacturer ID, `32` identifies this
+//!   synth.
+//! - The data is the `SynthState::to_bytes` image (see `crate::state`), the
+//!   same bytes the last-state slot keeps in flash, with their own version
+//!   and CRC-32. It is packed 7 bytes to 8 for the 7-bit MIDI data range:
+//!   each group starts with a byte holding the high bits of the following
+//!   bytes (bit 6 for the first, bit 5 for the second, ...), then the bytes
+//!   with their high bit cleared. The last group is shorter.
+//! - `cs` is a Roland-style checksum: the data bytes plus `cs` sum to 0
+//!   modulo 128.
+//!
+//! `SysExParser` takes the incoming MIDI byte stream one byte at a time, so
+//! it sits next to whatever reads the MIDI port. A message cut short by
+//! another status byte, longer than any message of ours, or failing either
+//! checksum is dropped and the parser waits for the next `F0`; real-time
+//! bytes (clock, active sensing) may arrive in the middle of a message.
+//! `midi::MidiInput` runs it on the MIDI input and passes its messages to
+//! `KeyboardSynth::handle_sysex`, which answers a dump request and applies
+//! a received patch, after which the last-state auto-save stores it in
+//! flash like any other edit.
+
+use crate::state::{STATE_BYTES_MAX, SynthState};
+use alloc::boxed::Box;
+use heapless::Vec;
+
+/// MIDI non-commercial / educational manufacturer ID
+pub const SYSEX_MANUFACTURER: u8 = 0x7D;
+/// Device byte that marks messages for this synth
+pub const SYSEX_DEVICE: u8 = 0x32;
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const COMMAND_DUMP_REQUEST: u8 = 0x01;
+const COMMAND_PATCH: u8 = 0x02;
+
+/// Largest state packed into 7-bit data bytes
+const PACKED_MAX: usize = STATE_BYTES_MAX + STATE_BYTES_MAX.div_ceil(7);
+/// Longest complete patch dump message, `F0` to `F7`
+pub const PATCH_DUMP_BYTES_MAX: usize = PACKED_MAX + 6;
+/// Bytes kept between `F0` and `F7`: header, command, data, checksum
+const BODY_MAX: usize = PACKED_MAX + 4;
+
+/// A complete SysEx message for this synth.
+pub enum SysExMessage {
+    /// Send the current patch back with `patch_dump`
+    DumpRequest,
+    /// Load this patch
+    PatchLoad(Box<SynthState>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    /// Outside SysEx, waiting for `F0`
+    Idle,
+    /// Collecting a message body
+    Receiving,
+    /// Inside a SysEx that isn't ours (or is too long): wait for its end
+    Skipping,
+}
+
+/// Incremental parser for the synth's SysEx messages.
+pub struct SysExParser {
+    state: State,
+    body: [u8; BODY_MAX],
+    len: usize,
+}
+
+impl Default for SysExParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SysExParser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Idle,
+            body: [0; BODY_MAX],
+            len: 0,
+        }
+    }
+
+    /// Feed one byte from the MIDI input. Returns the message when a valid
+    /// one for this synth ends with this byte.
+    pub fn push(&mut self, byte: u8) -> Option<SysExMessage> {
+        match byte {
+            // Real-time messages can interleave with anything
+            0xF8..=0xFF => None,
+            SYSEX_START => {
+                self.state = State::Receiving;
+                self.len = 0;
+                None
+            }
+            SYSEX_END => {
+                let state = core::mem::replace(&mut self.state, State::Idle);
+                if state == State::Receiving {
+                    self.parse()
+                } else {
+                    None
+                }
+            }
+            // Any other status byte cuts the message short
+            0x80..=0xF6 => {
+                self.state = State::Idle;
+                None
+            }
+            _ => {
+                if self.state == State::Receiving {
+                    if self.len < BODY_MAX {
+                        self.body[self.len] = byte;
+                        self.len += 1;
+                    } else {
+                        self.state = State::Skipping;
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    fn parse(&self) -> Option<SysExMessage> {
+        match self.body[..self.len] {
+            [SYSEX_MANUFACTURER, SYSEX_DEVICE, COMMAND_DUMP_REQUEST] => {
+                Some(SysExMessage::DumpRequest)
+            }
+            [
+                SYSEX_MANUFACTURER,
+                SYSEX_DEVICE,
+                COMMAND_PATCH,
+                ref data @ ..,
+            ] if !data.is_empty() => {
+                if data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) & 0x7F != 0 {
+                    return None;
+                }
+                let state = SynthState::from_bytes(&unpack(&data[..data.len() - 1])?)?;
+                Some(SysExMessage::PatchLoad(Box::new(state)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The complete patch dump message for a synth state, `F0` to `F7`.
+pub fn patch_dump(state: &SynthState) -> Vec<u8, PATCH_DUMP_BYTES_MAX> {
+    let packed = pack(&state.to_bytes());
+    let sum = packed.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    let mut message = Vec::new();
+    // The packed state is at most PACKED_MAX, so the pushes can't fail
+    message
+        .extend_from_slice(&[SYSEX_START, SYSEX_MANUFACTURER, SYSEX_DEVICE, COMMAND_PATCH])
+        .unwrap();
+    message.extend_from_slice(&packed).unwrap();
+    message
+        .extend_from_slice(&[0u8.wrapping_sub(sum) & 0x7F, SYSEX_END])
+        .unwrap();
+    message
+}
+
+/// Spread 8-bit bytes over 7-bit data bytes, see the module docs.
+fn pack(bytes: &[u8]) -> Vec<u8, PACKED_MAX> {
+    let mut packed = Vec::new();
+    for chunk in bytes.chunks(7) {
+        let high = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |high, (i, &byte)| high | (byte >> 7) << (6 - i));
+        packed.push(high).unwrap();
+        for &byte in chunk {
+            packed.push(byte & 0x7F).unwrap();
+        }
+    }
+    packed
+}
+
+/// Undo `pack`. Returns `None` for data that unpacks to more than a state.
+fn unpack(packed: &[u8]) -> Option<Vec<u8, STATE_BYTES_MAX>> {
+    let mut bytes = Vec::new();
+    for group in packed.chunks(8) {
+        for (i, &byte) in group[1..].iter().enumerate() {
+            bytes.push(byte | ((group[0] >> (6 - i)) & 1) << 7).ok()?;
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::{FilterSlope, GainMode, Waveform};
+    use crate::preset::Preset;
+
+    fn feed(parser: &mut SysExParser, bytes: &[u8]) -> Option<SysExMessage> {
+        bytes
+            .iter()
+            .fold(None, |found, &b| parser.push(b).or(found))
+    }
+
+    #[test]
+    fn patch_dump_loads_back_and_bad_messages_are_dropped() {
+        let state = SynthState {
+            preset: Preset {
+                waveform: Waveform::Square,
+                filter_slope: FilterSlope::TwoPole12,
+                filter_cutoff: 3200.0,
+                attack: 0.02,
+                ..Preset::default()
+            },
+            pulse_width: 0.3,
+            gain_mode: GainMode::Normalized,
+            ..SynthState::default()
+        };
+        let dump = patch_dump(&state);
+        assert!(dump[1..dump.len() - 1].iter().all(|&b| b < 0x80));
+
+        let mut parser = SysExParser::new();
+        let loaded = match feed(&mut parser, &dump) {
+            Some(SysExMessage::PatchLoad(loaded)) => loaded,
+            _ => panic!("patch not loaded"),
+        };
+        assert!(*loaded == state);
+        assert!(matches!(
+            feed(&mut parser, &[0xF0, 0x7D, 0x32, 0x01, 0xF7]),
+            Some(SysExMessage::DumpRequest)
+        ));
+
+        // Truncated by a note-on, then a clock byte inside a good message
+        assert!(feed(&mut parser, &dump[..20]).is_none());
+        assert!(feed(&mut parser, &[0x90, 0x3C, 0x40]).is_none());
+        let mut with_clock = alloc::vec::Vec::from(&dump[..]);
+        with_clock.insert(10, 0xF8);
+        assert!(feed(&mut parser, &with_clock).is_some());
+
+        // Corrupt data, another device and an overlong message
+        let mut corrupt = dump.clone();
+        corrupt[10] ^= 0x01;
+        assert!(feed(&mut parser, &corrupt).is_none());
+        let mut other = dump.clone();
+        other[2] = 0x33;
+        assert!(feed(&mut parser, &other).is_none());
+        assert!(feed(&mut parser, &[0xF0; 1]).is_none());
+        assert!(feed(&mut parser, &[0x01; BODY_MAX + 1]).is_none());
+        assert!(parser.push(0xF7).is_none());
+        assert!(feed(&mut parser, &dump).is_some());
+    }
+}
@@ -0,0 +1,136 @@
+//! Trance gate: a tempo-synced step pattern of levels multiplying the
+//! synth's output, chopping held pads into a rhythm.
+//!
+//! Each step lasts one note division at the synth's tempo and sets the
+//! output level, 0.0 (closed) to 1.0 (open); the pattern loops. The level
+//! slews to each step's over `TRANCE_GATE_SMOOTHING`, so the edges stay
+//! sharp enough to read as a rhythm but don't click. The gate works per
+//! frame on the mixed voices, before the granular freeze, the looper and
+//! the drums, and costs a few operations per frame.
+
+use crate::keyboard::NoteDivision;
+use fundsp::DEFAULT_SR;
+
+/// Most steps in a pattern
+pub const TRANCE_GATE_STEPS: usize = 16;
+/// Time the level takes to move between a closed and an open step
+pub const TRANCE_GATE_SMOOTHING: f32 = 0.002;
+
+/// Step pattern, clock and level of the trance gate.
+pub struct TranceGate {
+    levels: [f32; TRANCE_GATE_STEPS],
+    len: usize,
+    division: NoteDivision,
+    enabled: bool,
+    /// Frames left open before the first step
+    lead_in: f32,
+    /// Step playing
+    step: usize,
+    /// Frames left in `step`
+    until_step: f32,
+    /// Frames per step
+    step_frames: f32,
+    /// Current output level, slewing to the step's
+    level: f32,
+    /// Largest level change per frame
+    slew: f32,
+}
+
+impl Default for TranceGate {
+    fn default() -> Self {
+        let mut gate = Self {
+            levels: [1.0; TRANCE_GATE_STEPS],
+            len: TRANCE_GATE_STEPS,
+            division: NoteDivision::Sixteenth,
+            enabled: false,
+            lead_in: 0.0,
+            step: 0,
+            until_step: 0.0,
+            step_frames: 0.0,
+            level: 1.0,
+            slew: 1.0 / (TRANCE_GATE_SMOOTHING * DEFAULT_SR as f32),
+        };
+        gate.set_tempo(crate::keyboard::TEMPO);
+        gate
+    }
+}
+
+impl TranceGate {
+    /// Replace the pattern with `levels` (each clamped to 0.0-1.0), up to
+    /// `TRANCE_GATE_STEPS` of them. An empty pattern leaves the gate open.
+    pub fn set_pattern(&mut self, levels: &[f32]) {
+        self.len = core::cmp::min(levels.len(), TRANCE_GATE_STEPS);
+        for (step, level) in levels.iter().take(TRANCE_GATE_STEPS).enumerate() {
+            self.levels[step] = level.clamp(0.0, 1.0);
+        }
+        if self.step >= self.len {
+            self.step = 0;
+        }
+    }
+
+    /// Set one step's level (0.0-1.0) of the pattern, live. Steps past the
+    /// pattern's length are ignored.
+    pub fn set_step(&mut self, step: usize, level: f32) {
+        if step < self.len {
+            self.levels[step] = level.clamp(0.0, 1.0);
+        }
+    }
+
+    pub fn step(&self, step: usize) -> Option<f32> {
+        (step < self.len).then(|| self.levels[step])
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Step length from the tempo in BPM and the division.
+    pub(crate) fn set_tempo(&mut self, bpm: f32) {
+        self.step_frames = DEFAULT_SR as f32 * 60.0 / (bpm * self.division.per_beat());
+    }
+
+    /// Set the note division of a step, at the tempo `bpm`.
+    pub(crate) fn set_division(&mut self, division: NoteDivision, bpm: f32) {
+        self.division = division;
+        self.set_tempo(bpm);
+    }
+
+    /// Start the pattern from step 0 in `delay` frames (open until then),
+    /// or stop gating; the level slews back to open either way.
+    pub(crate) fn set_enabled(&mut self, on: bool, delay: usize) {
+        if on && !self.enabled {
+            self.lead_in = delay as f32;
+            self.step = 0;
+            self.until_step = self.step_frames;
+        }
+        self.enabled = on;
+    }
+
+    /// Advance one frame and return the output gain.
+    #[inline]
+    pub fn process(&mut self) -> f32 {
+        let target = if !self.enabled || self.len == 0 {
+            1.0
+        } else if self.lead_in > 0.0 {
+            self.lead_in -= 1.0;
+            1.0
+        } else {
+            self.until_step -= 1.0;
+            if self.until_step < 0.0 {
+                self.step = (self.step + 1) % self.len;
+                self.until_step += self.step_frames;
+            }
+            self.levels[self.step]
+        };
+        self.level += (target - self.level).clamp(-self.slew, self.slew);
+        self.level
+    }
+}